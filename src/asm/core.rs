@@ -27,14 +27,18 @@
 //! user defined functions in the core assembly language simply
 //! using `Put`, and assuming-standard out, to display the integer in decimal.
 use super::{
+    check_call_stack_size,
     location::{FP_STACK, TMP},
-    AssemblyProgram, Env, Error, Location, StandardOp, FP, GP, SP, STACK_START, START_OF_FP_STACK,
+    scratch, AssemblyProgram, Env, Error, Location, StandardOp, FP, GP, SP, STACK_START,
 };
 use crate::{
     side_effects::{Input, InputMode, Output, OutputMode},
     vm::{self, VirtualMachineProgram},
 };
-use std::{collections::BTreeSet, fmt};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
 
 use log::{info, trace};
 
@@ -48,6 +52,11 @@ pub struct CoreProgram {
     /// that has been defined so far. This helps the LIR compiler
     /// determine if a function has been compiled yet or not.
     labels: BTreeSet<String>,
+    /// The names of the functions known to the front-end of the compiler,
+    /// by their labels. These are only used for debugging.
+    pub(super) names: BTreeMap<String, String>,
+    /// The number of scratch registers reserved after the fixed registers.
+    pub(super) scratch_registers: usize,
 }
 
 /// A default program is an empty program.
@@ -66,7 +75,26 @@ impl CoreProgram {
                 labels.insert(label.clone());
             }
         }
-        Self { code, labels }
+        Self {
+            code,
+            labels,
+            names: BTreeMap::new(),
+            scratch_registers: 0,
+        }
+    }
+
+    /// Remove all of the comments from the program.
+    pub fn strip_comments(self) -> Self {
+        Self {
+            code: self
+                .code
+                .into_iter()
+                .filter(|op| !matches!(op, CoreOp::Comment(_)))
+                .collect(),
+            labels: self.labels,
+            names: self.names,
+            scratch_registers: self.scratch_registers,
+        }
     }
 
     /// Get the size of the globals in the program.
@@ -90,6 +118,7 @@ impl CoreProgram {
         let mut result = vm::CoreProgram(vec![]);
         // Create the environment in which to assemble the program.
         let mut env = Env::default();
+        check_call_stack_size(self.code.iter().map(Some), allowed_recursion_depth)?;
 
         // Get the size of the globals
         let size_of_globals = self.get_size_of_globals(&mut env)?;
@@ -97,14 +126,15 @@ impl CoreProgram {
         // Create the bootstrap code.
         result.comment("BEGIN BOOTSTRAP");
 
-        // Create the stack of frame pointers starting directly after the last register
-        // let start_of_fp_stack = F.offset(1);
-        START_OF_FP_STACK.copy_address_to(&FP_STACK, &mut result);
+        // Create the stack of frame pointers starting directly after the last register,
+        // including the scratch registers reserved by the program.
+        let start_of_fp_stack = scratch(self.scratch_registers);
+        start_of_fp_stack.copy_address_to(&FP_STACK, &mut result);
         info!(
-            "Frame pointer stack begins at {START_OF_FP_STACK:?}, and is {} cells long.",
+            "Frame pointer stack begins at {start_of_fp_stack:?}, and is {} cells long.",
             allowed_recursion_depth
         );
-        let end_of_fp_stack = START_OF_FP_STACK.offset(allowed_recursion_depth as isize);
+        let end_of_fp_stack = start_of_fp_stack.offset(allowed_recursion_depth as isize);
 
         // Copy the address just after the allocated space to the global pointer.
         let starting_gp_addr = end_of_fp_stack;
@@ -139,9 +169,13 @@ impl CoreProgram {
     }
 }
 
+/// Nested blocks are indented by the width of the formatter in spaces, or three
+/// spaces by default, so a width of zero prints every instruction without indentation.
+/// The alternate form `{:#}` also prints the address of every instruction.
 impl fmt::Display for CoreProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut indent = 0;
+        let tab = " ".repeat(f.width().unwrap_or(3));
         let mut comment_count = 0;
         for (i, op) in self.code.iter().enumerate() {
             if f.alternate() {
@@ -150,7 +184,7 @@ impl fmt::Display for CoreProgram {
                         write!(f, "{:8}  ", "")?;
                     }
                     comment_count += 1;
-                    writeln!(f, "{}// {}", "   ".repeat(indent), comment,)?;
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment,)?;
                     continue;
                 }
                 write!(f, "{:04x?}: ", i - comment_count)?;
@@ -164,16 +198,16 @@ impl fmt::Display for CoreProgram {
                 match op {
                     CoreOp::Fn(_) | CoreOp::If(_) | CoreOp::While(_) => {
                         indent += 1;
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     CoreOp::Else => {
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     CoreOp::End => {
                         indent -= 1;
-                        "   ".repeat(indent)
+                        tab.repeat(indent)
                     }
-                    _ => "   ".repeat(indent),
+                    _ => tab.repeat(indent),
                 },
                 op
             )?
@@ -199,6 +233,14 @@ impl AssemblyProgram for CoreProgram {
         self.labels.contains(label)
     }
 
+    fn name_label(&mut self, label: &str, name: &str) {
+        self.names.insert(label.to_string(), name.to_string());
+    }
+
+    fn reserve_registers(&mut self, count: usize) {
+        self.scratch_registers = self.scratch_registers.max(count);
+    }
+
     fn current_instruction(&self) -> usize {
         self.code.len()
     }
@@ -281,6 +323,25 @@ pub enum CoreOp {
         size: usize,
     },
 
+    /// Copy a number of cells from the address stored in a source location to
+    /// the address stored in a destination location, using a loop instead of
+    /// unrolling the copy like `Copy`.
+    ///
+    /// `memcpy(*dst, *src, size)`
+    CopyRange {
+        src: Location,
+        dst: Location,
+        size: usize,
+    },
+
+    /// Set a number of cells, starting at the address stored in a destination
+    /// location, to a constant value.
+    FillRange {
+        dst: Location,
+        value: i64,
+        size: usize,
+    },
+
     /// Swap the values of two locations.
     Swap(Location, Location),
 
@@ -328,6 +389,11 @@ pub enum CoreOp {
         src: Location,
         dst: Location,
     },
+    /// Raise a destination location to the integer power of a source value.
+    Pow {
+        src: Location,
+        dst: Location,
+    },
     /// Divide a destination location by a source value.
     /// Store the quotient in the destination, and the remainder in the source.
     DivRem {
@@ -336,6 +402,16 @@ pub enum CoreOp {
     },
     /// Negate an integer.
     Neg(Location),
+    /// Store the minimum of a destination and a source value in the destination.
+    Min {
+        src: Location,
+        dst: Location,
+    },
+    /// Store the maximum of a destination and a source value in the destination.
+    Max {
+        src: Location,
+        dst: Location,
+    },
 
     /// Replace a value in memory with its boolean complement.
     Not(Location),
@@ -448,6 +524,22 @@ pub enum CoreOp {
         dst: Location,
     },
     BitwiseNot(Location),
+
+    /// Shift a destination location left by the number of bits in a source location.
+    ShiftLeft {
+        src: Location,
+        dst: Location,
+    },
+    /// Arithmetic shift a destination location right by the number of bits in a source location.
+    ShiftRight {
+        src: Location,
+        dst: Location,
+    },
+    /// Logical shift a destination location right by the number of bits in a source location.
+    LogicalShiftRight {
+        src: Location,
+        dst: Location,
+    },
 }
 
 impl CoreOp {
@@ -673,13 +765,14 @@ impl CoreOp {
                 src.copy_to(&dst, result)
             }
 
-            CoreOp::Swap(a, b) => {
-                let a = env.resolve(a)?;
-                let b = env.resolve(b)?;
-                a.copy_to(&TMP, result);
-                b.copy_to(&a, result);
-                TMP.copy_to(&b, result);
+            CoreOp::CopyRange { src, dst, size } => {
+                env.resolve(src)?
+                    .copy_range(&env.resolve(dst)?, *size, result)
+            }
+            CoreOp::FillRange { dst, value, size } => {
+                env.resolve(dst)?.fill_range(*value, *size, result)
             }
+            CoreOp::Swap(a, b) => env.resolve(a)?.swap(&env.resolve(b)?, result),
 
             CoreOp::Inc(dst) => env.resolve(dst)?.inc(result),
             CoreOp::Dec(dst) => env.resolve(dst)?.dec(result),
@@ -689,6 +782,7 @@ impl CoreOp {
             CoreOp::Mul { src, dst } => env.resolve(dst)?.mul(src, result),
             CoreOp::Div { src, dst } => env.resolve(dst)?.div(src, result),
             CoreOp::Rem { src, dst } => env.resolve(dst)?.rem(src, result),
+            CoreOp::Pow { src, dst } => env.resolve(dst)?.pow(&env.resolve(src)?, result),
             CoreOp::DivRem { src, dst } => {
                 let src = env.resolve(src)?;
                 let dst = env.resolve(dst)?;
@@ -698,14 +792,13 @@ impl CoreOp {
                 dst.div(&TMP, result);
                 src.rem(&TMP, result);
             }
-            CoreOp::Neg(dst) => {
-                let dst = env.resolve(dst)?;
-
-                result.set_register(-1);
-                dst.to(result);
-                result.op(vm::CoreOp::Mul);
-                result.save();
-                dst.from(result)
+            CoreOp::Neg(dst) => env.resolve(dst)?.neg(result),
+            // Call these explicitly, so they aren't mistaken for `Ord::min` and `Ord::max`.
+            CoreOp::Min { src, dst } => {
+                Location::min(&env.resolve(dst)?, &env.resolve(src)?, result)
+            }
+            CoreOp::Max { src, dst } => {
+                Location::max(&env.resolve(dst)?, &env.resolve(src)?, result)
             }
 
             Self::BitwiseNand { src, dst } => {
@@ -715,33 +808,10 @@ impl CoreOp {
                 dst.bitwise_nand(&src, result);
             }
             Self::BitwiseXor { src, dst } => {
-                let src = env.resolve(src)?;
-                let dst = env.resolve(dst)?;
-
-                src.copy_to(&TMP, result);
-                TMP.bitwise_nand(&dst, result);
-                TMP.bitwise_nand(&dst, result);
-                dst.bitwise_nand(&src, result);
-                dst.bitwise_nand(&src, result);
-                dst.bitwise_nand(&TMP, result);
+                env.resolve(dst)?.bitwise_xor(&env.resolve(src)?, result)
             }
             Self::BitwiseOr { src, dst } => {
-                let src = env.resolve(src)?;
-                let dst = env.resolve(dst)?;
-
-                dst.to(result);
-                result.restore();
-                result.bitwise_nand();
-                result.save();
-                dst.from(result);
-                src.to(result);
-                result.restore();
-                result.bitwise_nand();
-                src.from(result);
-                dst.to(result);
-                result.bitwise_nand();
-                result.save();
-                dst.from(result);
+                env.resolve(dst)?.bitwise_or(&env.resolve(src)?, result)
             }
             Self::BitwiseNor { src, dst } => {
                 let src = env.resolve(src)?;
@@ -761,26 +831,18 @@ impl CoreOp {
                 dst.from(result);
             }
             Self::BitwiseAnd { src, dst } => {
-                let src = env.resolve(src)?;
-                let dst = env.resolve(dst)?;
-
-                src.restore_from(result);
-                dst.to(result);
-                result.bitwise_nand();
-                result.save();
-                result.bitwise_nand();
-                result.save();
-                dst.from(result);
+                env.resolve(dst)?.bitwise_and(&env.resolve(src)?, result)
             }
-            Self::BitwiseNot(dst) => {
-                let dst = env.resolve(dst)?;
-
-                dst.to(result);
-                result.restore();
-                result.bitwise_nand();
-                result.save();
-                dst.from(result);
+            Self::BitwiseNot(dst) => env.resolve(dst)?.bitwise_not(result),
+            Self::ShiftLeft { src, dst } => {
+                env.resolve(dst)?.shift_left(&env.resolve(src)?, result)
+            }
+            Self::ShiftRight { src, dst } => {
+                env.resolve(dst)?.shift_right(&env.resolve(src)?, result)
             }
+            Self::LogicalShiftRight { src, dst } => env
+                .resolve(dst)?
+                .shift_right_logical(&env.resolve(src)?, result),
 
             CoreOp::Not(dst) => env.resolve(dst)?.not(result),
             CoreOp::And { src, dst } => env.resolve(dst)?.and(&env.resolve(src)?, result),
@@ -873,23 +935,10 @@ impl CoreOp {
             }
 
             CoreOp::Compare { dst, a, b } => {
-                let dst = &env.resolve(dst)?;
-                let a = &env.resolve(a)?;
-                let b = &env.resolve(b)?;
-                a.is_greater_than(b, dst, result);
-                dst.restore_from(result);
-                result.begin_if();
-                result.set_register(1);
-                result.begin_else();
-                a.is_less_than(b, dst, result);
-                dst.restore_from(result);
-                result.begin_if();
-                result.set_register(-1);
-                result.begin_else();
-                result.set_register(0);
-                result.end();
-                result.end();
-                dst.save_to(result);
+                let dst = env.resolve(dst)?;
+                let a = env.resolve(a)?;
+                let b = env.resolve(b)?;
+                a.compare(&b, &dst, result)
             }
 
             CoreOp::Get(dst, input) => {
@@ -980,6 +1029,8 @@ impl fmt::Display for CoreOp {
 
             Self::Move { src, dst } => write!(f, "mov {src}, {dst}"),
             Self::Copy { src, dst, size } => write!(f, "copy {src}, {dst}, {size}"),
+            Self::CopyRange { src, dst, size } => write!(f, "copy-range {src}, {dst}, {size}"),
+            Self::FillRange { dst, value, size } => write!(f, "fill-range {dst}, {value}, {size}"),
             Self::Swap(a, b) => write!(f, "swap {a}, {b}"),
             Self::Next(loc, size) => {
                 write!(f, "next {loc}")?;
@@ -1010,6 +1061,11 @@ impl fmt::Display for CoreOp {
             Self::BitwiseOr { src, dst } => write!(f, "bitwise-or {src}, {dst}"),
             Self::BitwiseNor { src, dst } => write!(f, "bitwise-nor {src}, {dst}"),
             Self::BitwiseNot(loc) => write!(f, "bitwise-not {loc}"),
+            Self::ShiftLeft { src, dst } => write!(f, "shift-left {src}, {dst}"),
+            Self::ShiftRight { src, dst } => write!(f, "shift-right {src}, {dst}"),
+            Self::LogicalShiftRight { src, dst } => {
+                write!(f, "logical-shift-right {src}, {dst}")
+            }
 
             Self::And { src, dst } => write!(f, "and {src}, {dst}"),
             Self::Or { src, dst } => write!(f, "or {src}, {dst}"),
@@ -1020,8 +1076,11 @@ impl fmt::Display for CoreOp {
             Self::Mul { src, dst } => write!(f, "mul {src}, {dst}"),
             Self::Div { src, dst } => write!(f, "div {src}, {dst}"),
             Self::Rem { src, dst } => write!(f, "rem {src}, {dst}"),
+            Self::Pow { src, dst } => write!(f, "pow-int {src}, {dst}"),
             Self::DivRem { src, dst } => write!(f, "div-rem {src}, {dst}"),
             Self::Neg(loc) => write!(f, "neg {loc}"),
+            Self::Min { src, dst } => write!(f, "min {src}, {dst}"),
+            Self::Max { src, dst } => write!(f, "max {src}, {dst}"),
 
             Self::Array { src, vals, dst } => write!(f, "array {src}, {vals:?}, {dst}"),
 
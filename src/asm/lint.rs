@@ -0,0 +1,305 @@
+//! # Pointer Lint
+//!
+//! This module checks assembly programs for integer arithmetic on pointers.
+//! As the [memory model](../location) explains, the size of a cell is up to each
+//! virtual machine implementation, so moving a pointer with `inc`, `dec`, `add`,
+//! or `sub` only works on the implementations which use indices for pointers.
+//! Portable code has to use `next`, `prev`, or `index` instead.
+//!
+//! The lint follows which locations hold a pointer (stored there by `lea`, `index`,
+//! `next`, `prev`, `alloc`, or an array), as they're moved, pushed, and popped, and
+//! reports each instruction which does arithmetic with one of them.
+//!
+//! This is a heuristic, not a proof. The lint reads the program from top to bottom
+//! without following branches, and it forgets every pointer at the start of a
+//! function and at each call, since it can't tell what the callee changed.
+//! So it can miss some misuses, but a reported instruction is almost always wrong.
+use super::{CoreOp, CoreProgram, Location, StandardOp, StandardProgram, SP};
+use core::fmt;
+use std::collections::HashSet;
+
+/// A portability problem found by the lint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lint {
+    /// The index of the instruction in the program. Comments aren't counted,
+    /// so this matches the addresses printed in the debug listing of the program.
+    pub index: usize,
+    /// The instruction, as assembly code.
+    pub op: String,
+    /// What's wrong with the instruction.
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "instruction #{} (`{}`): {}",
+            self.index, self.op, self.message
+        )
+    }
+}
+
+impl CoreProgram {
+    /// Find the instructions which move a pointer with integer arithmetic,
+    /// instead of with `next`, `prev`, or `index`.
+    pub fn lint(&self) -> Vec<Lint> {
+        let mut linter = Linter::default();
+        for (index, op) in self
+            .code
+            .iter()
+            .filter(|op| !matches!(op, CoreOp::Comment(_)))
+            .enumerate()
+        {
+            linter.core_op(op, &mut |message| Lint {
+                index,
+                op: op.to_string(),
+                message,
+            });
+        }
+        linter.lints
+    }
+}
+
+impl StandardProgram {
+    /// Find the instructions which move a pointer with integer arithmetic,
+    /// instead of with `next`, `prev`, or `index`.
+    pub fn lint(&self) -> Vec<Lint> {
+        let mut linter = Linter::default();
+        for (index, op) in self
+            .code
+            .iter()
+            .filter(|op| !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))))
+            .enumerate()
+        {
+            linter.std_op(op, &mut |message| Lint {
+                index,
+                op: op.to_string(),
+                message,
+            });
+        }
+        linter.lints
+    }
+}
+
+/// Is this location found by dereferencing the value stored at `base`?
+/// Such a location is a different cell once `base` is overwritten.
+fn depends_on(loc: &Location, base: &Location) -> bool {
+    match loc {
+        Location::Indirect(inner) => inner.as_ref() == base || depends_on(inner, base),
+        Location::Offset(inner, _) => depends_on(inner, base),
+        Location::Address(_) | Location::Global(_) => false,
+    }
+}
+
+/// The state of the lint as it reads through a program.
+#[derive(Default)]
+struct Linter {
+    /// The locations known to hold a pointer.
+    pointers: HashSet<Location>,
+    /// The problems found so far.
+    lints: Vec<Lint>,
+}
+
+impl Linter {
+    fn is_pointer(&self, loc: &Location) -> bool {
+        self.pointers.contains(loc)
+    }
+
+    /// Forget the locations found through the value at `loc`, after it's overwritten.
+    fn forget_dependents(&mut self, loc: &Location) {
+        self.pointers.retain(|p| !depends_on(p, loc));
+    }
+
+    /// Record that a value was stored at a location, which may or may not be a pointer.
+    fn store(&mut self, loc: &Location, is_pointer: bool) {
+        self.forget_dependents(loc);
+        if is_pointer {
+            self.pointers.insert(loc.clone());
+        } else {
+            self.pointers.remove(loc);
+        }
+    }
+
+    /// Check an arithmetic instruction which adds or subtracts `src` to or from `dst`.
+    fn arithmetic(
+        &mut self,
+        name: &str,
+        src: &Location,
+        dst: &Location,
+        lint: &mut impl FnMut(String) -> Lint,
+    ) {
+        if self.is_pointer(dst) {
+            self.lints.push(lint(format!(
+                "`{name}` moves the pointer in {dst} by an integer, which depends on the size of a cell; use `index`, `next`, or `prev` instead"
+            )));
+        } else if self.is_pointer(src) {
+            self.lints.push(lint(format!(
+                "`{name}` uses the pointer in {src} as an integer, which depends on the size of a cell"
+            )));
+        }
+    }
+
+    /// Check an instruction which increments or decrements a location.
+    fn step(
+        &mut self,
+        name: &str,
+        instead: &str,
+        loc: &Location,
+        lint: &mut impl FnMut(String) -> Lint,
+    ) {
+        if self.is_pointer(loc) {
+            self.lints.push(lint(format!(
+                "`{name}` moves the pointer in {loc} by one integer, which depends on the size of a cell; use `{instead}` instead"
+            )));
+        }
+    }
+
+    fn core_op(&mut self, op: &CoreOp, lint: &mut impl FnMut(String) -> Lint) {
+        match op {
+            CoreOp::Many(ops) => {
+                for op in ops {
+                    self.core_op(op, lint);
+                }
+            }
+            CoreOp::Comment(_)
+            | CoreOp::Global { .. }
+            | CoreOp::While(_)
+            | CoreOp::If(_)
+            | CoreOp::Else
+            | CoreOp::End
+            | CoreOp::Put(..)
+            | CoreOp::CopyRange { .. }
+            | CoreOp::FillRange { .. } => {}
+
+            // We can't tell what's stored anywhere after a call, or in a new function.
+            CoreOp::Fn(_) | CoreOp::Call(_) | CoreOp::CallLabel(_) | CoreOp::Return => {
+                self.pointers.clear()
+            }
+
+            // The instructions which store a pointer.
+            CoreOp::GetAddress { dst, .. } | CoreOp::Index { dst, .. } => self.store(dst, true),
+            CoreOp::Next(loc, _) | CoreOp::Prev(loc, _) => self.store(loc, true),
+            CoreOp::Array { src, dst, vals } => {
+                for i in 0..vals.len() {
+                    self.store(&src.offset(i as isize), false);
+                }
+                self.store(dst, true);
+            }
+
+            // The instructions which copy values, which may be pointers.
+            CoreOp::Move { src, dst } => self.store(dst, self.is_pointer(src)),
+            CoreOp::Copy { src, dst, size } => {
+                for i in 0..*size as isize {
+                    self.store(&dst.offset(i), self.is_pointer(&src.offset(i)));
+                }
+            }
+            CoreOp::Swap(a, b) => {
+                let (a_is_pointer, b_is_pointer) = (self.is_pointer(a), self.is_pointer(b));
+                self.store(a, b_is_pointer);
+                self.store(b, a_is_pointer);
+            }
+            CoreOp::Push(src, size) => {
+                let pushed = (0..*size as isize)
+                    .map(|i| self.is_pointer(&src.offset(i)))
+                    .collect::<Vec<_>>();
+                self.forget_dependents(&SP);
+                for (i, is_pointer) in pushed.into_iter().enumerate() {
+                    self.store(
+                        &SP.deref().offset(i as isize + 1 - *size as isize),
+                        is_pointer,
+                    );
+                }
+            }
+            CoreOp::Pop(dst, size) => {
+                let popped = (0..*size as isize)
+                    .map(|i| self.is_pointer(&SP.deref().offset(i + 1 - *size as isize)))
+                    .collect::<Vec<_>>();
+                self.forget_dependents(&SP);
+                if let Some(dst) = dst {
+                    for (i, is_pointer) in popped.into_iter().enumerate() {
+                        self.store(&dst.offset(i as isize), is_pointer);
+                    }
+                }
+            }
+            CoreOp::PushTo { sp, .. } => self.forget_dependents(sp),
+            CoreOp::PopFrom { sp, dst, size } => {
+                self.forget_dependents(sp);
+                if let Some(dst) = dst {
+                    for i in 0..*size as isize {
+                        self.store(&dst.offset(i), false);
+                    }
+                }
+            }
+
+            // The instructions which do integer arithmetic with a pointer.
+            CoreOp::Inc(loc) => self.step("inc", "next", loc, lint),
+            CoreOp::Dec(loc) => self.step("dec", "prev", loc, lint),
+            CoreOp::Add { src, dst } => self.arithmetic("add", src, dst, lint),
+            CoreOp::Sub { src, dst } => self.arithmetic("sub", src, dst, lint),
+
+            // The rest of the instructions store an integer.
+            CoreOp::DivRem { src, dst } => {
+                self.store(src, false);
+                self.store(dst, false);
+            }
+            CoreOp::Set(dst, _)
+            | CoreOp::SetLabel(dst, _)
+            | CoreOp::Get(dst, _)
+            | CoreOp::Neg(dst)
+            | CoreOp::Not(dst)
+            | CoreOp::BitwiseNot(dst)
+            | CoreOp::Mul { dst, .. }
+            | CoreOp::Div { dst, .. }
+            | CoreOp::Rem { dst, .. }
+            | CoreOp::Pow { dst, .. }
+            | CoreOp::Min { dst, .. }
+            | CoreOp::Max { dst, .. }
+            | CoreOp::And { dst, .. }
+            | CoreOp::Or { dst, .. }
+            | CoreOp::Compare { dst, .. }
+            | CoreOp::IsGreater { dst, .. }
+            | CoreOp::IsGreaterEqual { dst, .. }
+            | CoreOp::IsLess { dst, .. }
+            | CoreOp::IsLessEqual { dst, .. }
+            | CoreOp::IsEqual { dst, .. }
+            | CoreOp::IsNotEqual { dst, .. }
+            | CoreOp::BitwiseNand { dst, .. }
+            | CoreOp::BitwiseXor { dst, .. }
+            | CoreOp::BitwiseOr { dst, .. }
+            | CoreOp::BitwiseNor { dst, .. }
+            | CoreOp::BitwiseAnd { dst, .. }
+            | CoreOp::ShiftLeft { dst, .. }
+            | CoreOp::ShiftRight { dst, .. }
+            | CoreOp::LogicalShiftRight { dst, .. } => self.store(dst, false),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp, lint: &mut impl FnMut(String) -> Lint) {
+        match op {
+            StandardOp::CoreOp(op) => self.core_op(op, lint),
+            StandardOp::Alloc(loc) => self.store(loc, true),
+            StandardOp::Call(_) => self.pointers.clear(),
+            StandardOp::Add { src, dst } => self.arithmetic("add-f", src, dst, lint),
+            StandardOp::Sub { src, dst } => self.arithmetic("sub-f", src, dst, lint),
+            StandardOp::Free(dst)
+            | StandardOp::Set(dst, _)
+            | StandardOp::ToFloat(dst)
+            | StandardOp::ToInt(dst)
+            | StandardOp::Sqrt(dst)
+            | StandardOp::Neg(dst)
+            | StandardOp::Sin(dst)
+            | StandardOp::Cos(dst)
+            | StandardOp::Tan(dst)
+            | StandardOp::ASin(dst)
+            | StandardOp::ACos(dst)
+            | StandardOp::ATan(dst)
+            | StandardOp::Pow { dst, .. }
+            | StandardOp::Mul { dst, .. }
+            | StandardOp::Div { dst, .. }
+            | StandardOp::Rem { dst, .. }
+            | StandardOp::IsGreater { dst, .. }
+            | StandardOp::IsLess { dst, .. } => self.store(dst, false),
+        }
+    }
+}
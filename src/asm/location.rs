@@ -32,7 +32,14 @@
 //! There are several constant locations to use:
 //!
 //! * `BOTTOM_OF_STACK`: The bottom of the stack.
-//! * `TMP`: A volatile register. Essentially a trashcan.
+//! * `TMP`: A volatile register. Essentially a trashcan. Helpers which need
+//!   a scratch cell use `TMP`, so they can't be nested inside each other; their
+//!   `_using` variants (like `min_using`) take the scratch cell as an argument instead.
+//!   The `_using` variants which change no cells but the ones they're given are public,
+//!   so they're also safe in code built outside of the assembler, which doesn't reserve
+//!   `TMP` for them. The others (`shift_right_using`, `shift_right_logical_using`, and
+//!   `pow_using`) also use the cells past the top of the stack, so they depend on the
+//!   assembler's `SP`, and are only used inside of the crate.
 //! * `SP`: The stack pointer. `SP.deref()` is the location of the top item
 //!   on the stack.
 //! * `FP`: The frame pointer. Automatically updated by `Call` and `Return`.
@@ -42,6 +49,10 @@
 //!   the old frame pointer is pushed to the `FP_STACK`. Whenever a function
 //!   returns, it pops the frame pointer from the `FP_STACK`.
 //! * `A`, `B`, `C`, `D`, `E`, `F`: General purpose registers.
+//! * `scratch(n)`: Extra general purpose registers, after the fixed ones.
+//!   A program only has these if it reserves them with
+//!   `AssemblyProgram::reserve_registers`, which moves the stacks and the
+//!   globals further along the tape to make room for them.
 //!
 //! ## What kinds of locations are there?
 //!
@@ -96,8 +107,16 @@ pub const E: Location = Location::Address(9);
 pub const F: Location = Location::Address(10);
 /// A register that points to the bottom of the stack.
 pub(crate) const STACK_START: Location = Location::Address(11);
-/// The bottom of the frame pointer stack.
-pub(crate) const START_OF_FP_STACK: Location = Location::Address(12);
+/// The address of the first cell after the fixed registers.
+const END_OF_REGISTERS: usize = 12;
+
+/// The `n`th extra scratch register, counting from zero. These come after all of the
+/// fixed registers, and can only be used by a program which has reserved more than `n`
+/// of them: the frame pointer stack starts directly after the last one reserved,
+/// so in a program without any, `scratch(0)` is the bottom of the frame pointer stack.
+pub const fn scratch(n: usize) -> Location {
+    Location::Address(END_OF_REGISTERS + n)
+}
 
 pub const REGISTERS: [Location; 12] = [SP, TMP, FP, FP_STACK, GP, A, B, C, D, E, F, STACK_START];
 
@@ -201,9 +220,13 @@ impl Location {
 
         match self {
             // If we are offsetting from another offset, then we can just add the offsets together.
-            Location::Offset(loc, x) => Location::Offset(loc.clone(), *x + offset),
+            // If they cancel out, then we can drop the offset entirely.
+            Location::Offset(loc, x) if x.wrapping_add(offset) == 0 => *loc.clone(),
+            Location::Offset(loc, x) => Location::Offset(loc.clone(), x.wrapping_add(offset)),
             // If we are offsetting from a constant address, then we can just add the offset to the address.
-            Location::Address(addr) => Location::Address((*addr as isize + offset) as usize),
+            Location::Address(addr) => {
+                Location::Address((*addr as isize).wrapping_add(offset) as usize)
+            }
             // Offsetting from a dereferenced pointer.
             Location::Indirect(_) => Location::Offset(Box::new(self.clone()), offset),
             // Offsetting from a global variable.
@@ -211,6 +234,25 @@ impl Location {
         }
     }
 
+    /// Collapse chained constant offsets and drop zero offsets in this location.
+    /// For example, `Offset(Offset(Address(8), -2), 3)` folds to `Address(9)`.
+    ///
+    /// Locations built with `offset` are already folded, but locations built
+    /// directly with the `Offset` constructor may not be.
+    pub fn fold(&self) -> Self {
+        match self {
+            Location::Offset(loc, offset) => loc.fold().offset(*offset),
+            Location::Indirect(loc) => loc.fold().deref(),
+            _ => self.clone(),
+        }
+    }
+
+    /// Is this location an offset that can be folded into a simpler location?
+    fn is_foldable(&self) -> bool {
+        matches!(self, Location::Offset(loc, offset)
+            if *offset == 0 || matches!(**loc, Location::Address(_) | Location::Offset(..)))
+    }
+
     /// Get the location of the value pointed to by this location.
     pub fn deref(&self) -> Self {
         Location::Indirect(Box::new(self.clone()))
@@ -238,6 +280,11 @@ impl Location {
 
     /// Move the pointer to this location.
     pub(crate) fn to(&self, result: &mut dyn VirtualMachineProgram) {
+        if self.is_foldable() {
+            // Move the pointer with as few instructions as possible.
+            return self.fold().to(result);
+        }
+
         match self {
             Location::Address(addr) => result.move_pointer(*addr as isize),
             Location::Indirect(loc) => {
@@ -256,6 +303,11 @@ impl Location {
 
     /// Move the pointer from this location.
     pub(crate) fn from(&self, result: &mut dyn VirtualMachineProgram) {
+        if self.is_foldable() {
+            // Undo the folded movement from `to`.
+            return self.fold().from(result);
+        }
+
         match self {
             Location::Address(addr) => result.move_pointer(-(*addr as isize)),
             Location::Indirect(loc) => {
@@ -365,6 +417,195 @@ impl Location {
         self.binop(vm::CoreOp::BitwiseNand, src, result);
     }
 
+    /// Replace this cell with its bitwise complement.
+    pub(crate) fn bitwise_not(&self, result: &mut dyn VirtualMachineProgram) {
+        // Nand the cell with itself.
+        self.to(result);
+        result.restore();
+        result.bitwise_nand();
+        result.save();
+        self.from(result);
+    }
+
+    /// Replace this cell with its two's complement negation (`!self + 1`).
+    pub(crate) fn neg(&self, result: &mut dyn VirtualMachineProgram) {
+        self.to(result);
+        result.restore();
+        result.bitwise_nand();
+        result.save();
+        result.set_register(1);
+        result.op(vm::CoreOp::Add);
+        result.save();
+        self.from(result);
+    }
+
+    /// This cell &= source cell.
+    pub(crate) fn bitwise_and(&self, src: &Self, result: &mut dyn VirtualMachineProgram) {
+        src.restore_from(result);
+        self.to(result);
+        // Store `!(src & self)` in this cell, and then nand it with itself.
+        result.bitwise_nand();
+        result.save();
+        result.bitwise_nand();
+        result.save();
+        self.from(result);
+    }
+
+    /// This cell |= source cell.
+    pub(crate) fn bitwise_or(&self, src: &Self, result: &mut dyn VirtualMachineProgram) {
+        // Store `!self` in this cell.
+        self.to(result);
+        result.restore();
+        result.bitwise_nand();
+        result.save();
+        self.from(result);
+        // Calculate `!src`.
+        src.to(result);
+        result.restore();
+        result.bitwise_nand();
+        src.from(result);
+        // `!(!src & !self)` is `src | self`.
+        self.to(result);
+        result.bitwise_nand();
+        result.save();
+        self.from(result);
+    }
+
+    /// This cell ^= source cell.
+    ///
+    /// This overwrites the `TMP` register.
+    pub(crate) fn bitwise_xor(&self, src: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.bitwise_xor_using(src, &TMP, result)
+    }
+
+    /// This cell ^= source cell, like `bitwise_xor`, but using the `scratch` cell
+    /// instead of `TMP`. The scratch cell is overwritten, and must be different
+    /// from both of the other cells; no other cells are changed.
+    pub fn bitwise_xor_using(
+        &self,
+        src: &Self,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        // scratch = !(self & !(src & self))
+        src.copy_to(scratch, result);
+        scratch.bitwise_nand(self, result);
+        scratch.bitwise_nand(self, result);
+        // self = !(src & !(src & self))
+        self.bitwise_nand(src, result);
+        self.bitwise_nand(src, result);
+        // self = !(scratch & self), which is `src ^ self`
+        self.bitwise_nand(scratch, result);
+    }
+
+    /// This cell <<= amount cell.
+    ///
+    /// The shift is performed by doubling this cell `amount` times, so
+    /// `amount` must not be negative. This overwrites the `TMP` register.
+    pub(crate) fn shift_left(&self, amount: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.shift_left_using(amount, &TMP, result)
+    }
+
+    /// This cell <<= amount cell, like `shift_left`, but counting the doublings in
+    /// the `scratch` cell instead of `TMP`. The scratch cell is overwritten, and must
+    /// be different from both of the other cells; no other cells are changed.
+    pub fn shift_left_using(
+        &self,
+        amount: &Self,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        amount.copy_to(scratch, result);
+        scratch.restore_from(result);
+        result.begin_while();
+        self.add(self, result);
+        scratch.dec(result);
+        scratch.restore_from(result);
+        result.end();
+    }
+
+    /// This cell >>= amount cell, as an arithmetic shift: the sign bit is
+    /// copied into the vacated bits, so negative values round towards negative infinity.
+    ///
+    /// `amount` must not be negative. This overwrites the `TMP` register,
+    /// and uses the two cells past the top of the stack as scratch space.
+    pub(crate) fn shift_right(&self, amount: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.shift_right_using(amount, &TMP, result)
+    }
+
+    /// This cell >>= amount cell, like `shift_right`, but counting the halvings in
+    /// the `scratch` cell instead of `TMP`. The scratch cell is overwritten, and must
+    /// be different from both of the other cells. Like `shift_right`, this still uses
+    /// the two cells past the top of the stack as scratch space.
+    pub(crate) fn shift_right_using(
+        &self,
+        amount: &Self,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        self.shift_right_by_halving(amount, false, scratch, result)
+    }
+
+    /// This cell >>= amount cell, as a logical shift: the vacated bits are zeroed,
+    /// so the cell is treated as an unsigned integer.
+    ///
+    /// `amount` must not be negative. This overwrites the `TMP` register,
+    /// and uses the two cells past the top of the stack as scratch space.
+    pub(crate) fn shift_right_logical(
+        &self,
+        amount: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        self.shift_right_logical_using(amount, &TMP, result)
+    }
+
+    /// This cell >>= amount cell, like `shift_right_logical`, but counting the halvings
+    /// in the `scratch` cell instead of `TMP`. The scratch cell is overwritten, and must
+    /// be different from both of the other cells. Like `shift_right_logical`, this still
+    /// uses the two cells past the top of the stack as scratch space.
+    pub(crate) fn shift_right_logical_using(
+        &self,
+        amount: &Self,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        self.shift_right_by_halving(amount, true, scratch, result)
+    }
+
+    /// Shift this cell right by halving it `amount` times, rounding towards negative infinity.
+    /// If `logical` is set, the sign bit is cleared after every halving.
+    /// The halvings are counted in the `counter` cell.
+    fn shift_right_by_halving(
+        &self,
+        amount: &Self,
+        logical: bool,
+        counter: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        let two = SP.deref().offset(1);
+        let scratch = SP.deref().offset(2);
+        two.set(2, result);
+
+        amount.copy_to(counter, result);
+        counter.restore_from(result);
+        result.begin_while();
+        // The remainder is negative when the division truncated upwards,
+        // so `self / 2 + (remainder >= 0) - 1` is the floor of `self / 2`.
+        self.copy_to(&scratch, result);
+        scratch.rem(&two, result);
+        scratch.whole_int(result);
+        self.div(&two, result);
+        self.add(&scratch, result);
+        self.dec(result);
+        if logical {
+            scratch.set(i64::MAX, result);
+            self.bitwise_and(&scratch, result);
+        }
+        counter.dec(result);
+        counter.restore_from(result);
+        result.end();
+    }
+
     /// If this cell is non-zero, then the value of this location is now 0.
     /// Otherwise, the value of this location is now 1.
     ///
@@ -484,21 +725,78 @@ impl Location {
         dst.whole_int(result);
     }
 
+    /// dst = this cell != source cell.
     pub(crate) fn is_not_equal(
         &self,
         src: &Self,
         dst: &Self,
         result: &mut dyn VirtualMachineProgram,
     ) {
-        src.copy_to(dst, result);
-        dst.sub(self, result);
+        self.is_equal(src, dst, result);
+        dst.not(result);
     }
 
+    /// dst = this cell == source cell.
     pub(crate) fn is_equal(&self, src: &Self, dst: &Self, result: &mut dyn VirtualMachineProgram) {
-        self.is_not_equal(src, dst, result);
+        // The cells are equal exactly when their difference is zero.
+        src.copy_to(dst, result);
+        dst.sub(self, result);
         dst.not(result);
     }
 
+    /// dst = -1 if this cell < source cell, 0 if they're equal, or 1 if this cell > source cell.
+    pub(crate) fn compare(&self, src: &Self, dst: &Self, result: &mut dyn VirtualMachineProgram) {
+        // dst = source cell - this cell, which is negative when this cell is greater.
+        src.copy_to(dst, result);
+        dst.sub(self, result);
+        dst.restore_from(result);
+        result.begin_if();
+        // dst = 1 - 2 * (source cell > this cell)
+        dst.whole_int(result);
+        dst.add(dst, result);
+        dst.neg(result);
+        dst.inc(result);
+        result.end();
+    }
+
+    /// This cell = the minimum of this cell and the source cell.
+    ///
+    /// This overwrites the `TMP` register, so neither cell may be `TMP`.
+    pub(crate) fn min(&self, src: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.min_using(src, &TMP, result)
+    }
+
+    /// This cell = the minimum of this cell and the source cell, like `min`, but
+    /// comparing them in the `scratch` cell instead of `TMP`. The scratch cell is
+    /// overwritten, and must be different from both of the other cells; no other
+    /// cells are changed, so comparisons can be nested by giving each its own scratch cell.
+    pub fn min_using(&self, src: &Self, scratch: &Self, result: &mut dyn VirtualMachineProgram) {
+        src.is_less_than(self, scratch, result);
+        scratch.restore_from(result);
+        result.begin_if();
+        src.copy_to(self, result);
+        result.end();
+    }
+
+    /// This cell = the maximum of this cell and the source cell.
+    ///
+    /// This overwrites the `TMP` register, so neither cell may be `TMP`.
+    pub(crate) fn max(&self, src: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.max_using(src, &TMP, result)
+    }
+
+    /// This cell = the maximum of this cell and the source cell, like `max`, but
+    /// comparing them in the `scratch` cell instead of `TMP`. The scratch cell is
+    /// overwritten, and must be different from both of the other cells; no other
+    /// cells are changed, so comparisons can be nested by giving each its own scratch cell.
+    pub fn max_using(&self, src: &Self, scratch: &Self, result: &mut dyn VirtualMachineProgram) {
+        src.is_greater_than(self, scratch, result);
+        scratch.restore_from(result);
+        result.begin_if();
+        src.copy_to(self, result);
+        result.end();
+    }
+
     /// This cell += source cell.
     pub(crate) fn add(&self, src: &Self, result: &mut dyn VirtualMachineProgram) {
         self.binop(vm::CoreOp::Add, src, result);
@@ -524,6 +822,43 @@ impl Location {
         self.binop(vm::CoreOp::Rem, src, result);
     }
 
+    /// This cell **= exponent cell, by repeated multiplication.
+    ///
+    /// Any number to the power of zero is one (including zero), and negative
+    /// exponents are treated as zero. This overwrites the `TMP` register, and uses
+    /// the cell past the top of the stack as scratch space.
+    pub(crate) fn pow(&self, exponent: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.pow_using(exponent, &TMP, result)
+    }
+
+    /// This cell **= exponent cell, like `pow`, but counting the multiplications in
+    /// the `scratch` cell instead of `TMP`. The scratch cell is overwritten, and must
+    /// be different from both of the other cells. Like `pow`, this still uses the cell
+    /// past the top of the stack as scratch space.
+    pub(crate) fn pow_using(
+        &self,
+        exponent: &Self,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        let base = SP.deref().offset(1);
+        self.copy_to(&base, result);
+        exponent.copy_to(scratch, result);
+
+        // Clamp negative exponents to zero.
+        exponent.copy_to(self, result);
+        self.whole_int(result);
+        scratch.mul(self, result);
+
+        self.set(1, result);
+        scratch.restore_from(result);
+        result.begin_while();
+        self.mul(&base, result);
+        scratch.dec(result);
+        scratch.restore_from(result);
+        result.end();
+    }
+
     /// This cell += source cell.
     pub(crate) fn add_float(
         &self,
@@ -687,6 +1022,108 @@ impl Location {
         Ok(())
     }
 
+    /// Copy `count` consecutive cells from the address stored in this cell
+    /// to the address stored in the destination cell.
+    ///
+    /// The pointers are advanced with `next` in a loop, and moved back with `prev`
+    /// afterwards, so both cells hold their original addresses when the copy is done.
+    /// The cells are copied in ascending order, so overlapping ranges are only copied
+    /// correctly when the destination starts before the source.
+    ///
+    /// This overwrites the `TMP` register, so neither cell may be `TMP`.
+    pub(crate) fn copy_range(
+        &self,
+        dst: &Self,
+        count: usize,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        self.copy_range_using(dst, count, &TMP, result)
+    }
+
+    /// Copy `count` consecutive cells, like `copy_range`, but counting the cells in
+    /// the `scratch` cell instead of `TMP`. The scratch cell is overwritten, and must
+    /// be different from both of the other cells and outside of both ranges.
+    pub fn copy_range_using(
+        &self,
+        dst: &Self,
+        count: usize,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        if count == 0 || self == dst {
+            return;
+        }
+
+        scratch.set(count as i64, result);
+        result.begin_while();
+        self.deref().copy_to(&dst.deref(), result);
+        self.next(1, result);
+        dst.next(1, result);
+        scratch.dec(result);
+        scratch.restore_from(result);
+        result.end();
+
+        self.prev(count as isize, result);
+        dst.prev(count as isize, result);
+    }
+
+    /// Set `count` consecutive cells, starting at the address stored in this cell, to a constant value.
+    ///
+    /// Like `copy_range`, the pointer is advanced with `next` in a loop and moved
+    /// back with `prev` afterwards, so this cell holds its original address when
+    /// the fill is done.
+    ///
+    /// This overwrites the `TMP` register, so this cell may not be `TMP`.
+    pub(crate) fn fill_range(
+        &self,
+        value: i64,
+        count: usize,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        self.fill_range_using(value, count, &TMP, result)
+    }
+
+    /// Set `count` consecutive cells to a constant value, like `fill_range`, but counting
+    /// the cells in the `scratch` cell instead of `TMP`. The scratch cell is overwritten,
+    /// and must be different from this cell and outside of the range.
+    pub fn fill_range_using(
+        &self,
+        value: i64,
+        count: usize,
+        scratch: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) {
+        if count == 0 {
+            return;
+        }
+
+        scratch.set(count as i64, result);
+        result.begin_while();
+        self.deref().set(value, result);
+        self.next(1, result);
+        scratch.dec(result);
+        scratch.restore_from(result);
+        result.end();
+
+        self.prev(count as isize, result);
+    }
+
+    /// Exchange the values of this cell and another cell.
+    ///
+    /// This overwrites the `TMP` register, so neither cell may be `TMP`.
+    pub(crate) fn swap(&self, other: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.swap_using(other, &TMP, result)
+    }
+
+    /// Exchange the values of this cell and another cell, like `swap`, but holding
+    /// the value in the `scratch` cell instead of `TMP`. The scratch cell is overwritten,
+    /// and must be different from both of the other cells; no other cells are changed.
+    pub fn swap_using(&self, other: &Self, scratch: &Self, result: &mut dyn VirtualMachineProgram) {
+        self.copy_to(scratch, result);
+        other.copy_to(self, result);
+        scratch.copy_to(other, result);
+    }
+
     /// Store the value of this cell into another cell.
     pub(crate) fn copy_to(&self, dst: &Self, result: &mut dyn VirtualMachineProgram) {
         self.restore_from(result);
@@ -13,6 +13,8 @@
 //! 2. [Standard Assembly](./std)
 //! 3. [Assembly Memory Model](./location)
 //! 4. [Global Variable Management](./globals)
+//! 5. [Pointer Lint](./lint)
+//! 6. [Symbol Map](./symbols)
 //!
 //! ## The Core Variant
 //!
@@ -27,20 +29,24 @@
 //! variant of the virtual machine. It is very portable: it only adds
 //! instructions for float operations, memory allocation, and I/O.
 use ::core::fmt::{Display, Formatter, Result as FmtResult};
-use ::std::collections::HashMap;
+use ::std::collections::{HashMap, HashSet};
 
 use log::{debug, error, trace, warn};
 
 pub mod core;
 pub mod globals;
+pub mod lint;
 pub mod location;
 pub mod std;
+pub mod symbols;
 
 pub use self::core::{CoreOp, CoreProgram};
 pub use self::std::{StandardOp, StandardProgram};
 pub use globals::Globals;
-pub use location::{Location, A, B, C, D, E, F, FP, GP, REGISTERS, SP};
-pub(crate) use location::{FP_STACK, STACK_START, START_OF_FP_STACK, TMP};
+pub use lint::Lint;
+pub use location::{scratch, Location, A, B, C, D, E, F, FP, GP, REGISTERS, SP};
+pub(crate) use location::{FP_STACK, STACK_START, TMP};
+pub use symbols::Symbol;
 
 /// A frontend to both the `CoreProgram` and `StandardProgram` types.
 /// This allows the compiler to append `CoreOp`s to both programs
@@ -63,6 +69,17 @@ pub trait AssemblyProgram {
     /// into the program code yet?
     fn is_defined(&self, label: &str) -> bool;
 
+    /// Record the name of the function defined with the given label,
+    /// as it's known to the front-end of the compiler. This is only used
+    /// to tell which function is which when debugging the compiled program.
+    fn name_label(&mut self, label: &str, name: &str);
+
+    /// Reserve `count` extra scratch registers for the program, which are used with
+    /// `scratch(0)` through `scratch(count - 1)`. The stacks
+    /// and the globals are moved after them when the program is assembled.
+    /// Reserving fewer registers than the program already has does nothing.
+    fn reserve_registers(&mut self, count: usize);
+
     /// Get the current instruction number.
     fn current_instruction(&self) -> usize;
 
@@ -105,7 +122,7 @@ impl Env {
         trace!("Declared label {}", name);
 
         if self.labels.contains_key(name) {
-            warn!("Label {} already declared", name);
+            warn!(target: crate::WARNING_TARGET, "Label {} already declared", name);
         }
         self.labels.insert(name.to_string(), self.label);
         self.label += 1;
@@ -158,6 +175,87 @@ impl Env {
     }
 }
 
+/// Check that the call stack has enough cells for the deepest chain of calls to labels
+/// in a program, since they're the only calls whose callee is known while assembling.
+/// Recursive calls aren't followed, because how deep they go depends on the input.
+/// Instructions which aren't core instructions are passed as `None`.
+fn check_call_stack_size<'a>(
+    ops: impl IntoIterator<Item = Option<&'a CoreOp>>,
+    call_stack_size: usize,
+) -> Result<(), Error> {
+    /// Record the labels called by each function, or by the top level for `None`.
+    /// `blocks` holds the function being defined by each open block, if any.
+    fn visit<'a>(
+        op: &'a CoreOp,
+        blocks: &mut Vec<Option<&'a str>>,
+        calls: &mut HashMap<Option<&'a str>, Vec<&'a str>>,
+    ) {
+        match op {
+            CoreOp::Many(ops) => {
+                for op in ops {
+                    visit(op, blocks, calls)
+                }
+            }
+            CoreOp::Fn(name) => blocks.push(Some(name)),
+            CoreOp::If(_) | CoreOp::While(_) => blocks.push(None),
+            CoreOp::End => {
+                blocks.pop();
+            }
+            CoreOp::CallLabel(name) => {
+                let caller = blocks.iter().rev().flatten().next().copied();
+                calls.entry(caller).or_default().push(name)
+            }
+            _ => {}
+        }
+    }
+
+    /// The number of frames pushed by calling a label, counting the calls it makes.
+    fn depth<'a>(
+        label: &'a str,
+        calls: &HashMap<Option<&'a str>, Vec<&'a str>>,
+        visiting: &mut HashSet<&'a str>,
+        depths: &mut HashMap<&'a str, usize>,
+    ) -> usize {
+        if let Some(depth) = depths.get(label) {
+            return *depth;
+        }
+        if !visiting.insert(label) {
+            return 0;
+        }
+        let callees = calls.get(&Some(label)).into_iter().flatten();
+        let result = 1 + callees
+            .map(|callee| depth(callee, calls, visiting, depths))
+            .max()
+            .unwrap_or(0);
+        visiting.remove(label);
+        depths.insert(label, result);
+        result
+    }
+
+    let mut blocks = vec![];
+    let mut calls = HashMap::new();
+    for op in ops.into_iter().flatten() {
+        visit(op, &mut blocks, &mut calls);
+    }
+
+    let (mut visiting, mut depths) = (HashSet::new(), HashMap::new());
+    let deepest = calls
+        .get(&None)
+        .into_iter()
+        .flatten()
+        .map(|callee| depth(callee, &calls, &mut visiting, &mut depths))
+        .max()
+        .unwrap_or(0);
+    // The first cell of the frame pointer stack is never used, since
+    // frame pointers are pushed by moving to the next cell first.
+    let needed = if deepest > 0 { deepest + 1 } else { 0 };
+    if needed > call_stack_size {
+        error!("The program needs a call stack of at least {needed} cells");
+        return Err(Error::CallStackTooSmall(needed, call_stack_size));
+    }
+    Ok(())
+}
+
 /// An error generated by assembling some assembly language code.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Error {
@@ -174,6 +272,9 @@ pub enum Error {
     Unmatched(CoreOp, usize),
     /// The given instruction was not expected, or cannot be used in this context.
     Unexpected(CoreOp, usize),
+    /// The call stack is too small for the deepest chain of calls in the program.
+    /// This holds the number of cells the program needs, and the number allocated.
+    CallStackTooSmall(usize, usize),
 }
 
 impl From<crate::vm::Error> for Error {
@@ -193,6 +294,11 @@ impl Display for Error {
             Self::UndefinedGlobal(name) => write!(f, "Undefined global {}", name),
             Self::Unmatched(op, i) => write!(f, "Unmatched {} at instruction #{}", op, i),
             Self::Unexpected(op, i) => write!(f, "Unexpected {} at instruction #{}", op, i),
+            Self::CallStackTooSmall(needed, size) => write!(
+                f,
+                "Call stack of {} cells is too small, the program needs at least {}",
+                size, needed
+            ),
         }
     }
 }
@@ -7,12 +7,15 @@
 //!
 //! [***Click here to view opcodes!***](./enum.StandardOp.html)
 use super::{
-    location::*, AssemblyProgram, CoreOp, CoreProgram, Env, Error, Location, FP, GP, SP,
-    START_OF_FP_STACK,
+    check_call_stack_size, location::*, AssemblyProgram, CoreOp, CoreProgram, Env, Error, Location,
+    FP, GP, SP,
 };
 use crate::side_effects::ffi::FFIBinding;
 use crate::vm::{self, VirtualMachineProgram};
-use std::{collections::BTreeSet, fmt};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
 
 use log::info;
 
@@ -26,6 +29,11 @@ pub struct StandardProgram {
     /// that has been defined so far. This helps the LIR compiler
     /// determine if a function has been compiled yet or not.
     labels: BTreeSet<String>,
+    /// The names of the functions known to the front-end of the compiler,
+    /// by their labels. These are only used for debugging.
+    pub(super) names: BTreeMap<String, String>,
+    /// The number of scratch registers reserved after the fixed registers.
+    scratch_registers: usize,
 }
 
 /// A default program is an empty program.
@@ -45,7 +53,26 @@ impl StandardProgram {
                 labels.insert(label.clone());
             }
         }
-        Self { code, labels }
+        Self {
+            code,
+            labels,
+            names: BTreeMap::new(),
+            scratch_registers: 0,
+        }
+    }
+
+    /// Remove all of the comments from the program.
+    pub fn strip_comments(self) -> Self {
+        Self {
+            code: self
+                .code
+                .into_iter()
+                .filter(|op| !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))))
+                .collect(),
+            labels: self.labels,
+            names: self.names,
+            scratch_registers: self.scratch_registers,
+        }
     }
 
     /// Get the size of the globals.
@@ -69,18 +96,26 @@ impl StandardProgram {
     pub fn assemble(&self, allowed_recursion_depth: usize) -> Result<vm::StandardProgram, Error> {
         let mut result = vm::StandardProgram(vec![]);
         let mut env = Env::default();
+        check_call_stack_size(
+            self.code.iter().map(|op| match op {
+                StandardOp::CoreOp(op) => Some(op),
+                _ => None,
+            }),
+            allowed_recursion_depth,
+        )?;
 
         // Get the size of the globals
         let size_of_globals = self.get_size_of_globals(&mut env)?;
 
-        // Create the stack of frame pointers starting directly after the last register
-        // let start_of_fp_stack = F.offset(1);
-        START_OF_FP_STACK.copy_address_to(&FP_STACK, &mut result);
+        // Create the stack of frame pointers starting directly after the last register,
+        // including the scratch registers reserved by the program.
+        let start_of_fp_stack = scratch(self.scratch_registers);
+        start_of_fp_stack.copy_address_to(&FP_STACK, &mut result);
         info!(
-            "Frame pointer stack begins at {FP_STACK:?}, and is {} cells long.",
+            "Frame pointer stack begins at {start_of_fp_stack:?}, and is {} cells long.",
             allowed_recursion_depth
         );
-        let end_of_fp_stack = START_OF_FP_STACK.offset(allowed_recursion_depth as isize);
+        let end_of_fp_stack = start_of_fp_stack.offset(allowed_recursion_depth as isize);
 
         // Copy the address just after the allocated space to the global pointer.
         let starting_gp_addr = end_of_fp_stack;
@@ -109,9 +144,12 @@ impl StandardProgram {
     }
 }
 
+/// Nested blocks are indented by the width of the formatter in spaces (three by default),
+/// like the core variant.
 impl fmt::Display for StandardProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut indent = 0;
+        let tab = " ".repeat(f.width().unwrap_or(3));
         let mut comment_count = 0;
         for (i, op) in self.code.iter().enumerate() {
             if f.alternate() {
@@ -120,7 +158,7 @@ impl fmt::Display for StandardProgram {
                         write!(f, "{:4}  ", "")?;
                     }
                     comment_count += 1;
-                    writeln!(f, "{}// {}", "   ".repeat(indent), comment,)?;
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment,)?;
                     continue;
                 }
 
@@ -137,16 +175,16 @@ impl fmt::Display for StandardProgram {
                     | StandardOp::CoreOp(CoreOp::If(_))
                     | StandardOp::CoreOp(CoreOp::While(_)) => {
                         indent += 1;
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     StandardOp::CoreOp(CoreOp::Else) => {
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     StandardOp::CoreOp(CoreOp::End) => {
                         indent -= 1;
-                        "   ".repeat(indent)
+                        tab.repeat(indent)
                     }
-                    _ => "   ".repeat(indent),
+                    _ => tab.repeat(indent),
                 },
                 op
             )?
@@ -180,6 +218,15 @@ impl AssemblyProgram for StandardProgram {
         self.labels.contains(label)
     }
 
+    /// Record the name of the function defined with the given label.
+    fn name_label(&mut self, label: &str, name: &str) {
+        self.names.insert(label.to_string(), name.to_string());
+    }
+
+    fn reserve_registers(&mut self, count: usize) {
+        self.scratch_registers = self.scratch_registers.max(count);
+    }
+
     /// Get the current instruction number.
     fn current_instruction(&self) -> usize {
         self.code.len()
@@ -193,7 +240,9 @@ impl AssemblyProgram for StandardProgram {
 
 impl From<CoreProgram> for StandardProgram {
     fn from(core: CoreProgram) -> Self {
-        Self::new(core.code.into_iter().map(StandardOp::CoreOp).collect())
+        let mut result = Self::new(core.code.into_iter().map(StandardOp::CoreOp).collect());
+        result.reserve_registers(core.scratch_registers);
+        result
     }
 }
 
@@ -0,0 +1,79 @@
+//! # Symbol Map
+//!
+//! When a program is assembled, each function is given an index in the order that
+//! its `fun` instruction appears in the program, and it's called by that index.
+//! Flattening the virtual machine code keeps these indices, so they're the same
+//! ones used by the targets (like the `funs[reg.i]()` calls in the C output).
+//!
+//! This module lists the functions in an assembly program with their indices,
+//! so that an index reported by a crashing program or a profiler can be traced
+//! back to the function it came from. Functions compiled from Sage or LIR code
+//! also have the name the function was given in the source code, if it had one.
+use super::{CoreOp, CoreProgram, StandardOp, StandardProgram};
+use core::fmt;
+use std::collections::BTreeMap;
+
+/// A function defined in an assembly program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    /// The index of the function in the assembled program.
+    pub index: usize,
+    /// The label of the function in the assembly code.
+    pub label: String,
+    /// The name of the function in the source code, if it was compiled from
+    /// a named procedure. Anonymous procedures and handwritten assembly code
+    /// only have a label.
+    pub name: Option<String>,
+}
+
+/// A symbol is written as its index, its label, and its name, separated by spaces.
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.index, self.label)?;
+        if let Some(name) = &self.name {
+            write!(f, " {name}")?;
+        }
+        Ok(())
+    }
+}
+
+impl CoreProgram {
+    /// List the functions defined in the program, in the order of their indices.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols = vec![];
+        for op in &self.code {
+            add_symbols(op, &self.names, &mut symbols);
+        }
+        symbols
+    }
+}
+
+impl StandardProgram {
+    /// List the functions defined in the program, in the order of their indices.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols = vec![];
+        for op in &self.code {
+            if let StandardOp::CoreOp(op) = op {
+                add_symbols(op, &self.names, &mut symbols);
+            }
+        }
+        symbols
+    }
+}
+
+/// Add the functions defined by an instruction to the list of symbols.
+fn add_symbols(op: &CoreOp, names: &BTreeMap<String, String>, symbols: &mut Vec<Symbol>) {
+    match op {
+        CoreOp::Many(ops) => {
+            for op in ops {
+                add_symbols(op, names, symbols);
+            }
+        }
+        CoreOp::Fn(label) => symbols.push(Symbol {
+            index: symbols.len(),
+            label: label.clone(),
+            name: names.get(label).cloned(),
+        }),
+        _ => {}
+    }
+}
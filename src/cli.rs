@@ -5,23 +5,36 @@
 //! the supported targets provided by the compiler.
 use clap::*;
 use sage::{
-    lir::*,
+    compile::{self, SourceFile},
     parse::*,
     targets::{self, CompiledTarget},
     vm::*,
     LOGO_WITH_COLOR, *,
 };
 use std::{
+    collections::BTreeMap,
     fmt,
-    fs::{read_to_string, write},
+    fs::{self, read, write, File},
+    io::{self, stderr, stdout, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
 };
 
+use codespan_reporting::term::termcolor::ColorChoice;
+
 use log::error;
 
-// The stack sizes of the threads used to compile the code.
+// The default stack sizes of the threads used to compile the code.
+// These can be changed with `--compiler-stack-mb`.
 const RELEASE_STACK_SIZE_MB: usize = 512;
 const DEBUG_STACK_SIZE_MB: usize = RELEASE_STACK_SIZE_MB;
 
+// How often `--watch` checks the input file for changes.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(clap::ValueEnum, Default, Clone, Debug, PartialEq)]
 enum LogLevel {
     /// Print all the errors
@@ -39,11 +52,92 @@ enum LogLevel {
     Off,
 }
 
+/// When to use colors in the diagnostics printed by the compiler.
+#[derive(clap::ValueEnum, Default, Clone, Copy, Debug, PartialEq)]
+enum ColorMode {
+    /// Use colors only when printing to a terminal.
+    #[default]
+    Auto,
+    /// Always use colors.
+    Always,
+    /// Never use colors.
+    Never,
+}
+
+/// The formats the instruction counts of `--stats` and `--profile` can be printed in.
+#[derive(clap::ValueEnum, Default, Clone, Copy, Debug, PartialEq)]
+enum StatsFormat {
+    /// A table of the instructions from most to least common,
+    /// with the percentage of the total for each one.
+    #[default]
+    Table,
+    /// A JSON object with the count of each instruction and the total,
+    /// for scripts which track the size of the code over time.
+    Json,
+}
+
+impl ColorMode {
+    /// Get the color choice to use for printing diagnostics to stderr.
+    fn color_choice(self) -> ColorChoice {
+        match self {
+            Self::Auto if stderr().is_terminal() => ColorChoice::Auto,
+            Self::Auto => ColorChoice::Never,
+            Self::Always => ColorChoice::Always,
+            Self::Never => ColorChoice::Never,
+        }
+    }
+}
+
+/// The color choice used when printing diagnostics. This is set by the `--color` flag.
+static COLOR_CHOICE: OnceLock<ColorChoice> = OnceLock::new();
+
+/// The time spent in each phase of the compilation, in order. This is printed by `--timings`.
+static TIMINGS: Mutex<Vec<(String, Duration)>> = Mutex::new(Vec::new());
+
+/// The warnings about the program produced during the compilation, in order.
+/// These are recorded even when they aren't printed, so `--deny-warnings` can report them.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// A logger which records the warnings about the program being compiled
+/// before passing every message on to the logger which prints them.
+struct WarningRecorder(env_logger::Logger);
+
+impl log::Log for WarningRecorder {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == sage::WARNING_TARGET || self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.target() == sage::WARNING_TARGET && record.level() == log::Level::Warn {
+            WARNINGS.lock().unwrap().push(record.args().to_string());
+        }
+        self.0.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Take the warnings recorded since the last call, and fail if they're denied.
+fn check_warnings(deny_warnings: bool) -> Result<(), Error> {
+    let warnings = std::mem::take(&mut *WARNINGS.lock().unwrap());
+    if deny_warnings && !warnings.is_empty() {
+        return Err(Error::DeniedWarnings(warnings));
+    }
+    Ok(())
+}
+
 /// The target options to compile the given source code to.
 #[derive(clap::ValueEnum, Clone, Copy, Debug)]
 enum TargetType {
     /// Execute the source code in the interpreter.
     Run,
+    /// Compile the code to native code in memory and run it,
+    /// which is much faster than the interpreter for compute-heavy programs.
+    /// The options for debugging the interpreter, like `--max-steps`, can't be used with it.
+    #[cfg(feature = "jit")]
+    Jit,
     /// Compile to the core variant of the assembly language.
     CoreASM,
     /// Compile to the standard variant of the assembly language.
@@ -52,65 +146,382 @@ enum TargetType {
     CoreVM,
     /// Compile to the standard variant of the virtual machine.
     StdVM,
-    /// Compile to My OS source code (GCC only).
+    /// Compile to core variant virtual machine bytecode.
+    CoreVMBin,
+    /// Compile to standard variant virtual machine bytecode.
+    StdVMBin,
+    /// Compile to SageOS source code.
     SageOS,
-    /// Compile to C source code (GCC only).
+    /// Compile to C source code.
     C,
-    /// Compile to x86 assembly code.
+    /// Compile to C source code, build it with a C compiler, and run the result.
+    /// The compiler and its flags are chosen with `--cc` and `--cc-flags`.
+    CRun,
+    /// Compile to x86-64 (AMD64) assembly code.
+    #[value(alias = "x86-64")]
     X86,
+    /// Compile to RISC-V assembly code.
+    #[value(name = "riscv")]
+    RiscV,
+    /// Compile to AArch64 (64-bit ARM) assembly code.
+    #[value(name = "aarch64")]
+    AArch64,
+    /// Compile to WebAssembly text.
+    Wat,
+    /// Compile to LLVM IR.
+    #[allow(clippy::upper_case_acronyms)]
+    LLVM,
+    /// Compile to JavaScript source code.
+    #[value(name = "javascript")]
+    JavaScript,
+    /// Compile to Python source code.
+    Python,
+    /// Compile to Go source code.
+    Go,
+    /// Compile to Rust source code.
+    Rust,
+    /// Compile to Brainfuck. Only a subset of the core instructions is supported.
+    Brainfuck,
+    /// Compile to virtual machine code, and print the number of instructions
+    /// to stderr without emitting anything. With `--timings`, this measures
+    /// the compiler without the cost of any backend.
+    None,
 }
 
-/// The source language options to compile.
-#[derive(clap::ValueEnum, Clone, Copy, Debug)]
-enum SourceType {
-    /// Compile Sage Frontend code.
-    Sage,
-    /// Compile LIR code.
-    LowIR,
-    /// Compile core variant assembly code.
-    CoreASM,
-    /// Compile standard variant assembly code.
-    StdASM,
-    /// Compile core variant virtual machine code.
-    CoreVM,
-    /// Compile standard variant virtual machine code.
-    StdVM,
+impl TargetType {
+    /// Is this target generated from assembly code, rather than virtual machine code?
+    fn is_asm(&self) -> bool {
+        matches!(self, Self::CoreASM | Self::StdASM)
+    }
+
+    /// Does this target write its output to the output path?
+    fn writes_output(&self) -> bool {
+        match self {
+            Self::Run | Self::None => false,
+            #[cfg(feature = "jit")]
+            Self::Jit => false,
+            _ => true,
+        }
+    }
 }
 
 /// The argument parser for the CLI.
 #[derive(Parser, Debug)]
 #[clap(author, version, about = Some(LOGO_WITH_COLOR), long_about = Some(LOGO_WITH_COLOR), max_term_width=90)]
 struct Args {
-    /// The input file to compiler.
-    #[clap(value_parser)]
-    input: String,
+    /// The input files to compile. This is required unless `--repl` is given.
+    /// Sage code can be split across several files: their top-level declarations
+    /// are combined in order into one program, so each name can only be declared once.
+    #[clap(value_parser, required_unless_present = "repl")]
+    inputs: Vec<String>,
 
     /// The file to write the output of the compiler to.
+    /// Use `-` to write the output to stdout.
+    /// If the name has an extension, like `out.c`, the output is written to exactly that file,
+    /// so only one target can be emitted.
+    /// Otherwise, the target's extension is added, so emitting several targets
+    /// with `--emit` and a bare name like `out` writes `out.c`, `out.s`, and so on.
     #[clap(short, long, value_parser, default_value = "out")]
     output: String,
 
-    /// The source language to compile.
-    #[clap(short, value_parser, default_value = "sage")]
-    source_type: SourceType,
+    /// The source language to compile. If not given, this is inferred
+    /// from the input file's extension, defaulting to `sage`.
+    #[clap(short, value_parser)]
+    source_type: Option<SourceType>,
 
     /// The target language to compile to.
     #[clap(short, value_parser, default_value = "run")]
     target_type: TargetType,
 
-    /// The number of cells allocated for the call stack.
+    /// A comma separated list of targets to emit in a single invocation.
+    /// The source is only compiled once for all of the targets.
+    /// This overrides the `-t` flag when given.
+    #[clap(long, value_parser, value_delimiter = ',')]
+    emit: Vec<TargetType>,
+
+    /// The number of cells on the tape of the generated C and WebAssembly code.
+    #[clap(long, value_parser)]
+    tape_size: Option<usize>,
+
+    /// The number of pointers on the reference stack of the generated C and WebAssembly code.
+    #[clap(long, value_parser)]
+    ref_stack_size: Option<usize>,
+
+    /// Make the generated WebAssembly a WASI command which reads stdin and writes stdout
+    /// itself, so it can be run with `wasmtime`, instead of importing I/O from the host.
+    #[clap(long)]
+    wasi: bool,
+
+    /// Make the generated x86 assembly code position independent, so it can be linked
+    /// with `cc out.s` on toolchains which build position independent executables by default.
+    #[clap(long)]
+    pic: bool,
+
+    /// Emit `#line` directives in the generated C code, mapping it back to the source.
+    #[clap(long)]
+    line_directives: bool,
+
+    /// Mark the code generated from each line of Sage code with a `file:line` comment,
+    /// in the virtual machine code and the targets built from it.
+    /// The markers are comments, so this can't be used with `--strip-comments`.
+    #[clap(long, conflicts_with_all = ["line_directives", "strip_comments"])]
+    annotate_source: bool,
+
+    /// Check for integer division by zero in the generated C code.
+    #[clap(long)]
+    checked_div: bool,
+
+    /// Check for reference stack overflows in the generated C code.
+    #[clap(long)]
+    checked_refs: bool,
+
+    /// Allocate the tape of the generated C code on the heap when the program starts,
+    /// instead of in a static array, so that much larger tapes can be used.
+    #[clap(long)]
+    heap_tape: bool,
+
+    /// The C compiler used to build the generated C code for the `c-run` target.
+    #[clap(long, value_parser, default_value = "cc")]
+    cc: String,
+
+    /// The whitespace separated flags passed to the C compiler for the `c-run` target.
+    #[clap(long, value_parser, default_value = "-O2", allow_hyphen_values = true)]
+    cc_flags: String,
+
+    /// Read the input of the program run by the interpreter from this file,
+    /// instead of from stdin.
+    #[clap(long, value_parser)]
+    stdin_file: Option<String>,
+
+    /// Write the output of the program run by the interpreter to this file,
+    /// instead of to stdout.
+    #[clap(long, value_parser)]
+    stdout_file: Option<String>,
+
+    /// Supply the program run by the interpreter with pseudo-random input
+    /// generated from this seed, instead of reading stdin.
+    #[clap(long, value_parser, value_name = "SEED")]
+    random_input: Option<u64>,
+
+    /// Print the number of times each instruction is executed by the interpreter
+    /// to stderr, after the program halts.
+    #[clap(long)]
+    profile: bool,
+
+    /// The format of the instruction counts printed by `--stats` and `--profile`.
+    #[clap(long, value_enum, value_name = "FORMAT", default_value = "table")]
+    stats_format: StatsFormat,
+
+    /// The maximum number of instructions the interpreter executes before
+    /// halting the program with an error.
+    #[clap(long, value_parser)]
+    max_steps: Option<u64>,
+
+    /// Pause the interpreter before it executes the instruction at this index
+    /// in the virtual machine code, counting from zero, and print the register,
+    /// the pointer, and the cells around the pointer to stderr before continuing.
+    /// This can be given more than once.
+    #[clap(long, value_parser, value_name = "INDEX")]
+    break_at: Vec<usize>,
+
+    /// The number of bits in a cell for the interpreter. Integer arithmetic wraps
+    /// at this width, to test programs for targets with 32-bit cells.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=64), default_value = "64")]
+    cell_bits: u32,
+
+    /// Stop the interpreter with an error when the result of integer addition,
+    /// subtraction, or multiplication overflows a cell, instead of wrapping it.
+    #[clap(long)]
+    trap_overflow: bool,
+
+    /// Stop the interpreter with an error when a function is called or returns with its
+    /// frame pointer (`FP`) or frame pointer stack (`FP_STACK`) corrupted, to debug
+    /// handwritten or generated assembly code. Programs which move their own stack
+    /// (like by reallocating it) are reported as corrupted too.
+    #[clap(long)]
+    check_frames: bool,
+
+    /// Write a trace of the instructions executed by the interpreter to this file,
+    /// one JSON object per line with the index of the instruction, its opcode,
+    /// and the register and the pointer before it ran.
+    #[clap(long, value_parser, value_name = "PATH")]
+    trace_exec: Option<String>,
+
+    /// Only write the last N instructions executed to the `--trace-exec` file,
+    /// once the program halts or fails, to debug a crash in a long running program.
+    #[clap(long, value_parser, value_name = "N", requires = "trace_exec")]
+    trace_last: Option<usize>,
+
+    /// After the interpreter finishes, write the cells of the tape
+    /// to this file as newline separated values.
+    #[clap(long, value_parser)]
+    dump_tape: Option<String>,
+
+    /// The optimization level of the virtual machine code, before it's emitted.
+    /// `-O0` runs no optimization passes, `-O1` runs the cheap local passes,
+    /// which merge moves and remove redundant saves, and `-O2` also removes
+    /// unreachable code and folds constants.
+    #[clap(short = 'O', value_parser = clap::value_parser!(u8).range(0..=2), default_value = "0")]
+    opt_level: u8,
+
+    /// Remove the comments from the generated code before emitting it.
+    #[clap(long)]
+    strip_comments: bool,
+
+    /// The number of spaces each nested block is indented by in the emitted
+    /// virtual machine and assembly code. Use `0` to disable the indentation.
+    #[clap(long, value_parser, default_value = "3")]
+    indent: usize,
+
+    /// Print the index of every instruction before it in the emitted virtual machine
+    /// code, counting from zero like `--break-at` does, to use the code as a reference
+    /// when debugging. The listing can't be read back in as virtual machine code.
+    #[clap(long)]
+    listing: bool,
+
+    /// Print the number of times each instruction is used in the virtual machine code
+    /// to stderr, instead of emitting any targets.
+    #[clap(long)]
+    stats: bool,
+
+    /// Write the functions in the program to this file, one per line: the index each
+    /// function is called by in the compiled code, its label in the assembly code,
+    /// and its name in the source code if it has one. This tells which function
+    /// an index reported by a crash or a profiler belongs to. Virtual machine code
+    /// has no labels, so it can't be given as the input.
+    #[clap(long, value_parser, value_name = "PATH")]
+    emit_symbol_map: Option<String>,
+
+    /// Check the assembly code of the program for pointers moved with integer
+    /// arithmetic (like `inc` instead of `next`), which doesn't work on every target,
+    /// and print a warning to stderr for each one, instead of emitting any targets.
+    /// Virtual machine code can't be checked. With `--deny-warnings`, any warning fails.
+    #[clap(long)]
+    lint: bool,
+
+    /// Start an interactive session which runs each line of Sage code as it's entered,
+    /// instead of compiling a file.
+    #[clap(long)]
+    repl: bool,
+
+    /// Print the virtual machine code in the given bytecode files as text,
+    /// instead of compiling them. `-d` includes the address of every instruction.
+    #[clap(long)]
+    disasm: bool,
+
+    /// Print the time spent in each phase of the compilation to stderr.
+    #[clap(long)]
+    timings: bool,
+
+    /// Fail the compilation if it produces any warnings about the program.
+    /// Warnings are currently produced for declaring the same label twice in
+    /// assembly code, for accessing a member of a value of type `Any`, and for
+    /// types which are too deeply nested to compare.
+    #[clap(long)]
+    deny_warnings: bool,
+
+    /// Recompile the input files every time one of them changes on disk.
+    /// Errors are reported without stopping.
+    #[clap(long)]
+    watch: bool,
+
+    /// Run Sage code by calling the procedure with this name, instead of running
+    /// its top-level statements. The procedure can't take any parameters.
+    #[clap(long, value_parser, value_name = "NAME")]
+    entry: Option<String>,
+
+    /// A directory to search for the files included by Sage code, after the directory
+    /// of the including file. This can be given more than once, and the directories
+    /// are searched in order.
+    #[clap(short = 'I', long = "include-dir", value_parser, value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+
+    /// The number of cells allocated for the call stack. Assembly fails if it's
+    /// too small for the deepest chain of calls to labels in the program.
     #[clap(short, long, value_parser, default_value = "8192")]
     call_stack_size: usize,
 
+    /// The deepest that expressions can be nested in Sage or LIR code. The compiler
+    /// recurses into every nested expression, so deeper nesting needs a larger stack:
+    /// a program nested more deeply than this fails with an error, instead of
+    /// overflowing the compiler's stack. There is no limit by default.
+    #[clap(long, value_parser, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// The size of the stack of the thread which runs the compiler, in megabytes.
+    /// Small programs can be compiled with much less than the default of 512,
+    /// and very large or deeply nested programs may need more.
+    #[clap(long, value_parser = parse_stack_size_mb, value_name = "MB")]
+    compiler_stack_mb: Option<usize>,
+
     /// The log level to use.
     #[clap(short, long, value_parser, default_value = "off")]
     log_level: LogLevel,
 
+    /// When to use colors in diagnostics.
+    #[clap(long, value_parser, default_value = "auto")]
+    color: ColorMode,
+
+    /// Only print the essential part of each message: errors and warnings are
+    /// printed without their log level prefix, and without any colors.
+    #[clap(short, long)]
+    quiet: bool,
+
     /// The symbol to debug (if any exists). This will
     /// also enable debug logging.
     #[clap(short, long, value_parser)]
     debug: Option<String>,
 }
 
+impl Args {
+    /// The targets to emit: the ones given with `--emit`, or else the one from `-t`.
+    fn targets(&self) -> Vec<TargetType> {
+        if self.emit.is_empty() {
+            vec![self.target_type]
+        } else {
+            self.emit.clone()
+        }
+    }
+
+    /// Check the combinations of arguments which can't be checked by the parser itself,
+    /// returning a usage error like the parser's own if they can't be used together.
+    fn check(&self) -> Result<(), clap::Error> {
+        let conflict = |message: String| {
+            Err(Self::command().error(error::ErrorKind::ArgumentConflict, message))
+        };
+        // An output name with an extension is written as it is, so every target would
+        // be written to the same file.
+        let written = self.targets().iter().filter(|t| t.writes_output()).count();
+        if written > 1 && self.output != "-" && Path::new(&self.output).extension().is_some() {
+            return conflict(format!(
+                "the output `{}` has an extension, so it can only be used for one target, but {written} targets are emitted. Use a name without an extension, like `{}`, to write each target with its own extension",
+                self.output,
+                Path::new(&self.output).with_extension("").display()
+            ));
+        }
+        // The JIT compiler doesn't step through the program like the interpreter does,
+        // so it can't support the options which inspect or limit each step.
+        #[cfg(feature = "jit")]
+        if self.targets().iter().any(|t| matches!(t, TargetType::Jit)) {
+            let interpreter_only = [
+                ("--max-steps", self.max_steps.is_some()),
+                ("--profile", self.profile),
+                ("--cell-bits", self.cell_bits != 64),
+                ("--trap-overflow", self.trap_overflow),
+                ("--check-frames", self.check_frames),
+                ("--trace-exec", self.trace_exec.is_some()),
+                ("--break-at", !self.break_at.is_empty()),
+            ];
+            if let Some((flag, _)) = interpreter_only.iter().find(|(_, given)| *given) {
+                return conflict(format!(
+                    "`{flag}` is only supported by the interpreter, so it can't be used with the `jit` target. Use the `run` target instead"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 /// The types of errors returned by the CLI.
 enum Error {
     /// With the given source code location and the source code itself.
@@ -129,36 +540,96 @@ enum Error {
     AsmError(asm::Error),
     /// Error generated by the interpreter executing input code.
     InterpreterError(String),
+    /// Error in malformed virtual machine code.
+    VmError(vm::Error),
     /// Error when building the virtual machine code for a given target.
     BuildError(String),
     /// Invalid source code (expected core but got standard).
     InvalidSource(String),
+    /// Several errors found in the same compilation, reported together.
+    Multiple(Vec<Self>),
+    /// Warnings were produced while compiling with `--deny-warnings`.
+    DeniedWarnings(Vec<String>),
 }
 
 impl Error {
-    pub fn annotate_with_source(self, code: &str) -> Self {
+    /// The exit code the process should return for this error.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::WithSourceCode { err, .. } => err.exit_code(),
+            Self::IO(_) => 2,
+            Self::Parse(_) => 3,
+            Self::LirError(_) => 4,
+            Self::AsmError(_) => 5,
+            Self::InterpreterError(_) => 6,
+            Self::VmError(_) => 7,
+            Self::BuildError(_) | Self::InvalidSource(_) | Self::DeniedWarnings(_) => 1,
+            Self::Multiple(errs) => errs.first().map_or(1, Self::exit_code),
+        }
+    }
+
+    /// Attach the source code of the file an error happened in to the error.
+    /// `sources` are the names and contents of the files which were compiled.
+    /// Files which aren't among them (like included files) are read again.
+    pub fn annotate_with_source(self, sources: &[(Option<&str>, String)]) -> Self {
         match self {
             Self::LirError(lir::Error::Annotated(ref err, ref metadata)) => {
-                if let Some(loc) = metadata.location().cloned() {
+                let loc = metadata.location().cloned();
+                let code = loc.as_ref().and_then(|loc| {
+                    let source = sources
+                        .iter()
+                        .find(|(name, _)| *name == loc.filename.as_deref());
+                    match (source, &loc.filename) {
+                        (Some((_, code)), _) => Some(code.clone()),
+                        (None, Some(filename)) => fs::read_to_string(filename).ok(),
+                        (None, None) => sources.first().map(|(_, code)| code.clone()),
+                    }
+                });
+                if let (Some(loc), Some(code)) = (loc, code) {
                     Self::WithSourceCode {
                         loc,
-                        source_code: code.to_owned(),
+                        source_code: code,
                         err: Box::new(Error::LirError(*err.clone())),
                     }
                 } else {
                     self
                 }
             }
+            Self::Multiple(errs) => Self::Multiple(
+                errs.into_iter()
+                    .map(|err| err.annotate_with_source(sources))
+                    .collect(),
+            ),
             _ => self,
         }
     }
 }
 
+impl From<compile::Error> for Error {
+    fn from(err: compile::Error) -> Self {
+        match err {
+            compile::Error::Encoding(e) => Self::IO(io::Error::new(io::ErrorKind::InvalidData, e)),
+            compile::Error::Parse(e) => Self::Parse(e),
+            compile::Error::LirError(e) => Self::LirError(*e),
+            compile::Error::AsmError(e) => Self::AsmError(e),
+            compile::Error::VmError(e) => Self::VmError(e),
+            compile::Error::InvalidSource(e) => Self::InvalidSource(e),
+            compile::Error::Multiple(errs) => {
+                Self::Multiple(errs.into_iter().map(Self::from).collect())
+            }
+        }
+    }
+}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IO(e) => write!(f, "IO error: {:?}", e),
             Error::Parse(e) => write!(f, "Parse error: {}", e),
+            Error::AsmError(e @ asm::Error::CallStackTooSmall(needed, _)) => write!(
+                f,
+                "Assembly error: {e}\n  help: rerun with `--call-stack-size {needed}`"
+            ),
             Error::AsmError(e) => write!(f, "Assembly error: {:?}", e),
             Error::LirError(e) => write!(f, "LIR error: {}", e),
             Error::WithSourceCode {
@@ -169,11 +640,7 @@ impl fmt::Debug for Error {
                 // use codespan_reporting::files::SimpleFiles;
                 use codespan_reporting::diagnostic::{Diagnostic, Label};
                 use codespan_reporting::files::SimpleFiles;
-                use codespan_reporting::term::{
-                    emit,
-                    termcolor::{ColorChoice, StandardStream},
-                };
-                use no_comment::{languages, IntoWithoutComments};
+                use codespan_reporting::term::{emit, termcolor::StandardStream};
 
                 let SourceCodeLocation {
                     line,
@@ -185,17 +652,13 @@ impl fmt::Debug for Error {
 
                 let mut files = SimpleFiles::new();
 
-                let source_code = source_code
-                    .to_string()
-                    .chars()
-                    .without_comments(languages::rust())
-                    .collect::<String>();
-
+                // The parser keeps the comments' places in the code, so the location
+                // is shown in the original source code, comments and all.
                 let filename = filename.clone().unwrap_or("unknown".to_string());
 
                 let file_id = files.add(
                     filename.clone(),
-                    source_code,
+                    source_code.clone(),
                 );
 
                 let loc = format!("{}:{}:{}:{}", filename, line, column, offset);
@@ -211,7 +674,8 @@ impl fmt::Debug for Error {
                     )
                         .with_message(format!("{err:?}"))]);
 
-                let writer = StandardStream::stderr(ColorChoice::Always);
+                let color_choice = COLOR_CHOICE.get().copied().unwrap_or(ColorChoice::Auto);
+                let writer = StandardStream::stderr(color_choice);
                 let config = codespan_reporting::term::Config::default();
 
                 emit(&mut writer.lock(), &config, &files, &diagnostic).unwrap();
@@ -219,253 +683,850 @@ impl fmt::Debug for Error {
                 Ok(())
             }
             Error::InterpreterError(e) => write!(f, "Interpreter error: {}", e),
+            Error::VmError(e) => write!(f, "VM error: {}", e),
             Error::BuildError(e) => write!(f, "Build error: {}", e),
             Error::InvalidSource(e) => write!(f, "Invalid source: {}", e),
+            Error::DeniedWarnings(warnings) => {
+                write!(f, "Denied {} warning(s):", warnings.len())?;
+                for warning in warnings {
+                    write!(f, "\n  {warning}")?;
+                }
+                Ok(())
+            }
+            Error::Multiple(errs) => {
+                for (i, err) in errs.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err:?}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 /// Compile a given source language to virtual machine code.
 fn compile_source_to_vm(
-    filename: Option<&str>,
-    src: String,
+    sources: &[SourceFile],
     src_type: SourceType,
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
     call_stack_size: usize,
 ) -> Result<Result<sage::vm::CoreProgram, sage::vm::StandardProgram>, Error> {
-    match src_type {
-        SourceType::StdVM => {
-            // Simply parse the virtual machine code
-            parse_vm(src).map_err(Error::Parse)
-        }
-        SourceType::CoreVM => {
-            // Parse the virtual machine code
-            match parse_vm(src).map_err(Error::Parse)? {
-                // If we got a core program back, return it.
-                Ok(prog) => Ok(Ok(prog)),
-                // Otherwise, our core program was actually a standard program. Throw an error.
-                Err(_) => Err(Error::InvalidSource(
-                    "expected core VM program, got standard VM program".to_string(),
-                )),
+    compile::compile_files_to_vm(
+        sources,
+        src_type,
+        entry,
+        include_dirs,
+        call_stack_size,
+        record_timing,
+    )
+    .map_err(|e| Error::from(e).annotate_with_source(&source_texts(sources)))
+}
+
+/// Compile code in a given source language to assembly code.
+fn compile_source_to_asm(
+    sources: &[SourceFile],
+    src_type: SourceType,
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
+) -> Result<Result<sage::asm::CoreProgram, sage::asm::StandardProgram>, Error> {
+    compile::compile_files_to_asm(sources, src_type, entry, include_dirs, record_timing)
+        .map_err(|e| Error::from(e).annotate_with_source(&source_texts(sources)))
+}
+
+/// Get the names and contents of the source files, to show them in diagnostics.
+fn source_texts(sources: &[SourceFile]) -> Vec<(Option<&str>, String)> {
+    sources
+        .iter()
+        .map(|source| {
+            let code = String::from_utf8_lossy(&source.contents).into_owned();
+            (source.name.as_deref(), code)
+        })
+        .collect()
+}
+
+/// Run the optimization passes for the given optimization level on virtual machine code,
+/// which the interpreter runs with cells of the given number of bits.
+fn optimize(
+    vm_code: Result<sage::vm::CoreProgram, sage::vm::StandardProgram>,
+    opt_level: u8,
+    cell_bits: u32,
+) -> Result<sage::vm::CoreProgram, sage::vm::StandardProgram> {
+    match vm_code {
+        Ok(core) => Ok(core.optimize_with_cell_bits(opt_level, cell_bits)),
+        Err(std) => Err(std.optimize_with_cell_bits(opt_level, cell_bits)),
+    }
+}
+
+/// The options for running a program with the interpreter.
+struct InterpreterOptions {
+    /// The device used by the interpreter for input and output.
+    device: StandardDevice,
+    /// The maximum number of instructions to execute, if any.
+    max_steps: Option<u64>,
+    /// The file to dump the tape to after the program halts, if any.
+    dump_tape: Option<String>,
+    /// The seed used to generate the input of the program, if any.
+    random_seed: Option<u64>,
+    /// The format to print the number of times each instruction was executed in, if at all.
+    profile: Option<StatsFormat>,
+    /// The number of bits in a cell, which integer arithmetic wraps at.
+    cell_bits: u32,
+    /// Whether overflowing a cell in integer arithmetic is an error.
+    trap_overflow: bool,
+    /// Whether the frame pointer convention is checked at every call.
+    check_frames: bool,
+    /// The file to write the execution trace to, if any.
+    trace: Option<String>,
+    /// The number of instructions at the end of the execution trace to keep, if limited.
+    trace_last: Option<usize>,
+    /// The indices of the instructions to print the state of the machine before.
+    breakpoints: Vec<usize>,
+}
+
+impl InterpreterOptions {
+    /// Run a program with the interpreter for its variant, using the given device.
+    fn run(
+        &self,
+        device: impl Device,
+        vm_code: &Result<sage::vm::CoreProgram, sage::vm::StandardProgram>,
+    ) -> Result<(), Error> {
+        match vm_code {
+            // If the code is core variant virtual machine code
+            Ok(vm_code) => {
+                let mut vm = CoreInterpreter::new(device)
+                    .with_max_steps(self.max_steps)
+                    .with_profile(self.profile.is_some())
+                    .with_cell_bits(self.cell_bits)
+                    .with_trap_overflow(self.trap_overflow)
+                    .with_check_frames(self.check_frames)
+                    .with_trace(self.trace()?)
+                    .with_breakpoints(self.breakpoints.iter().copied());
+                let result = loop {
+                    match vm.execute_until_break(vm_code) {
+                        Ok(true) => eprintln!("{}", vm.describe_state(vm_code)),
+                        Ok(false) => break Ok(()),
+                        Err(e) => break Err(e),
+                    }
+                };
+                vm.finish_trace().map_err(Error::InterpreterError)?;
+                self.dump_tape(vm.tape())?;
+                if let (Some(profile), Some(format)) = (vm.profile(), self.profile) {
+                    print_stats(profile.clone(), format);
+                }
+                result.map_err(Error::InterpreterError)
             }
-        }
-        SourceType::StdASM => {
-            // Parse the assembly code.
-            // Then, assembly the program with the given recursion depth,
-            // and return the virtual machine output.
-            match parse_asm(src).map_err(Error::Parse)? {
-                Ok(prog) => Ok(Ok(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(prog) => Ok(Err(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
+            // If the code is standard variant virtual machine code
+            Err(vm_code) => {
+                let mut vm = StandardInterpreter::new(device)
+                    .with_max_steps(self.max_steps)
+                    .with_profile(self.profile.is_some())
+                    .with_cell_bits(self.cell_bits)
+                    .with_trap_overflow(self.trap_overflow)
+                    .with_check_frames(self.check_frames)
+                    .with_trace(self.trace()?)
+                    .with_breakpoints(self.breakpoints.iter().copied());
+                let result = loop {
+                    match vm.execute_until_break(vm_code) {
+                        Ok(true) => eprintln!("{}", vm.describe_state(vm_code)),
+                        Ok(false) => break Ok(()),
+                        Err(e) => break Err(e),
+                    }
+                };
+                vm.finish_trace().map_err(Error::InterpreterError)?;
+                self.dump_tape(vm.tape())?;
+                if let (Some(profile), Some(format)) = (vm.profile(), self.profile) {
+                    print_stats(profile.clone(), format);
+                }
+                result.map_err(Error::InterpreterError)
             }
         }
-        SourceType::CoreASM => {
-            // Parse the assembly code.
-            match parse_asm(src).map_err(Error::Parse)? {
-                // If we got back a core program, assembly it and return the virtual machine code.
-                Ok(prog) => Ok(Ok(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                // Otherwise, our core program was actually a standard program. Throw an error.
-                Err(_) => Err(Error::InvalidSource(
-                    "expected core assembly program, got standard assembly program".to_string(),
-                )),
+    }
+
+    /// Run a program with the JIT compiler, using the given device.
+    #[cfg(feature = "jit")]
+    fn run_jit(
+        &self,
+        device: impl Device,
+        vm_code: &Result<sage::vm::CoreProgram, sage::vm::StandardProgram>,
+    ) -> Result<(), Error> {
+        let mut jit = Jit::new(device);
+        let result = match vm_code {
+            Ok(vm_code) => jit.execute(&vm_code.clone().into()),
+            Err(vm_code) => jit.execute(vm_code),
+        };
+        self.dump_tape(jit.tape())?;
+        result.map_err(Error::InterpreterError)
+    }
+
+    /// Create the file for the execution trace, if the program is being traced.
+    /// When only the last instructions are kept, they're written when the trace is finished.
+    fn trace(&self) -> Result<Option<Trace>, Error> {
+        match &self.trace {
+            Some(path) => {
+                let file = File::create(path).map_err(Error::IO)?;
+                Ok(Some(
+                    Trace::new(io::BufWriter::new(file)).with_limit(self.trace_last),
+                ))
             }
+            None => Ok(None),
         }
-        SourceType::LowIR => {
-            // Parse the lower intermediate representation code.
-            match parse_lir(src)
-                .map_err(Error::Parse)?
-                .compile()
-                .map_err(Error::LirError)?
-            {
-                // If we got back a valid program, assemble it and return the result.
-                Ok(asm_code) => Ok(Ok(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(asm_code) => Ok(Err(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
+    }
+
+    /// Write the cells of the tape to the dump file, if one was given.
+    fn dump_tape(&self, tape: &[i64]) -> Result<(), Error> {
+        if let Some(path) = &self.dump_tape {
+            let contents: String = tape.iter().map(|cell| format!("{cell}\n")).collect();
+            write_file(path.clone(), contents)?;
         }
-        SourceType::Sage => {
-            match parse_frontend(&src, filename)
-                .map_err(Error::Parse)?
-                .compile()
-                .map_err(Error::LirError)
-                .map_err(|e| e.annotate_with_source(&src))?
-            {
-                // If we got back a valid program, assemble it and return the result.
-                Ok(asm_code) => Ok(Ok(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(asm_code) => Ok(Err(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
+        Ok(())
+    }
+}
+
+/// The options for building the generated C code with a C compiler and running it.
+struct NativeOptions {
+    /// The C compiler to run.
+    cc: String,
+    /// The flags passed to the C compiler.
+    cc_flags: Vec<String>,
+}
+
+impl NativeOptions {
+    /// Write the C code next to the output path, build it into an executable there,
+    /// and run the executable with the standard input and output of the compiler.
+    /// If the output path is `-`, the files are written to a temporary directory instead.
+    fn build_and_run(&self, output: &str, c_code: String) -> Result<(), Error> {
+        let base = if output == "-" {
+            let dir = std::env::temp_dir();
+            dir.join(format!("sage-{}", std::process::id()))
+                .display()
+                .to_string()
+        } else {
+            output.to_string()
+        };
+        let src = format!("{base}.c");
+        let exe = format!("{base}{}", std::env::consts::EXE_SUFFIX);
+        write_file(src.clone(), c_code)?;
+
+        let result = self.build(&src, &exe).and_then(|()| Self::run(&exe));
+        if output == "-" {
+            let _ = fs::remove_file(&src);
+            let _ = fs::remove_file(&exe);
+        }
+        result
+    }
+
+    /// Build the C source file into an executable, reporting the compiler's
+    /// error messages if it fails.
+    fn build(&self, src: &str, exe: &str) -> Result<(), Error> {
+        let result = Command::new(&self.cc)
+            .args(&self.cc_flags)
+            .arg(src)
+            .arg("-o")
+            .arg(exe)
+            .arg("-lm")
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| Error::BuildError(format!("could not run `{}`: {e}", self.cc)))?;
+        if result.status.success() {
+            Ok(())
+        } else {
+            Err(Error::BuildError(format!(
+                "`{}` failed with {}\n{}",
+                self.cc,
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim_end()
+            )))
+        }
+    }
+
+    /// Run the built executable, forwarding the standard input and output.
+    fn run(exe: &str) -> Result<(), Error> {
+        // Use an absolute path, so the executable isn't looked up in `PATH`.
+        let exe = fs::canonicalize(exe).map_err(Error::IO)?;
+        stdout().flush().map_err(Error::IO)?;
+        let status = Command::new(&exe).status().map_err(Error::IO)?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::InterpreterError(format!(
+                "the compiled program exited with {status}"
+            )))
         }
     }
 }
 
-/// Compile code in a given source language to assembly code.
-fn compile_source_to_asm(
-    filename: Option<&str>,
-    src: String,
-    src_type: SourceType,
-) -> Result<Result<sage::asm::CoreProgram, sage::asm::StandardProgram>, Error> {
-    match src_type {
-        // If the source language is standard assembly, then parse it and return it.
-        SourceType::StdASM => parse_asm(src).map_err(Error::Parse),
-        // If the source language is core assembly, then parse it and return it if it's actually a core variant program.
-        // Otherwise, throw an error.
-        SourceType::CoreASM => match parse_asm(src).map_err(Error::Parse)? {
-            Ok(prog) => Ok(Ok(prog)),
-            Err(_) => Err(Error::InvalidSource(
-                "expected core assembly program, got standard assembly program".to_string(),
-            )),
-        },
-        // If the source language is LIR, parse it and compile it to assembly code.
-        SourceType::LowIR => parse_lir(src)
-            .map_err(Error::Parse)?
-            .compile()
-            .map_err(Error::LirError),
-
-        // If the source language is Sage, parse it and compile it to assembly code.
-        SourceType::Sage => parse_frontend(&src, filename)
-            .map_err(Error::Parse)?
-            .compile()
-            .map_err(Error::LirError)
-            .map_err(|e| e.annotate_with_source(&src)),
-        // If the source language is a virtual machine program,
-        // then we cannot compile it to assembly. Throw an error.
-        SourceType::CoreVM | SourceType::StdVM => Err(Error::InvalidSource(
-            "cannot compile a core VM program to assembly".to_string(),
+/// Check whether code in the given source language can be compiled to the given target,
+/// and explain what to do instead if it can't.
+fn is_valid(src: SourceType, target: TargetType) -> Result<(), String> {
+    let name = |value: Option<builder::PossibleValue>| value.unwrap().get_name().to_string();
+    let (src_name, target_name) = (
+        name(src.to_possible_value()),
+        name(target.to_possible_value()),
+    );
+    match (src, target) {
+        // Virtual machine code is compiled from assembly code, and can't be turned back into it.
+        (
+            SourceType::CoreVM | SourceType::StdVM | SourceType::CoreVMBin | SourceType::StdVMBin,
+            TargetType::CoreASM | TargetType::StdASM,
+        ) => Err(format!(
+            "cannot compile virtual machine code (`{src_name}`) to assembly code (`{target_name}`), because virtual machine code is a lower level than assembly code. Compile Sage, LIR, or assembly code to `{target_name}` instead, or compile the virtual machine code to a virtual machine target like `std-vm` or to another target like `c`"
         )),
+        // Standard variant code may use instructions which the core variant doesn't have.
+        (
+            SourceType::StdASM | SourceType::StdVM | SourceType::StdVMBin,
+            TargetType::CoreASM | TargetType::CoreVM | TargetType::CoreVMBin,
+        ) => {
+            let core_src = src_name.replacen("std", "core", 1);
+            Err(format!(
+                "cannot compile standard variant code (`{src_name}`) to a core variant target (`{target_name}`), because the core variant doesn't support the standard instructions. If the code only uses core instructions, read it as `{core_src}` instead, or compile it to a standard variant target like `std-vm` or to another target like `c`"
+            ))
+        }
+        // Every other source can be compiled to assembly code or virtual machine code,
+        // and every target can be built from them.
+        _ => Ok(()),
     }
 }
 
-/// Compile code in a given source language to a given target language.
-fn compile(
-    filename: Option<&str>,
-    src: String,
-    src_type: SourceType,
-    target: TargetType,
-    output: String,
+/// The options for compiling the sources, shared by every target.
+struct CompileOptions<'a> {
+    /// The procedure to run instead of the top-level statements, if any.
+    entry: Option<&'a str>,
+    /// The directories searched for included files.
+    include_dirs: &'a [PathBuf],
+    /// The number of cells allocated for the call stack.
     call_stack_size: usize,
+    /// The level to optimize the virtual machine code at.
+    opt_level: u8,
+    /// Whether the comments are stripped from the compiled code.
+    strip_comments: bool,
+    /// Whether the compiled code is annotated with the source it came from.
+    annotate_source: bool,
+    /// The format to print statistics about the code in instead of emitting targets, if any.
+    stats: Option<StatsFormat>,
+    /// The file to write the symbol map to, if any.
+    symbol_map: Option<&'a str>,
+    /// Whether any warning is treated as an error.
+    deny_warnings: bool,
+}
+
+/// The options for emitting the compiled code to its targets.
+struct EmitOptions {
+    /// The path the targets are written to.
+    output: String,
+    /// The configuration of the C target.
+    c_target: targets::C,
+    /// The configuration of the WebAssembly target.
+    wat_target: targets::Wat,
+    /// The configuration of the x86 target.
+    x86_target: targets::X86,
+    /// The options for building and running the generated C code.
+    native: NativeOptions,
+    /// Whether debugging output is enabled.
     debug: bool,
+    /// The number of spaces to indent the emitted code with.
+    indent: usize,
+    /// Whether virtual machine code is printed as a listing.
+    listing: bool,
+}
+
+/// Compile code in a given source language to each of the given target languages.
+fn compile(
+    sources: &[SourceFile],
+    src_type: SourceType,
+    targets: &[TargetType],
+    options: CompileOptions,
+    emit: EmitOptions,
+    interpreter: InterpreterOptions,
 ) -> Result<(), Error> {
-    match target {
-        // If the target is `Run`, then compile the code and execute it with the interpreter.
-        TargetType::Run => match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-            // If the code is core variant virtual machine code
-            Ok(vm_code) => {
-                CoreInterpreter::new(StandardDevice::default())
-                    .run(&vm_code)
-                    .map_err(Error::InterpreterError)?;
-            }
-            // If the code is standard variant virtual machine code
-            Err(vm_code) => {
-                StandardInterpreter::new(StandardDevice::default())
-                    .run(&vm_code)
-                    .map_err(Error::InterpreterError)?;
-            }
-        },
-        // If the target is SageOS source code, then compile the code to virtual machine code,
-        // and then use the SageOS target implementation to build the output source code.
-        TargetType::SageOS => write_file(
-            format!("{output}.c"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) => targets::SageOS.build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::SageOS.build_std(&vm_code.flatten()),
-            }
-            .map_err(Error::BuildError)?,
-        )?,
-        // If the target is C source code, then compile the code to virtual machine code,
-        // and then use the C target implementation to build the output source code.
-        TargetType::C => write_file(
-            format!("{output}.c"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) => targets::C.build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::C.build_std(&vm_code.flatten()),
-            }
-            .map_err(Error::BuildError)?,
-        )?,
-        // If the target is x86 assembly code, then compile the code to virtual machine code,
-        // and then use the x86 target implementation to build the output source code.
-        TargetType::X86 => write_file(
-            format!("{output}.s"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) => targets::X86::default().build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::X86::default().build_std(&vm_code.flatten()),
-            }
-            .map_err(Error::BuildError)?,
-        )?,
-        // If the target is core virtual machine code, then try to compile the source to the core variant.
-        // If not possible, throw an error.
-        TargetType::CoreVM => match compile_source_to_vm(filename, src, src_type, call_stack_size)?
-        {
-            Ok(vm_code) if debug => write_file(
-                format!("{output}.vm.sg"),
-                format!("{:#}", vm_code.flatten()),
-            ),
-            Ok(vm_code) => write_file(format!("{output}.vm.sg"), vm_code.flatten().to_string()),
-            Err(_) => Err(Error::InvalidSource(
-                "expected core VM program, got standard VM program".to_string(),
-            )),
-        }?,
-        // If the target is standard virtual machine code, the compile it to virtual machine code.
-        // If the result is core variant, we don't care. Just return the generated code.
-        TargetType::StdVM => write_file(
-            format!("{output}.vm.sg"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) if debug => format!("{:#}", vm_code.flatten()),
-                Err(vm_code) if debug => format!("{:#}", vm_code.flatten()),
-                Ok(vm_code) => vm_code.flatten().to_string(),
-                Err(vm_code) => vm_code.flatten().to_string(),
-            },
-        )?,
-        // If the target is core assembly code, then try to compile the source to the core variant.
-        // If not possible, throw an error.
-        TargetType::CoreASM => match compile_source_to_asm(filename, src, src_type)? {
-            Ok(asm_code) if debug => {
-                write_file(format!("{output}.asm.sg"), format!("{:#}", asm_code))
+    let CompileOptions {
+        entry,
+        include_dirs,
+        call_stack_size,
+        opt_level,
+        strip_comments,
+        annotate_source,
+        stats,
+        symbol_map,
+        deny_warnings,
+    } = options;
+    let EmitOptions {
+        output,
+        mut c_target,
+        mut wat_target,
+        mut x86_target,
+        native,
+        debug,
+        indent,
+        listing,
+    } = emit;
+    // Fail before compiling anything if the source can't be compiled to one of the targets.
+    // Nothing is emitted with `--stats`, so every target is fine.
+    if stats.is_none() {
+        for target in targets {
+            is_valid(src_type, *target).map_err(Error::InvalidSource)?;
+        }
+    }
+    // Only check the warnings from this compilation, in case an earlier one failed.
+    WARNINGS.lock().unwrap().clear();
+    // Compile the source to assembly code and virtual machine code at most once,
+    // and reuse the result for every target we're emitting.
+    let asm_code =
+        if (stats.is_none() && targets.iter().any(TargetType::is_asm)) || symbol_map.is_some() {
+            let asm_code = compile_source_to_asm(sources, src_type, entry, include_dirs)?;
+            Some(match asm_code {
+                Ok(core) if strip_comments => Ok(core.strip_comments()),
+                Err(std) if strip_comments => Err(std.strip_comments()),
+                asm_code => asm_code,
+            })
+        } else {
+            None
+        };
+    let vm_code = if stats.is_some() || targets.iter().any(|target| !target.is_asm()) {
+        let vm_code =
+            compile_source_to_vm(sources, src_type, entry, include_dirs, call_stack_size)?;
+        // Fail fast on malformed virtual machine code before running or building it.
+        match &vm_code {
+            Ok(core) => core.validate(),
+            Err(std) => std.validate(),
+        }
+        .map_err(Error::VmError)?;
+        // Don't fold arithmetic which the interpreter would wrap or trap at a narrower cell width.
+        let vm_code = timed("optimize", || {
+            optimize(vm_code, opt_level, interpreter.cell_bits)
+        });
+        Some(match vm_code {
+            Ok(core) if strip_comments => Ok(core.strip_comments()),
+            Err(std) if strip_comments => Err(std.strip_comments()),
+            Ok(core) if annotate_source => Ok(core.annotate_source()),
+            Err(std) if annotate_source => Err(std.annotate_source()),
+            vm_code => vm_code,
+        })
+    } else {
+        None
+    };
+    check_warnings(deny_warnings)?;
+
+    // Write the index, label, and name of each function to the symbol map.
+    if let (Some(path), Some(asm_code)) = (symbol_map, &asm_code) {
+        let symbols = match asm_code {
+            Ok(core) => core.symbols(),
+            Err(std) => std.symbols(),
+        };
+        let contents: String = symbols.iter().map(|symbol| format!("{symbol}\n")).collect();
+        write_file(path.to_string(), contents)?;
+    }
+
+    // If we're only printing statistics, then don't emit any targets.
+    if let Some(format) = stats {
+        if let Some(vm_code) = &vm_code {
+            print_stats(
+                match vm_code {
+                    Ok(core) => core.op_counts(),
+                    Err(std) => std.op_counts(),
+                },
+                format,
+            );
+        }
+        return Ok(());
+    }
+
+    for target in targets {
+        let phase = format!("target {}", target.to_possible_value().unwrap().get_name());
+        timed(phase, || -> Result<(), Error> {
+            match (target, &asm_code, &vm_code) {
+                // If the target is `Run`, then execute the code with the interpreter.
+                // The input is generated instead if a random seed was given.
+                (TargetType::Run, _, Some(vm_code)) => match interpreter.random_seed {
+                    Some(seed) => {
+                        let device = RandomDevice::new(interpreter.device.clone(), seed);
+                        interpreter.run(device, vm_code)?
+                    }
+                    None => interpreter.run(interpreter.device.clone(), vm_code)?,
+                },
+                // If the target is `Jit`, then compile the code to native code and run it.
+                #[cfg(feature = "jit")]
+                (TargetType::Jit, _, Some(vm_code)) => match interpreter.random_seed {
+                    Some(seed) => {
+                        let device = RandomDevice::new(interpreter.device.clone(), seed);
+                        interpreter.run_jit(device, vm_code)?
+                    }
+                    None => interpreter.run_jit(interpreter.device.clone(), vm_code)?,
+                },
+                // If the target is SageOS source code, then use the SageOS target
+                // implementation to build the output source code.
+                (TargetType::SageOS, _, Some(vm_code)) => write_output(
+                    &output,
+                    "c",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::SageOS::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::SageOS::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is C source code, then use the C target implementation
+                // to build the output source code.
+                (TargetType::C, _, Some(vm_code)) => write_output(
+                    &output,
+                    "c",
+                    match vm_code.clone() {
+                        Ok(vm_code) => c_target.build_core(&vm_code.flatten()),
+                        Err(vm_code) => c_target.build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is `CRun`, then build the C code with the C compiler
+                // and run the executable.
+                (TargetType::CRun, _, Some(vm_code)) => native.build_and_run(
+                    &output,
+                    match vm_code.clone() {
+                        Ok(vm_code) => c_target.build_core(&vm_code.flatten()),
+                        Err(vm_code) => c_target.build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is x86 assembly code, then use the x86 target implementation
+                // to build the output source code.
+                (TargetType::X86, _, Some(vm_code)) => write_output(
+                    &output,
+                    "s",
+                    match vm_code.clone() {
+                        Ok(vm_code) => x86_target.build_core(&vm_code.flatten()),
+                        Err(vm_code) => x86_target.build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is RISC-V, then use the RISC-V target implementation
+                // to build the output assembly code.
+                (TargetType::RiscV, _, Some(vm_code)) => write_output(
+                    &output,
+                    "s",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::RiscV::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::RiscV::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is AArch64, then use the AArch64 target implementation
+                // to build the output assembly code.
+                (TargetType::AArch64, _, Some(vm_code)) => write_output(
+                    &output,
+                    "s",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::AArch64::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::AArch64::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is WebAssembly text, then use the Wat target implementation
+                // to build the output source code.
+                (TargetType::Wat, _, Some(vm_code)) => write_output(
+                    &output,
+                    "wat",
+                    match vm_code.clone() {
+                        Ok(vm_code) => wat_target.build_core(&vm_code.flatten()),
+                        Err(vm_code) => wat_target.build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is LLVM IR, then use the LLVM target implementation
+                // to build the output source code.
+                (TargetType::LLVM, _, Some(vm_code)) => write_output(
+                    &output,
+                    "ll",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::LLVM::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::LLVM::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is JavaScript, then use the JavaScript target implementation
+                // to build the output source code.
+                (TargetType::JavaScript, _, Some(vm_code)) => write_output(
+                    &output,
+                    "js",
+                    match vm_code.clone() {
+                        Ok(vm_code) => {
+                            targets::JavaScript::default().build_core(&vm_code.flatten())
+                        }
+                        Err(vm_code) => {
+                            targets::JavaScript::default().build_std(&vm_code.flatten())
+                        }
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is Python, then use the Python target implementation
+                // to build the output source code.
+                (TargetType::Python, _, Some(vm_code)) => write_output(
+                    &output,
+                    "py",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::Python::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::Python::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is Go, then use the Go target implementation
+                // to build the output source code.
+                (TargetType::Go, _, Some(vm_code)) => write_output(
+                    &output,
+                    "go",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::Go::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::Go::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is Rust, then use the Rust target implementation
+                // to build the output source code.
+                (TargetType::Rust, _, Some(vm_code)) => write_output(
+                    &output,
+                    "rs",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::Rust::default().build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::Rust::default().build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If the target is Brainfuck, then use the Brainfuck target implementation
+                // to build the output source code.
+                (TargetType::Brainfuck, _, Some(vm_code)) => write_output(
+                    &output,
+                    "bf",
+                    match vm_code.clone() {
+                        Ok(vm_code) => targets::Brainfuck.build_core(&vm_code.flatten()),
+                        Err(vm_code) => targets::Brainfuck.build_std(&vm_code.flatten()),
+                    }
+                    .map_err(Error::BuildError)?,
+                )?,
+                // If there's no target, then just count the instructions (other than comments).
+                (TargetType::None, _, Some(vm_code)) => {
+                    let counts = match vm_code {
+                        Ok(core) => core.op_counts(),
+                        Err(std) => std.op_counts(),
+                    };
+                    eprintln!("{} instructions", counts.values().sum::<usize>());
+                }
+                // If the target is core virtual machine code, then the source must have compiled
+                // to the core variant. If not, throw an error.
+                (TargetType::CoreVM, _, Some(vm_code)) => match vm_code.clone() {
+                    Ok(vm_code) => write_output(&output, "vm.sg", {
+                        let vm_code = vm_code.flatten();
+                        if listing {
+                            format!("{:indent$}", vm_code.listing())
+                        } else if annotate_source {
+                            format_code(vm_code.commented(), indent, debug)
+                        } else {
+                            format_code(vm_code, indent, debug)
+                        }
+                    }),
+                    Err(_) => Err(Error::InvalidSource(
+                        "expected core VM program, got standard VM program".to_string(),
+                    )),
+                }?,
+                // If the target is standard virtual machine code, then just write the generated code.
+                // If the result is core variant, we don't care.
+                (TargetType::StdVM, _, Some(vm_code)) => write_output(
+                    &output,
+                    "vm.sg",
+                    match vm_code.clone() {
+                        Ok(vm_code) if listing => {
+                            format!("{:indent$}", vm_code.flatten().listing())
+                        }
+                        Err(vm_code) if listing => {
+                            format!("{:indent$}", vm_code.flatten().listing())
+                        }
+                        Ok(vm_code) if annotate_source => {
+                            format_code(vm_code.flatten().commented(), indent, debug)
+                        }
+                        Err(vm_code) if annotate_source => {
+                            format_code(vm_code.flatten().commented(), indent, debug)
+                        }
+                        Ok(vm_code) => format_code(vm_code.flatten(), indent, debug),
+                        Err(vm_code) => format_code(vm_code.flatten(), indent, debug),
+                    },
+                )?,
+                // If the target is core virtual machine bytecode, then the source must have compiled
+                // to the core variant. If not, throw an error.
+                (TargetType::CoreVMBin, _, Some(vm_code)) => match vm_code {
+                    Ok(vm_code) => {
+                        write_output(&output, "vm.sgb", vm_code.clone().flatten().to_bytes())
+                    }
+                    Err(_) => Err(Error::InvalidSource(
+                        "expected core VM program, got standard VM program".to_string(),
+                    )),
+                }?,
+                // If the target is standard virtual machine bytecode, then just write the encoded code.
+                (TargetType::StdVMBin, _, Some(vm_code)) => write_output(
+                    &output,
+                    "vm.sgb",
+                    match vm_code.clone() {
+                        Ok(vm_code) => vm_code.flatten().to_bytes(),
+                        Err(vm_code) => vm_code.flatten().to_bytes(),
+                    },
+                )?,
+                // If the target is core assembly code, then the source must have compiled
+                // to the core variant. If not, throw an error.
+                (TargetType::CoreASM, Some(asm_code), _) => match asm_code {
+                    Ok(asm_code) => {
+                        write_output(&output, "asm.sg", format_code(asm_code, indent, debug))
+                    }
+                    Err(_) => Err(Error::InvalidSource(
+                        "expected core assembly program, got standard assembly program".to_string(),
+                    )),
+                }?,
+                // If the target is standard assembly code, then just write the generated code.
+                // If the result is core variant, we don't care.
+                (TargetType::StdASM, Some(asm_code), _) => write_output(
+                    &output,
+                    "asm.sg",
+                    match asm_code {
+                        Ok(core_asm_code) => format_code(core_asm_code, indent, debug),
+                        Err(std_asm_code) => format_code(std_asm_code, indent, debug),
+                    },
+                )?,
+                // The code for every target is compiled above.
+                _ => unreachable!("no code was compiled for target {target:?}"),
             }
-            Ok(asm_code) => write_file(format!("{output}.asm.sg"), asm_code.to_string()),
-            Err(_) => Err(Error::InvalidSource(
-                "expected core assembly program, got standard assembly program".to_string(),
-            )),
-        }?,
-        // If the target is standard assembly code, then try to compile the source to the standard variant.
-        // If the result is core variant, we don't care. Just return the generated code.
-        TargetType::StdASM => write_file(
-            format!("{output}.asm.sg"),
-            match compile_source_to_asm(filename, src, src_type)? {
-                Ok(core_asm_code) if debug => format!("{:#}", core_asm_code),
-                Err(std_asm_code) if debug => format!("{:#}", std_asm_code),
-                Ok(core_asm_code) => core_asm_code.to_string(),
-                Err(std_asm_code) => std_asm_code.to_string(),
-            },
-        )?,
+            Ok(())
+        })?;
     }
     Ok(())
 }
 
+/// Run a phase of the compilation, and record how long it took.
+fn timed<T>(phase: impl ToString, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record_timing(&phase.to_string(), start.elapsed());
+    result
+}
+
+/// Record how long a phase of the compilation took.
+fn record_timing(phase: &str, duration: Duration) {
+    TIMINGS.lock().unwrap().push((phase.to_string(), duration));
+}
+
+/// Print the time spent in each recorded phase of the compilation to stderr,
+/// followed by the total time.
+fn print_timings() {
+    let timings = std::mem::take(&mut *TIMINGS.lock().unwrap());
+    let width = timings
+        .iter()
+        .map(|(phase, _)| phase.len())
+        .max()
+        .unwrap_or(0)
+        .max("total".len());
+    for (phase, duration) in &timings {
+        eprintln!("{phase:<width$}  {duration:>10.3?}");
+    }
+    let total: Duration = timings.iter().map(|(_, duration)| *duration).sum();
+    eprintln!("{:<width$}  {total:>10.3?}", "total");
+}
+
+/// Print the number of times each instruction is used to stderr in the given format,
+/// from most to least common, followed by the total number of instructions.
+fn print_stats(counts: BTreeMap<&'static str, usize>, format: StatsFormat) {
+    let total: usize = counts.values().sum();
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+    match format {
+        StatsFormat::Table => {
+            let name_width = counts
+                .iter()
+                .map(|(name, _)| name.len())
+                .max()
+                .unwrap_or(0)
+                .max("total".len());
+            let count_width = total.to_string().len();
+            let percent = |count: usize| match total {
+                0 => 0.0,
+                _ => count as f64 * 100.0 / total as f64,
+            };
+            for (name, count) in counts {
+                eprintln!(
+                    "{name:<name_width$}  {count:>count_width$}  {:>5.1}%",
+                    percent(count)
+                );
+            }
+            eprintln!(
+                "{:<name_width$}  {total:>count_width$}  {:>5.1}%",
+                "total",
+                percent(total)
+            );
+        }
+        StatsFormat::Json => {
+            let counts = counts
+                .iter()
+                .map(|(name, count)| format!("{name:?}: {count}"))
+                .collect::<Vec<_>>();
+            eprintln!(
+                "{{\"counts\": {{{}}}, \"total\": {total}}}",
+                counts.join(", ")
+            );
+        }
+    }
+}
+
+/// Format virtual machine or assembly code as text, indenting nested blocks by `indent` spaces.
+/// In debug mode, the address of every instruction is included.
+fn format_code(code: impl fmt::Display, indent: usize, debug: bool) -> String {
+    if debug {
+        format!("{code:#indent$}")
+    } else {
+        format!("{code:indent$}")
+    }
+}
+
+/// Write the output of the compiler to the given output path, with the given extension.
+/// If the output path is `-`, the contents are written to stdout instead.
+fn write_output(output: &str, extension: &str, contents: impl AsRef<[u8]>) -> Result<(), Error> {
+    if output == "-" {
+        let mut stdout = stdout().lock();
+        stdout.write_all(contents.as_ref()).map_err(Error::IO)?;
+        stdout.flush().map_err(Error::IO)
+    } else {
+        write_file(output_path(output, extension), contents)
+    }
+}
+
+/// The path of the file to write the output to. If the output already has an
+/// extension, it's used as it is. Otherwise, the target's extension is added.
+fn output_path(output: &str, extension: &str) -> String {
+    if Path::new(output).extension().is_some() {
+        output.to_string()
+    } else {
+        format!("{output}.{extension}")
+    }
+}
+
 /// Write some contents to a file.
-fn write_file(file: String, contents: String) -> Result<(), Error> {
+fn write_file(file: String, contents: impl AsRef<[u8]>) -> Result<(), Error> {
     write(file, contents).map_err(Error::IO)
 }
 
+/// Parse the size of the compiler's stack in megabytes, which must be
+/// at least one megabyte, and small enough to be a number of bytes.
+fn parse_stack_size_mb(mb: &str) -> Result<usize, String> {
+    match mb.parse::<usize>() {
+        Ok(0) => Err("the compiler's stack size must be at least 1 MB".to_string()),
+        Ok(mb) if mb.checked_mul(1024 * 1024).is_none() => {
+            Err(format!("{mb} MB is too large for the compiler's stack"))
+        }
+        Ok(mb) => Ok(mb),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 /// Read the contents of a file.
-fn read_file(name: &str) -> Result<String, Error> {
-    read_to_string(name).map_err(Error::IO)
+fn read_file(name: &str) -> Result<Vec<u8>, Error> {
+    read(name).map_err(Error::IO)
 }
 
 /// Run the CLI.
-fn cli() {
-    // Parse the arguments to the CLI.
-    let args = Args::parse();
+fn cli(args: Args) -> Result<(), Error> {
     let mut builder = env_logger::Builder::from_default_env();
     builder.format_timestamp(None);
 
@@ -481,49 +1542,363 @@ fn cli() {
         _ => log::LevelFilter::Debug,
     });
 
-    builder.init();
-
-    match read_file(&args.input) {
-        Ok(file_contents) => {
-            match compile(
-                Some(&args.input),
-                file_contents,
-                args.source_type,
-                args.target_type,
-                args.output,
-                args.call_stack_size,
-                args.debug.is_some(),
-            ) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("{e:#?}");
-                }
+    // In quiet mode, print just the messages themselves.
+    if args.quiet {
+        builder.format(|buf, record| writeln!(buf, "{}", record.args()));
+        builder.write_style(env_logger::WriteStyle::Never);
+    }
+
+    // Record the warnings about the program, even if they aren't printed.
+    let logger = builder.build();
+    log::set_max_level(logger.filter().max(log::LevelFilter::Warn));
+    log::set_boxed_logger(Box::new(WarningRecorder(logger))).unwrap();
+
+    // Set the color choice used to print diagnostics.
+    let _ = COLOR_CHOICE.set(if args.quiet {
+        ColorChoice::Never
+    } else {
+        args.color.color_choice()
+    });
+
+    // Limit how deeply the compiler recurses into nested expressions.
+    sage::lir::set_max_depth(args.max_depth);
+    sage::lir::set_line_markers(args.line_directives || args.annotate_source);
+
+    if args.repl {
+        return repl(&args);
+    }
+    if args.disasm {
+        return disasm(&args);
+    }
+
+    let targets = args.targets();
+
+    // If no source language was given, infer it from the input file's extension.
+    let source_type = args
+        .source_type
+        .or_else(|| SourceType::infer(args.inputs.first()?))
+        .unwrap_or(SourceType::Sage);
+
+    if args.lint {
+        return lint(&args, source_type);
+    }
+
+    if args.watch {
+        watch(&args.inputs, || {
+            compile_with_args(&args, &targets, source_type)
+        })
+    } else {
+        compile_with_args(&args, &targets, source_type)
+    }
+}
+
+/// Decode each bytecode input file, and print its virtual machine code to stdout.
+/// The code isn't validated, so malformed programs can be inspected too.
+fn disasm(args: &Args) -> Result<(), Error> {
+    let mut stdout = stdout().lock();
+    for input in &args.inputs {
+        let program =
+            sage::vm::StandardProgram::from_bytes(&read_file(input)?).map_err(Error::VmError)?;
+        // Label each program when disassembling several files.
+        if args.inputs.len() > 1 {
+            writeln!(stdout, "// {input}").map_err(Error::IO)?;
+        }
+        let text = if args.listing {
+            format!("{:indent$}", program.listing(), indent = args.indent)
+        } else {
+            format_code(program, args.indent, args.debug.is_some())
+        };
+        stdout.write_all(text.as_bytes()).map_err(Error::IO)?;
+    }
+    stdout.flush().map_err(Error::IO)
+}
+
+/// Compile the input files to assembly code, and report the instructions which
+/// move a pointer with integer arithmetic, instead of building any targets.
+fn lint(args: &Args, source_type: SourceType) -> Result<(), Error> {
+    let sources = read_sources(&args.inputs)?;
+    let lints = match compile_source_to_asm(
+        &sources,
+        source_type,
+        args.entry.as_deref(),
+        &args.include_dirs,
+    )? {
+        Ok(core) => core.lint(),
+        Err(std) => std.lint(),
+    };
+    let warnings = lints.iter().map(ToString::to_string).collect::<Vec<_>>();
+    if args.deny_warnings && !warnings.is_empty() {
+        return Err(Error::DeniedWarnings(warnings));
+    }
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+    Ok(())
+}
+
+/// Read the contents of each input file.
+fn read_sources(inputs: &[String]) -> Result<Vec<SourceFile>, Error> {
+    inputs
+        .iter()
+        .map(|input| {
+            Ok(SourceFile {
+                name: Some(input.clone()),
+                contents: read_file(input)?,
+            })
+        })
+        .collect()
+}
+
+/// Compile the input files with the options given on the command line.
+fn compile_with_args(
+    args: &Args,
+    targets: &[TargetType],
+    source_type: SourceType,
+) -> Result<(), Error> {
+    // Configure the C target.
+    let mut c_target = targets::C::default();
+    if let Some(tape_size) = args.tape_size {
+        c_target.tape_size = tape_size;
+    }
+    if let Some(ref_stack_size) = args.ref_stack_size {
+        c_target.ref_stack_size = ref_stack_size;
+    }
+    c_target.emit_line_directives = args.line_directives;
+    c_target.checked_div = args.checked_div;
+    c_target.checked_refs = args.checked_refs;
+    c_target.heap_tape = args.heap_tape;
+
+    // Configure the WebAssembly target.
+    let mut wat_target = targets::Wat::default();
+    if let Some(tape_size) = args.tape_size {
+        wat_target.tape_size = tape_size;
+    }
+    if let Some(ref_stack_size) = args.ref_stack_size {
+        wat_target.ref_stack_size = ref_stack_size;
+    }
+    wat_target.wasi = args.wasi;
+
+    // Configure the x86 target.
+    let mut x86_target = targets::X86::default();
+    x86_target.pic = args.pic;
+
+    // Configure the device used by the interpreter.
+    let mut device = match &args.stdin_file {
+        Some(path) => File::open(path)
+            .and_then(StandardDevice::with_input)
+            .map_err(Error::IO)?,
+        None => StandardDevice::default(),
+    };
+    if let Some(path) = &args.stdout_file {
+        device = device.with_output(File::create(path).map_err(Error::IO)?);
+    }
+
+    let sources = read_sources(&args.inputs)?;
+    let result = compile(
+        &sources,
+        source_type,
+        targets,
+        CompileOptions {
+            entry: args.entry.as_deref(),
+            include_dirs: &args.include_dirs,
+            call_stack_size: args.call_stack_size,
+            opt_level: args.opt_level,
+            strip_comments: args.strip_comments,
+            annotate_source: args.annotate_source,
+            stats: args.stats.then_some(args.stats_format),
+            symbol_map: args.emit_symbol_map.as_deref(),
+            deny_warnings: args.deny_warnings,
+        },
+        EmitOptions {
+            output: args.output.clone(),
+            c_target,
+            wat_target,
+            x86_target,
+            native: NativeOptions {
+                cc: args.cc.clone(),
+                cc_flags: args.cc_flags.split_whitespace().map(String::from).collect(),
+            },
+            debug: args.debug.is_some(),
+            indent: args.indent,
+            listing: args.listing,
+        },
+        InterpreterOptions {
+            device,
+            max_steps: args.max_steps,
+            dump_tape: args.dump_tape.clone(),
+            random_seed: args.random_input,
+            profile: args.profile.then_some(args.stats_format),
+            cell_bits: args.cell_bits,
+            trap_overflow: args.trap_overflow,
+            check_frames: args.check_frames,
+            trace: args.trace_exec.clone(),
+            trace_last: args.trace_last,
+            breakpoints: args.break_at.clone(),
+        },
+    );
+    if args.timings {
+        print_timings();
+    } else {
+        TIMINGS.lock().unwrap().clear();
+    }
+    result
+}
+
+/// The keywords which start a declaration that the REPL keeps for the following lines.
+const REPL_DEFINITION_KEYWORDS: &[&str] = &[
+    "def", "unit", "type", "struct", "enum", "impl", "const", "extern", "let",
+];
+
+/// Run an interactive session, which reads Sage code from stdin and runs each entry
+/// with the interpreter. Lines are read until their brackets are balanced.
+///
+/// Definitions are remembered by prepending them to every following entry, so they're
+/// re-evaluated each time. Entries which are just an expression have their value printed.
+/// Errors are reported, but they don't end the session.
+fn repl(args: &Args) -> Result<(), Error> {
+    let mut definitions = String::new();
+    loop {
+        // Read an entry, which may span several lines.
+        let mut entry = String::new();
+        loop {
+            eprint!("{}", if entry.is_empty() { "sage> " } else { "  ... " });
+            // Read each line separately, so that the program can read from stdin too.
+            if io::stdin().read_line(&mut entry).map_err(Error::IO)? == 0 {
+                // At the end of the input, end the session.
+                eprintln!();
+                return Ok(());
+            }
+            let depth = |open, close| entry.matches(open).count() > entry.matches(close).count();
+            if !depth('{', '}') && !depth('(', ')') && !depth('[', ']') {
+                break;
+            }
+        }
+
+        let code = entry.trim();
+        if code.is_empty() {
+            continue;
+        }
+        let is_definition = REPL_DEFINITION_KEYWORDS.iter().any(|keyword| {
+            code.strip_prefix(keyword)
+                .is_some_and(|rest| rest.starts_with(char::is_whitespace))
+        });
+
+        // Print the value of a lone expression if it's printable, otherwise run it as-is.
+        let vm_code = if is_definition || code.ends_with(';') || code.ends_with('}') {
+            None
+        } else {
+            compile_repl_entry(args, &format!("{definitions}\nprintln({code});")).ok()
+        };
+        let result = match vm_code {
+            Some(vm_code) => Ok(vm_code),
+            None => compile_repl_entry(args, &format!("{definitions}\n{code}")),
+        }
+        .and_then(|vm_code| {
+            let device = StandardDevice::default();
+            match vm_code {
+                Ok(vm_code) => CoreInterpreter::new(device)
+                    .with_max_steps(args.max_steps)
+                    .with_cell_bits(args.cell_bits)
+                    .with_trap_overflow(args.trap_overflow)
+                    .with_check_frames(args.check_frames)
+                    .execute(&vm_code),
+                Err(vm_code) => StandardInterpreter::new(device)
+                    .with_max_steps(args.max_steps)
+                    .with_cell_bits(args.cell_bits)
+                    .with_trap_overflow(args.trap_overflow)
+                    .with_check_frames(args.check_frames)
+                    .execute(&vm_code),
             }
+            .map_err(Error::InterpreterError)
+        });
+
+        match result {
+            Ok(()) if is_definition => {
+                definitions += "\n";
+                definitions += code;
+            }
+            Ok(()) => {}
+            Err(e) => error!("{e:#?}"),
+        }
+    }
+}
+
+/// Compile an entry in the REPL, along with the definitions before it, to virtual machine code.
+fn compile_repl_entry(
+    args: &Args,
+    src: &str,
+) -> Result<Result<sage::vm::CoreProgram, sage::vm::StandardProgram>, Error> {
+    let source = SourceFile {
+        name: Some("<repl>".to_string()),
+        contents: src.as_bytes().to_vec(),
+    };
+    WARNINGS.lock().unwrap().clear();
+    let vm_code = compile_source_to_vm(
+        &[source],
+        SourceType::Sage,
+        None,
+        &args.include_dirs,
+        args.call_stack_size,
+    )?;
+    check_warnings(args.deny_warnings)?;
+    Ok(optimize(vm_code, args.opt_level, args.cell_bits))
+}
+
+/// Run `compile` once, and then again every time one of the files at `paths` is modified.
+/// Errors are reported, but they don't stop the loop.
+fn watch(paths: &[String], mut compile: impl FnMut() -> Result<(), Error>) -> Result<(), Error> {
+    let modified = || {
+        paths
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect::<Vec<_>>()
+    };
+    loop {
+        let last_modified = modified();
+        if let Err(e) = compile() {
+            error!("{e:#?}");
+        }
+
+        // Poll the modification times of the files until one of them changes.
+        while modified() == last_modified {
+            thread::sleep(WATCH_POLL_INTERVAL);
         }
+    }
+}
+
+/// Run the CLI, and report any error that occurred.
+/// This returns the exit code for the process.
+fn run(args: Args) -> i32 {
+    match cli(args) {
+        Ok(()) => 0,
         Err(e) => {
-            error!("Error reading file: {e:?}");
+            error!("{e:#?}");
+            e.exit_code()
         }
     }
 }
 
 fn main() {
-    // If we're in debug mode, start the compilation in a separate thread.
-    // This is to allow the process to have more stack space.
-    if !cfg!(debug_assertions) {
-        let child = std::thread::Builder::new()
-            .stack_size(RELEASE_STACK_SIZE_MB * 1024 * 1024)
-            .spawn(cli)
-            .unwrap();
-
-        // Wait for the thread to finish.
-        child.join().unwrap()
+    // Parse the arguments to the CLI, which decide how large the compiler's stack is.
+    let args = Args::parse();
+    if let Err(e) = args.check() {
+        e.exit()
+    }
+    let default_stack_size_mb = if !cfg!(debug_assertions) {
+        RELEASE_STACK_SIZE_MB
     } else {
-        let child = std::thread::Builder::new()
-            .stack_size(DEBUG_STACK_SIZE_MB * 1024 * 1024)
-            .spawn(cli)
-            .unwrap();
+        DEBUG_STACK_SIZE_MB
+    };
+    let stack_size_mb = args.compiler_stack_mb.unwrap_or(default_stack_size_mb);
 
-        // Wait for the thread to finish.
-        child.join().unwrap()
-    }
+    // Start the compilation in a separate thread.
+    // This is to allow the process to have more stack space.
+    let child = std::thread::Builder::new()
+        .stack_size(stack_size_mb * 1024 * 1024)
+        .spawn(move || run(args))
+        .unwrap();
+
+    // Wait for the thread to finish.
+    let exit_code = child.join().unwrap();
+    std::process::exit(exit_code)
 }
@@ -0,0 +1,339 @@
+//! # Compile Module
+//!
+//! This module compiles source code in any of the languages the compiler accepts
+//! down to assembly code or virtual machine code, which can then be run with an
+//! interpreter or built for a target. This is what the `sage` binary does before
+//! building its targets, so that other programs (like editors, playgrounds, or test
+//! harnesses) can embed the compiler without going through the command line.
+//!
+//! ```rust
+//! use sage::{compile_to_vm, vm::*, SourceType};
+//!
+//! let vm_code = compile_to_vm("println(1 + 2);", SourceType::Sage, 8192).unwrap();
+//! let output = match vm_code {
+//!     Ok(core) => CoreInterpreter::new(TestingDevice::default()).run(&core),
+//!     Err(std) => StandardInterpreter::new(TestingDevice::default()).run(&std),
+//! };
+//! assert_eq!(output.unwrap().output_str(), "3\n");
+//! ```
+use crate::{
+    asm,
+    lir::{self, Compile},
+    parse::*,
+    vm::{self, VirtualMachineProgram},
+};
+use core::fmt;
+use std::{
+    path::PathBuf,
+    string::FromUtf8Error,
+    time::{Duration, Instant},
+};
+
+/// The source language options to compile.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum SourceType {
+    /// Compile Sage Frontend code.
+    Sage,
+    /// Compile LIR code.
+    LowIR,
+    /// Compile core variant assembly code.
+    CoreASM,
+    /// Compile standard variant assembly code.
+    StdASM,
+    /// Compile core variant virtual machine code.
+    CoreVM,
+    /// Compile standard variant virtual machine code.
+    StdVM,
+    /// Load core variant virtual machine bytecode.
+    CoreVMBin,
+    /// Load standard variant virtual machine bytecode.
+    StdVMBin,
+}
+
+impl SourceType {
+    /// Infer the source language of a file from its extension.
+    pub fn infer(path: &str) -> Option<Self> {
+        if path.ends_with(".asm.sg") {
+            Some(Self::StdASM)
+        } else if path.ends_with(".vm.sg") {
+            Some(Self::StdVM)
+        } else if path.ends_with(".vm.sgb") {
+            Some(Self::StdVMBin)
+        } else if path.ends_with(".lir.sg") || path.ends_with(".lir") {
+            Some(Self::LowIR)
+        } else if path.ends_with(".sg") || path.ends_with(".sage") {
+            Some(Self::Sage)
+        } else {
+            None
+        }
+    }
+}
+
+/// A source file given to the compiler.
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    /// The name of the file, which is used in diagnostics.
+    pub name: Option<String>,
+    /// The contents of the file.
+    pub contents: Vec<u8>,
+}
+
+/// The types of errors returned when compiling source code.
+#[derive(Debug)]
+pub enum Error {
+    /// The source code of a text language isn't valid UTF-8.
+    Encoding(FromUtf8Error),
+    /// Error parsing the source code.
+    Parse(String),
+    /// Error generated when compiling LIR code.
+    /// This is boxed because LIR errors are much larger than the others.
+    LirError(Box<lir::Error>),
+    /// Error generated when assembling input code.
+    AsmError(asm::Error),
+    /// Error in malformed virtual machine bytecode.
+    VmError(vm::Error),
+    /// Invalid source code (expected core but got standard).
+    InvalidSource(String),
+    /// Several errors found in the same compilation, reported together.
+    Multiple(Vec<Self>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Encoding(e) => write!(f, "Encoding error: {e}"),
+            Self::Parse(e) => write!(f, "Parse error: {e}"),
+            Self::LirError(e) => write!(f, "LIR error: {e}"),
+            Self::AsmError(e) => write!(f, "Assembly error: {e}"),
+            Self::VmError(e) => write!(f, "VM error: {e}"),
+            Self::InvalidSource(e) => write!(f, "Invalid source: {e}"),
+            Self::Multiple(errs) => {
+                for (i, err) in errs.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compile source code in a given language to virtual machine code. The assembled
+/// code has a call stack of `call_stack_size` cells. This returns core variant code
+/// if the program only uses core instructions, and standard variant code otherwise.
+pub fn compile_to_vm(
+    src: impl AsRef<[u8]>,
+    src_type: SourceType,
+    call_stack_size: usize,
+) -> Result<Result<vm::CoreProgram, vm::StandardProgram>, Error> {
+    let sources = [SourceFile {
+        name: None,
+        contents: src.as_ref().to_vec(),
+    }];
+    compile_files_to_vm(&sources, src_type, None, &[], call_stack_size, |_, _| {})
+}
+
+/// Compile source files in a given language to virtual machine code, like `compile_to_vm`.
+///
+/// Sage code can be split across several files, and can start from the procedure
+/// named by `entry`. Its included files are searched for next to the including file,
+/// and then in each of the `include_dirs`. The other languages must be given as a single file.
+/// `on_phase` is called with the name of each phase of the compilation
+/// (like `parse`, `compile`, or `assemble`) and the time spent in it.
+pub fn compile_files_to_vm(
+    sources: &[SourceFile],
+    src_type: SourceType,
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
+    call_stack_size: usize,
+    mut on_phase: impl FnMut(&str, Duration),
+) -> Result<Result<vm::CoreProgram, vm::StandardProgram>, Error> {
+    // Sage code can be split across several files, but the other languages can't.
+    if let SourceType::Sage = src_type {
+        // If we got back a valid program, assemble it and return the result.
+        let asm_code = compile_sage(sources, entry, include_dirs, &mut on_phase)?;
+        return match asm_code {
+            Ok(prog) => timed(&mut on_phase, "assemble", || prog.assemble(call_stack_size)).map(Ok),
+            Err(prog) => {
+                timed(&mut on_phase, "assemble", || prog.assemble(call_stack_size)).map(Err)
+            }
+        }
+        .map_err(Error::AsmError);
+    }
+    let src = single_source(sources, src_type, entry)?;
+
+    match src_type {
+        SourceType::StdVMBin => {
+            // Decode the bytecode, and return it as core code if it only uses core instructions.
+            timed(&mut on_phase, "decode", || {
+                vm::StandardProgram::from_bytes(&src)
+            })
+            .map(|prog| prog.code())
+            .map_err(Error::VmError)
+        }
+        SourceType::CoreVMBin => {
+            // Decode the bytecode, which fails if it uses any standard instructions.
+            timed(&mut on_phase, "decode", || {
+                vm::CoreProgram::from_bytes(&src)
+            })
+            .map(Ok)
+            .map_err(Error::VmError)
+        }
+        SourceType::StdVM => {
+            // Simply parse the virtual machine code
+            let src = source_text(src)?;
+            timed(&mut on_phase, "parse", || parse_vm(src)).map_err(Error::Parse)
+        }
+        SourceType::CoreVM => {
+            // Parse the virtual machine code
+            let src = source_text(src)?;
+            match timed(&mut on_phase, "parse", || parse_vm(src)).map_err(Error::Parse)? {
+                // If we got a core program back, return it.
+                Ok(prog) => Ok(Ok(prog)),
+                // Otherwise, our core program was actually a standard program. Throw an error.
+                Err(_) => Err(Error::InvalidSource(
+                    "expected core VM program, got standard VM program".to_string(),
+                )),
+            }
+        }
+        SourceType::StdASM | SourceType::CoreASM | SourceType::LowIR => {
+            // Compile the code to assembly code. Then, assemble the program
+            // with the given recursion depth, and return the virtual machine output.
+            match compile_files_to_asm(sources, src_type, entry, include_dirs, &mut on_phase)? {
+                Ok(prog) => Ok(Ok(timed(&mut on_phase, "assemble", || {
+                    prog.assemble(call_stack_size)
+                })
+                .map_err(Error::AsmError)?)),
+                Err(prog) => Ok(Err(timed(&mut on_phase, "assemble", || {
+                    prog.assemble(call_stack_size)
+                })
+                .map_err(Error::AsmError)?)),
+            }
+        }
+        SourceType::Sage => unreachable!("Sage code is compiled above"),
+    }
+}
+
+/// Compile source files in a given language to assembly code.
+/// See `compile_files_to_vm` for how the files, `entry`, `include_dirs`, and `on_phase` are used.
+pub fn compile_files_to_asm(
+    sources: &[SourceFile],
+    src_type: SourceType,
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
+    mut on_phase: impl FnMut(&str, Duration),
+) -> Result<Result<asm::CoreProgram, asm::StandardProgram>, Error> {
+    // If the source language is Sage, parse it and compile it to assembly code.
+    // Sage code can be split across several files, but the other languages can't.
+    if let SourceType::Sage = src_type {
+        return compile_sage(sources, entry, include_dirs, &mut on_phase);
+    }
+    let src = single_source(sources, src_type, entry)?;
+
+    match src_type {
+        // If the source language is standard assembly, then parse it and return it.
+        SourceType::StdASM => {
+            let src = source_text(src)?;
+            timed(&mut on_phase, "parse", || parse_asm(src)).map_err(Error::Parse)
+        }
+        // If the source language is core assembly, then parse it and return it if it's actually a core variant program.
+        // Otherwise, throw an error.
+        SourceType::CoreASM => {
+            let src = source_text(src)?;
+            match timed(&mut on_phase, "parse", || parse_asm(src)).map_err(Error::Parse)? {
+                Ok(prog) => Ok(Ok(prog)),
+                Err(_) => Err(Error::InvalidSource(
+                    "expected core assembly program, got standard assembly program".to_string(),
+                )),
+            }
+        }
+        // If the source language is LIR, parse it and compile it to assembly code.
+        SourceType::LowIR => {
+            let src = source_text(src)?;
+            let expr = timed(&mut on_phase, "parse", || parse_lir(src)).map_err(Error::Parse)?;
+            timed(&mut on_phase, "compile", || {
+                expr.compile().map_err(Box::new)
+            })
+            .map_err(Error::LirError)
+        }
+        SourceType::Sage => unreachable!("Sage code is compiled above"),
+        // If the source language is a virtual machine program,
+        // then we cannot compile it to assembly. Throw an error.
+        SourceType::CoreVM | SourceType::StdVM | SourceType::CoreVMBin | SourceType::StdVMBin => {
+            Err(Error::InvalidSource(
+                "cannot compile virtual machine code to assembly code, because virtual machine code is a lower level than assembly code. Compile Sage, LIR, or assembly code instead".to_string(),
+            ))
+        }
+    }
+}
+
+/// Parse and compile Sage code to assembly code. The top-level declarations
+/// of all of the source files are combined, in order, into one program,
+/// which starts from the procedure named by `entry` if there is one.
+fn compile_sage(
+    sources: &[SourceFile],
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
+    on_phase: &mut impl FnMut(&str, Duration),
+) -> Result<Result<asm::CoreProgram, asm::StandardProgram>, Error> {
+    let sources = sources
+        .iter()
+        .map(|source| {
+            let code = source_text(source.contents.clone())?;
+            Ok((source.name.as_deref(), code))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let expr = timed(on_phase, "parse", || {
+        parse_frontend_files_with_include_dirs(
+            sources.iter().map(|(name, code)| (code, *name)),
+            entry,
+            include_dirs,
+        )
+    })
+    .map_err(Error::Parse)?;
+    timed(on_phase, "compile", || expr.compile_all_errors()).map_err(|errs| {
+        let mut errs = errs
+            .into_iter()
+            .map(|e| Error::LirError(Box::new(e)))
+            .collect::<Vec<_>>();
+        match errs.len() {
+            1 => errs.remove(0),
+            _ => Error::Multiple(errs),
+        }
+    })
+}
+
+/// Get the contents of the only source file, for the source languages
+/// which can't be split across several files or started from an entry point.
+fn single_source(
+    sources: &[SourceFile],
+    src_type: SourceType,
+    entry: Option<&str>,
+) -> Result<Vec<u8>, Error> {
+    if entry.is_some() {
+        return Err(Error::InvalidSource(
+            "an entry point can only be chosen for Sage code".to_string(),
+        ));
+    }
+    match sources {
+        [source] => Ok(source.contents.clone()),
+        _ => Err(Error::InvalidSource(format!(
+            "expected a single input file for {src_type:?} code, only Sage code can be split across several files"
+        ))),
+    }
+}
+
+/// Decode the contents of a source file in a text language.
+fn source_text(src: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(src).map_err(Error::Encoding)
+}
+
+/// Run a phase of the compilation, and report the time spent in it to `on_phase`.
+fn timed<T>(on_phase: &mut impl FnMut(&str, Duration), phase: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    on_phase(phase, start.elapsed());
+    result
+}
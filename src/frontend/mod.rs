@@ -5,17 +5,105 @@
 
 mod parse;
 use crate::side_effects::Output;
-use no_comment::{languages, IntoWithoutComments};
 use parse::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
 
 pub fn parse(code: impl ToString, filename: Option<&str>) -> Result<crate::lir::Expr, String> {
-    let code = code
-        .to_string()
-        .chars()
-        .without_comments(languages::rust())
-        .collect::<String>();
+    parse_files([(code, filename)])
+}
+
+/// Parse several files of frontend code into a single LIR expression.
+///
+/// The top-level declarations of the files are combined in order into one program,
+/// as if the files were concatenated. Because of this, a name can only be declared
+/// at the top level of one of the files.
+///
+/// A file can also splice in the declarations of another file with `include "path";`.
+/// The path is relative to the directory of the including file, and every file
+/// is only included once. To search other directories too, use `parse_files_with_include_dirs`.
+pub fn parse_files<'a, T: ToString>(
+    files: impl IntoIterator<Item = (T, Option<&'a str>)>,
+) -> Result<crate::lir::Expr, String> {
+    parse_files_with_entry(files, None)
+}
 
-    match parse_frontend(code.as_ref(), filename) {
+/// Parse several files of frontend code into a single LIR expression, like `parse_files`.
+///
+/// If an entry point is given, the program runs by calling the procedure with that name,
+/// instead of running the top-level statements of the files.
+pub fn parse_files_with_entry<'a, T: ToString>(
+    files: impl IntoIterator<Item = (T, Option<&'a str>)>,
+    entry: Option<&str>,
+) -> Result<crate::lir::Expr, String> {
+    parse_files_with_include_dirs(files, entry, &[])
+}
+
+/// Parse several files of frontend code into a single LIR expression, like
+/// `parse_files_with_entry`.
+///
+/// An included path is first looked up relative to the directory of the including
+/// file, and then relative to each of the `include_dirs` in order, like a C include path.
+pub fn parse_files_with_include_dirs<'a, T: ToString>(
+    files: impl IntoIterator<Item = (T, Option<&'a str>)>,
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
+) -> Result<crate::lir::Expr, String> {
+    let files = files.into_iter().collect::<Vec<_>>();
+    let many_files = files.len() > 1;
+
+    let mut program: Option<Program> = None;
+    let mut includes = Includes {
+        dirs: include_dirs.to_vec(),
+        ..Includes::default()
+    };
+    // The file which declared each top-level name.
+    let mut declared_in = HashMap::new();
+    for (code, filename) in files {
+        // Skip the input files which were already included by an earlier file.
+        let path = filename.and_then(|filename| Path::new(filename).canonicalize().ok());
+        if let (Some(path), Some(filename)) = (path, filename) {
+            if !includes.parsed.insert(path.clone()) {
+                continue;
+            }
+            includes.stack.push((path, filename.to_string()));
+        }
+        // Say which file the error is in, if there's more than one.
+        let file_program = parse_file(code, filename, many_files, &mut includes)?;
+        includes.stack.clear();
+
+        for name in file_program.declared_names() {
+            // A name declared twice in the same file is reported when the program
+            // is compiled, along with the locations of both declarations.
+            if let Some(other) = declared_in.insert(name.to_string(), filename) {
+                if other == filename {
+                    continue;
+                }
+                return Err(format!(
+                    "`{name}` is declared in both `{}` and `{}`, but the top-level declarations of every input file are combined into one program, so a name can only be declared in one file",
+                    other.unwrap_or("unknown"),
+                    filename.unwrap_or("unknown"),
+                ));
+            }
+        }
+
+        match &mut program {
+            Some(program) => program.extend(file_program),
+            None => program = Some(file_program),
+        }
+    }
+
+    let program = match (program, entry) {
+        (Some(program), Some(entry)) => Some(program.with_entry(entry)?),
+        (program, _) => program,
+    };
+    match program
+        .map(Program::to_expr)
+        .ok_or_else(|| "no input files were given".to_string())
+    {
         Ok(result) => {
             let alloc = crate::lir::ConstExpr::StandardBuiltin(crate::lir::StandardBuiltin {
                 name: "alloc".to_string(),
@@ -330,26 +418,213 @@ pub fn parse(code: impl ToString, filename: Option<&str>) -> Result<crate::lir::
             //     )
             // ],
 
-            Ok(crate::lir::Expr::let_consts(
-                vec![
-                    ("free", free),
-                    ("alloc", alloc),
-                    // ("realloc_fp_stack", realloc_fp_stack),
-                    // ("realloc_stack", realloc_stack),
-                    ("debug", debug),
-                    ("get_sp", get_sp),
-                    ("get_fp", get_fp),
-                    ("set_sp", set_sp),
-                    ("set_fp", set_fp),
-                    ("set_gp", set_gp),
-                    ("get_fp_stack", get_fp_stack),
-                    ("get_stack_start", get_stack_start),
-                    ("set_stack_start", set_stack_start),
-                    ("get_gp", get_gp),
-                ],
-                result,
+            // The builtins are declared in a scope around the program, instead of
+            // alongside its declarations, so that the program can redefine them.
+            let builtins: Vec<(&str, crate::lir::ConstExpr)> = vec![
+                ("free", free),
+                ("alloc", alloc),
+                // ("realloc_fp_stack", realloc_fp_stack),
+                // ("realloc_stack", realloc_stack),
+                ("debug", debug),
+                ("get_sp", get_sp),
+                ("get_fp", get_fp),
+                ("set_sp", set_sp),
+                ("set_fp", set_fp),
+                ("set_gp", set_gp),
+                ("get_fp_stack", get_fp_stack),
+                ("get_stack_start", get_stack_start),
+                ("set_stack_start", set_stack_start),
+                ("get_gp", get_gp),
+            ];
+            Ok(crate::lir::Expr::Declare(
+                Box::new(builtins.into()),
+                Box::new(result),
             ))
         }
-        Err(e) => Err(e.to_string()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The files which have been included while parsing a program.
+#[derive(Default)]
+struct Includes {
+    /// Every file which has been parsed, so that it isn't included again.
+    parsed: HashSet<PathBuf>,
+    /// The chain of files which are currently being parsed, and their names,
+    /// to detect files which include themselves.
+    stack: Vec<(PathBuf, String)>,
+    /// The directories searched for included files which aren't
+    /// next to the including file.
+    dirs: Vec<PathBuf>,
+}
+
+/// Parse a file of frontend code into its top-level declarations, replacing its `include`
+/// directives with the declarations of the included files. If `name_in_errors` is set,
+/// syntax errors say which file they're in.
+fn parse_file(
+    code: impl ToString,
+    filename: Option<&str>,
+    name_in_errors: bool,
+    includes: &mut Includes,
+) -> Result<Program, String> {
+    let code = blank_comments(&code.to_string());
+    let program = match parse_frontend_program(code.as_ref(), filename) {
+        Ok(program) => program,
+        Err(e) if name_in_errors => {
+            return Err(format!("in `{}`: {e}", filename.unwrap_or("unknown")))
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // Included paths are relative to the directory of this file,
+    // or to one of the include directories.
+    let dir = filename
+        .and_then(|filename| Path::new(filename).parent())
+        .unwrap_or(Path::new(""));
+    let searched = [dir.to_path_buf()]
+        .into_iter()
+        .chain(includes.dirs.iter().cloned())
+        .collect::<Vec<_>>();
+    let mut decls = vec![];
+    for decl in program.0 {
+        let include = match decl {
+            Declaration::Include(include) => include,
+            decl => {
+                decls.push(decl);
+                continue;
+            }
+        };
+        let path = match searched
+            .iter()
+            .map(|dir| dir.join(&include))
+            .find(|path| path.is_file())
+        {
+            Some(path) => path,
+            None => {
+                let dirs = searched
+                    .iter()
+                    .map(|dir| match dir.to_string_lossy() {
+                        dir if dir.is_empty() => "`.`".to_string(),
+                        dir => format!("`{dir}`"),
+                    })
+                    .collect::<Vec<_>>();
+                return Err(format!(
+                    "could not find `{include}` to include from `{}`, searched in {}",
+                    filename.unwrap_or("unknown"),
+                    dirs.join(", ")
+                ));
+            }
+        };
+        let name = path.to_string_lossy().to_string();
+        let could_not_include = |e: std::io::Error| {
+            format!(
+                "could not include `{name}` from `{}`: {e}",
+                filename.unwrap_or("unknown")
+            )
+        };
+
+        let canonical = path.canonicalize().map_err(could_not_include)?;
+        let stack = &includes.stack;
+        if let Some(start) = stack.iter().position(|(path, _)| *path == canonical) {
+            let chain = stack[start..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .chain([name.as_str()])
+                .collect::<Vec<_>>();
+            return Err(format!("cyclic include: {}", chain.join(" -> ")));
+        }
+        if !includes.parsed.insert(canonical.clone()) {
+            continue;
+        }
+
+        let code = fs::read_to_string(&path).map_err(could_not_include)?;
+        includes.stack.push((canonical, name.clone()));
+        decls.extend(parse_file(code, Some(&name), true, includes)?.0);
+        includes.stack.pop();
+    }
+    Ok(Program(decls))
+}
+
+/// Replace the comments in frontend code with spaces, keeping the line breaks.
+/// Unlike removing the comments, this leaves every token at the same line, column,
+/// and byte offset as in the original code, so diagnostics point at what was written.
+fn blank_comments(code: &str) -> String {
+    // Blank out a character of a comment, keeping its length in bytes.
+    fn blank(c: char, result: &mut String) {
+        match c {
+            '\n' | '\r' => result.push(c),
+            _ => result.push_str(&" ".repeat(c.len_utf8())),
+        }
+    }
+
+    let mut result = String::with_capacity(code.len());
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            // Copy string literals as they are, including any escaped quotes.
+            ('"', _) => {
+                result.push(c);
+                while let Some(c) = chars.next() {
+                    result.push(c);
+                    match c {
+                        '\\' => result.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            // Copy character literals too, so that `'"'` doesn't start a string.
+            ('\'', Some('\\')) => {
+                result.push(c);
+                result.extend(chars.next());
+                result.extend(chars.next());
+                for c in chars.by_ref() {
+                    result.push(c);
+                    if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            ('\'', Some(_)) => {
+                result.push(c);
+                let mut lookahead = chars.clone();
+                if let (Some(ch), Some('\'')) = (lookahead.next(), lookahead.next()) {
+                    result.push(ch);
+                    result.push('\'');
+                    chars = lookahead;
+                }
+            }
+            ('/', Some('/')) => {
+                blank(c, &mut result);
+                while let Some(c) = chars.next_if(|c| *c != '\n') {
+                    blank(c, &mut result);
+                }
+            }
+            // Block comments can be nested, like in Rust.
+            ('/', Some('*')) => {
+                blank(c, &mut result);
+                let mut depth = 0;
+                let mut prev = c;
+                for c in chars.by_ref() {
+                    blank(c, &mut result);
+                    match (prev, c) {
+                        ('/', '*') => depth += 1,
+                        ('*', '/') => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    // Don't let the end of one delimiter start another, like in `/*/`.
+                    prev = if matches!((prev, c), ('/', '*') | ('*', '/')) {
+                        ' '
+                    } else {
+                        c
+                    };
+                }
+            }
+            _ => result.push(c),
+        }
     }
+    result
 }
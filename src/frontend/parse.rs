@@ -1,5 +1,9 @@
 use crate::{lir::*, parse::SourceCodeLocation};
-use pest::{error::Error, iterators::Pair, Parser};
+use pest::{
+    error::{Error, ErrorVariant},
+    iterators::{Pair, Pairs},
+    Parser,
+};
 use pest_derive::Parser;
 
 #[derive(Parser)]
@@ -29,6 +33,23 @@ pub enum Statement {
 }
 
 impl Statement {
+    /// Whether this is a `let static` declaration, which doesn't run any code.
+    fn is_let_static(&self) -> bool {
+        match self {
+            Self::AnnotatedWithSource { stmt, .. } => stmt.is_let_static(),
+            Self::LetStatic(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The location of this statement in the source code, if it's annotated with one.
+    fn location(&self) -> Option<&SourceCodeLocation> {
+        match self {
+            Self::AnnotatedWithSource { loc, .. } => Some(loc),
+            _ => None,
+        }
+    }
+
     fn with_loc(self, loc: SourceCodeLocation) -> Self {
         match self {
             Self::AnnotatedWithSource { .. } => self,
@@ -44,7 +65,16 @@ impl Statement {
 
         let stmt = match (self, rest.clone()) {
             (Self::AnnotatedWithSource { stmt, loc }, _) => {
-                return stmt.to_expr(rest).annotate(loc);
+                let has_rest = rest.is_some();
+                return match stmt.to_expr(rest) {
+                    // Only annotate this statement, so that a block stays flat
+                    // instead of nesting one level deeper with every statement.
+                    Expr::Many(mut stmts) if has_rest && !stmts.is_empty() => {
+                        stmts[0] = stmts[0].annotate(loc);
+                        Expr::Many(stmts)
+                    }
+                    expr => expr.annotate(loc),
+                };
             }
             (Self::Assign(lhs, op, rhs), _) => {
                 match op {
@@ -181,6 +211,8 @@ pub enum Declaration {
     ),
     Type(Vec<(String, Type)>),
     Statement(Statement),
+    /// Include the declarations of the file at the given path.
+    Include(String),
 }
 
 impl Declaration {
@@ -190,12 +222,14 @@ impl Declaration {
         ret: Option<Type>,
         body: Statement,
     ) -> Procedure {
+        let location = body.location().cloned();
         Procedure::new(
             Some(name),
             args,
             ret.unwrap_or(Type::None),
             body.to_expr(None),
         )
+        .with_location(location)
     }
 
     fn to_expr(self, rest: Option<Expr>) -> Expr {
@@ -258,28 +292,110 @@ impl Declaration {
             (Self::Proc(name, params, ret, stmt), _) => {
                 rest_expr.with((name.clone(), Self::proc_to_expr(name, params, ret, *stmt)))
             }
-            (Self::PolyProc(name, ty_params, params, ret, stmt), _) => rest_expr.with((
-                name.clone(),
-                ConstExpr::PolyProc(PolyProcedure::new(
-                    name,
-                    ty_params,
-                    params,
-                    ret.unwrap_or(Type::None),
-                    stmt.to_expr(None),
-                )),
-            )),
+            (Self::PolyProc(name, ty_params, params, ret, stmt), _) => {
+                let location = stmt.location().cloned();
+                rest_expr.with((
+                    name.clone(),
+                    ConstExpr::PolyProc(
+                        PolyProcedure::new(
+                            name,
+                            ty_params,
+                            params,
+                            ret.unwrap_or(Type::None),
+                            stmt.to_expr(None),
+                        )
+                        .with_location(location),
+                    ),
+                ))
+            }
             (Self::Type(types), _) => rest_expr.with(types),
             (Self::Statement(stmt), Some(rest)) => stmt.to_expr(Some(rest)),
             (Self::Statement(stmt), None) => stmt.to_expr(None),
+            // Includes are replaced by the included declarations before this.
+            (Self::Include(_), _) => *rest_expr,
         }
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct Program(Vec<Declaration>);
+pub struct Program(pub Vec<Declaration>);
 
 impl Program {
-    fn to_expr(self) -> Expr {
+    /// Append the declarations of another program to the end of this one.
+    pub fn extend(&mut self, other: Self) {
+        self.0.extend(other.0)
+    }
+
+    /// The names of the constants, procedures, and types declared at the top level of the program.
+    /// Variables bound by `let` statements are not included, since they can be shadowed.
+    pub fn declared_names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        for decl in &self.0 {
+            match decl {
+                Declaration::Struct(name, _)
+                | Declaration::Extern(name, _, _)
+                | Declaration::Enum(name, _)
+                | Declaration::Proc(name, _, _, _)
+                | Declaration::PolyProc(name, _, _, _, _) => names.push(name.as_str()),
+                Declaration::Const(consts) => {
+                    names.extend(consts.iter().map(|(name, _)| name.as_str()))
+                }
+                Declaration::Type(types) => {
+                    names.extend(types.iter().map(|(name, _)| name.as_str()))
+                }
+                Declaration::Impl(..) | Declaration::Statement(_) | Declaration::Include(_) => {}
+            }
+        }
+        names
+    }
+
+    /// Run the program from the procedure with the given name, instead of from its
+    /// top-level statements. The top-level statements are replaced with a call to the
+    /// procedure, except for `let static` declarations, which procedures can use.
+    pub fn with_entry(mut self, entry: &str) -> Result<Self, String> {
+        let mut candidates = vec![];
+        let mut found = None;
+        for decl in &self.0 {
+            if let Declaration::Proc(name, params, _, _) = decl {
+                if params.is_empty() {
+                    candidates.push(format!("`{name}`"));
+                }
+                if name == entry {
+                    found = Some(params.len());
+                }
+            }
+        }
+        match found {
+            Some(0) => {}
+            Some(_) => {
+                return Err(format!(
+                    "the entry point `{entry}` takes parameters, but it's called with none"
+                ))
+            }
+            None if candidates.is_empty() => {
+                return Err(format!(
+                    "the entry point `{entry}` is not declared, and there are no procedures without parameters to use instead"
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "the entry point `{entry}` is not declared, the procedures without parameters are {}",
+                    candidates.join(", ")
+                ))
+            }
+        }
+
+        self.0.retain(|decl| match decl {
+            Declaration::Statement(stmt) => stmt.is_let_static(),
+            _ => true,
+        });
+        self.0.push(Declaration::Statement(Statement::Expr(
+            Expr::var(entry).app(vec![]),
+        )));
+        Ok(self)
+    }
+
+    pub fn to_expr(self) -> Expr {
         let mut rest = None;
 
         for expr in self.0.into_iter().rev() {
@@ -293,9 +409,55 @@ impl Program {
     }
 }
 
-pub fn parse_frontend(code: &str, filename: Option<&str>) -> Result<Expr, Box<Error<Rule>>> {
+/// Parse frontend code into its top-level declarations, without converting them to LIR.
+pub fn parse_frontend_program(
+    code: &str,
+    filename: Option<&str>,
+) -> Result<Program, Box<Error<Rule>>> {
     let x = FrontendParser::parse(Rule::program, code)?;
-    Ok(parse_program(x.into_iter().next().unwrap(), filename).to_expr())
+    check_int_literals(&x)?;
+    Ok(parse_program(x.into_iter().next().unwrap(), filename))
+}
+
+/// Check that every integer literal in the parsed code fits in an `Int`,
+/// so that the rest of the parser can convert them without failing.
+fn check_int_literals(pairs: &Pairs<Rule>) -> Result<(), Box<Error<Rule>>> {
+    for pair in pairs.clone().flatten() {
+        let fits = match pair.as_rule() {
+            Rule::const_int => parse_int(pair.as_str()).is_ok(),
+            // The index of a tuple field
+            Rule::decimal => pair.as_str().parse::<i64>().is_ok(),
+            _ => true,
+        };
+        if !fits {
+            return Err(Box::new(Error::new_from_span(
+                ErrorVariant::CustomError {
+                    message: "integer literal is out of range".to_string(),
+                },
+                pair.as_span(),
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Parse an integer literal, which may be signed, and may be written
+/// in binary (`0b`), octal (`0o`), or hexadecimal (`0x`).
+fn parse_int(s: &str) -> Result<i64, std::num::ParseIntError> {
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (radix, digits) = if let Some(digits) = digits.strip_prefix("0b") {
+        (2, digits)
+    } else if let Some(digits) = digits.strip_prefix("0o") {
+        (8, digits)
+    } else if let Some(digits) = digits.strip_prefix("0x") {
+        (16, digits)
+    } else {
+        (10, digits)
+    };
+    i64::from_str_radix(&format!("{sign}{digits}"), radix)
 }
 
 fn parse_symbol(pair: Pair<Rule>) -> (Mutability, String) {
@@ -378,7 +540,8 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
 
         Rule::decl_proc_block | Rule::decl_proc_expr => {
             let mut inner_rules = pair.into_inner();
-            let name = inner_rules.next().unwrap().as_str().to_string();
+            let name_pair = inner_rules.next().unwrap();
+            let name = name_pair.as_str().to_string();
 
             let mut ty_params = vec![];
             if let Some(ty_params_pair) = inner_rules.peek() {
@@ -415,6 +578,12 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
                     other => panic!("unexpected rule {:?}", other),
                 }
             }
+            // The location of the procedure's name is kept around the body,
+            // so that the procedure can say where it was defined.
+            let stmt = Statement::AnnotatedWithSource {
+                stmt: Box::new(stmt),
+                loc: source_location(&name_pair, filename),
+            };
             if ty_params.is_empty() {
                 Declaration::Proc(name, params, ret, Box::new(stmt))
             } else {
@@ -595,24 +764,33 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
             Declaration::Const(defs)
         }
         Rule::stmt | Rule::stmt_block => Declaration::Statement(parse_stmt(pair, filename)),
+        Rule::decl_include => {
+            let path = pair.into_inner().next().unwrap().as_str();
+            Declaration::Include(snailquote::unescape(path).unwrap_or_else(|_| path.to_string()))
+        }
         Rule::EOI => Declaration::Statement(Statement::Block(vec![])),
         other => panic!("Unexpected rule: {:?}: {:?}", other, pair),
     }
 }
 
-fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
+/// The location of the code matched by a rule, in the file with the given name.
+fn source_location(pair: &Pair<Rule>, filename: Option<&str>) -> SourceCodeLocation {
     let span = pair.as_span();
     let (line, column) = span.start_pos().line_col();
     let length = span.end_pos().pos() - span.start_pos().pos();
     let offset = span.start_pos().pos();
 
-    let loc = SourceCodeLocation {
+    SourceCodeLocation {
         filename: filename.map(|x| x.to_string()),
         line,
         column,
         length: Some(length),
         offset,
-    };
+    }
+}
+
+fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
+    let loc = source_location(&pair, filename);
 
     match pair.as_rule() {
         Rule::stmt | Rule::long_stmt | Rule::short_stmt | Rule::stmt_let_in => pair
@@ -1158,32 +1336,19 @@ fn parse_const(pair: Pair<Rule>) -> ConstExpr {
             }
         }
         Rule::const_symbol => ConstExpr::Symbol(pair.as_str().to_string()),
-        Rule::const_int => {
-            let s = pair.as_str();
-            ConstExpr::Int(if s.len() > 2 && &s[..2] == "0b" {
-                i64::from_str_radix(&s[2..], 2).unwrap()
-            } else if s.len() > 2 && &s[..2] == "0o" {
-                i64::from_str_radix(&s[2..], 8).unwrap()
-            } else if s.len() > 2 && &s[..2] == "0x" {
-                i64::from_str_radix(&s[2..], 16).unwrap()
-            } else if !s.is_empty() {
-                s.parse::<i64>().unwrap()
-            } else {
-                0
-            })
-        }
+        // The literals were already checked by `check_int_literals`.
+        Rule::const_int => ConstExpr::Int(parse_int(pair.as_str()).unwrap()),
         Rule::const_float => ConstExpr::Float(pair.as_str().parse().unwrap()),
         Rule::const_char => {
             let token = pair.into_inner().next().unwrap().as_str();
             let token = &token[1..token.len() - 1];
-            let result = snailquote::unescape(
-                &format!("\"{token}\"")
-                    .replace("\\0", "\\\\0")
-                    .replace("\\/", "/"),
-            )
-            .unwrap()
-            .replace("\\0", "\0")
-            .replace("\\\"", "\"");
+            let escaped = format!("\"{token}\"")
+                .replace("\\0", "\\\\0")
+                .replace("\\/", "/");
+            let result = snailquote::unescape(&escaped)
+                .unwrap_or_else(|_| token.to_string())
+                .replace("\\0", "\0")
+                .replace("\\\"", "\"");
             // let result = snailquote::unescape(
             //     &pair
             //         .clone()
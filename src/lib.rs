@@ -49,6 +49,7 @@
 //! 2. [The Assembly Language](./asm/index.html)
 //! 3. [The Virtual Machine](./vm/index.html)
 //! 4. [Target Backends](./targets/index.html)
+//! 5. [Compiling Source Code](./compile/index.html)
 //!
 //! ## Stages of IR
 //!
@@ -88,6 +89,7 @@
 //! | Arithmetic             | `IsNonNegative?` | `Add`     | `Subtract`      | `Multiply` | `Divide`    | `Remainder`  |
 //! | Worldly                | `GetChar`        | `PutChar` | `GetInt`        | `PutInt`   | `GetFloat`  | `PutFloat`   |
 pub mod asm;
+pub mod compile;
 pub mod frontend;
 pub mod lir;
 pub mod parse;
@@ -95,6 +97,8 @@ pub mod side_effects;
 pub mod targets;
 pub mod vm;
 
+pub use compile::{compile_to_vm, SourceType};
+
 /// The value of the NULL pointer constant.
 ///
 /// I've chosen to use the smallest value that can be expressed by an 8-bit signed integer.
@@ -102,6 +106,15 @@ pub mod vm;
 /// The value of this constant might change in the future though.
 pub const NULL: i64 = i8::MIN as i64;
 
+/// The log target used for warnings about the program being compiled,
+/// as opposed to messages about the inner workings of the compiler.
+///
+/// These are currently logged for:
+/// - Declaring the same label twice in assembly code.
+/// - Accessing a member of a value of type `Any`, which can't be checked.
+/// - Comparing types which are too deeply nested to tell whether they're equal.
+pub const WARNING_TARGET: &str = "sage::warning";
+
 /// The UNICODE character art for the logo of the language.
 pub const LOGO: &str = r#"
    █████   ██████    ███████  ██████   `-.        _.-'
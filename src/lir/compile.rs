@@ -15,10 +15,70 @@ use crate::asm::{
 };
 use crate::NULL;
 use log::*;
-use std::sync::Mutex;
+use std::{cell::Cell, sync::Mutex};
 
 use log::{error, info, trace, warn};
 
+thread_local! {
+    /// The deepest that expressions can be nested when they're type checked
+    /// or compiled on this thread, if there's a limit. See `set_max_depth`.
+    static MAX_DEPTH: Cell<Option<usize>> = const { Cell::new(None) };
+    /// How deeply nested the expression being type checked or compiled on this thread is.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// Are the expressions compiled on this thread marked with their source locations?
+    /// See `set_line_markers`.
+    static LINE_MARKERS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Mark the code compiled from each annotated expression on the current thread with
+/// a `#line` comment giving its location in the source code. Targets can turn these
+/// into line directives or `file:line` comments. By default, no markers are emitted,
+/// since the extra comments are executed like any other instruction by the interpreter,
+/// and they get in the way of optimizations which merge adjacent instructions.
+pub fn set_line_markers(enabled: bool) {
+    LINE_MARKERS.with(|markers| markers.set(enabled));
+}
+
+/// Limit how deeply expressions can be nested when they're type checked or
+/// compiled on the current thread, or remove the limit with `None`.
+///
+/// The type checker and the compiler recurse into every nested expression, so
+/// each level of nesting uses more of the thread's stack. Sequences of statements
+/// don't count, since a block is handled one statement at a time, but deeply
+/// nested operators, calls, and blocks do. A program nested more deeply than the
+/// limit fails with `Error::RecursionDepthExpr`, instead of overflowing the stack.
+/// A lower limit is safe on a smaller stack, but rejects more programs.
+pub fn set_max_depth(max_depth: Option<usize>) {
+    MAX_DEPTH.with(|limit| limit.set(max_depth));
+}
+
+/// One level of nesting in the expression being type checked or compiled,
+/// which is left when this is dropped.
+pub(crate) struct Depth;
+
+impl Depth {
+    /// Go one level deeper, or fail with the limit set by `set_max_depth` if that's too deep.
+    ///
+    /// This returns the limit instead of an `Error`, so that the (much larger) error
+    /// doesn't take up space in the stack frame of every call which checks the depth.
+    pub(crate) fn enter() -> Result<Self, usize> {
+        let depth = DEPTH.with(Cell::get) + 1;
+        if let Some(max_depth) = MAX_DEPTH.with(Cell::get) {
+            if depth > max_depth {
+                return Err(max_depth);
+            }
+        }
+        DEPTH.with(|current| current.set(depth));
+        Ok(Self)
+    }
+}
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|current| current.set(current.get() - 1));
+    }
+}
+
 /// A trait which allows an LIR expression to be compiled to one of the
 /// two variants of the assembly language.
 pub trait Compile: TypeCheck + std::fmt::Debug + std::fmt::Display {
@@ -37,54 +97,107 @@ pub trait Compile: TypeCheck + std::fmt::Debug + std::fmt::Display {
         // First, type check the expression.
         self.type_check(&Env::default())?;
         info!("Type checked successfully.");
-        // Then, attempt to compile the expression into a core assembly program.
-        let mut core_asm = CoreProgram::default();
-
-        info!("Compiling...");
-        // If the expression cannot be compiled into a core assembly program,
-        // then compile it into a standard assembly program.
-        if let Err(err) = self
-            .clone()
-            // Compile the expression into the core assembly program.
-            .compile_expr(&mut Env::default(), &mut core_asm)
-        {
-            warn!("Failed to compile into core assembly program: {err}, falling back on standard assembly");
-            let mut std_asm = StandardProgram::default();
-            // Compile the expression into the standard assembly program.
-            self.compile_expr(&mut Env::default(), &mut std_asm)?;
-            info!("Compiled to standard assembly successfully.");
-            // Return the fallback standard assembly program.
-            Ok(Err(std_asm))
-        } else {
-            info!("Compiled to core assembly successfully.");
-            // Return the successfully compiled core assembly program.
-            Ok(Ok(core_asm))
-        }
+        compile_type_checked(self)
     }
+
+    /// Compile the expression into an assembly program, like `compile`.
+    ///
+    /// Instead of stopping at the first type error, this will keep type checking
+    /// wherever it can (like the other procedures after an error in one procedure),
+    /// and return an Err value containing all of the errors it found.
+    fn compile_all_errors(self) -> Result<Result<CoreProgram, StandardProgram>, Vec<Error>>
+    where
+        Self: Sized + Clone,
+    {
+        info!("Type checking...");
+        self.type_check_all(&Env::default())?;
+        info!("Type checked successfully.");
+        compile_type_checked(self).map_err(|e| vec![e])
+    }
+
     // Compile a specific expression into an assembly program.
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error>;
 }
 
+/// Compile an expression which has already been type checked into an assembly program.
+fn compile_type_checked<T: Compile + Clone>(
+    expr: T,
+) -> Result<Result<CoreProgram, StandardProgram>, Error> {
+    // Then, attempt to compile the expression into a core assembly program.
+    let mut core_asm = CoreProgram::default();
+
+    info!("Compiling...");
+    // If the expression cannot be compiled into a core assembly program,
+    // then compile it into a standard assembly program.
+    if let Err(err) = expr
+        .clone()
+        // Compile the expression into the core assembly program.
+        .compile_expr(&mut Env::default(), &mut core_asm)
+    {
+        warn!("Failed to compile into core assembly program: {err}, falling back on standard assembly");
+        let mut std_asm = StandardProgram::default();
+        // Compile the expression into the standard assembly program.
+        expr.compile_expr(&mut Env::default(), &mut std_asm)?;
+        info!("Compiled to standard assembly successfully.");
+        // Return the fallback standard assembly program.
+        Ok(Err(std_asm))
+    } else {
+        info!("Compiled to core assembly successfully.");
+        // Return the successfully compiled core assembly program.
+        Ok(Ok(core_asm))
+    }
+}
+
 /// Compile an LIR expression into several core assembly instructions.
 impl Compile for Expr {
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
         trace!("Compiling expression {self} in environment {env}");
-        let mut debug_str = format!("{self:50}");
-        debug_str.truncate(50);
+        let _depth = Depth::enter().map_err(Error::RecursionDepthExpr)?;
 
-        // Write a little comment about what we're compiling.
-        if !matches!(self, Self::ConstExpr(_)) {
-            let mut comment = format!("{self}");
-            comment.truncate(70);
-        }
-
-        // Compile the expression.
+        // Annotations and blocks are nested the most deeply in most programs,
+        // so they're compiled here, where each level of nesting uses much less
+        // of the stack than the rest of the expressions do.
         match self {
             Self::Annotated(expr, metdata) => {
+                // Mark where the expression came from in the source code, if asked to.
+                if LINE_MARKERS.with(Cell::get) {
+                    if let Some(location) = metdata.location() {
+                        output.comment(location.line_marker());
+                    }
+                }
                 // Compile the expression.
                 expr.compile_expr(env, output)
                     .map_err(|e| e.annotate(metdata))?;
             }
+            // Compile a block of expressions.
+            Self::Many(exprs) => {
+                for expr in exprs {
+                    // Compile the expression in the block.
+                    expr.compile_expr(env, output)?;
+                }
+            }
+            // Compile the expression.
+            expr => expr.compile_other_expr(env, output)?,
+        }
+
+        // Return success.
+        Ok(())
+    }
+}
+
+impl Expr {
+    /// Compile any expression other than an annotation or a block.
+    /// This is kept out of `compile_expr`, so that its large stack frame
+    /// isn't used for every level of nesting in the program.
+    #[inline(never)]
+    fn compile_other_expr(
+        self,
+        env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<(), Error> {
+        match self {
+            // These are compiled by `compile_expr`.
+            expr @ (Self::Annotated(..) | Self::Many(_)) => expr.compile_expr(env, output)?,
 
             Self::Match(expr, branches) => {
                 // Generate the pattern matching code.
@@ -163,13 +276,6 @@ impl Compile for Expr {
 
             // Compile a constant expression.
             Self::ConstExpr(expr) => expr.compile_expr(env, output)?,
-            // Compile a block of expressions.
-            Self::Many(exprs) => {
-                for expr in exprs {
-                    // Compile the expression in the block.
-                    expr.compile_expr(env, output)?;
-                }
-            }
 
             // Compile a type cast.
             Self::As(ref expr, ref t) => {
@@ -347,6 +453,27 @@ impl Compile for Expr {
             Self::Return(e) => {
                 // Get the size of the arguments and return value.
                 let args_size = env.get_args_size();
+                if let Some(args) = e.get_self_tail_call_args(env) {
+                    // Push the arguments of the tail call.
+                    for arg in args {
+                        arg.compile_expr(env, output)?;
+                    }
+                    // Overwrite the current arguments with the new ones.
+                    output.op(CoreOp::Copy {
+                        dst: FP.deref().offset(1 - args_size as isize),
+                        src: SP.deref().offset(1 - args_size as isize),
+                        size: args_size,
+                    });
+                    // Reset the stack pointer to the end of the arguments.
+                    output.op(CoreOp::GetAddress {
+                        addr: FP.deref(),
+                        dst: SP,
+                    });
+                    // Tell the procedure to run its body again.
+                    output.op(CoreOp::Set(A, 1));
+                    output.op(CoreOp::Return);
+                    return Ok(());
+                }
                 let ret_size = e.get_size(env)?;
                 // Execute the body to leave the return value
                 e.compile_expr(env, output)?;
@@ -372,6 +499,10 @@ impl Compile for Expr {
                     SP,
                     Some(args_size as isize - ret_size as isize),
                 ));
+                if env.get_tail_call().is_some() {
+                    // Tell the procedure not to run its body again.
+                    output.op(CoreOp::Set(A, 0));
+                }
                 output.op(CoreOp::Return);
             }
 
@@ -50,6 +50,10 @@ pub struct Env {
     /// Expected return type of the current function.
     /// This is `None` if we are not currently compiling a function.
     expected_ret: Option<Type>,
+    /// The mangled name of the procedure whose body is being compiled, if
+    /// the body returns the result of calling the procedure itself.
+    /// Such a `return` reuses the current frame instead of making a new call.
+    tail_call: Option<String>,
 
     /// Memoized type sizes.
     type_sizes: Rc<HashMap<Type, usize>>,
@@ -75,6 +79,7 @@ impl Default for Env {
             fp_offset: 1,
             args_size: 0,
             expected_ret: None,
+            tail_call: None,
         }
     }
 }
@@ -709,6 +714,7 @@ impl Env {
                 // }
             }
             Declaration::Many(decls) => {
+                declaration.check_redefinitions()?;
                 for decl in decls {
                     self.add_compile_time_declaration(decl)?;
                 }
@@ -867,6 +873,7 @@ impl Env {
     pub(super) fn define_const(&mut self, name: impl ToString, e: ConstExpr) {
         let name = name.to_string();
         trace!("Defining constant {name} as {e}");
+        self.forget_proc(&name);
         Rc::make_mut(&mut self.consts).insert(name, e);
     }
 
@@ -879,6 +886,11 @@ impl Env {
     pub(super) fn define_proc(&mut self, name: impl ToString, proc: Procedure) {
         let name = name.to_string();
         trace!("Defining procedure {name} as {proc}");
+        // Procedures and constants are looked up separately, so a procedure
+        // has to remove any constant it shadows, like a builtin of the same name.
+        if self.consts.contains_key(&name) {
+            Rc::make_mut(&mut self.consts).remove(&name);
+        }
         Rc::make_mut(&mut self.procs).insert(name, proc);
     }
 
@@ -886,6 +898,7 @@ impl Env {
     pub(super) fn define_poly_proc(&mut self, name: impl ToString, proc: PolyProcedure) {
         let name = name.to_string();
         trace!("Defining polymorphic procedure {name} as {proc}");
+        self.forget_proc(&name);
         Rc::make_mut(&mut self.consts).insert(name, ConstExpr::PolyProc(proc));
     }
 
@@ -893,9 +906,17 @@ impl Env {
     pub(super) fn define_ffi_proc(&mut self, name: impl ToString, proc: FFIProcedure) {
         let name = name.to_string();
         trace!("Defining FFI procedure {name} as {proc}");
+        self.forget_proc(&name);
         Rc::make_mut(&mut self.consts).insert(name, ConstExpr::FFIProcedure(proc));
     }
 
+    /// Remove a procedure which is shadowed by a constant of the same name.
+    fn forget_proc(&mut self, name: &str) {
+        if self.procs.contains_key(name) {
+            Rc::make_mut(&mut self.procs).remove(name);
+        }
+    }
+
     /// Get a procedure definition from this environment.
     pub(super) fn get_proc(&self, name: &str) -> Option<&Procedure> {
         self.procs.get(name)
@@ -1016,6 +1037,18 @@ impl Env {
         self.expected_ret = Some(t);
     }
 
+    /// Get the mangled name of the procedure whose tail calls to itself
+    /// reuse the current frame, if there is one.
+    pub(super) fn get_tail_call(&self) -> Option<&str> {
+        self.tail_call.as_deref()
+    }
+
+    /// Compile tail calls to the procedure with the given mangled name
+    /// by reusing the current frame.
+    pub(super) fn set_tail_call(&mut self, mangled_name: impl ToString) {
+        self.tail_call = Some(mangled_name.to_string());
+    }
+
     /// Does the environment have some precalculated size for the given type?
     /// This helps the compiler memoize the size of types so that it doesn't have to
     /// recalculate the size of the same type multiple times.
@@ -2,6 +2,7 @@ use super::{
     Annotation, AssignOp, BinaryOp, ConstExpr, Expr, Mutability, Pattern, PolyProcedure, TernaryOp,
     Type, UnaryOp,
 };
+use crate::parse::SourceCodeLocation;
 use core::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 /// An LIR compilation error.
@@ -30,6 +31,8 @@ pub enum Error {
     CouldntSimplify(Type, Type),
     /// Recursion depth exceeded when trying to confirm a type's equality to another type.
     RecursionDepthTypeEquality(Type, Type),
+    /// Expressions were nested more deeply than the limit set by `set_max_depth`.
+    RecursionDepthExpr(usize),
     /// Got another type when expecting an integer, bool, or char.
     NonIntegralConst(ConstExpr),
     /// Tried to instantiate a type that cannot be sized.
@@ -104,6 +107,15 @@ pub enum Error {
 
     /// Tried to define a type that already exists.
     TypeRedefined(String),
+    /// A type, constant, procedure, or static variable was defined twice in the same scope.
+    SymbolRedefined {
+        /// The name that was defined twice.
+        name: String,
+        /// The location of the first definition, if it's known.
+        first: Option<Box<SourceCodeLocation>>,
+        /// The location of the redefinition, if it's known.
+        second: Option<Box<SourceCodeLocation>>,
+    },
 
     /// Unused expression returned a non-None value.
     UnusedExpr(Expr, Type),
@@ -198,6 +210,13 @@ impl Display for Error {
                     ty1, ty2
                 )
             }
+            Self::RecursionDepthExpr(max_depth) => {
+                write!(
+                    f,
+                    "recursion depth exceeded: expressions are nested more than {} levels deep",
+                    max_depth
+                )
+            }
             Self::NonIntegralConst(expr) => {
                 write!(f, "got non-integral constant expression {}", expr)
             }
@@ -300,6 +319,19 @@ impl Display for Error {
             Self::TypeRedefined(ty) => {
                 write!(f, "type {} redefined", ty)
             }
+            Self::SymbolRedefined { name, first, .. } => {
+                write!(f, "symbol {} redefined", name)?;
+                if let Some(first) = first {
+                    write!(
+                        f,
+                        ", it was first defined at {}:{}:{}",
+                        first.filename.as_deref().unwrap_or("unknown"),
+                        first.line,
+                        first.column
+                    )?;
+                }
+                Ok(())
+            }
             Self::UnusedExpr(expr, ty) => {
                 write!(f, "unused expression {} of type {}", expr, ty)
             }
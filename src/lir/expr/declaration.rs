@@ -2,16 +2,17 @@ use super::{PolyProcedure, Procedure};
 use crate::{
     asm::{AssemblyProgram, CoreOp, Location, SP},
     lir::{
-        Compile, ConstExpr, Env, Error, Expr, FFIProcedure, GetSize, GetType, Mutability, Pattern,
-        Type, TypeCheck,
+        Annotation, Compile, ConstExpr, Env, Error, Expr, FFIProcedure, GetSize, GetType,
+        Mutability, Pattern, Type, TypeCheck,
     },
+    parse::SourceCodeLocation,
 };
 use core::{
     fmt::{Display, Formatter, Result as FmtResult},
     ops::{Add, AddAssign},
 };
 use log::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::{Hash, Hasher};
 
 /// A declaration of a variable, function, type, etc.
@@ -63,6 +64,68 @@ impl Declaration {
         }
     }
 
+    /// The declarations in this declaration, with multi-declarations flattened out.
+    fn leaves(&self) -> Vec<&Self> {
+        match self {
+            Self::Many(decls) => decls.iter().flat_map(|decl| decl.leaves()).collect(),
+            decl => vec![decl],
+        }
+    }
+
+    /// The name defined at compile-time by this declaration, if it defines a type,
+    /// constant, procedure, or static variable. Types have their own namespace,
+    /// so the name is paired with whether it's the name of a type.
+    fn compile_time_name(&self) -> Option<(&str, bool)> {
+        match self {
+            Self::Type(name, _) => Some((name, true)),
+            Self::Const(name, _)
+            | Self::Proc(name, _)
+            | Self::PolyProc(name, _)
+            | Self::ExternProc(name, _)
+            | Self::StaticVar(name, ..) => Some((name, false)),
+            Self::Var(..) | Self::VarPat(..) | Self::Impl(..) | Self::Many(_) => None,
+        }
+    }
+
+    /// The location of this declaration in the source code, if it's known.
+    /// Only procedures and annotated constants know where they were defined.
+    fn location(&self) -> Option<&SourceCodeLocation> {
+        match self {
+            Self::Proc(_, proc) | Self::Const(_, ConstExpr::Proc(proc)) => proc.get_location(),
+            Self::PolyProc(_, proc) | Self::Const(_, ConstExpr::PolyProc(proc)) => {
+                proc.get_location()
+            }
+            Self::Const(_, ConstExpr::Annotated(_, annotation)) => annotation.location(),
+            _ => None,
+        }
+    }
+
+    /// Make sure that no two declarations in this declaration define the same type,
+    /// constant, procedure, or static variable. Otherwise, the later definition would
+    /// silently replace the earlier one. Variables can still be shadowed.
+    pub(crate) fn check_redefinitions(&self) -> Result<(), Error> {
+        let mut defined = HashMap::new();
+        for decl in self.leaves() {
+            let name = match decl.compile_time_name() {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(first) = defined.insert(name, decl) {
+                let second = decl.location().cloned();
+                let err = Error::SymbolRedefined {
+                    name: name.0.to_string(),
+                    first: first.location().cloned().map(Box::new),
+                    second: second.clone().map(Box::new),
+                };
+                return Err(match second {
+                    Some(second) => err.annotate(Annotation::Location(second)),
+                    None => err,
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Does this declaration include a local variable declaration?
     pub(crate) fn has_local_variable_declaration(&self) -> bool {
         match self {
@@ -484,6 +547,39 @@ impl TypeCheck for Declaration {
         }
         Ok(())
     }
+
+    fn type_check_all(&self, env: &Env) -> Result<(), Vec<Error>> {
+        match self {
+            // Typecheck each declaration of a multi-declaration, so that errors
+            // in independent declarations are all reported.
+            Self::Many(decls) => {
+                let mut new_env = env.clone();
+                // Add all the compile-time declarations to the environment.
+                new_env
+                    .add_compile_time_declaration(&self.clone())
+                    .map_err(|e| vec![e])?;
+                let mut errors = vec![];
+                for decl in decls {
+                    // Typecheck any variable declarations in the old scope
+                    if let Err(errs) = decl.type_check_all(&new_env) {
+                        errors.extend(errs);
+                    }
+                    // Add them to the new scope. If this fails, the rest
+                    // of the declarations can't be checked.
+                    if let Err(e) = new_env.add_declaration(decl) {
+                        errors.push(e);
+                        break;
+                    }
+                }
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors)
+                }
+            }
+            _ => self.type_check(env).map_err(|e| vec![e]),
+        }
+    }
 }
 
 impl Display for Declaration {
@@ -454,6 +454,33 @@ impl Expr {
         }
     }
 
+    /// If this expression calls the procedure whose tail calls reuse the
+    /// current frame (see `Env::get_tail_call`), get the arguments of the call.
+    pub(crate) fn get_self_tail_call_args(&self, env: &Env) -> Option<Vec<Self>> {
+        let tail_call = env.get_tail_call()?;
+        match self {
+            Self::Annotated(inner, _) => inner.get_self_tail_call_args(env),
+            Self::Apply(fun, args) => match fun.as_ref() {
+                Self::Annotated(inner, _) => {
+                    Self::Apply(inner.clone(), args.clone()).get_self_tail_call_args(env)
+                }
+                Self::ConstExpr(ConstExpr::Symbol(name))
+                    // Variables and constants shadow procedures with the same name.
+                    if env.get_var(name).is_none()
+                        && env.get_static_var(name).is_none()
+                        && env.get_const(name).is_none() =>
+                {
+                    match env.get_proc(name) {
+                        Some(proc) if proc.get_mangled_name() == tail_call => Some(args.clone()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     /// An annotated expression with some metadata.
     pub fn annotate(&self, annotation: impl Into<Annotation>) -> Self {
         match self {
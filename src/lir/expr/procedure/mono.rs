@@ -10,8 +10,10 @@
 //! Procedures are created by the `proc` keyword.
 use crate::asm::{AssemblyProgram, CoreOp, A, FP, SP};
 use crate::lir::{
-    Compile, ConstExpr, Env, Error, Expr, GetSize, GetType, Mutability, Type, TypeCheck,
+    Compile, ConstExpr, Declaration, Env, Error, Expr, GetSize, GetType, Mutability, Type,
+    TypeCheck,
 };
+use crate::parse::SourceCodeLocation;
 use core::fmt;
 use std::hash::Hash;
 use std::{
@@ -43,6 +45,8 @@ pub struct Procedure {
     ret: Type,
     /// The procedure's body expression
     body: Box<Expr>,
+    /// Where the procedure was defined in the source code, if it's known.
+    location: Option<Box<SourceCodeLocation>>,
     has_type_checked: Rc<RwLock<bool>>,
 }
 
@@ -73,10 +77,22 @@ impl Procedure {
             args,
             ret,
             body: Box::new(body.into()),
+            location: None,
             has_type_checked: Rc::new(RwLock::new(false)),
         }
     }
 
+    /// Record where the procedure was defined in the source code.
+    pub fn with_location(mut self, location: Option<SourceCodeLocation>) -> Self {
+        self.location = location.map(Box::new);
+        self
+    }
+
+    /// Get where the procedure was defined in the source code, if it's known.
+    pub fn get_location(&self) -> Option<&SourceCodeLocation> {
+        self.location.as_deref()
+    }
+
     /// Get the arguments of the procedure.
     pub fn get_args(&self) -> &[(String, Mutability, Type)] {
         &self.args
@@ -110,6 +126,112 @@ impl Procedure {
         self.common_name = Some(name.to_string());
     }
 
+    /// Does the body return the result of calling this procedure by name?
+    /// This only looks for `return` statements in the blocks and branches
+    /// of the body, and not inside of any nested procedures.
+    fn has_self_tail_call(&self) -> bool {
+        fn is_symbol(expr: &Expr, name: &str) -> bool {
+            match expr {
+                Expr::Annotated(expr, _) => is_symbol(expr, name),
+                Expr::ConstExpr(ConstExpr::Symbol(symbol)) => symbol == name,
+                _ => false,
+            }
+        }
+
+        fn is_self_call(expr: &Expr, name: &str) -> bool {
+            match expr {
+                Expr::Annotated(expr, _) => is_self_call(expr, name),
+                Expr::Apply(f, _) => is_symbol(f, name),
+                _ => false,
+            }
+        }
+
+        fn has_tail_call(expr: &Expr, name: &str) -> bool {
+            match expr {
+                Expr::Annotated(expr, _) => has_tail_call(expr, name),
+                Expr::Return(expr) => is_self_call(expr, name),
+                Expr::Many(exprs) => exprs.iter().any(|expr| has_tail_call(expr, name)),
+                Expr::Declare(_, body) | Expr::While(_, body) => has_tail_call(body, name),
+                Expr::If(_, then, otherwise)
+                | Expr::When(_, then, otherwise)
+                | Expr::IfLet(_, _, then, otherwise) => {
+                    has_tail_call(then, name) || has_tail_call(otherwise, name)
+                }
+                Expr::Match(_, branches) => branches
+                    .iter()
+                    .any(|(_, branch)| has_tail_call(branch, name)),
+                _ => false,
+            }
+        }
+
+        match &self.common_name {
+            Some(name) => has_tail_call(&self.body, name),
+            None => false,
+        }
+    }
+
+    /// Does the body take the address of anything, other than the target of an assignment?
+    /// A pointer like this could point into the frame, which a tail call would overwrite
+    /// while the pointer is still in use, so these procedures always make normal calls.
+    fn takes_address(&self) -> bool {
+        fn in_decl(decl: &Declaration) -> bool {
+            match decl {
+                Declaration::Var(_, _, _, expr) | Declaration::VarPat(_, expr) => in_expr(expr),
+                Declaration::Many(decls) => decls.iter().any(in_decl),
+                // Nested procedures have their own frames.
+                _ => false,
+            }
+        }
+
+        // Assignments take the address of their target, but only to write to it.
+        fn in_target(dst: &Expr) -> bool {
+            match dst {
+                Expr::Annotated(dst, _) => in_target(dst),
+                Expr::Refer(_, dst) => in_expr(dst),
+                dst => in_expr(dst),
+            }
+        }
+
+        fn in_expr(expr: &Expr) -> bool {
+            match expr {
+                Expr::Refer(_, _) => true,
+                Expr::AssignOp(_, dst, src) | Expr::DerefMut(dst, src) => {
+                    in_target(dst) || in_expr(src)
+                }
+                Expr::Annotated(expr, _)
+                | Expr::UnaryOp(_, expr)
+                | Expr::Deref(expr)
+                | Expr::Return(expr)
+                | Expr::Union(_, _, expr)
+                | Expr::EnumUnion(_, _, expr)
+                | Expr::As(expr, _)
+                | Expr::Member(expr, _) => in_expr(expr),
+                Expr::Declare(decl, body) => in_decl(decl) || in_expr(body),
+                Expr::While(cond, body)
+                | Expr::BinaryOp(_, cond, body)
+                | Expr::Index(cond, body) => in_expr(cond) || in_expr(body),
+                Expr::If(cond, then, otherwise) | Expr::TernaryOp(_, cond, then, otherwise) => {
+                    in_expr(cond) || in_expr(then) || in_expr(otherwise)
+                }
+                Expr::When(_, then, otherwise) => in_expr(then) || in_expr(otherwise),
+                Expr::IfLet(_, val, then, otherwise) => {
+                    in_expr(val) || in_expr(then) || in_expr(otherwise)
+                }
+                Expr::Match(val, branches) => {
+                    in_expr(val) || branches.iter().any(|(_, branch)| in_expr(branch))
+                }
+                Expr::Apply(fun, args) => in_expr(fun) || args.iter().any(in_expr),
+                Expr::Many(exprs) | Expr::Array(exprs) | Expr::Tuple(exprs) => {
+                    exprs.iter().any(in_expr)
+                }
+                Expr::Struct(fields) => fields.values().any(in_expr),
+                Expr::ConstExpr(_) => false,
+            }
+        }
+
+        in_expr(&self.body)
+    }
+
     /// Push this procedure's label to the stack.
     pub fn push_label(&self, output: &mut dyn AssemblyProgram) {
         // Set a register to the address of the procedure's label.
@@ -180,6 +302,9 @@ impl Compile for Procedure {
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
         // Compile the contents of the procedure under a new environment
         let mut new_env = env.new_scope();
+        // Check if the body returns the result of calling this procedure,
+        // and that reusing the frame for the call can't invalidate a pointer.
+        let is_tail_recursive = self.has_self_tail_call() && !self.takes_address();
 
         // Declare the arguments and get their size
         let args_size = new_env.define_args(self.args)?;
@@ -199,10 +324,25 @@ impl Compile for Procedure {
         // Declare the function body
         output.op(CoreOp::Fn(self.mangled_name.clone()));
         if let Some(common_name) = &self.common_name {
+            output.name_label(&self.mangled_name, common_name);
             output.comment(format!("{}({})", common_name, args_size));
         }
         let current_instruction = output.current_instruction();
 
+        // If the body has a tail call to this procedure, compile the body as
+        // an inner function which is called in a loop. A tail call overwrites
+        // the arguments in the frame and returns with `A` set to 1 to run the
+        // body again, so tail recursion doesn't grow the call stack.
+        let body_name = format!("{}_BODY", self.mangled_name);
+        if is_tail_recursive {
+            new_env.set_tail_call(&self.mangled_name);
+            // Nothing is pushed before calling the body, so it shares our frame.
+            output.op(CoreOp::Fn(body_name.clone()));
+            if let Some(common_name) = &self.common_name {
+                output.name_label(&body_name, common_name);
+            }
+        }
+
         // Execute the body to leave the return value
         self.body.compile_expr(&mut new_env, output)?;
 
@@ -215,6 +355,17 @@ impl Compile for Procedure {
         // Decrement the stack pointer by the difference between the size of the
         // arguments and return value, to leave the return value on the stack.
         output.op(CoreOp::Pop(None, args_size));
+
+        if is_tail_recursive {
+            // The body finished without a tail call, so stop the loop.
+            output.op(CoreOp::Set(A, 0));
+            output.op(CoreOp::End);
+            // Run the body until it doesn't end in a tail call.
+            output.op(CoreOp::Set(A, 1));
+            output.op(CoreOp::While(A));
+            output.op(CoreOp::CallLabel(body_name));
+            output.op(CoreOp::End);
+        }
         // End the function body
         output.op(CoreOp::End);
 
@@ -4,6 +4,7 @@
 //! This is mono-morphed into a `Procedure` when it is called with a list of type arguments.
 //! A procedure is compiled down to a label in the assembly code.
 use crate::lir::{ConstExpr, Env, Error, Expr, GetType, Mutability, Type, TypeCheck};
+use crate::parse::SourceCodeLocation;
 use core::fmt;
 use log::{debug, error, trace};
 use std::{collections::HashMap, rc::Rc, sync::RwLock};
@@ -26,6 +27,8 @@ pub struct PolyProcedure {
     ret: Type,
     /// The body of the procedure.
     body: Box<Expr>,
+    /// Where the procedure was defined in the source code, if it's known.
+    location: Option<Box<SourceCodeLocation>>,
     /// The monomorphs of the procedure.
     monomorphs: Rc<RwLock<HashMap<String, Procedure>>>,
     has_type_checked: Rc<RwLock<bool>>,
@@ -57,11 +60,23 @@ impl PolyProcedure {
             args,
             ret,
             body: Box::new(body.into()),
+            location: None,
             monomorphs: Rc::new(RwLock::new(HashMap::new())),
             has_type_checked: Rc::new(RwLock::new(false)),
         }
     }
 
+    /// Record where the procedure was defined in the source code.
+    pub fn with_location(mut self, location: Option<SourceCodeLocation>) -> Self {
+        self.location = location.map(Box::new);
+        self
+    }
+
+    /// Get where the procedure was defined in the source code, if it's known.
+    pub fn get_location(&self) -> Option<&SourceCodeLocation> {
+        self.location.as_deref()
+    }
+
     pub fn from_mono(mono: Procedure, ty_params: Vec<String>) -> Self {
         debug!(target: "mono", "Creating polymorphic procedure from monomorph {}", mono);
         let name = mono
@@ -75,6 +90,7 @@ impl PolyProcedure {
             args: mono.get_args().to_vec(),
             ret: mono.get_ret().clone(),
             body: mono.get_body().clone().into(),
+            location: mono.get_location().cloned().map(Box::new),
             monomorphs: Rc::new(RwLock::new(HashMap::new())),
             has_type_checked: Rc::new(RwLock::new(false)),
         }
@@ -169,6 +185,7 @@ impl PolyProcedure {
                 );
 
                 Procedure::new(Some(mangled_name.clone()), args, ret, body)
+                    .with_location(self.get_location().cloned())
             })
             .clone();
 
@@ -10,7 +10,7 @@
 //! - Ensuring that all array lengths are non-negative.
 //! - Ensuring that you don't attempt to access a variable that is out of scope.
 use super::*;
-use crate::lir::Pattern;
+use crate::lir::{Depth, Pattern};
 
 use log::{error, trace};
 
@@ -21,6 +21,12 @@ use log::{error, trace};
 pub trait TypeCheck {
     /// Type check the expression.
     fn type_check(&self, env: &Env) -> Result<(), Error>;
+
+    /// Type check the expression, but keep going after an error wherever
+    /// the rest of the expression can still be checked, and return every error found.
+    fn type_check_all(&self, env: &Env) -> Result<(), Vec<Error>> {
+        self.type_check(env).map_err(|e| vec![e])
+    }
 }
 
 /// Check the soundness of a given type in the environment.
@@ -179,15 +185,69 @@ impl TypeCheck for Type {
 impl TypeCheck for Expr {
     fn type_check(&self, env: &Env) -> Result<(), Error> {
         trace!("Type checking expression: {self}");
-        let ty = self.get_type(env)?;
-        ty.type_check(env)?;
+        let _depth = Depth::enter().map_err(Error::RecursionDepthExpr)?;
 
         match self {
+            // Every statement is annotated, so annotations are checked
+            // without the large stack frame of `type_check_other`.
             Self::Annotated(expr, metadata) => {
                 // Check the inner expression.
                 expr.type_check(env)
                     .map_err(|e| e.annotate(metadata.clone()))
             }
+            _ => self.type_check_other(env),
+        }
+    }
+
+    fn type_check_all(&self, env: &Env) -> Result<(), Vec<Error>> {
+        match self {
+            Self::Annotated(expr, metadata) => {
+                // Check the inner expression.
+                expr.type_check_all(env).map_err(|errs| {
+                    errs.into_iter()
+                        .map(|e| e.annotate(metadata.clone()))
+                        .collect()
+                })
+            }
+
+            Self::Declare(declaration, body) => {
+                // Check the declaration, and keep going if the names
+                // it declares can still be added to the environment.
+                let mut errors = declaration.type_check_all(env).err().unwrap_or_default();
+                let mut new_env = env.clone();
+                if let Err(e) = new_env.add_declaration(declaration) {
+                    // Checking the declaration may have already found this error.
+                    if !errors.iter().any(|err| err.to_string() == e.to_string()) {
+                        errors.push(e);
+                    }
+                    return Err(errors);
+                }
+                // Check the body with the declarations defined.
+                if let Err(errs) = body.type_check_all(&new_env) {
+                    errors.extend(errs);
+                }
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors)
+                }
+            }
+
+            _ => self.type_check(env).map_err(|e| vec![e]),
+        }
+    }
+}
+
+impl Expr {
+    /// Check any expression other than an annotation.
+    #[inline(never)]
+    fn type_check_other(&self, env: &Env) -> Result<(), Error> {
+        let ty = self.get_type(env)?;
+        ty.type_check(env)?;
+
+        match self {
+            // These are checked by `type_check`.
+            Self::Annotated(..) => self.type_check(env),
 
             Self::Declare(declaration, body) => {
                 // Create a new environment with the declarations defined.
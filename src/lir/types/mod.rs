@@ -1543,6 +1543,7 @@ impl Type {
 
         if i >= Self::SIMPLIFY_RECURSION_LIMIT {
             warn!(
+                target: crate::WARNING_TARGET,
                 "Recursion depth limit reached while checking if {} equals {}",
                 self, other
             );
@@ -1993,7 +1994,10 @@ impl Type {
 
             Type::Any => {
                 // Any type can have any member
-                warn!("Type checking member `{member}` of Any type {self}");
+                warn!(
+                    target: crate::WARNING_TARGET,
+                    "Type checking member `{member}` of Any type {self}"
+                );
                 Ok(())
             }
 
@@ -34,6 +34,7 @@ use log::trace;
 
 use lalrpop_util::lalrpop_mod;
 use no_comment::{languages, IntoWithoutComments};
+use std::path::PathBuf;
 
 /// A struct representing a location in the source code.
 /// This is used to format errors properly.
@@ -47,6 +48,23 @@ pub struct SourceCodeLocation {
 }
 
 impl SourceCodeLocation {
+    /// A comment marking the code generated from this location.
+    /// Targets like C can turn these markers into `#line` directives,
+    /// so that debuggers can map the generated code back to the source.
+    pub fn line_marker(&self) -> String {
+        format!(
+            "#line {} {:?}",
+            self.line,
+            self.filename.as_deref().unwrap_or("unknown")
+        )
+    }
+
+    /// The line number and filename in a comment made by `line_marker`, if it is one.
+    pub fn parse_line_marker(comment: &str) -> Option<(usize, String)> {
+        let (line, filename) = comment.strip_prefix("#line ")?.split_once(' ')?;
+        Some((line.parse().ok()?, snailquote::unescape(filename).ok()?))
+    }
+
     // Given the source code, get the string associated with this location.
     pub fn get_code(&self, source: &str) -> String {
         let mut code = String::new();
@@ -73,6 +91,29 @@ impl SourceCodeLocation {
     }
 }
 
+/// An error found by an action in one of the LALRPOP grammars,
+/// with the span of the source code it's about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct GrammarError {
+    /// The offset of the start of the span.
+    pub start: usize,
+    /// The offset of the end of the span.
+    pub end: usize,
+    /// What's wrong with the code in the span.
+    pub message: &'static str,
+}
+
+impl GrammarError {
+    /// Move the span of the error, for code parsed from inside of other code.
+    pub fn shifted(self, offset: usize) -> Self {
+        Self {
+            start: self.start + offset,
+            end: self.end + offset,
+            ..self
+        }
+    }
+}
+
 lalrpop_mod!(
     #[allow(clippy::all)]
     asm_parser
@@ -153,16 +194,66 @@ pub fn parse_frontend(input: impl ToString, filename: Option<&str>) -> Result<Ex
     Ok(result)
 }
 
-type SyntaxError<'a, T> = lalrpop_util::ParseError<usize, T, &'a str>;
+/// Parse several files of frontend sage code into one LIR expression.
+/// The top-level declarations of the files are combined in order, and
+/// it's an error for two files to declare the same name.
+pub fn parse_frontend_files<'a>(
+    files: impl IntoIterator<Item = (impl ToString, Option<&'a str>)>,
+) -> Result<Expr, String> {
+    parse_frontend_files_with_entry(files, None)
+}
+
+/// Parse several files of frontend sage code into one LIR expression, like
+/// `parse_frontend_files`. If an entry point is given, the program starts by
+/// calling the procedure with that name, instead of running its top-level statements.
+pub fn parse_frontend_files_with_entry<'a>(
+    files: impl IntoIterator<Item = (impl ToString, Option<&'a str>)>,
+    entry: Option<&str>,
+) -> Result<Expr, String> {
+    parse_frontend_files_with_include_dirs(files, entry, &[])
+}
+
+/// Parse several files of frontend sage code into one LIR expression, like
+/// `parse_frontend_files_with_entry`. Included files which aren't next to the
+/// including file are searched for in each of the `include_dirs`, in order.
+pub fn parse_frontend_files_with_include_dirs<'a>(
+    files: impl IntoIterator<Item = (impl ToString, Option<&'a str>)>,
+    entry: Option<&str>,
+    include_dirs: &[PathBuf],
+) -> Result<Expr, String> {
+    let result = frontend::parse_files_with_include_dirs(files, entry, include_dirs)?;
+    trace!(target: "parse", "Parsed frontend code: {result}");
+    Ok(result)
+}
+
+type SyntaxError<T> = lalrpop_util::ParseError<usize, T, GrammarError>;
 
 /// This formats an error properly given the line, the `unexpected` token as a string,
 /// the line number, and the column number of the unexpected token.
 fn make_error(line: &str, unexpected: &str, line_number: usize, column_number: usize) -> String {
-    // The string used to underline the unexpected token
+    make_error_with_message(
+        line,
+        unexpected,
+        line_number,
+        column_number,
+        &format!("unexpected `{unexpected}`"),
+    )
+}
+
+/// This formats an error like `make_error`, but with a message about the
+/// underlined code instead of saying that it was unexpected.
+fn make_error_with_message(
+    line: &str,
+    underlined: &str,
+    line_number: usize,
+    column_number: usize,
+    message: &str,
+) -> String {
+    // The string used to underline the code
     let underline = format!(
         "{}^{}",
         " ".repeat(column_number),
-        "-".repeat(unexpected.len() - 1)
+        "-".repeat(underlined.chars().count().saturating_sub(1))
     );
 
     // Format string properly and return
@@ -171,12 +262,12 @@ fn make_error(line: &str, unexpected: &str, line_number: usize, column_number: u
 {line_number} | {line}
 {WS} | {underline}
 {WS} |
-{WS} = unexpected `{unexpected}`",
+{WS} = {message}",
         WS = " ".repeat(line_number.to_string().len()),
         line_number = line_number,
         line = line,
         underline = underline,
-        unexpected = unexpected
+        message = message
     )
 }
 
@@ -186,8 +277,14 @@ fn get_line(script: &str, location: usize) -> (usize, String, usize) {
         return (1, "".to_string(), 0);
     }
 
+    // The location is a byte offset, so move it back to the start of the character it's in
+    let mut location = location.min(script.len());
+    while !script.is_char_boundary(location) {
+        location -= 1;
+    }
+
     // Get the line number from the character location
-    let line_number = script[..(location + 1).min(script.len())].lines().count();
+    let line_number = script[..location].matches('\n').count() + 1;
     // Get the line from the line number
     let line = match script.lines().nth(line_number - 1) {
         Some(line) => line,
@@ -202,8 +299,8 @@ fn get_line(script: &str, location: usize) -> (usize, String, usize) {
     .replace('\t', "    ");
 
     // Get the column number from the location
-    let mut column = {
-        let mut current_column = 0;
+    let column = {
+        let mut current_column: usize = 0;
         // For every character in the script until the location of the error,
         // keep track of the column location
         for ch in script[..location].chars() {
@@ -220,9 +317,13 @@ fn get_line(script: &str, location: usize) -> (usize, String, usize) {
 
     // Trim the beginning of the line and subtract the number of spaces from the column
     let trimmed_line = line.trim_start();
-    column -= (line.len() - trimmed_line.len()) as i32;
+    let indent = line.chars().count() - trimmed_line.chars().count();
 
-    (line_number, String::from(trimmed_line), column as usize)
+    (
+        line_number,
+        String::from(trimmed_line),
+        column.saturating_sub(indent),
+    )
 }
 
 /// This is used to take an LALRPOP error and convert
@@ -231,12 +332,11 @@ fn format_error<T: core::fmt::Debug>(script: &str, err: SyntaxError<T>) -> Strin
     match err {
         SyntaxError::InvalidToken { location } => {
             let (line_number, line, column) = get_line(script, location);
-            make_error(
-                &line,
-                &(script.as_bytes()[location] as char).to_string(),
-                line_number,
-                column,
-            )
+            let unexpected = match script.get(location..).and_then(|s| s.chars().next()) {
+                Some(ch) => ch.to_string(),
+                None => "EOF".to_string(),
+            };
+            make_error(&line, &unexpected, line_number, column)
         }
         SyntaxError::UnrecognizedEOF { location, .. } => {
             let (line_number, line, _) = get_line(script, location);
@@ -261,10 +361,10 @@ fn format_error<T: core::fmt::Debug>(script: &str, err: SyntaxError<T>) -> Strin
 
             make_error(&line, unexpected, line_number, column)
         }
-        SyntaxError::User { error } => format!(
-            "  |\n? | {}\n  | ^{}\n  |\n  = unexpected compiling error",
-            error,
-            "-".repeat(error.len() - 1)
-        ),
+        SyntaxError::User { error } => {
+            let (line_number, line, column) = get_line(script, error.start);
+            let underlined = script.get(error.start..error.end).unwrap_or("");
+            make_error_with_message(&line, underlined, line_number, column, error.message)
+        }
     }
 }
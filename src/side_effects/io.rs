@@ -64,6 +64,8 @@ pub enum InputMode {
     StdinInt,
     /// Standard input (float)
     StdinFloat,
+    /// Standard input (a whole UTF-8 encoded character, as its code point)
+    StdinUtf8,
 
     ///////////////////////////////////////////////////////////////
     /// Special input modes
@@ -199,6 +201,10 @@ pub enum OutputMode {
     StderrInt,
     /// Standard error (float)
     StderrFloat,
+    /// Standard output (a code point, written as a UTF-8 encoded character)
+    StdoutUtf8,
+    /// Standard error (a code point, written as a UTF-8 encoded character)
+    StderrUtf8,
 
     ///////////////////////////////////////////////////////////////
     /// Alternative output modes for standard output
@@ -331,6 +337,10 @@ impl Input {
     pub const fn stdin_float() -> Self {
         Self::new(InputMode::StdinFloat, 0)
     }
+    /// Input from STDIN (UTF-8 character)
+    pub const fn stdin_utf8() -> Self {
+        Self::new(InputMode::StdinUtf8, 0)
+    }
 
     /// A random number
     pub const fn random() -> Self {
@@ -390,6 +400,16 @@ impl Output {
     pub const fn stderr_float() -> Self {
         Self::new(OutputMode::StderrFloat, 0)
     }
+
+    /// Output to STDOUT (UTF-8 character)
+    pub const fn stdout_utf8() -> Self {
+        Self::new(OutputMode::StdoutUtf8, 0)
+    }
+
+    /// Output to STDERR (UTF-8 character)
+    pub const fn stderr_utf8() -> Self {
+        Self::new(OutputMode::StderrUtf8, 0)
+    }
 }
 
 impl Display for Input {
@@ -418,6 +438,8 @@ impl Display for InputMode {
             InputMode::StdinInt => write!(f, "stdin.int"),
             // Standard input (float)
             InputMode::StdinFloat => write!(f, "stdin.float"),
+            // Standard input (UTF-8 character)
+            InputMode::StdinUtf8 => write!(f, "stdin.utf8"),
 
             ///////////////////////////////////////////////////////////////
             // Special input modes
@@ -556,6 +578,10 @@ impl Display for OutputMode {
             OutputMode::StderrInt => write!(f, "stderr.int"),
             // Standard error (float)
             OutputMode::StderrFloat => write!(f, "stderr.float"),
+            // Standard output (UTF-8 character)
+            OutputMode::StdoutUtf8 => write!(f, "stdout.utf8"),
+            // Standard error (UTF-8 character)
+            OutputMode::StderrUtf8 => write!(f, "stderr.utf8"),
 
             ///////////////////////////////////////////////////////////////
             // Alternative output modes for standard output
@@ -0,0 +1,346 @@
+//! # AArch64 Target
+//!
+//! An implementation of the virtual machine for AArch64 (64-bit ARM).
+//!
+//! This allows the virtual machine to target ARMv8 CPUs,
+//! generating GNU assembly for the AAPCS64 Linux ABI.
+//!
+//! ## Register Usage
+//!
+//! Cells are 64 bits wide, and floats use the `d` registers.
+//! - `x0` holds the register.
+//! - `x19` holds the tape pointer.
+//! - `x20` saves the register across calls into libc.
+//! - `x21` points to the top of the ref stack used by `Deref` and `Refer`.
+//! - `x22` points to the top of the FFI channel.
+//!
+//! `While`, `If`, and `Else` are lowered like the x86 targets, branching with
+//! `cbz` when the register is zero, so a block can span up to 1MiB of code.
+//! The tape, ref stack, and FFI channel live in `.bss`, and I/O is done
+//! with the externally linked libc functions, so the output can be built with
+//! `aarch64-linux-gnu-gcc out.s -lm`.
+use super::{x86::ControlFlow, Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the AArch64 target which implements the `Target` trait.
+/// This allows the compiler to target 64-bit ARM.
+pub struct AArch64 {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// The number of pointers on the stack used by `Deref` and `Refer`.
+    pub ref_stack_size: usize,
+    control_flow: ControlFlow,
+}
+
+impl Default for AArch64 {
+    fn default() -> Self {
+        Self {
+            tape_size: 200000,
+            ref_stack_size: 1024,
+            control_flow: ControlFlow::new("b"),
+        }
+    }
+}
+
+impl AArch64 {
+    /// Load a 64-bit constant into a register, 16 bits at a time.
+    fn load_immediate(&self, reg: &str, n: i64) -> String {
+        if (-0x10000..0x10000).contains(&n) {
+            return format!("mov {reg}, #{n}");
+        }
+        let indent = self.indentation().unwrap();
+        let bits = n as u64;
+        let mut result = format!("movz {reg}, #{}", bits & 0xffff);
+        for shift in [16, 32, 48] {
+            let half = (bits >> shift) & 0xffff;
+            if half != 0 {
+                result += &format!("\n{indent}movk {reg}, #{half}, lsl #{shift}");
+            }
+        }
+        result
+    }
+
+    /// Load the address of a symbol into a register.
+    fn address(&self, reg: &str, symbol: &str) -> String {
+        let indent = self.indentation().unwrap();
+        format!("adrp {reg}, {symbol}\n{indent}add {reg}, {reg}, :lo12:{symbol}")
+    }
+
+    /// Call a libc function, saving the register in `x20`.
+    /// The register is passed as the first argument unless `setup` moves it elsewhere.
+    fn call_saving_reg(&self, setup: &str, function: &str) -> String {
+        let indent = self.indentation().unwrap();
+        let mut result = "mov x20, x0\n".to_string();
+        if !setup.is_empty() {
+            result += &format!("{indent}{setup}\n");
+        }
+        result + &format!("{indent}bl {function}\n{indent}mov x0, x20")
+    }
+
+    /// Apply a libm function to the float in the register.
+    fn call_float_fn(&self, function: &str, with_cell: bool) -> String {
+        let indent = self.indentation().unwrap();
+        let cell = if with_cell {
+            format!("\n{indent}ldr d1, [x19]")
+        } else {
+            String::new()
+        };
+        format!("fmov d0, x0{cell}\n{indent}bl {function}\n{indent}fmov x0, d0")
+    }
+
+    /// Apply an integer instruction to the register and the value in the current cell.
+    fn int_binop(&self, instruction: &str) -> String {
+        let indent = self.indentation().unwrap();
+        format!("ldr x9, [x19]\n{indent}{instruction} x0, x0, x9")
+    }
+
+    /// Apply a float instruction to the register and the float in the current cell.
+    fn float_binop(&self, instruction: &str) -> String {
+        let indent = self.indentation().unwrap();
+        format!(
+            "fmov d0, x0\n{indent}ldr d1, [x19]\n{indent}{instruction} d0, d0, d1\n{indent}fmov x0, d0"
+        )
+    }
+
+    /// Read a value with `scanf` into the register.
+    fn scan(&self, format: &str) -> String {
+        let indent = self.indentation().unwrap();
+        format!(
+            "{}\n{indent}{}\n{indent}bl scanf\n{indent}adrp x9, scratch\n{indent}ldr x0, [x9, :lo12:scratch]",
+            self.address("x0", format),
+            self.address("x1", "scratch")
+        )
+    }
+}
+
+impl Architecture for AArch64 {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        let indent = self.indentation().unwrap();
+        match op {
+            CoreOp::Comment(text) => {
+                format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
+            }
+            CoreOp::While => self.control_flow.begin_while("cbz x0,", &indent),
+            CoreOp::If => self.control_flow.begin_if("cbz x0,"),
+            CoreOp::Else => self.control_flow.begin_else(),
+            CoreOp::Set(n) => self.load_immediate("x0", *n),
+            CoreOp::Call => format!(
+                "{}\n{indent}ldr x9, [x9, x0, lsl #3]\n{indent}blr x9",
+                self.address("x9", "funs")
+            ),
+            CoreOp::Return => format!("ldp x29, x30, [sp], #16\n{indent}ret"),
+            CoreOp::Save => "str x0, [x19]".to_string(),
+            CoreOp::Restore => "ldr x0, [x19]".to_string(),
+            CoreOp::Move(n) if (0..4096).contains(&(n * 8)) => {
+                format!("add x19, x19, #{}", n * 8)
+            }
+            CoreOp::Move(n) if (-4095..0).contains(&(n * 8)) => {
+                format!("sub x19, x19, #{}", -n * 8)
+            }
+            CoreOp::Move(n) => format!(
+                "{}\n{indent}add x19, x19, x9",
+                self.load_immediate("x9", *n as i64 * 8)
+            ),
+            CoreOp::Where => "mov x0, x19".to_string(),
+            CoreOp::Deref => format!("str x19, [x21], #8\n{indent}ldr x19, [x19]"),
+            CoreOp::Refer => "ldr x19, [x21, #-8]!".to_string(),
+            CoreOp::Index => format!("ldr x9, [x19]\n{indent}add x0, x0, x9, lsl #3"),
+            CoreOp::BitwiseNand => format!("{}\n{indent}mvn x0, x0", self.int_binop("and")),
+            CoreOp::Add => self.int_binop("add"),
+            CoreOp::Sub => self.int_binop("sub"),
+            CoreOp::Mul => self.int_binop("mul"),
+            CoreOp::Div => self.int_binop("sdiv"),
+            CoreOp::Rem => {
+                format!("ldr x9, [x19]\n{indent}sdiv x10, x0, x9\n{indent}msub x0, x10, x9, x0")
+            }
+            CoreOp::IsNonNegative => format!("mvn x0, x0\n{indent}lsr x0, x0, #63"),
+            _ => unreachable!("Invalid op for AArch64 target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        Ok(match op {
+            StandardOp::Call(_) => {
+                return Err("Foreign functions are not supported by the AArch64 target".to_string())
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) => self.load_immediate("x0", n.to_bits() as i64),
+            StandardOp::ToInt => format!("fmov d0, x0\n{indent}fcvtzs x0, d0"),
+            StandardOp::ToFloat => format!("scvtf d0, x0\n{indent}fmov x0, d0"),
+            StandardOp::ACos => self.call_float_fn("acos", false),
+            StandardOp::ASin => self.call_float_fn("asin", false),
+            StandardOp::ATan => self.call_float_fn("atan", false),
+            StandardOp::Sin => self.call_float_fn("sin", false),
+            StandardOp::Cos => self.call_float_fn("cos", false),
+            StandardOp::Tan => self.call_float_fn("tan", false),
+            StandardOp::Add => self.float_binop("fadd"),
+            StandardOp::Sub => self.float_binop("fsub"),
+            StandardOp::Mul => self.float_binop("fmul"),
+            StandardOp::Div => self.float_binop("fdiv"),
+            StandardOp::Rem => self.call_float_fn("fmod", true),
+            StandardOp::Pow => self.call_float_fn("pow", true),
+            StandardOp::IsNonNegative => {
+                format!("fmov d0, x0\n{indent}fcmp d0, #0.0\n{indent}cset x0, ge")
+            }
+            StandardOp::Alloc => format!("lsl x0, x0, #3\n{indent}bl malloc"),
+            StandardOp::Free => self.call_saving_reg("", "free"),
+            _ => return Err(format!("Invalid standard op for AArch64 target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::Function => format!(
+                "ldp x29, x30, [sp], #16\n{indent}ret\n",
+                indent = self.indentation().unwrap()
+            ),
+            CoreOp::While | CoreOp::If | CoreOp::Else => {
+                format!("{}:", self.control_flow.pop_label())
+            }
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        let indent = self.indentation().unwrap();
+        format!("f{label_id}:\n{indent}stp x29, x30, [sp, #-16]!\n{indent}mov x29, sp")
+    }
+
+    fn name(&self) -> &str {
+        "AArch64"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        match src.mode {
+            InputMode::StdinChar => Ok(format!("bl getchar\n{indent}sxtw x0, w0")),
+            InputMode::StdinInt => Ok(self.scan("int_format")),
+            InputMode::StdinFloat => Ok(self.scan("float_format")),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        match dst.mode {
+            OutputMode::StdoutChar => Ok(self.call_saving_reg("", "putchar")),
+            OutputMode::StdoutInt => Ok(self.call_saving_reg(
+                &format!("mov x1, x0\n{indent}{}", self.address("x0", "int_format")),
+                "printf",
+            )),
+            OutputMode::StdoutFloat => Ok(self.call_saving_reg(
+                &format!(
+                    "fmov d0, x0\n{indent}{}",
+                    self.address("x0", "float_format")
+                ),
+                "printf",
+            )),
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `Poke` pushes the register onto the channel, and `Peek` pops the last value pushed.
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("ldr x0, [x22], #-8".to_string())
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("str x0, [x22, #8]!".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(".text\n.globl main\n\n".to_string())
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let indent = self.indentation().unwrap();
+        let mut funs = funs;
+        funs.sort();
+        let mut result = String::from(".data\n.align 3\nfuns:\n");
+        for fun in funs {
+            result += &format!("{indent}.xword f{fun}\n");
+        }
+        result += &format!(
+            ".text\nmain:
+{indent}stp x29, x30, [sp, #-48]!
+{indent}mov x29, sp
+{indent}stp x19, x20, [sp, #16]
+{indent}stp x21, x22, [sp, #32]
+{indent}{}
+{indent}{}
+{indent}{}
+{indent}mov x0, #0
+",
+            self.address("x19", "tape"),
+            self.address("x21", "refs"),
+            self.address("x22", "ffi_channel")
+        );
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        let indent = self.indentation().unwrap();
+        Some(format!(
+            "mov x0, #0
+{indent}ldp x21, x22, [sp, #32]
+{indent}ldp x19, x20, [sp, #16]
+{indent}ldp x29, x30, [sp], #48
+{indent}ret
+
+.section .rodata
+int_format:
+{indent}.string \"%ld\"
+float_format:
+{indent}.string \"%lf\"
+
+.bss
+.align 3
+tape:
+{indent}.zero {}
+refs:
+{indent}.zero {}
+ffi_channel:
+{indent}.zero 2048
+scratch:
+{indent}.zero 8
+
+.section .note.GNU-stack,\"\",@progbits
+",
+            self.tape_size * 8,
+            self.ref_stack_size * 8
+        ))
+    }
+}
+
+impl CompiledTarget for AArch64 {}
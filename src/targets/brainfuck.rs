@@ -0,0 +1,273 @@
+//! # Brainfuck Target
+//!
+//! An implementation of the virtual machine for Brainfuck.
+//!
+//! The virtual machine's tape and pointer are close to Brainfuck's, so the core
+//! instructions which don't need to jump around the program can be compiled to it.
+//! This is mostly useful for sharing programs with other esoteric language tools.
+//!
+//! ## Memory Model
+//!
+//! Every cell of the virtual machine's tape is a block of five Brainfuck cells:
+//! the register, the value of the cell, and three scratch cells used to copy values
+//! without destroying them. Brainfuck has no registers, so the register is carried
+//! along with the pointer: `Move` transfers it to the block at the destination.
+//! Between instructions, the pointer is always on the register of the current block,
+//! and every other register and scratch cell is zero.
+//!
+//! ## Limitations
+//!
+//! Every value is stored in a single Brainfuck cell, so arithmetic wraps at the cell
+//! size of the Brainfuck implementation (usually 8 bits). The generated code assumes
+//! that cells wrap around, and `Set` only accepts values from -255 to 255, since they're
+//! built up with `+` and `-`. `Get` and `Put` only support characters, and what `Get`
+//! does at the end of the input depends on the implementation.
+//!
+//! Brainfuck can't jump to code or address the tape, so functions, `Call`, `Return`,
+//! `Where`, `Deref`, `Refer`, and `Index` aren't supported. Neither are `Div`, `Rem`,
+//! `BitwiseNand`, and `IsNonNegative`, which depend on the sign or the bits of the values,
+//! or any of the standard instructions. Compiling a program which uses them is an error.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the Brainfuck target which implements the `Target` trait.
+/// This allows the compiler to target Brainfuck.
+#[derive(Default)]
+pub struct Brainfuck;
+
+/// The number of Brainfuck cells used for each cell of the virtual machine's tape.
+const BLOCK_SIZE: isize = 5;
+/// The offset of the register in a block.
+const REG: isize = 0;
+/// The offset of the value of the virtual machine's cell in a block.
+const VALUE: isize = 1;
+/// The offset of the scratch cell used to copy values back to where they came from.
+const TEMP: isize = 2;
+/// The offset of the scratch cell which is set while the `Else` of an `If` should run.
+const ELSE_FLAG: isize = 3;
+/// The offset of the scratch cell used to count down while multiplying and copying conditions.
+const COUNTER: isize = 4;
+
+/// Move the pointer from one offset to another, relative to the register of the current block.
+fn go(from: isize, to: isize) -> String {
+    if to >= from {
+        ">".repeat((to - from) as usize)
+    } else {
+        "<".repeat((from - to) as usize)
+    }
+}
+
+/// Add the Brainfuck cell at `from` to each of the cells at `to` (or subtract it, for the
+/// cells paired with `false`), leaving `from` zero. The pointer starts and ends on the register.
+fn transfer(from: isize, to: &[(isize, bool)]) -> String {
+    let mut result = go(REG, from) + "[-";
+    let mut at = from;
+    for &(offset, add) in to {
+        result += &go(at, offset);
+        result.push(if add { '+' } else { '-' });
+        at = offset;
+    }
+    result + &go(at, from) + "]" + &go(from, REG)
+}
+
+/// Add (or subtract) the value of the current cell to the register, leaving the cell unchanged.
+fn add_value(add: bool) -> String {
+    transfer(VALUE, &[(REG, add), (TEMP, true)]) + &transfer(TEMP, &[(VALUE, true)])
+}
+
+impl Architecture for Brainfuck {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(i.mode, InputMode::StdinChar)
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(o.mode, OutputMode::StdoutChar)
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            // Anything but the eight commands is a comment in Brainfuck.
+            CoreOp::Comment(text) => text
+                .replace(|c: char| "+-<>[].,".contains(c), "")
+                .replace('\n', " "),
+            CoreOp::While => "[".to_string(),
+            CoreOp::If => {
+                // Set the else flag, and copy the register to the condition.
+                go(REG, ELSE_FLAG)
+                    + "+"
+                    + &go(ELSE_FLAG, REG)
+                    + &transfer(REG, &[(TEMP, true), (COUNTER, true)])
+                    + &transfer(COUNTER, &[(REG, true)])
+                    // If the condition isn't zero, clear both flags and run the body.
+                    // The body may move the pointer, so the loop ends on the
+                    // condition of another block, which is always zero.
+                    + &go(REG, TEMP)
+                    + "[[-]"
+                    + &go(TEMP, ELSE_FLAG)
+                    + "[-]"
+                    + &go(ELSE_FLAG, REG)
+            }
+            CoreOp::Else => {
+                // The else flag is only still set if the body of the `If` didn't run,
+                // and so the pointer is still on the block where it was set.
+                go(REG, TEMP) + "]" + &go(TEMP, ELSE_FLAG) + "[[-]" + &go(ELSE_FLAG, REG)
+            }
+            CoreOp::Set(n) => {
+                let step = if *n < 0 { "-" } else { "+" };
+                "[-]".to_string() + &step.repeat(n.unsigned_abs() as usize)
+            }
+            CoreOp::Save => {
+                go(REG, VALUE)
+                    + "[-]"
+                    + &go(VALUE, REG)
+                    + &transfer(REG, &[(VALUE, true), (TEMP, true)])
+                    + &transfer(TEMP, &[(REG, true)])
+            }
+            CoreOp::Restore => "[-]".to_string() + &add_value(true),
+            CoreOp::Move(0) => String::new(),
+            CoreOp::Move(n) => {
+                // Carry the register along to the destination block.
+                let offset = *n * BLOCK_SIZE;
+                transfer(REG, &[(offset, true)]) + &go(REG, offset)
+            }
+            CoreOp::Add => add_value(true),
+            CoreOp::Sub => add_value(false),
+            CoreOp::Mul => {
+                // Add the value to the register once for every count of the register.
+                transfer(REG, &[(COUNTER, true)])
+                    + &go(REG, COUNTER)
+                    + "[-"
+                    + &go(COUNTER, REG)
+                    + &add_value(true)
+                    + &go(REG, COUNTER)
+                    + "]"
+                    + &go(COUNTER, REG)
+            }
+            // The other instructions are rejected by `build_op`.
+            _ => String::new(),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Err(format!("Invalid standard op for Brainfuck target {op:?}"))
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::If => go(REG, TEMP) + "]" + &go(TEMP, ELSE_FLAG) + "[-]" + &go(ELSE_FLAG, REG),
+            CoreOp::Else => go(REG, ELSE_FLAG) + "]" + &go(ELSE_FLAG, REG),
+            _ => "]".to_string(),
+        }
+    }
+
+    fn declare_proc(&mut self, _label_id: usize) -> String {
+        // Functions are rejected by `build_op`.
+        String::new()
+    }
+
+    fn name(&self) -> &str {
+        "Brainfuck"
+    }
+
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        false
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok(",".to_string()),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        match dst.mode {
+            OutputMode::StdoutChar => Ok(".".to_string()),
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    fn peek(&mut self) -> Result<String, String> {
+        Err("Peek not supported by this target".to_string())
+    }
+
+    fn poke(&mut self) -> Result<String, String> {
+        Err("Poke not supported by this target".to_string())
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn indentation(&self) -> Option<String> {
+        None
+    }
+}
+
+impl CompiledTarget for Brainfuck {
+    /// Compile an instruction, or fail if it can't be expressed in Brainfuck.
+    fn build_op(
+        &mut self,
+        op: &CoreOp,
+        matching_ops: &mut Vec<CoreOp>,
+        _matching_funs: &mut Vec<usize>,
+        _current_fun: &mut usize,
+        indent: &mut usize,
+    ) -> Result<String, String> {
+        Ok(match op {
+            CoreOp::While | CoreOp::If => {
+                matching_ops.push(op.clone());
+                *indent += 1;
+                self.op(op)
+            }
+            CoreOp::Else => match matching_ops.pop() {
+                Some(CoreOp::If) => {
+                    matching_ops.push(op.clone());
+                    self.op(op)
+                }
+                _ => return Err("Unexpected else".to_string()),
+            },
+            CoreOp::End => match matching_ops.pop() {
+                Some(matching) => {
+                    *indent -= 1;
+                    self.end(&matching, None)
+                }
+                None => return Err("Unexpected end".to_string()),
+            },
+            CoreOp::Get(src) if self.supports_input(src) => self.get(src)?,
+            CoreOp::Put(dst) if self.supports_output(dst) => self.put(dst)?,
+            CoreOp::Get(src) => {
+                return Err(format!("Input {src:?} not supported on target Brainfuck"))
+            }
+            CoreOp::Put(dst) => {
+                return Err(format!("Output {dst:?} not supported on target Brainfuck"))
+            }
+            CoreOp::Set(n) if !(-255..=255).contains(n) => {
+                return Err(format!(
+                    "Cannot set the register to {n} on target Brainfuck, only values from -255 to 255 are supported"
+                ))
+            }
+            CoreOp::Comment(_)
+            | CoreOp::Set(_)
+            | CoreOp::Save
+            | CoreOp::Restore
+            | CoreOp::Move(_)
+            | CoreOp::Add
+            | CoreOp::Sub
+            | CoreOp::Mul => self.op(op),
+            other => {
+                return Err(format!(
+                    "Instruction `{}` not supported on target Brainfuck",
+                    other.name()
+                ))
+            }
+        })
+    }
+}
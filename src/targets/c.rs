@@ -3,6 +3,14 @@
 //! An implementation of the virtual machine for the C language.
 //!
 //! This allows the virtual machine to target C programs.
+//!
+//! ## Portability
+//!
+//! Every function is defined at the top level as `void fN()`, before `main`.
+//! The function table is declared before the functions so they can call each
+//! other through it, and defined after them with a single initializer of
+//! `[N] = fN` entries. No nested functions are emitted, so the output compiles
+//! with both GCC and Clang.
 use super::{Architecture, CompiledTarget};
 use crate::{
     side_effects::{Input, InputMode, Output, OutputMode},
@@ -11,26 +19,81 @@ use crate::{
 
 /// The type for the C target which implements the `Target` trait.
 /// This allows the compiler to target the C language.
-#[derive(Default)]
-pub struct C;
+pub struct C {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// The number of pointers on the stack used by `Deref` and `Refer`.
+    pub ref_stack_size: usize,
+    /// The number of entries in the function table.
+    pub fn_table_size: usize,
+    /// Whether to turn the source location markers left in comments by the compiler
+    /// into `#line` directives, so that a debugger can map the generated C code
+    /// back to the original source.
+    pub emit_line_directives: bool,
+    /// Whether to check for division by zero in integer `Div` and `Rem`,
+    /// printing an error and aborting instead of invoking undefined behavior.
+    pub checked_div: bool,
+    /// Whether to check that `Deref` and `Refer` stay within the reference stack,
+    /// printing an error and aborting instead of corrupting memory.
+    pub checked_refs: bool,
+    /// Whether to allocate the tape, the reference stack, and the function table
+    /// on the heap when the program starts, instead of in static arrays.
+    /// This allows much larger tapes than some platforms allow for static data.
+    pub heap_tape: bool,
+    /// Whether the program reads UTF-8 encoded characters, so the decoder is emitted.
+    /// This is set when an instruction using `StdinUtf8` is compiled.
+    utf8_input: bool,
+    /// Whether the program writes UTF-8 encoded characters, so the encoder is emitted.
+    /// This is set when an instruction using `StdoutUtf8` or `StderrUtf8` is compiled.
+    utf8_output: bool,
+}
+
+impl Default for C {
+    fn default() -> Self {
+        Self {
+            tape_size: 200000,
+            ref_stack_size: 1024,
+            fn_table_size: 10000,
+            emit_line_directives: false,
+            checked_div: false,
+            checked_refs: false,
+            heap_tape: false,
+            utf8_input: false,
+            utf8_output: false,
+        }
+    }
+}
 
 impl Architecture for C {
     fn supports_input(&self, i: &Input) -> bool {
         matches!(
             i.mode,
-            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+            InputMode::StdinChar
+                | InputMode::StdinFloat
+                | InputMode::StdinInt
+                | InputMode::StdinUtf8
         )
     }
 
     fn supports_output(&self, o: &Output) -> bool {
         matches!(
             o.mode,
-            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
+                | OutputMode::StdoutUtf8
+                | OutputMode::StderrUtf8
         )
     }
 
     fn op(&mut self, op: &CoreOp) -> String {
         match op {
+            CoreOp::Comment(text) if self.emit_line_directives && text.starts_with("#line ") => {
+                text.clone()
+            }
             CoreOp::Comment(text) => {
                 format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
                 // let mut comment = String::new();
@@ -49,6 +112,14 @@ impl Architecture for C {
             CoreOp::Restore => "reg = *ptr;".to_string(),
             CoreOp::Move(n) => format!("ptr += {};", n),
             CoreOp::Where => "reg.p = ptr;".to_string(),
+            CoreOp::Deref if self.checked_refs => format!(
+                "if (ref == refs + {}) sage_abort(\"reference stack overflow\"); *ref++ = ptr; ptr = ptr->p;",
+                self.ref_stack_size
+            ),
+            CoreOp::Refer if self.checked_refs => {
+                "if (ref == refs) sage_abort(\"reference stack underflow\"); ptr = *--ref;"
+                    .to_string()
+            }
             CoreOp::Deref => "*ref++ = ptr; ptr = ptr->p;".to_string(),
             CoreOp::Refer => "ptr = *--ref;".to_string(),
             CoreOp::Index => "reg.p += ptr->i;".to_string(),
@@ -56,6 +127,12 @@ impl Architecture for C {
             CoreOp::Add => "reg.i += ptr->i;".to_string(),
             CoreOp::Sub => "reg.i -= ptr->i;".to_string(),
             CoreOp::Mul => "reg.i *= ptr->i;".to_string(),
+            CoreOp::Div if self.checked_div => {
+                "if (!ptr->i) sage_abort(\"division by zero\"); reg.i /= ptr->i;".to_string()
+            }
+            CoreOp::Rem if self.checked_div => {
+                "if (!ptr->i) sage_abort(\"division by zero\"); reg.i %= ptr->i;".to_string()
+            }
             CoreOp::Div => "reg.i /= ptr->i;".to_string(),
             CoreOp::Rem => "reg.i %= ptr->i;".to_string(),
             CoreOp::IsNonNegative => "reg.i = reg.i >= 0;".to_string(),
@@ -63,12 +140,22 @@ impl Architecture for C {
         }
     }
 
+    fn set_and_save(&mut self, n: i64) -> Option<String> {
+        // Keep the register up to date, in case it's read afterwards.
+        Some(format!("ptr->i = reg.i = {n};"))
+    }
+
     fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
         Ok(match op {
             StandardOp::Call(ffi) => format!("__{}();", ffi.name),
             StandardOp::Peek => self.peek()?,
             StandardOp::Poke => self.poke()?,
-            StandardOp::Set(n) => format!("reg.f = {};", n),
+            StandardOp::Set(n) if n.is_nan() => "reg.f = NAN;".to_string(),
+            StandardOp::Set(n) if n.is_infinite() && *n > 0.0 => "reg.f = INFINITY;".to_string(),
+            StandardOp::Set(n) if n.is_infinite() => "reg.f = -INFINITY;".to_string(),
+            // The debug format always includes a decimal point or an exponent,
+            // so the value is never mistaken for an (overflowing) integer literal.
+            StandardOp::Set(n) => format!("reg.f = {:?};", n),
             StandardOp::ToInt => "reg.i = reg.f;".to_string(),
             StandardOp::ToFloat => "reg.f = reg.i;".to_string(),
             StandardOp::ACos => "reg.f = acos(reg.f);".to_string(),
@@ -118,6 +205,10 @@ impl Architecture for C {
             InputMode::StdinChar => Ok("reg.i = getchar();".to_string()),
             InputMode::StdinInt => Ok("scanf(\"%ld\", &reg.i);".to_string()),
             InputMode::StdinFloat => Ok("scanf(\"%lf\", &reg.f);".to_string()),
+            InputMode::StdinUtf8 => {
+                self.utf8_input = true;
+                Ok("reg.i = sage_get_utf8();".to_string())
+            }
             InputMode::Thermometer => Ok("reg.f = 293.15;".to_string()),
             InputMode::Clock => Ok("reg.i = time(NULL);".to_string()),
             InputMode::Random => Ok("reg.i = rand();".to_string()),
@@ -136,17 +227,38 @@ impl Architecture for C {
             OutputMode::StderrChar => Ok("fprintf(stderr, \"%c\", reg.i);".to_string()),
             OutputMode::StderrInt => Ok("fprintf(stderr, \"%lld\", reg.i);".to_string()),
             OutputMode::StderrFloat => Ok("fprintf(stderr, \"%lf\", reg.f);".to_string()),
+            OutputMode::StdoutUtf8 => {
+                self.utf8_output = true;
+                Ok("sage_put_utf8(stdout, reg.i);".to_string())
+            }
+            OutputMode::StderrUtf8 => {
+                self.utf8_output = true;
+                Ok("sage_put_utf8(stderr, reg.i);".to_string())
+            }
             OutputMode::Heater => Ok("printf(\"Heating...\");".to_string()),
             OutputMode::Cooler => Ok("printf(\"Cooling...\");".to_string()),
             _ => Err("Output not supported by this target".to_string()),
         }
     }
+    /// Peek a value from the FFI channel.
+    ///
+    /// The FFI channel is the `ffi_channel` array of cells in the generated code,
+    /// with `ffi_ptr` pointing to the last cell written. `Poke` pushes the register
+    /// onto the channel, and `Peek` pops the last value pushed back into the register.
+    /// Foreign functions (`__name()`) take their arguments from the channel and push
+    /// their results to it in the same way.
     fn peek(&mut self) -> Result<String, String> {
         Ok("reg = *(ffi_ptr--);".to_string())
     }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
     fn poke(&mut self) -> Result<String, String> {
         Ok("*(++ffi_ptr) = reg;".to_string())
     }
+    fn reset(&mut self) {
+        self.utf8_input = false;
+        self.utf8_output = false;
+    }
+
     fn prelude(&self, is_core: bool) -> Option<String> {
         let mut result = r#"#include <stdint.h>
 #include <stdio.h>
@@ -159,14 +271,87 @@ typedef union cell {
     union cell *p;
 } cell;
 
-cell tape[200000], *refs[1024], *ptr = tape, **ref = refs, reg, ffi_channel[256], *ffi_ptr = ffi_channel;
-
-unsigned int ref_ptr = 0;
-void (*funs[10000])(void);
 "#
         .to_string();
+        if self.heap_tape {
+            // The memory is allocated at the start of `main`.
+            result += r#"cell *tape, **refs, *ptr, **ref, reg, ffi_channel[256], *ffi_ptr = ffi_channel;
 
-        if !is_core {
+unsigned int ref_ptr = 0;
+void (**funs)(void);
+"#;
+        } else {
+            result += &format!(
+                r#"cell tape[{}], *refs[{}], *ptr = tape, **ref = refs, reg, ffi_channel[256], *ffi_ptr = ffi_channel;
+
+unsigned int ref_ptr = 0;
+void (*funs[{}])(void);
+"#,
+                self.tape_size, self.ref_stack_size, self.fn_table_size
+            );
+        }
+
+        // Read and write whole UTF-8 encoded characters, only if the UTF-8 modes are used.
+        // Invalid input is read as `U+FFFD`, and the end of the input as `EOF`, like `getchar`.
+        // Cells which aren't Unicode scalar values are written as `U+FFFD`.
+        if self.utf8_input {
+            result += r#"
+int64_t sage_get_utf8(void) {
+    int c = getchar(), len, i;
+    int64_t cp;
+    if (c == EOF || c < 0x80) return c;
+    if (c >= 0xc2 && c <= 0xdf) { len = 2; cp = c & 0x1f; }
+    else if (c >= 0xe0 && c <= 0xef) { len = 3; cp = c & 0x0f; }
+    else if (c >= 0xf0 && c <= 0xf4) { len = 4; cp = c & 0x07; }
+    else return 0xfffd;
+    for (i = 1; i < len; i++) {
+        c = getchar();
+        if (c < 0x80 || c > 0xbf) {
+            if (c != EOF) ungetc(c, stdin);
+            return 0xfffd;
+        }
+        cp = cp << 6 | (c & 0x3f);
+    }
+    if ((len == 3 && cp < 0x800) || (len == 4 && cp < 0x10000)
+        || (cp >= 0xd800 && cp <= 0xdfff) || cp > 0x10ffff) return 0xfffd;
+    return cp;
+}
+"#;
+        }
+        if self.utf8_output {
+            result += r#"
+void sage_put_utf8(FILE *f, int64_t cp) {
+    if (cp < 0 || (cp >= 0xd800 && cp <= 0xdfff) || cp > 0x10ffff) cp = 0xfffd;
+    if (cp < 0x80) {
+        fputc(cp, f);
+    } else if (cp < 0x800) {
+        fputc(0xc0 | cp >> 6, f);
+        fputc(0x80 | (cp & 0x3f), f);
+    } else if (cp < 0x10000) {
+        fputc(0xe0 | cp >> 12, f);
+        fputc(0x80 | (cp >> 6 & 0x3f), f);
+        fputc(0x80 | (cp & 0x3f), f);
+    } else {
+        fputc(0xf0 | cp >> 18, f);
+        fputc(0x80 | (cp >> 12 & 0x3f), f);
+        fputc(0x80 | (cp >> 6 & 0x3f), f);
+        fputc(0x80 | (cp & 0x3f), f);
+    }
+}
+"#;
+        }
+
+        let checked = self.checked_div || self.checked_refs;
+        if checked {
+            result += r#"
+void sage_abort(const char *msg) {
+    fprintf(stderr, "sage: %s\n", msg);
+    abort();
+}
+"#;
+        }
+
+        if !is_core || checked || self.heap_tape {
             result = "#include <stdlib.h>\n".to_string() + &result;
         }
 
@@ -174,10 +359,40 @@ void (*funs[10000])(void);
     }
 
     fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
-        let mut result = String::from("int main () {\n");
-        for fun in funs {
-            result += &format!("\tfuns[{fun}] = f{fun};\n", fun = fun)
+        if self.heap_tape {
+            // Allocate the memory, zeroed like the static arrays would be,
+            // and then fill in the table now that the functions are defined.
+            let mut result = format!(
+                r#"int main () {{
+	tape = calloc({}, sizeof(cell));
+	refs = calloc({}, sizeof(cell *));
+	funs = calloc({}, sizeof(*funs));
+	if (!tape || !refs || !funs) {{
+		fprintf(stderr, "sage: could not allocate the tape\n");
+		return 1;
+	}}
+	ptr = tape;
+	ref = refs;
+"#,
+                self.tape_size, self.ref_stack_size, self.fn_table_size
+            );
+            for fun in funs {
+                result += &format!("\tfuns[{fun}] = f{fun};\n");
+            }
+            return Some(result);
         }
+
+        // Define the table declared in the prelude, now that the functions are defined.
+        // Without any functions, the declaration alone leaves the table empty.
+        let mut result = String::new();
+        if !funs.is_empty() {
+            result += &format!("void (*funs[{}])(void) = {{\n", self.fn_table_size);
+            for fun in funs {
+                result += &format!("\t[{fun}] = f{fun},\n")
+            }
+            result += "};\n\n";
+        }
+        result += "int main () {\n";
         Some(result)
     }
 
@@ -186,6 +401,9 @@ void (*funs[10000])(void);
     }
 
     fn postlude(&self, _is_core: bool) -> Option<String> {
+        if self.heap_tape {
+            return Some("free(funs);\n\tfree(refs);\n\tfree(tape);\n\treturn 0;\n}".to_string());
+        }
         Some("return 0;\n}".to_string())
     }
 }
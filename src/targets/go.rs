@@ -0,0 +1,293 @@
+//! # Go Target
+//!
+//! An implementation of the virtual machine for the Go language.
+//!
+//! This allows the virtual machine to target Go programs.
+//!
+//! ## Memory Model
+//!
+//! The tape is a `[]int64`, and pointers are indices into it, so `ptr` and the
+//! pointers on the `refs` stack are plain `int`s. Floats are stored in the same
+//! cells as their bits, like a union in C: the `f` and `b` helpers reinterpret
+//! a cell as a float and back. `Alloc` bump allocates cells by growing the tape
+//! past its end, and `Free` does nothing.
+//!
+//! ## Functions and I/O
+//!
+//! Every function is defined at the top level as `func fN()`, and `main` fills in
+//! the `funs` slice of functions used by `Call`. Foreign functions are called as
+//! `__name()`, which must be defined in another file of the `main` package, and
+//! which take their arguments from and push their results onto the `ffi` slice.
+//! Input and output go through a `bufio` reader and writer over `os.Stdin` and
+//! `os.Stdout`, and the output is flushed before reading input and at the end.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the Go target which implements the `Target` trait.
+/// This allows the compiler to target the Go language.
+pub struct Go {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+}
+
+impl Default for Go {
+    fn default() -> Self {
+        Self { tape_size: 200000 }
+    }
+}
+
+impl Go {
+    /// Apply a binary float operation to the register and the cell under the pointer.
+    fn float_binop(op: &str) -> String {
+        format!("reg = b(f(reg) {op} f(tape[ptr]))")
+    }
+
+    /// Apply a function from the `math` package to the register as a float.
+    fn float_fn(name: &str) -> String {
+        format!("reg = b(math.{name}(f(reg)))")
+    }
+}
+
+impl Architecture for Go {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
+            }
+            CoreOp::While => "for reg != 0 {".to_string(),
+            CoreOp::If => "if reg != 0 {".to_string(),
+            CoreOp::Else => "} else {".to_string(),
+            CoreOp::Set(n) => format!("reg = {n}"),
+            CoreOp::Call => "funs[reg]()".to_string(),
+            CoreOp::Return => "return".to_string(),
+            CoreOp::Save => "tape[ptr] = reg".to_string(),
+            CoreOp::Restore => "reg = tape[ptr]".to_string(),
+            CoreOp::Move(n) => format!("ptr += {n}"),
+            CoreOp::Where => "reg = int64(ptr)".to_string(),
+            CoreOp::Deref => "refs = append(refs, ptr); ptr = int(tape[ptr])".to_string(),
+            CoreOp::Refer => "ptr = refs[len(refs)-1]; refs = refs[:len(refs)-1]".to_string(),
+            CoreOp::Index => "reg += tape[ptr]".to_string(),
+            CoreOp::BitwiseNand => "reg = ^(reg & tape[ptr])".to_string(),
+            CoreOp::Add => "reg += tape[ptr]".to_string(),
+            CoreOp::Sub => "reg -= tape[ptr]".to_string(),
+            CoreOp::Mul => "reg *= tape[ptr]".to_string(),
+            CoreOp::Div => "reg /= tape[ptr]".to_string(),
+            CoreOp::Rem => "reg %= tape[ptr]".to_string(),
+            CoreOp::IsNonNegative => "reg = gez(reg >= 0)".to_string(),
+            _ => unreachable!("Invalid op for Go target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => format!("__{}()", ffi.name),
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) if n.is_nan() => "reg = b(math.NaN())".to_string(),
+            StandardOp::Set(n) if n.is_infinite() && *n > 0.0 => "reg = b(math.Inf(1))".to_string(),
+            StandardOp::Set(n) if n.is_infinite() => "reg = b(math.Inf(-1))".to_string(),
+            StandardOp::Set(n) => format!("reg = b({n:?})"),
+            StandardOp::ToInt => "reg = int64(f(reg))".to_string(),
+            StandardOp::ToFloat => "reg = b(float64(reg))".to_string(),
+            StandardOp::ACos => Self::float_fn("Acos"),
+            StandardOp::ASin => Self::float_fn("Asin"),
+            StandardOp::ATan => Self::float_fn("Atan"),
+            StandardOp::Sin => Self::float_fn("Sin"),
+            StandardOp::Cos => Self::float_fn("Cos"),
+            StandardOp::Tan => Self::float_fn("Tan"),
+            StandardOp::Add => Self::float_binop("+"),
+            StandardOp::Sub => Self::float_binop("-"),
+            StandardOp::Mul => Self::float_binop("*"),
+            StandardOp::Div => Self::float_binop("/"),
+            StandardOp::Rem => "reg = b(math.Mod(f(reg), f(tape[ptr])))".to_string(),
+            StandardOp::Pow => "reg = b(math.Pow(f(reg), f(tape[ptr])))".to_string(),
+            StandardOp::IsNonNegative => "reg = gez(f(reg) >= 0)".to_string(),
+            StandardOp::Alloc => "reg = alloc(reg)".to_string(),
+            StandardOp::Free => "// free".to_string(),
+            _ => return Err(format!("Invalid standard op for Go target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        match (matching, fun) {
+            (CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else, _) => "}".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("func f{label_id}() {{")
+    }
+
+    fn name(&self) -> &str {
+        "Go"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok("reg = getchar()".to_string()),
+            InputMode::StdinInt => Ok("reg = readInt()".to_string()),
+            InputMode::StdinFloat => Ok("reg = b(readFloat())".to_string()),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        match dst.mode {
+            OutputMode::StdoutChar => Ok("out.WriteByte(byte(reg))".to_string()),
+            OutputMode::StdoutInt => Ok("fmt.Fprint(out, reg)".to_string()),
+            OutputMode::StdoutFloat => Ok("out.WriteString(formatFloat(f(reg)))".to_string()),
+            OutputMode::StderrChar => Ok("os.Stderr.Write([]byte{byte(reg)})".to_string()),
+            OutputMode::StderrInt => Ok("fmt.Fprint(os.Stderr, reg)".to_string()),
+            OutputMode::StderrFloat => Ok("fmt.Fprint(os.Stderr, formatFloat(f(reg)))".to_string()),
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `Poke` pushes the register onto the channel, and `Peek` pops the last value pushed.
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("reg = ffi[len(ffi)-1]; ffi = ffi[:len(ffi)-1]".to_string())
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("ffi = append(ffi, reg)".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        // Every import is used by the helpers, since Go rejects unused imports.
+        Some(format!(
+            r#"package main
+
+import (
+	"bufio"
+	"fmt"
+	"math"
+	"os"
+	"strconv"
+	"strings"
+)
+
+var tape = make([]int64, {})
+var refs []int
+var ffi []int64
+var funs []func()
+var ptr int
+var reg int64
+
+var in = bufio.NewReader(os.Stdin)
+var out = bufio.NewWriter(os.Stdout)
+
+// Reinterpret the bits of a cell as a float.
+func f(x int64) float64 {{
+	return math.Float64frombits(uint64(x))
+}}
+
+// Reinterpret the bits of a float as a cell.
+func b(x float64) int64 {{
+	return int64(math.Float64bits(x))
+}}
+
+func gez(ok bool) int64 {{
+	if ok {{
+		return 1
+	}}
+	return 0
+}}
+
+func alloc(size int64) int64 {{
+	result := len(tape)
+	tape = append(tape, make([]int64, size)...)
+	return int64(result)
+}}
+
+func getchar() int64 {{
+	out.Flush()
+	c, err := in.ReadByte()
+	if err != nil {{
+		return -1
+	}}
+	return int64(c)
+}}
+
+func readInt() int64 {{
+	out.Flush()
+	var n int64
+	fmt.Fscan(in, &n)
+	return n
+}}
+
+func readFloat() float64 {{
+	out.Flush()
+	var n float64
+	fmt.Fscan(in, &n)
+	return n
+}}
+
+// Format a float like the interpreter does, always with a decimal point.
+func formatFloat(x float64) string {{
+	if math.IsInf(x, 1) {{
+		return "inf"
+	}} else if math.IsInf(x, -1) {{
+		return "-inf"
+	}}
+	s := strconv.FormatFloat(x, 'f', -1, 64)
+	if !strings.ContainsAny(s, ".N") {{
+		s += ".0"
+	}}
+	return s
+}}
+
+"#,
+            self.tape_size
+        ))
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut funs = funs;
+        funs.sort();
+        let funs = funs.iter().map(|fun| format!("f{fun}")).collect::<Vec<_>>();
+        Some(format!(
+            "func main() {{\n\tfuns = []func(){{{}}}\n",
+            funs.join(", ")
+        ))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("out.Flush()\n}\n".to_string())
+    }
+}
+
+impl CompiledTarget for Go {}
@@ -0,0 +1,257 @@
+//! # JavaScript Target
+//!
+//! An implementation of the virtual machine for JavaScript.
+//!
+//! This allows the virtual machine to target Node and the browser, without any dependencies.
+//!
+//! ## Memory Model
+//!
+//! The tape is a `BigInt64Array`, with a `Float64Array` view over the same buffer
+//! for float operations; the register is modeled the same way. `ptr` is an index into
+//! the tape, and the ref stack is a plain array of indices. `Alloc` bump allocates
+//! cells past the end of the tape, growing the tape as needed.
+//!
+//! Every function is defined at the top level as `fN`, and `Call` calls through
+//! the `funs` array.
+//!
+//! ## Host Interface
+//!
+//! Before running the program, the host can define `globalThis.sage = { read, write }`
+//! to redirect I/O: `read()` returns the next character code of input (or `-1` on EOF),
+//! and `write(text)` writes some output. By default, Node's stdin and stdout are used.
+//! Foreign functions are called from `globalThis.sage.ffi`, with the FFI channel
+//! array as their argument.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the JavaScript target which implements the `Target` trait.
+/// This allows the compiler to target JavaScript.
+pub struct JavaScript {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+}
+
+impl Default for JavaScript {
+    fn default() -> Self {
+        Self { tape_size: 200000 }
+    }
+}
+
+impl Architecture for JavaScript {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
+            }
+            CoreOp::While => "while (reg[0]) {".to_string(),
+            CoreOp::If => "if (reg[0]) {".to_string(),
+            CoreOp::Else => "} else {".to_string(),
+            CoreOp::Set(n) => format!("reg[0] = {n}n;"),
+            CoreOp::Call => "funs[Number(reg[0])]();".to_string(),
+            CoreOp::Return => "return;".to_string(),
+            CoreOp::Save => "tape[ptr] = reg[0];".to_string(),
+            CoreOp::Restore => "reg[0] = tape[ptr];".to_string(),
+            CoreOp::Move(n) => format!("ptr += {n};"),
+            CoreOp::Where => "reg[0] = BigInt(ptr);".to_string(),
+            CoreOp::Deref => "refs.push(ptr); ptr = Number(tape[ptr]);".to_string(),
+            CoreOp::Refer => "ptr = refs.pop();".to_string(),
+            CoreOp::Index => "reg[0] += tape[ptr];".to_string(),
+            CoreOp::BitwiseNand => "reg[0] = ~(reg[0] & tape[ptr]);".to_string(),
+            CoreOp::Add => "reg[0] += tape[ptr];".to_string(),
+            CoreOp::Sub => "reg[0] -= tape[ptr];".to_string(),
+            CoreOp::Mul => "reg[0] *= tape[ptr];".to_string(),
+            CoreOp::Div => "reg[0] /= tape[ptr];".to_string(),
+            CoreOp::Rem => "reg[0] %= tape[ptr];".to_string(),
+            CoreOp::IsNonNegative => "reg[0] = reg[0] >= 0n ? 1n : 0n;".to_string(),
+            _ => unreachable!("Invalid op for JavaScript target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => format!("sage.ffi[{:?}](ffi);", ffi.name),
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) if n.is_nan() => "freg[0] = NaN;".to_string(),
+            StandardOp::Set(n) if n.is_infinite() && *n > 0.0 => "freg[0] = Infinity;".to_string(),
+            StandardOp::Set(n) if n.is_infinite() => "freg[0] = -Infinity;".to_string(),
+            StandardOp::Set(n) => format!("freg[0] = {n:?};"),
+            StandardOp::ToInt => "reg[0] = BigInt(Math.trunc(freg[0]));".to_string(),
+            StandardOp::ToFloat => "freg[0] = Number(reg[0]);".to_string(),
+            StandardOp::ACos => "freg[0] = Math.acos(freg[0]);".to_string(),
+            StandardOp::ASin => "freg[0] = Math.asin(freg[0]);".to_string(),
+            StandardOp::ATan => "freg[0] = Math.atan(freg[0]);".to_string(),
+            StandardOp::Sin => "freg[0] = Math.sin(freg[0]);".to_string(),
+            StandardOp::Cos => "freg[0] = Math.cos(freg[0]);".to_string(),
+            StandardOp::Tan => "freg[0] = Math.tan(freg[0]);".to_string(),
+            StandardOp::Add => "freg[0] += ftape[ptr];".to_string(),
+            StandardOp::Sub => "freg[0] -= ftape[ptr];".to_string(),
+            StandardOp::Mul => "freg[0] *= ftape[ptr];".to_string(),
+            StandardOp::Div => "freg[0] /= ftape[ptr];".to_string(),
+            StandardOp::Rem => "freg[0] %= ftape[ptr];".to_string(),
+            StandardOp::Pow => "freg[0] = Math.pow(freg[0], ftape[ptr]);".to_string(),
+            StandardOp::IsNonNegative => "reg[0] = freg[0] >= 0 ? 1n : 0n;".to_string(),
+            StandardOp::Alloc => "reg[0] = BigInt(alloc(Number(reg[0])));".to_string(),
+            // The heap is a bump allocator, so freeing memory does nothing.
+            StandardOp::Free => "// free".to_string(),
+            _ => return Err(format!("Invalid standard op for JavaScript target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        match (matching, fun) {
+            (CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else, _) => "}".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("function f{label_id}() {{")
+    }
+
+    fn name(&self) -> &str {
+        "JavaScript"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok("reg[0] = BigInt(read_char());".to_string()),
+            InputMode::StdinInt => Ok("reg[0] = BigInt(parseInt(read_number()) || 0);".to_string()),
+            InputMode::StdinFloat => Ok("freg[0] = parseFloat(read_number()) || 0;".to_string()),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        match dst.mode {
+            OutputMode::StdoutChar => {
+                Ok("write(String.fromCharCode(Number(reg[0]) & 0xff));".to_string())
+            }
+            OutputMode::StdoutInt => Ok("write(reg[0].toString());".to_string()),
+            OutputMode::StdoutFloat => Ok("write(format_float(freg[0]));".to_string()),
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `Poke` pushes the register onto the channel, and `Peek` pops the last value pushed.
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("reg[0] = ffi.pop();".to_string())
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("ffi.push(reg[0]);".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(format!(
+            r#""use strict";
+const sage = globalThis.sage || {{}};
+let tape = new BigInt64Array({tape_size}), ftape = new Float64Array(tape.buffer);
+const reg = new BigInt64Array(1), freg = new Float64Array(reg.buffer);
+const refs = [], ffi = [];
+let ptr = 0, heap = {tape_size};
+
+function alloc(size) {{
+    const result = heap;
+    heap += size;
+    if (heap > tape.length) {{
+        const grown = new BigInt64Array(Math.max(heap, tape.length * 2));
+        grown.set(tape);
+        tape = grown;
+        ftape = new Float64Array(tape.buffer);
+    }}
+    return result;
+}}
+
+let output = "", peeked = null;
+function flush() {{
+    if (output.length == 0) return;
+    if (sage.write) sage.write(output);
+    else require("fs").writeSync(1, Buffer.from(output, "latin1"));
+    output = "";
+}}
+function write(text) {{
+    output += text;
+    if (output.length > 4096) flush();
+}}
+function format_float(n) {{
+    if (Number.isNaN(n)) return "NaN";
+    if (!Number.isFinite(n)) return n > 0 ? "inf" : "-inf";
+    const text = n.toString().replace("e+", "e");
+    return Number.isInteger(n) && !text.includes("e") ? text + ".0" : text;
+}}
+function read_char() {{
+    if (peeked !== null) {{
+        const ch = peeked;
+        peeked = null;
+        return ch;
+    }}
+    flush();
+    if (sage.read) return sage.read();
+    const buf = Buffer.alloc(1);
+    try {{
+        return require("fs").readSync(0, buf, 0, 1) ? buf[0] : -1;
+    }} catch (e) {{
+        return -1;
+    }}
+}}
+function read_number() {{
+    let ch = read_char(), text = "";
+    while (ch == 32 || ch == 9 || ch == 10 || ch == 13) ch = read_char();
+    while (ch >= 0 && "0123456789.eE+-".includes(String.fromCharCode(ch))) {{
+        text += String.fromCharCode(ch);
+        ch = read_char();
+    }}
+    peeked = ch;
+    return text;
+}}
+
+"#,
+            tape_size = self.tape_size
+        ))
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut result = String::from("const funs = [];\n");
+        for fun in funs {
+            result += &format!("funs[{fun}] = f{fun};\n");
+        }
+        result += "\nfunction main() {\n";
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("flush();\n}\n\nmain();\n".to_string())
+    }
+}
+
+impl CompiledTarget for JavaScript {}
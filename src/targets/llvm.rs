@@ -0,0 +1,422 @@
+//! # LLVM Target
+//!
+//! An implementation of the virtual machine for LLVM, in the textual IR format.
+//!
+//! This allows the virtual machine to target any architecture supported by LLVM,
+//! and to use LLVM's optimizer on the output (with `opt` and `llc`).
+//!
+//! ## Memory Model
+//!
+//! The tape, the ref stack, and the FFI channel are global arrays, and the register,
+//! `ptr`, and `ref` are globals, since they're shared across every function. Each
+//! instruction loads the values it needs from these globals, and stores its result
+//! back, leaving it to LLVM to promote them to registers.
+//!
+//! Every function is defined at the top level as `@fN`, and `Call` calls through
+//! the `@funs` array of function pointers. `While`, `If`, and `Else` are lowered to
+//! basic blocks with conditional branches on the register.
+//!
+//! The output uses opaque pointers (`ptr`), so it requires LLVM 15 or newer
+//! (or `-opaque-pointers` on LLVM 14).
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+use std::collections::BTreeSet;
+
+/// The type for the LLVM target which implements the `Target` trait.
+/// This allows the compiler to target LLVM IR.
+pub struct LLVM {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// The number of pointers on the stack used by `Deref` and `Refer`.
+    pub ref_stack_size: usize,
+    /// The number of temporary values created so far.
+    tmp_count: usize,
+    /// The number of branches created so far.
+    branch_count: usize,
+    /// The branches that haven't been ended yet.
+    branch_match: Vec<usize>,
+    /// How many functions are we inside of?
+    fun_depth: usize,
+    /// The foreign functions called by the program.
+    ffi_names: BTreeSet<String>,
+}
+
+impl Default for LLVM {
+    fn default() -> Self {
+        Self {
+            tape_size: 200000,
+            ref_stack_size: 1024,
+            tmp_count: 0,
+            branch_count: 0,
+            branch_match: vec![],
+            fun_depth: 0,
+            ffi_names: BTreeSet::new(),
+        }
+    }
+}
+
+impl LLVM {
+    /// Create a new temporary value name.
+    fn tmp(&mut self) -> String {
+        self.tmp_count += 1;
+        format!("%t{}", self.tmp_count)
+    }
+
+    /// Create a new branch number, and push it to the branches that must be ended.
+    fn branch(&mut self) -> usize {
+        let branch = self.branch_count;
+        self.branch_count += 1;
+        self.branch_match.push(branch);
+        branch
+    }
+
+    /// Apply a binary integer instruction to the register and the cell under the pointer.
+    fn int_binop(&mut self, op: &str) -> String {
+        let (reg, ptr, val, result) = (self.tmp(), self.tmp(), self.tmp(), self.tmp());
+        [
+            format!("{reg} = load i64, ptr @reg"),
+            format!("{ptr} = load ptr, ptr @ptr"),
+            format!("{val} = load i64, ptr {ptr}"),
+            format!("{result} = {op} i64 {reg}, {val}"),
+            format!("store i64 {result}, ptr @reg"),
+        ]
+        .join("\n\t")
+    }
+
+    /// Apply a binary float instruction to the register and the cell under the pointer.
+    fn float_binop(&mut self, op: &str) -> String {
+        let (reg, a, ptr, b, result, bits) = (
+            self.tmp(),
+            self.tmp(),
+            self.tmp(),
+            self.tmp(),
+            self.tmp(),
+            self.tmp(),
+        );
+        let result_op = if op == "pow" {
+            format!("{result} = call double @pow(double {a}, double {b})")
+        } else {
+            format!("{result} = {op} double {a}, {b}")
+        };
+        [
+            format!("{reg} = load i64, ptr @reg"),
+            format!("{a} = bitcast i64 {reg} to double"),
+            format!("{ptr} = load ptr, ptr @ptr"),
+            format!("{b} = load double, ptr {ptr}"),
+            result_op,
+            format!("{bits} = bitcast double {result} to i64"),
+            format!("store i64 {bits}, ptr @reg"),
+        ]
+        .join("\n\t")
+    }
+
+    /// Call a float function of one argument on the register.
+    fn float_fn(&mut self, name: &str) -> String {
+        let (reg, a, result, bits) = (self.tmp(), self.tmp(), self.tmp(), self.tmp());
+        [
+            format!("{reg} = load i64, ptr @reg"),
+            format!("{a} = bitcast i64 {reg} to double"),
+            format!("{result} = call double @{name}(double {a})"),
+            format!("{bits} = bitcast double {result} to i64"),
+            format!("store i64 {bits}, ptr @reg"),
+        ]
+        .join("\n\t")
+    }
+}
+
+impl Architecture for LLVM {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("; {}", text.replace('\n', "\n; ").replace('\r', ""))
+            }
+            CoreOp::While => {
+                let branch = self.branch();
+                let (reg, cond) = (self.tmp(), self.tmp());
+                format!("br label %while{branch}\nwhile{branch}:\n\t{reg} = load i64, ptr @reg\n\t{cond} = icmp ne i64 {reg}, 0\n\tbr i1 {cond}, label %while_body{branch}, label %while_end{branch}\nwhile_body{branch}:")
+            }
+            CoreOp::If => {
+                let branch = self.branch();
+                let (reg, cond) = (self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{cond} = icmp ne i64 {reg}, 0\n\tbr i1 {cond}, label %then{branch}, label %else{branch}\nthen{branch}:")
+            }
+            CoreOp::Else => {
+                let branch = self.branch_match.last().copied().unwrap_or_default();
+                format!("br label %end{branch}\nelse{branch}:")
+            }
+            CoreOp::Set(n) => format!("store i64 {n}, ptr @reg"),
+            CoreOp::Call => {
+                let (reg, fun_ptr, fun) = (self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{fun_ptr} = getelementptr ptr, ptr @funs, i64 {reg}\n\t{fun} = load ptr, ptr {fun_ptr}\n\tcall void {fun}()")
+            }
+            CoreOp::Return => {
+                // Any instructions after the return need a new basic block.
+                let branch = self.branch_count;
+                self.branch_count += 1;
+                if self.fun_depth > 0 {
+                    format!("ret void\nafter_return{branch}:")
+                } else {
+                    format!("ret i32 0\nafter_return{branch}:")
+                }
+            }
+            CoreOp::Save => {
+                let (ptr, reg) = (self.tmp(), self.tmp());
+                format!("{ptr} = load ptr, ptr @ptr\n\t{reg} = load i64, ptr @reg\n\tstore i64 {reg}, ptr {ptr}")
+            }
+            CoreOp::Restore => {
+                let (ptr, val) = (self.tmp(), self.tmp());
+                format!("{ptr} = load ptr, ptr @ptr\n\t{val} = load i64, ptr {ptr}\n\tstore i64 {val}, ptr @reg")
+            }
+            CoreOp::Move(n) => {
+                let (ptr, moved) = (self.tmp(), self.tmp());
+                format!("{ptr} = load ptr, ptr @ptr\n\t{moved} = getelementptr i64, ptr {ptr}, i64 {n}\n\tstore ptr {moved}, ptr @ptr")
+            }
+            CoreOp::Where => {
+                let (ptr, addr) = (self.tmp(), self.tmp());
+                format!("{ptr} = load ptr, ptr @ptr\n\t{addr} = ptrtoint ptr {ptr} to i64\n\tstore i64 {addr}, ptr @reg")
+            }
+            CoreOp::Deref => {
+                let (refs, ptr, next, val, deref) =
+                    (self.tmp(), self.tmp(), self.tmp(), self.tmp(), self.tmp());
+                format!("{refs} = load ptr, ptr @ref\n\t{ptr} = load ptr, ptr @ptr\n\tstore ptr {ptr}, ptr {refs}\n\t{next} = getelementptr ptr, ptr {refs}, i64 1\n\tstore ptr {next}, ptr @ref\n\t{val} = load i64, ptr {ptr}\n\t{deref} = inttoptr i64 {val} to ptr\n\tstore ptr {deref}, ptr @ptr")
+            }
+            CoreOp::Refer => {
+                let (refs, prev, ptr) = (self.tmp(), self.tmp(), self.tmp());
+                format!("{refs} = load ptr, ptr @ref\n\t{prev} = getelementptr ptr, ptr {refs}, i64 -1\n\tstore ptr {prev}, ptr @ref\n\t{ptr} = load ptr, ptr {prev}\n\tstore ptr {ptr}, ptr @ptr")
+            }
+            CoreOp::Index => {
+                let (reg, ptr, val, offset, result) =
+                    (self.tmp(), self.tmp(), self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{ptr} = load ptr, ptr @ptr\n\t{val} = load i64, ptr {ptr}\n\t{offset} = mul i64 {val}, 8\n\t{result} = add i64 {reg}, {offset}\n\tstore i64 {result}, ptr @reg")
+            }
+            CoreOp::BitwiseNand => {
+                let and = self.int_binop("and");
+                let (reg, result) = (self.tmp(), self.tmp());
+                format!("{and}\n\t{reg} = load i64, ptr @reg\n\t{result} = xor i64 {reg}, -1\n\tstore i64 {result}, ptr @reg")
+            }
+            CoreOp::Add => self.int_binop("add"),
+            CoreOp::Sub => self.int_binop("sub"),
+            CoreOp::Mul => self.int_binop("mul"),
+            CoreOp::Div => self.int_binop("sdiv"),
+            CoreOp::Rem => self.int_binop("srem"),
+            CoreOp::IsNonNegative => {
+                let (reg, cond, result) = (self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{cond} = icmp sge i64 {reg}, 0\n\t{result} = zext i1 {cond} to i64\n\tstore i64 {result}, ptr @reg")
+            }
+            _ => unreachable!("Invalid op for LLVM target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => {
+                self.ffi_names.insert(ffi.name.clone());
+                format!("call void @__{}()", ffi.name)
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            // Store the bits of the float directly, to avoid any loss of precision.
+            StandardOp::Set(n) => format!("store i64 {}, ptr @reg", n.to_bits() as i64),
+            StandardOp::ToInt => {
+                let (reg, a, result) = (self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{a} = bitcast i64 {reg} to double\n\t{result} = fptosi double {a} to i64\n\tstore i64 {result}, ptr @reg")
+            }
+            StandardOp::ToFloat => {
+                let (reg, a, result) = (self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{a} = sitofp i64 {reg} to double\n\t{result} = bitcast double {a} to i64\n\tstore i64 {result}, ptr @reg")
+            }
+            StandardOp::ACos => self.float_fn("acos"),
+            StandardOp::ASin => self.float_fn("asin"),
+            StandardOp::ATan => self.float_fn("atan"),
+            StandardOp::Sin => self.float_fn("sin"),
+            StandardOp::Cos => self.float_fn("cos"),
+            StandardOp::Tan => self.float_fn("tan"),
+            StandardOp::Add => self.float_binop("fadd"),
+            StandardOp::Sub => self.float_binop("fsub"),
+            StandardOp::Mul => self.float_binop("fmul"),
+            StandardOp::Div => self.float_binop("fdiv"),
+            StandardOp::Rem => self.float_binop("frem"),
+            StandardOp::Pow => self.float_binop("pow"),
+            StandardOp::IsNonNegative => {
+                let (reg, a, cond, result) = (self.tmp(), self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{a} = bitcast i64 {reg} to double\n\t{cond} = fcmp oge double {a}, 0.0\n\t{result} = zext i1 {cond} to i64\n\tstore i64 {result}, ptr @reg")
+            }
+            StandardOp::Alloc => {
+                let (reg, size, mem, addr) = (self.tmp(), self.tmp(), self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{size} = mul i64 {reg}, 8\n\t{mem} = call ptr @malloc(i64 {size})\n\t{addr} = ptrtoint ptr {mem} to i64\n\tstore i64 {addr}, ptr @reg")
+            }
+            StandardOp::Free => {
+                let (reg, mem) = (self.tmp(), self.tmp());
+                format!("{reg} = load i64, ptr @reg\n\t{mem} = inttoptr i64 {reg} to ptr\n\tcall void @free(ptr {mem})")
+            }
+            _ => return Err(format!("Invalid standard op for LLVM target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        match (matching, fun) {
+            (CoreOp::Function, _) => {
+                self.fun_depth -= 1;
+                "ret void\n}".to_string()
+            }
+            (CoreOp::While, _) => {
+                let branch = self.branch_match.pop().unwrap_or_default();
+                format!("br label %while{branch}\nwhile_end{branch}:")
+            }
+            (CoreOp::If, _) => {
+                let branch = self.branch_match.pop().unwrap_or_default();
+                format!("br label %else{branch}\nelse{branch}:")
+            }
+            (CoreOp::Else, _) => {
+                let branch = self.branch_match.pop().unwrap_or_default();
+                format!("br label %end{branch}\nend{branch}:")
+            }
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        self.fun_depth += 1;
+        format!("define void @f{label_id}() {{\nentry:")
+    }
+
+    fn name(&self) -> &str {
+        "LLVM"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => {
+                let (ch, result) = (self.tmp(), self.tmp());
+                Ok(format!("{ch} = call i32 @getchar()\n\t{result} = sext i32 {ch} to i64\n\tstore i64 {result}, ptr @reg"))
+            }
+            InputMode::StdinInt => {
+                let result = self.tmp();
+                Ok(format!(
+                    "{result} = call i32 (ptr, ...) @scanf(ptr @int_fmt, ptr @reg)"
+                ))
+            }
+            InputMode::StdinFloat => {
+                let result = self.tmp();
+                Ok(format!(
+                    "{result} = call i32 (ptr, ...) @scanf(ptr @float_fmt, ptr @reg)"
+                ))
+            }
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        match dst.mode {
+            OutputMode::StdoutChar => {
+                let (reg, ch, result) = (self.tmp(), self.tmp(), self.tmp());
+                Ok(format!("{reg} = load i64, ptr @reg\n\t{ch} = trunc i64 {reg} to i32\n\t{result} = call i32 @putchar(i32 {ch})"))
+            }
+            OutputMode::StdoutInt => {
+                let (reg, result) = (self.tmp(), self.tmp());
+                Ok(format!("{reg} = load i64, ptr @reg\n\t{result} = call i32 (ptr, ...) @printf(ptr @int_fmt, i64 {reg})"))
+            }
+            OutputMode::StdoutFloat => {
+                let (reg, result) = (self.tmp(), self.tmp());
+                Ok(format!("{reg} = load double, ptr @reg\n\t{result} = call i32 (ptr, ...) @printf(ptr @float_fmt, double {reg})"))
+            }
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `@ffi_ptr` points to the last cell written, and `Peek` pops it into the register.
+    fn peek(&mut self) -> Result<String, String> {
+        let (ffi_ptr, val, prev) = (self.tmp(), self.tmp(), self.tmp());
+        Ok(format!("{ffi_ptr} = load ptr, ptr @ffi_ptr\n\t{val} = load i64, ptr {ffi_ptr}\n\tstore i64 {val}, ptr @reg\n\t{prev} = getelementptr i64, ptr {ffi_ptr}, i64 -1\n\tstore ptr {prev}, ptr @ffi_ptr"))
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        let (ffi_ptr, next, reg) = (self.tmp(), self.tmp(), self.tmp());
+        Ok(format!("{ffi_ptr} = load ptr, ptr @ffi_ptr\n\t{next} = getelementptr i64, ptr {ffi_ptr}, i64 1\n\tstore ptr {next}, ptr @ffi_ptr\n\t{reg} = load i64, ptr @reg\n\tstore i64 {reg}, ptr {next}"))
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(format!(
+            r#"@tape = global [{tape_size} x i64] zeroinitializer
+@refs = global [{ref_stack_size} x ptr] zeroinitializer
+@ffi_channel = global [256 x i64] zeroinitializer
+@ptr = global ptr @tape
+@ref = global ptr @refs
+@ffi_ptr = global ptr @ffi_channel
+@reg = global i64 0
+@int_fmt = private constant [4 x i8] c"%ld\00"
+@float_fmt = private constant [4 x i8] c"%lf\00"
+
+declare i32 @getchar()
+declare i32 @putchar(i32)
+declare i32 @printf(ptr, ...)
+declare i32 @scanf(ptr, ...)
+declare ptr @malloc(i64)
+declare void @free(ptr)
+declare double @sin(double)
+declare double @cos(double)
+declare double @tan(double)
+declare double @asin(double)
+declare double @acos(double)
+declare double @atan(double)
+declare double @pow(double, double)
+
+"#,
+            tape_size = self.tape_size,
+            ref_stack_size = self.ref_stack_size,
+        ))
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut funs = funs;
+        funs.sort();
+        let funs = funs
+            .iter()
+            .map(|fun| format!("ptr @f{fun}"))
+            .collect::<Vec<_>>();
+        Some(format!(
+            "@funs = global [{} x ptr] [{}]\n\ndefine i32 @main() {{\nentry:\n",
+            funs.len(),
+            funs.join(", ")
+        ))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        let mut result = "ret i32 0\n}\n".to_string();
+        for name in &self.ffi_names {
+            result += &format!("declare void @__{name}()\n");
+        }
+        Some(result)
+    }
+}
+
+impl CompiledTarget for LLVM {}
@@ -5,7 +5,7 @@
 //!
 //! ## Current Structure
 //!
-//! Right now, this module is a bit empty, only implementing C (GCC only)
+//! Right now, this module is a bit empty, only implementing C
 //! as a compiler target. This is due to the fact that it has been much
 //! simpler to build the language on top of the virtual machine when there
 //! are fewer implementations to change.
@@ -36,15 +36,44 @@
 //! also *choose* to fail under unsupported targets to prevent use where
 //! not intended.
 
+pub mod aarch64;
+pub use aarch64::*;
+
+pub mod brainfuck;
+pub use brainfuck::*;
+
 pub mod c;
 pub use c::*;
 
+pub mod go;
+pub use go::*;
+
+pub mod javascript;
+pub use javascript::*;
+
+pub mod llvm;
+pub use llvm::*;
+
+pub mod python;
+pub use python::*;
+
+pub mod riscv;
+pub use riscv::*;
+
+pub mod rust;
+pub use rust::*;
+
 pub mod sage_os;
 pub use sage_os::*;
 
+pub mod wat;
+pub use wat::*;
+
 pub mod x86;
 pub use x86::*;
 
+use std::collections::HashMap;
+
 use log::info;
 
 use crate::{
@@ -75,7 +104,12 @@ pub trait Architecture {
     /// Poke a value to the device connected to the program.
     fn poke(&mut self) -> Result<String, String>;
 
+    /// Forget what was learned while compiling an earlier program, like which
+    /// support code the prelude needs, before compiling another one.
+    fn reset(&mut self) {}
+
     /// The code before the program starts.
+    /// This is generated after the rest of the program, so it can depend on the instructions used.
     fn prelude(&self, _is_core: bool) -> Option<String> {
         None
     }
@@ -105,6 +139,13 @@ pub trait Architecture {
         Some("\t".to_string())
     }
 
+    /// Compile a `Set` instruction immediately followed by a `Save`, which stores
+    /// the constant in the register and in the cell under the pointer at once.
+    /// By default, this returns `None` to compile the two instructions separately.
+    fn set_and_save(&mut self, _n: i64) -> Option<String> {
+        None
+    }
+
     /// Compile the declaration of a procedure.
     fn declare_proc(&mut self, label_id: usize) -> String;
     /// Compile an `End` instruction (with the matching `If` or `While` or `Function`)
@@ -151,8 +192,8 @@ pub trait CompiledTarget: Architecture {
                 }
             }
             CoreOp::End => {
-                *indent -= 1;
                 if let Some(op) = matching_ops.pop() {
+                    *indent -= 1;
                     self.end(
                         &op,
                         if let CoreOp::Function = op {
@@ -190,6 +231,29 @@ pub trait CompiledTarget: Architecture {
         })
     }
 
+    /// Compile an instruction together with the next one, if the target can do
+    /// better than compiling them separately (see `Architecture::set_and_save`).
+    fn build_op_pair(&mut self, op: &vm::CoreOp, next: Option<&vm::CoreOp>) -> Option<String> {
+        match (op, next) {
+            (CoreOp::Set(n), Some(CoreOp::Save)) => self.set_and_save(*n),
+            _ => None,
+        }
+    }
+
+    /// Compile a standard instruction together with the next one (see `build_op_pair`).
+    fn build_std_op_pair(
+        &mut self,
+        std_op: &vm::StandardOp,
+        next: Option<&vm::StandardOp>,
+    ) -> Option<String> {
+        match (std_op, next) {
+            (StandardOp::CoreOp(op), Some(StandardOp::CoreOp(next))) => {
+                self.build_op_pair(op, Some(next))
+            }
+            _ => None,
+        }
+    }
+
     fn build_std_op(
         &mut self,
         std_op: &vm::StandardOp,
@@ -209,104 +273,160 @@ pub trait CompiledTarget: Architecture {
     /// Compile the core variant of the machine code (must be implemented for every target).
     fn build_core(&mut self, program: &vm::CoreProgram) -> Result<String, String> {
         info!("Compiling core program for target {}", self.name());
+        // Reject unbalanced blocks before splitting the program into its functions.
+        program.validate().map_err(|e| e.to_string())?;
         let (main_ops, function_defs) = program.clone().get_main_and_functions();
-        let mut result = self.prelude(true).unwrap_or("".to_string());
-
-        let mut matching_ops = vec![];
-        let mut matching_funs = vec![];
-        let mut current_fun: usize = 0;
-
-        let tab = self.indentation().unwrap_or("".to_string());
-
-        let mut indent = 0;
-
-        result += &self
-            .pre_funs(function_defs.keys().cloned().collect())
-            .unwrap_or("".to_string());
-        for i in 0..function_defs.len() as i32 {
-            let f = &function_defs[&i];
-            for op in f {
-                result += &tab.repeat(indent);
-                result += &self.build_op(
-                    op,
-                    &mut matching_ops,
-                    &mut matching_funs,
-                    &mut current_fun,
-                    &mut indent,
-                )?;
-                result += &self.postop().unwrap_or("".to_string());
-            }
-        }
-        result += &self
-            .post_funs(function_defs.keys().cloned().collect())
-            .unwrap_or("".to_string());
-        indent = 1;
-        for op in main_ops {
-            result += &tab.repeat(indent);
-            result += &self.build_op(
-                &op,
-                &mut matching_ops,
-                &mut matching_funs,
-                &mut current_fun,
-                &mut indent,
-            )?;
-            result += &self.postop().unwrap_or("".to_string());
-        }
+        let result = build_program(self, &main_ops, &function_defs)?;
 
         info!("Finished compiling core program for target {}", self.name());
-        Ok(result + &tab + self.postlude(true).unwrap_or("".to_string()).as_str())
+        // The prelude is generated last, so it can include support code for the instructions used.
+        Ok(self.prelude(true).unwrap_or("".to_string())
+            + &result
+            + self.postlude(true).unwrap_or("".to_string()).as_str())
     }
 
     /// Compile the standard variant of the machine code (should be implemented for every target possible).
     fn build_std(&mut self, program: &vm::StandardProgram) -> Result<String, String> {
         info!("Compiling standard program for target {}", self.name());
+        // Reject unbalanced blocks before splitting the program into its functions.
+        program.validate().map_err(|e| e.to_string())?;
         let (main_ops, function_defs) = program.clone().get_main_and_functions();
-        let mut result = self.prelude(false).unwrap_or("".to_string());
-
-        let mut matching_ops = vec![];
-        let mut matching_funs = vec![];
-        let mut current_fun: usize = 0;
-
-        let tab = self.indentation().unwrap_or("".to_string());
-
-        let mut indent = 0;
-        result += &self
-            .pre_funs(function_defs.keys().cloned().collect())
-            .unwrap_or("".to_string());
-        for i in 0..function_defs.len() as i32 {
-            let f = &function_defs[&i];
-            for op in f {
-                result += &tab.repeat(indent);
-                result += &self.build_std_op(
-                    op,
-                    &mut matching_ops,
-                    &mut matching_funs,
-                    &mut current_fun,
-                    &mut indent,
-                )?;
-                result += &self.postop().unwrap_or("".to_string());
-            }
-        }
-        result += &self
-            .post_funs(function_defs.keys().cloned().collect())
-            .unwrap_or("".to_string());
-        indent = 1;
-        for op in main_ops {
-            result += &tab.repeat(indent);
-            result += &self.build_std_op(
-                &op,
-                &mut matching_ops,
-                &mut matching_funs,
-                &mut current_fun,
-                &mut indent,
-            )?;
-            result += &self.postop().unwrap_or("".to_string());
-        }
+        let result = build_program(self, &main_ops, &function_defs)?;
 
         info!(
             "Finished compiling standard program for target {}",
             self.name()
         );
-        Ok(result + &tab + self.postlude(false).unwrap_or("".to_string()).as_str())
+        // The prelude is generated last, so it can include support code for the instructions used.
+        Ok(self.prelude(false).unwrap_or("".to_string())
+            + &result
+            + self.postlude(false).unwrap_or("".to_string()).as_str())
+    }
+}
+
+/// An instruction of either variant, which a target can compile.
+/// This lets both variants share the code which compiles a whole program.
+trait BuildOp: Sized {
+    /// Compile this instruction together with the next one, if the target can
+    /// (see `CompiledTarget::build_op_pair`).
+    fn build_pair<T: CompiledTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        next: Option<&Self>,
+    ) -> Option<String>;
+
+    /// Compile this instruction on its own (see `CompiledTarget::build_op`).
+    fn build<T: CompiledTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        blocks: &mut Blocks,
+    ) -> Result<String, String>;
+}
+
+impl BuildOp for vm::CoreOp {
+    fn build_pair<T: CompiledTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        next: Option<&Self>,
+    ) -> Option<String> {
+        target.build_op_pair(self, next)
+    }
+
+    fn build<T: CompiledTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        blocks: &mut Blocks,
+    ) -> Result<String, String> {
+        target.build_op(
+            self,
+            &mut blocks.matching_ops,
+            &mut blocks.matching_funs,
+            &mut blocks.current_fun,
+            &mut blocks.indent,
+        )
+    }
+}
+
+impl BuildOp for vm::StandardOp {
+    fn build_pair<T: CompiledTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        next: Option<&Self>,
+    ) -> Option<String> {
+        target.build_std_op_pair(self, next)
+    }
+
+    fn build<T: CompiledTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        blocks: &mut Blocks,
+    ) -> Result<String, String> {
+        target.build_std_op(
+            self,
+            &mut blocks.matching_ops,
+            &mut blocks.matching_funs,
+            &mut blocks.current_fun,
+            &mut blocks.indent,
+        )
+    }
+}
+
+/// The blocks which are open while a program is compiled.
+#[derive(Default)]
+struct Blocks {
+    /// The instructions which started the open blocks, to match with their `End`.
+    matching_ops: Vec<vm::CoreOp>,
+    /// The indices of the functions being defined.
+    matching_funs: Vec<usize>,
+    /// The index of the next function to be defined.
+    current_fun: usize,
+    /// The indentation level of the current instruction.
+    indent: usize,
+}
+
+/// Compile the functions of a program, and then its main body, without the prelude
+/// and postlude. This is shared by `build_core` and `build_std`.
+fn build_program<T: CompiledTarget + ?Sized, Op: BuildOp>(
+    target: &mut T,
+    main_ops: &[Op],
+    function_defs: &HashMap<i32, Vec<Op>>,
+) -> Result<String, String> {
+    target.reset();
+    let tab = target.indentation().unwrap_or("".to_string());
+    let mut blocks = Blocks::default();
+    let funs: Vec<i32> = function_defs.keys().cloned().collect();
+
+    let mut result = target.pre_funs(funs.clone()).unwrap_or("".to_string());
+    for i in 0..function_defs.len() as i32 {
+        result += &build_ops(target, &function_defs[&i], &tab, &mut blocks)?;
+    }
+    result += &target.post_funs(funs).unwrap_or("".to_string());
+    blocks.indent = 1;
+    result += &build_ops(target, main_ops, &tab, &mut blocks)?;
+    Ok(result + &tab)
+}
+
+/// Compile a list of instructions, each indented by the blocks it's in.
+/// An instruction is compiled together with the next one if the target can do better
+/// than compiling them separately, and then the next one is skipped.
+fn build_ops<T: CompiledTarget + ?Sized, Op: BuildOp>(
+    target: &mut T,
+    ops: &[Op],
+    tab: &str,
+    blocks: &mut Blocks,
+) -> Result<String, String> {
+    let mut result = String::new();
+    let mut ops = ops.iter().peekable();
+    while let Some(op) = ops.next() {
+        result += &tab.repeat(blocks.indent);
+        if let Some(code) = op.build_pair(target, ops.peek().copied()) {
+            // Skip the instruction compiled along with this one.
+            ops.next();
+            result += &code;
+        } else {
+            result += &op.build(target, blocks)?;
+        }
+        result += &target.postop().unwrap_or("".to_string());
     }
+    Ok(result)
 }
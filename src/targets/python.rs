@@ -0,0 +1,303 @@
+//! # Python Target
+//!
+//! An implementation of the virtual machine for Python 3.
+//!
+//! This allows the virtual machine to target Python programs.
+//!
+//! ## Memory Model
+//!
+//! The tape is a Python list, and pointers are integer indices into the list.
+//! Cells hold either Python integers or floats, so no bit casting is needed
+//! to move values between the integer and float instructions. `Alloc` bump allocates
+//! cells past the end of the tape, growing the list as needed, and `Free` does nothing.
+//!
+//! ## Indentation
+//!
+//! Python is indentation sensitive, so this target keeps track of its own
+//! indentation level instead of using the indentation of the `CompiledTarget` builder.
+//! Functions are defined at the top level as `fN`, and the main program runs at
+//! the top level after the `funs` dispatch table is built.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the Python target which implements the `Target` trait.
+/// This allows the compiler to target Python 3.
+pub struct Python {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// The current indentation level of the generated code.
+    indent: usize,
+    /// Whether the innermost block has no statements yet. Python does not allow empty
+    /// blocks, so these are filled with a `pass` statement.
+    empty_block: bool,
+}
+
+impl Default for Python {
+    fn default() -> Self {
+        Self {
+            tape_size: 200000,
+            indent: 0,
+            empty_block: false,
+        }
+    }
+}
+
+impl Python {
+    /// Indent a statement at the current indentation level.
+    fn line(&mut self, code: impl ToString) -> String {
+        self.empty_block = false;
+        format!("{}{}", "    ".repeat(self.indent), code.to_string())
+    }
+
+    /// Open a new block with the given header, like `while reg:`.
+    fn open(&mut self, header: impl ToString) -> String {
+        let result = self.line(header);
+        self.indent += 1;
+        self.empty_block = true;
+        result
+    }
+
+    /// Close the innermost block, filling it with `pass` if it's empty.
+    fn close(&mut self) -> String {
+        let result = if self.empty_block {
+            self.line("pass")
+        } else {
+            String::new()
+        };
+        self.indent -= 1;
+        self.empty_block = false;
+        result
+    }
+}
+
+impl Architecture for Python {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                // Comments are not statements, so they don't fill an empty block.
+                let tab = "    ".repeat(self.indent);
+                format!(
+                    "{tab}# {}",
+                    text.replace('\n', &format!("\n{tab}# ")).replace('\r', "")
+                )
+            }
+            CoreOp::While => self.open("while reg:"),
+            CoreOp::If => self.open("if reg:"),
+            CoreOp::Else => {
+                let mut result = self.close();
+                if !result.is_empty() {
+                    result += "\n";
+                }
+                result + &self.open("else:")
+            }
+            CoreOp::Set(n) => self.line(format!("reg = {n}")),
+            CoreOp::Call => self.line("funs[reg]()"),
+            CoreOp::Return => self.line("return"),
+            CoreOp::Save => self.line("tape[ptr] = reg"),
+            CoreOp::Restore => self.line("reg = tape[ptr]"),
+            CoreOp::Move(n) => self.line(format!("ptr += {n}")),
+            CoreOp::Where => self.line("reg = ptr"),
+            CoreOp::Deref => self.line("refs.append(ptr); ptr = tape[ptr]"),
+            CoreOp::Refer => self.line("ptr = refs.pop()"),
+            CoreOp::Index => self.line("reg += tape[ptr]"),
+            CoreOp::BitwiseNand => self.line("reg = ~(reg & tape[ptr])"),
+            CoreOp::Add => self.line("reg = wrap(reg + tape[ptr])"),
+            CoreOp::Sub => self.line("reg = wrap(reg - tape[ptr])"),
+            CoreOp::Mul => self.line("reg = wrap(reg * tape[ptr])"),
+            CoreOp::Div => self.line("reg = wrap(div(reg, tape[ptr]))"),
+            CoreOp::Rem => self.line("reg = reg - tape[ptr] * div(reg, tape[ptr])"),
+            CoreOp::IsNonNegative => self.line("reg = int(reg >= 0)"),
+            _ => unreachable!("Invalid op for Python target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) if n.is_nan() => self.line("reg = math.nan"),
+            StandardOp::Set(n) if n.is_infinite() && *n > 0.0 => self.line("reg = math.inf"),
+            StandardOp::Set(n) if n.is_infinite() => self.line("reg = -math.inf"),
+            StandardOp::Set(n) => self.line(format!("reg = {n:?}")),
+            StandardOp::ToInt => self.line("reg = int(reg)"),
+            StandardOp::ToFloat => self.line("reg = float(reg)"),
+            StandardOp::ACos => self.line("reg = math.acos(reg)"),
+            StandardOp::ASin => self.line("reg = math.asin(reg)"),
+            StandardOp::ATan => self.line("reg = math.atan(reg)"),
+            StandardOp::Sin => self.line("reg = math.sin(reg)"),
+            StandardOp::Cos => self.line("reg = math.cos(reg)"),
+            StandardOp::Tan => self.line("reg = math.tan(reg)"),
+            StandardOp::Add => self.line("reg = float(reg) + tape[ptr]"),
+            StandardOp::Sub => self.line("reg = float(reg) - tape[ptr]"),
+            StandardOp::Mul => self.line("reg = float(reg) * tape[ptr]"),
+            StandardOp::Div => self.line("reg = float(reg) / tape[ptr]"),
+            StandardOp::Rem => self.line("reg = math.fmod(reg, tape[ptr])"),
+            StandardOp::Pow => self.line("reg = math.pow(reg, tape[ptr])"),
+            StandardOp::IsNonNegative => self.line("reg = int(reg >= 0)"),
+            StandardOp::Alloc => self.line("reg = alloc(int(reg))"),
+            StandardOp::Free => self.line("pass"),
+            _ => return Err(format!("Invalid standard op for Python target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        match (matching, fun) {
+            (CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else, _) => self.close(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        let header = self.open(format!("def f{label_id}():"));
+        header + "\n" + &self.line("global reg, ptr")
+    }
+
+    fn name(&self) -> &str {
+        "Python"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok(self.line("reg = read_char()")),
+            InputMode::StdinInt => Ok(self.line("reg = int(read_number() or 0)")),
+            InputMode::StdinFloat => Ok(self.line("reg = float(read_number() or 0)")),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    /// Everything is written to the binary buffer of the stream, so that characters
+    /// are written as raw bytes like the other targets, instead of being encoded as UTF-8.
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        let (stream, value) = match dst.mode {
+            OutputMode::StdoutChar => ("stdout", "bytes([reg & 0xff])"),
+            OutputMode::StdoutInt => ("stdout", "b\"%d\" % reg"),
+            OutputMode::StdoutFloat => ("stdout", "repr(float(reg)).replace('e+', 'e').encode()"),
+            OutputMode::StderrChar => ("stderr", "bytes([reg & 0xff])"),
+            OutputMode::StderrInt => ("stderr", "b\"%d\" % reg"),
+            OutputMode::StderrFloat => ("stderr", "repr(float(reg)).replace('e+', 'e').encode()"),
+            _ => return Err("Output not supported by this target".to_string()),
+        };
+        Ok(self.line(format!("sys.{stream}.buffer.write({value})")))
+    }
+
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `Poke` pushes the register onto the channel, and `Peek` pops the last value pushed.
+    fn peek(&mut self) -> Result<String, String> {
+        Ok(self.line("reg = ffi.pop()"))
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        Ok(self.line("ffi.append(reg)"))
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(format!(
+            r#"import math
+import sys
+
+sys.setrecursionlimit(1 << 20)
+
+tape = [0] * {0}
+refs = []
+ffi = []
+ptr = 0
+reg = 0
+heap = {0}
+peeked = ""
+
+
+def alloc(size):
+    global heap
+    result = heap
+    heap += size
+    if heap > len(tape):
+        tape.extend([0] * max(heap - len(tape), len(tape)))
+    return result
+
+
+def wrap(n):
+    return ((n + 0x8000000000000000) & 0xFFFFFFFFFFFFFFFF) - 0x8000000000000000
+
+
+def div(a, b):
+    q = abs(a) // abs(b)
+    return q if (a < 0) == (b < 0) else -q
+
+
+def read_char():
+    global peeked
+    ch, peeked = peeked or sys.stdin.read(1), ""
+    return ord(ch) if ch else -1
+
+
+def read_number():
+    global peeked
+    ch = peeked or sys.stdin.read(1)
+    while ch.isspace():
+        ch = sys.stdin.read(1)
+    text = ""
+    while ch and ch in "0123456789.eE+-":
+        text += ch
+        ch = sys.stdin.read(1)
+    peeked = ch
+    return text
+
+
+"#,
+            self.tape_size
+        ))
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut funs = funs;
+        funs.sort();
+        let funs = funs
+            .iter()
+            .map(|fun| format!("{fun}: f{fun}"))
+            .collect::<Vec<_>>();
+        Some(format!("\nfuns = {{{}}}\n\n", funs.join(", ")))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn indentation(&self) -> Option<String> {
+        None
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("sys.stdout.flush()\n".to_string())
+    }
+}
+
+impl CompiledTarget for Python {}
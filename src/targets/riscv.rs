@@ -0,0 +1,336 @@
+//! # RISC-V Target
+//!
+//! An implementation of the virtual machine for RISC-V.
+//!
+//! This allows the virtual machine to target 64-bit RISC-V CPUs,
+//! generating GNU assembly for the RV64 Linux ABI.
+//!
+//! ## Register Usage
+//!
+//! Cells are 64 bits wide. The integer instructions use the base RV64I
+//! instruction set along with the `M` extension for multiplication and division,
+//! and the float instructions use the `D` extension.
+//! - `a0` holds the register.
+//! - `s0` holds the tape pointer.
+//! - `s1` saves the register across calls into libc.
+//! - `s2` points to the top of the ref stack used by `Deref` and `Refer`.
+//! - `s3` points to the top of the FFI channel.
+//!
+//! The tape, ref stack, and FFI channel live in `.bss`, and I/O is done
+//! with the externally linked libc functions, so the output can be built with
+//! `riscv64-linux-gnu-gcc out.s -lm`.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the RISC-V target which implements the `Target` trait.
+/// This allows the compiler to target 64-bit RISC-V.
+pub struct RiscV {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// The number of pointers on the stack used by `Deref` and `Refer`.
+    pub ref_stack_size: usize,
+    branch_count: usize,
+    /// The labels placed by the `End` instructions matching each `While`, `If`, and `Else`.
+    branch_match: Vec<String>,
+}
+
+impl Default for RiscV {
+    fn default() -> Self {
+        Self {
+            tape_size: 200000,
+            ref_stack_size: 1024,
+            branch_count: 0,
+            branch_match: vec![],
+        }
+    }
+}
+
+impl RiscV {
+    /// Get a new unique branch number for labels.
+    fn branch(&mut self) -> usize {
+        self.branch_count += 1;
+        self.branch_count - 1
+    }
+
+    /// Call a libc function, saving the register in `s1`.
+    /// The register is passed as the first argument unless `setup` moves it elsewhere.
+    fn call_saving_reg(&self, setup: &str, function: &str) -> String {
+        let indent = self.indentation().unwrap();
+        let mut result = "mv s1, a0\n".to_string();
+        if !setup.is_empty() {
+            result += &format!("{indent}{setup}\n");
+        }
+        result + &format!("{indent}call {function}\n{indent}mv a0, s1")
+    }
+
+    /// Apply a libm function to the float in the register.
+    fn call_float_fn(&self, function: &str, with_cell: bool) -> String {
+        let indent = self.indentation().unwrap();
+        let cell = if with_cell {
+            format!("\n{indent}fld fa1, 0(s0)")
+        } else {
+            String::new()
+        };
+        format!("fmv.d.x fa0, a0{cell}\n{indent}call {function}\n{indent}fmv.x.d a0, fa0")
+    }
+
+    /// Apply an integer instruction to the register and the value in the current cell.
+    fn int_binop(&self, instruction: &str) -> String {
+        let indent = self.indentation().unwrap();
+        format!("ld t0, 0(s0)\n{indent}{instruction} a0, a0, t0")
+    }
+
+    /// Apply a float instruction to the register and the float in the current cell.
+    fn float_binop(&self, instruction: &str) -> String {
+        let indent = self.indentation().unwrap();
+        format!(
+            "fmv.d.x ft0, a0\n{indent}fld ft1, 0(s0)\n{indent}{instruction} ft0, ft0, ft1\n{indent}fmv.x.d a0, ft0"
+        )
+    }
+}
+
+impl Architecture for RiscV {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        let indent = self.indentation().unwrap();
+        match op {
+            CoreOp::Comment(text) => {
+                format!("# {}", text.replace('\n', "\n# ").replace('\r', ""))
+            }
+            // Conditional branches only reach 4KiB, so jump over an unconditional `j` instead.
+            CoreOp::While => {
+                let n = self.branch();
+                self.branch_match.push(format!("j while{n}\nwhile_end{n}:"));
+                format!("while{n}:\n{indent}bnez a0, while_body{n}\n{indent}j while_end{n}\nwhile_body{n}:")
+            }
+            CoreOp::If => {
+                let n = self.branch();
+                self.branch_match.push(format!("else{n}:"));
+                format!("bnez a0, then{n}\n{indent}j else{n}\nthen{n}:")
+            }
+            CoreOp::Else => {
+                let label = self.branch_match.pop().expect("Unexpected else");
+                let n = self.branch();
+                self.branch_match.push(format!("end{n}:"));
+                format!("j end{n}\n{label}")
+            }
+            CoreOp::Set(n) => format!("li a0, {n}"),
+            CoreOp::Call => {
+                format!("la t0, funs\n{indent}slli t1, a0, 3\n{indent}add t0, t0, t1\n{indent}ld t0, 0(t0)\n{indent}jalr t0")
+            }
+            CoreOp::Return => format!("ld ra, 8(sp)\n{indent}addi sp, sp, 16\n{indent}ret"),
+            CoreOp::Save => "sd a0, 0(s0)".to_string(),
+            CoreOp::Restore => "ld a0, 0(s0)".to_string(),
+            CoreOp::Move(n) if (-2048..2048).contains(&(n * 8)) => {
+                format!("addi s0, s0, {}", n * 8)
+            }
+            CoreOp::Move(n) => format!("li t0, {}\n{indent}add s0, s0, t0", n * 8),
+            CoreOp::Where => "mv a0, s0".to_string(),
+            CoreOp::Deref => {
+                format!("sd s0, 0(s2)\n{indent}addi s2, s2, 8\n{indent}ld s0, 0(s0)")
+            }
+            CoreOp::Refer => format!("addi s2, s2, -8\n{indent}ld s0, 0(s2)"),
+            CoreOp::Index => {
+                format!("ld t0, 0(s0)\n{indent}slli t0, t0, 3\n{indent}add a0, a0, t0")
+            }
+            CoreOp::BitwiseNand => format!("{}\n{indent}not a0, a0", self.int_binop("and")),
+            CoreOp::Add => self.int_binop("add"),
+            CoreOp::Sub => self.int_binop("sub"),
+            CoreOp::Mul => self.int_binop("mul"),
+            CoreOp::Div => self.int_binop("div"),
+            CoreOp::Rem => self.int_binop("rem"),
+            CoreOp::IsNonNegative => format!("slt a0, a0, zero\n{indent}xori a0, a0, 1"),
+            _ => unreachable!("Invalid op for RISC-V target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        Ok(match op {
+            StandardOp::Call(_) => {
+                return Err("Foreign functions are not supported by the RISC-V target".to_string())
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) => format!("li a0, {}", n.to_bits() as i64),
+            StandardOp::ToInt => format!("fmv.d.x ft0, a0\n{indent}fcvt.l.d a0, ft0, rtz"),
+            StandardOp::ToFloat => format!("fcvt.d.l ft0, a0\n{indent}fmv.x.d a0, ft0"),
+            StandardOp::ACos => self.call_float_fn("acos", false),
+            StandardOp::ASin => self.call_float_fn("asin", false),
+            StandardOp::ATan => self.call_float_fn("atan", false),
+            StandardOp::Sin => self.call_float_fn("sin", false),
+            StandardOp::Cos => self.call_float_fn("cos", false),
+            StandardOp::Tan => self.call_float_fn("tan", false),
+            StandardOp::Add => self.float_binop("fadd.d"),
+            StandardOp::Sub => self.float_binop("fsub.d"),
+            StandardOp::Mul => self.float_binop("fmul.d"),
+            StandardOp::Div => self.float_binop("fdiv.d"),
+            StandardOp::Rem => self.call_float_fn("fmod", true),
+            StandardOp::Pow => self.call_float_fn("pow", true),
+            StandardOp::IsNonNegative => {
+                format!("fmv.d.x ft0, a0\n{indent}fmv.d.x ft1, zero\n{indent}fle.d a0, ft1, ft0")
+            }
+            StandardOp::Alloc => format!("slli a0, a0, 3\n{indent}call malloc"),
+            StandardOp::Free => self.call_saving_reg("", "free"),
+            _ => return Err(format!("Invalid standard op for RISC-V target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::Function => format!(
+                "ld ra, 8(sp)\n{indent}addi sp, sp, 16\n{indent}ret\n",
+                indent = self.indentation().unwrap()
+            ),
+            CoreOp::While | CoreOp::If | CoreOp::Else => {
+                self.branch_match.pop().expect("Unexpected end")
+            }
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        let indent = self.indentation().unwrap();
+        format!("f{label_id}:\n{indent}addi sp, sp, -16\n{indent}sd ra, 8(sp)")
+    }
+
+    fn name(&self) -> &str {
+        "RISC-V"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        let scan = |format: &str| {
+            format!("la a0, {format}\n{indent}la a1, scratch\n{indent}call scanf\n{indent}la t0, scratch\n{indent}ld a0, 0(t0)")
+        };
+        match src.mode {
+            InputMode::StdinChar => Ok("call getchar".to_string()),
+            InputMode::StdinInt => Ok(scan("int_format")),
+            InputMode::StdinFloat => Ok(scan("float_format")),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        match dst.mode {
+            OutputMode::StdoutChar => Ok(self.call_saving_reg("", "putchar")),
+            // Variadic floats are passed in the integer registers, so ints and floats
+            // are printed the same way.
+            OutputMode::StdoutInt => Ok(
+                self.call_saving_reg(&format!("mv a1, a0\n{indent}la a0, int_format"), "printf")
+            ),
+            OutputMode::StdoutFloat => {
+                Ok(self
+                    .call_saving_reg(&format!("mv a1, a0\n{indent}la a0, float_format"), "printf"))
+            }
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `Poke` pushes the register onto the channel, and `Peek` pops the last value pushed.
+    fn peek(&mut self) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        Ok(format!("ld a0, 0(s3)\n{indent}addi s3, s3, -8"))
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        let indent = self.indentation().unwrap();
+        Ok(format!("addi s3, s3, 8\n{indent}sd a0, 0(s3)"))
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(".text\n.globl main\n\n".to_string())
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let indent = self.indentation().unwrap();
+        let mut funs = funs;
+        funs.sort();
+        let mut result = String::from(".data\n.align 3\nfuns:\n");
+        for fun in funs {
+            result += &format!("{indent}.dword f{fun}\n");
+        }
+        result += &format!(
+            ".text\nmain:
+{indent}addi sp, sp, -48
+{indent}sd ra, 40(sp)
+{indent}sd s0, 32(sp)
+{indent}sd s1, 24(sp)
+{indent}sd s2, 16(sp)
+{indent}sd s3, 8(sp)
+{indent}la s0, tape
+{indent}la s2, refs
+{indent}la s3, ffi_channel
+{indent}li a0, 0
+"
+        );
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        let indent = self.indentation().unwrap();
+        Some(format!(
+            "li a0, 0
+{indent}ld ra, 40(sp)
+{indent}ld s0, 32(sp)
+{indent}ld s1, 24(sp)
+{indent}ld s2, 16(sp)
+{indent}ld s3, 8(sp)
+{indent}addi sp, sp, 48
+{indent}ret
+
+.section .rodata
+int_format:
+{indent}.string \"%ld\"
+float_format:
+{indent}.string \"%lf\"
+
+.bss
+.align 3
+tape:
+{indent}.zero {}
+refs:
+{indent}.zero {}
+ffi_channel:
+{indent}.zero 2048
+scratch:
+{indent}.zero 8
+
+.section .note.GNU-stack,\"\",@progbits
+",
+            self.tape_size * 8,
+            self.ref_stack_size * 8
+        ))
+    }
+}
+
+impl CompiledTarget for RiscV {}
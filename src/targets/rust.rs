@@ -0,0 +1,311 @@
+//! # Rust Target
+//!
+//! An implementation of the virtual machine for the Rust language.
+//!
+//! This allows the virtual machine to target standalone Rust programs,
+//! which can be built with just `rustc out.rs`.
+//!
+//! ## Memory Model
+//!
+//! The state of the machine is a `Vm` struct which is passed to every function.
+//! The tape is a `Vec<i64>`, and pointers are indices into it, so `ptr` and the
+//! pointers on the `refs` stack are `usize`s. Floats are stored in the same cells
+//! as their bits, like a union in C: the `f` and `b` helpers reinterpret a cell as
+//! a float and back. Integer arithmetic wraps, like the interpreter's. `Alloc` bump
+//! allocates cells by growing the tape past its end, and `Free` does nothing.
+//!
+//! ## Functions and I/O
+//!
+//! Every function is defined at the top level as `fn fN(vm: &mut Vm)`, and `Call`
+//! looks them up in the `FUNS` table. Foreign functions are called as `__name(vm)`,
+//! which must be added to the generated file, and which take their arguments from and
+//! push their results onto `vm.ffi`. Input and output go through locked handles to
+//! stdin and stdout, and the output is flushed before reading input and at the end.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the Rust target which implements the `Target` trait.
+/// This allows the compiler to target the Rust language.
+pub struct Rust {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+}
+
+impl Default for Rust {
+    fn default() -> Self {
+        Self { tape_size: 200000 }
+    }
+}
+
+impl Rust {
+    /// Apply a binary float operation to the register and the cell under the pointer.
+    fn float_binop(op: &str) -> String {
+        format!("vm.reg = b(f(vm.reg) {op} f(vm.tape[vm.ptr]));")
+    }
+
+    /// Apply a float method to the register.
+    fn float_fn(name: &str) -> String {
+        format!("vm.reg = b(f(vm.reg).{name}());")
+    }
+
+    /// Apply a wrapping integer method to the register and the cell under the pointer.
+    fn int_binop(name: &str) -> String {
+        format!("vm.reg = vm.reg.{name}(vm.tape[vm.ptr]);")
+    }
+}
+
+impl Architecture for Rust {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
+            }
+            CoreOp::While => "while vm.reg != 0 {".to_string(),
+            CoreOp::If => "if vm.reg != 0 {".to_string(),
+            CoreOp::Else => "} else {".to_string(),
+            CoreOp::Set(n) => format!("vm.reg = {n};"),
+            CoreOp::Call => "FUNS[vm.reg as usize](vm);".to_string(),
+            CoreOp::Return => "return;".to_string(),
+            CoreOp::Save => "vm.tape[vm.ptr] = vm.reg;".to_string(),
+            CoreOp::Restore => "vm.reg = vm.tape[vm.ptr];".to_string(),
+            CoreOp::Move(n) => format!("vm.ptr = (vm.ptr as isize + {n}) as usize;"),
+            CoreOp::Where => "vm.reg = vm.ptr as i64;".to_string(),
+            CoreOp::Deref => "vm.refs.push(vm.ptr); vm.ptr = vm.tape[vm.ptr] as usize;".to_string(),
+            CoreOp::Refer => "vm.ptr = vm.refs.pop().unwrap();".to_string(),
+            CoreOp::Index => Self::int_binop("wrapping_add"),
+            CoreOp::BitwiseNand => "vm.reg = !(vm.reg & vm.tape[vm.ptr]);".to_string(),
+            CoreOp::Add => Self::int_binop("wrapping_add"),
+            CoreOp::Sub => Self::int_binop("wrapping_sub"),
+            CoreOp::Mul => Self::int_binop("wrapping_mul"),
+            CoreOp::Div => Self::int_binop("wrapping_div"),
+            CoreOp::Rem => Self::int_binop("wrapping_rem"),
+            CoreOp::IsNonNegative => "vm.reg = (vm.reg >= 0) as i64;".to_string(),
+            _ => unreachable!("Invalid op for Rust target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => format!("__{}(vm);", ffi.name),
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) if n.is_nan() => "vm.reg = b(f64::NAN);".to_string(),
+            StandardOp::Set(n) if n.is_infinite() && *n > 0.0 => {
+                "vm.reg = b(f64::INFINITY);".to_string()
+            }
+            StandardOp::Set(n) if n.is_infinite() => "vm.reg = b(f64::NEG_INFINITY);".to_string(),
+            // The debug format always includes a decimal point or an exponent,
+            // so the value is always a float literal.
+            StandardOp::Set(n) => format!("vm.reg = b({n:?});"),
+            StandardOp::ToInt => "vm.reg = f(vm.reg) as i64;".to_string(),
+            StandardOp::ToFloat => "vm.reg = b(vm.reg as f64);".to_string(),
+            StandardOp::ACos => Self::float_fn("acos"),
+            StandardOp::ASin => Self::float_fn("asin"),
+            StandardOp::ATan => Self::float_fn("atan"),
+            StandardOp::Sin => Self::float_fn("sin"),
+            StandardOp::Cos => Self::float_fn("cos"),
+            StandardOp::Tan => Self::float_fn("tan"),
+            StandardOp::Add => Self::float_binop("+"),
+            StandardOp::Sub => Self::float_binop("-"),
+            StandardOp::Mul => Self::float_binop("*"),
+            StandardOp::Div => Self::float_binop("/"),
+            StandardOp::Rem => Self::float_binop("%"),
+            StandardOp::Pow => "vm.reg = b(f(vm.reg).powf(f(vm.tape[vm.ptr])));".to_string(),
+            StandardOp::IsNonNegative => "vm.reg = (f(vm.reg) >= 0.0) as i64;".to_string(),
+            StandardOp::Alloc => "vm.reg = vm.alloc(vm.reg);".to_string(),
+            StandardOp::Free => "// free".to_string(),
+            _ => return Err(format!("Invalid standard op for Rust target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        match (matching, fun) {
+            (CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else, _) => "}".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("fn f{label_id}(vm: &mut Vm) {{")
+    }
+
+    fn name(&self) -> &str {
+        "Rust"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok("vm.reg = vm.getchar();".to_string()),
+            InputMode::StdinInt => Ok("vm.reg = vm.read_token().parse().unwrap_or(0);".to_string()),
+            InputMode::StdinFloat => {
+                Ok("vm.reg = b(vm.read_token().parse().unwrap_or(0.0));".to_string())
+            }
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        match dst.mode {
+            OutputMode::StdoutChar => Ok("vm.out.write_all(&[vm.reg as u8]).unwrap();".to_string()),
+            OutputMode::StdoutInt => Ok("write!(vm.out, \"{}\", vm.reg).unwrap();".to_string()),
+            OutputMode::StdoutFloat => {
+                Ok("write!(vm.out, \"{:?}\", f(vm.reg)).unwrap();".to_string())
+            }
+            OutputMode::StderrChar => Ok("eprint!(\"{}\", vm.reg as u8 as char);".to_string()),
+            OutputMode::StderrInt => Ok("eprint!(\"{}\", vm.reg);".to_string()),
+            OutputMode::StderrFloat => Ok("eprint!(\"{:?}\", f(vm.reg));".to_string()),
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `Poke` pushes the register onto the channel, and `Peek` pops the last value pushed.
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("vm.reg = vm.ffi.pop().unwrap();".to_string())
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("vm.ffi.push(vm.reg);".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(
+            r#"#![allow(dead_code, unreachable_code, unused_mut)]
+use std::io::{self, BufRead, BufWriter, Read, StdinLock, StdoutLock, Write};
+
+struct Vm {
+    tape: Vec<i64>,
+    refs: Vec<usize>,
+    ffi: Vec<i64>,
+    ptr: usize,
+    reg: i64,
+    input: StdinLock<'static>,
+    out: BufWriter<StdoutLock<'static>>,
+}
+
+impl Vm {
+    fn alloc(&mut self, size: i64) -> i64 {
+        // Leave a spare cell after the allocation, since the interpreter
+        // lets programs read one cell past the end of their allocations.
+        let result = self.tape.len();
+        self.tape.resize(result + size as usize + 1, 0);
+        result as i64
+    }
+
+    fn getchar(&mut self) -> i64 {
+        self.out.flush().unwrap();
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => byte[0] as i64,
+            _ => -1,
+        }
+    }
+
+    // Read a token of the input which is separated by whitespace.
+    fn read_token(&mut self) -> String {
+        self.out.flush().unwrap();
+        let mut token = String::new();
+        loop {
+            let (done, used) = match self.input.fill_buf() {
+                Ok(buf) if !buf.is_empty() => {
+                    let mut used = 0;
+                    let mut done = false;
+                    for &byte in buf {
+                        if byte.is_ascii_whitespace() {
+                            if !token.is_empty() {
+                                done = true;
+                                break;
+                            }
+                        } else {
+                            token.push(byte as char);
+                        }
+                        used += 1;
+                    }
+                    (done, used)
+                }
+                _ => (true, 0),
+            };
+            self.input.consume(used);
+            if done {
+                return token;
+            }
+        }
+    }
+}
+
+// Reinterpret the bits of a cell as a float.
+fn f(x: i64) -> f64 {
+    f64::from_bits(x as u64)
+}
+
+// Reinterpret the bits of a float as a cell.
+fn b(x: f64) -> i64 {
+    x.to_bits() as i64
+}
+
+"#
+            .to_string(),
+        )
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut funs = funs;
+        funs.sort();
+        let funs = funs.iter().map(|fun| format!("f{fun}")).collect::<Vec<_>>();
+        Some(format!(
+            r#"const FUNS: &[fn(&mut Vm)] = &[{}];
+
+fn main() {{
+    let vm = &mut Vm {{
+        tape: vec![0; {}],
+        refs: vec![],
+        ffi: vec![],
+        ptr: 0,
+        reg: 0,
+        input: io::stdin().lock(),
+        out: BufWriter::new(io::stdout().lock()),
+    }};
+"#,
+            funs.join(", "),
+            self.tape_size
+        ))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("vm.out.flush().unwrap();\n}\n".to_string())
+    }
+}
+
+impl CompiledTarget for Rust {}
@@ -1,30 +1,55 @@
-//! # C Target
+//! # SageOS Target
 //!
-//! An implementation of the virtual machine for the C language.
+//! An implementation of the virtual machine for SageOS, written in C.
 //!
-//! This allows the virtual machine to target C programs.
+//! This allows the virtual machine to target C programs running on SageOS.
 //!
 //! ## Portability
 //!
-//! Right now, this target only supports GCC due to a quirk
-//! with the way this implementation compiles functions.
-//! For some reason, Clang doesn't like nested functions,
-//! even though the function's addresses can still be known
-//! as labels at compile time. I'm really not sure why Clang
-//! *chooses* not to compile nested functions. This can be
-//! fixed by this implementations by just moving function definitions
-//! code outside of the `main` function, since the virtual machine
-//! does not depend on defining functions at runtime.
+//! Every function is defined at the top level as `void fN()`, before `main`,
+//! and `main` fills in the function table with `funs[N] = fN;`. The virtual
+//! machine never defines functions at runtime, so no nested functions are
+//! needed, and the output compiles with both GCC and Clang.
+//!
+//! ## Runtime
+//!
+//! The generated code is freestanding, and includes `<sage.h>` for the OS's runtime.
+//! The header must declare the console primitives used for character I/O,
+//! which are `getchar` and `putchar` unless configured otherwise, along with `printf`,
+//! `memcpy`, `memset`, and `exit`. The foreign functions for the rest of the OS call
+//! `get_time`, `get_pid`, `next_pid`, `spawn_process`, `get_env`, `put_env`,
+//! `pid_get_env`, `pid_put_env`, `path_exists`, `path_is_dir`, `path_is_file`,
+//! `path_list_dir`, `get_keyboard_event`, `get_tablet_event`, `screen_draw_rect`,
+//! `screen_flush`, and `screen_get_dims`, so those must be stubbed out if they're unused.
+//!
+//! By default a `main` function is generated. A kernel can configure an entry
+//! symbol instead, which is generated as a `void` function taking no arguments.
 use super::{Architecture, CompiledTarget};
 use crate::{
     side_effects::{Input, InputMode, Output, OutputMode},
     vm::{CoreOp, StandardOp},
 };
 
-/// The type for the C target which implements the `Target` trait.
-/// This allows the compiler to target the C language.
-#[derive(Default)]
-pub struct SageOS;
+/// The type for the SageOS target which implements the `Target` trait.
+/// This allows the compiler to target C programs running on SageOS.
+pub struct SageOS {
+    /// The console function which reads a character.
+    pub getchar: String,
+    /// The console function which writes a character.
+    pub putchar: String,
+    /// The symbol for the entry point, if a `main` function should not be generated.
+    pub entry: Option<String>,
+}
+
+impl Default for SageOS {
+    fn default() -> Self {
+        Self {
+            getchar: "getchar".to_string(),
+            putchar: "putchar".to_string(),
+            entry: None,
+        }
+    }
+}
 
 impl Architecture for SageOS {
     fn supports_input(&self, i: &Input) -> bool {
@@ -142,7 +167,7 @@ impl Architecture for SageOS {
 
     fn put(&mut self, dst: &Output) -> Result<String, String> {
         match dst.mode {
-            OutputMode::StdoutChar => Ok("putchar(reg.i);".to_string()),
+            OutputMode::StdoutChar => Ok(format!("{}(reg.i);", self.putchar)),
             OutputMode::StdoutInt => Ok("putint(reg.i, 10);".to_string()),
             OutputMode::StdoutFloat => Ok("putfloat(reg.f);".to_string()),
             OutputMode::StderrChar => Ok("fprintf(stderr, \"%c\", reg.i);".to_string()),
@@ -184,7 +209,7 @@ impl Architecture for SageOS {
         "#
                 .to_string();
             */
-        let result = r#"#include <stdio.h>
+        let mut result = r#"#include <stdio.h>
 #include <string.h>
 #include <stdint.h>
 #include <ctype.h>
@@ -934,6 +959,20 @@ void __get_tablet_event() {
 "#
         .to_string();
 
+        // Route the runtime's console I/O through the configured primitives.
+        let mut console = String::new();
+        if self.getchar != "getchar" {
+            console += &format!("#define getchar {}\n", self.getchar);
+        }
+        if self.putchar != "putchar" {
+            console += &format!("#define putchar {}\n", self.putchar);
+        }
+        result = result.replacen(
+            "#include <sage.h>\n",
+            &format!("#include <sage.h>\n{console}"),
+            1,
+        );
+
         // if !is_core {
         //     result = "#include <stdlib.h>\n".to_string() + &result;
         // }
@@ -942,9 +981,11 @@ void __get_tablet_event() {
     }
 
     fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
-        let mut result = String::from(
-            r#"int main () {
-    uint8_t buf[0x800000] = {0};
+        let mut result = match &self.entry {
+            Some(entry) => format!("void {entry}(void) {{\n"),
+            None => "int main () {\n".to_string(),
+        };
+        result += r#"    uint8_t buf[0x800000] = {0};
     salloc_init(buf, buf + sizeof(buf));
 
     funs = (void(**)(void))salloc(200 * sizeof(void*));
@@ -958,8 +999,7 @@ void __get_tablet_event() {
     ref = (cell**)refs;
     reg.i = 0;
     ffi_ptr = ffi_channel;
-"#,
-        );
+"#;
         for fun in funs {
             result += &format!("\tfuns[{fun}] = f{fun};\n", fun = fun)
         }
@@ -971,7 +1011,10 @@ void __get_tablet_event() {
     }
 
     fn postlude(&self, _is_core: bool) -> Option<String> {
-        Some("return 0;\n}".to_string())
+        match self.entry {
+            Some(_) => Some("return;\n}".to_string()),
+            None => Some("return 0;\n}".to_string()),
+        }
     }
 }
 
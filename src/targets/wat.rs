@@ -0,0 +1,429 @@
+//! # WebAssembly Text Target
+//!
+//! An implementation of the virtual machine for WebAssembly, in the text format.
+//!
+//! This allows the virtual machine to target the browser (or any other WebAssembly host).
+//!
+//! ## Memory Model
+//!
+//! The tape is stored at the start of linear memory, with each cell taking 8 bytes.
+//! The ref stack, the FFI channel, and the heap used by `Alloc` follow the tape.
+//! The register, `ptr`, and `ref` are globals, since they're shared across every function.
+//! Every function is defined at the top level as `$fN`, and `Call` uses `call_indirect`
+//! on a table of all the functions.
+//!
+//! ## Host Interface
+//!
+//! The module exports its `memory` and a `main` function to run the program.
+//! I/O goes through functions imported from `env`: `getchar` (returning an `i32`,
+//! or `-1` on EOF), `putchar` (taking an `i32`), `putint` (taking an `i64`), and
+//! `putfloat` (taking an `f64`). Standard programs also import the math functions
+//! `sin`, `cos`, `tan`, `asin`, `acos`, `atan`, and `pow` from `env`, which
+//! can simply be the ones from JavaScript's `Math` object.
+//!
+//! ## WASI
+//!
+//! With `wasi` enabled, the module is a WASI command instead, which can be run
+//! with `wasmtime out.wat`. It exports `main` as `_start`, and implements the I/O
+//! functions itself with `fd_read` and `fd_write` from `wasi_snapshot_preview1`,
+//! using a small buffer after the FFI channel. Integers are printed in decimal,
+//! and floats with six decimal places like C's `%lf`. WASI has no math functions,
+//! so the trigonometric functions and `Pow` aren't supported in this mode.
+use super::{Architecture, CompiledTarget};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The type for the WebAssembly text target which implements the `Target` trait.
+/// This allows the compiler to target WebAssembly.
+pub struct Wat {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// The number of pointers on the stack used by `Deref` and `Refer`.
+    pub ref_stack_size: usize,
+    /// Whether to build a WASI command which does its own I/O,
+    /// instead of importing the I/O functions from the host.
+    pub wasi: bool,
+}
+
+impl Default for Wat {
+    fn default() -> Self {
+        Self {
+            tape_size: 200000,
+            ref_stack_size: 1024,
+            wasi: false,
+        }
+    }
+}
+
+/// The number of cells in the FFI channel.
+const FFI_CHANNEL_SIZE: usize = 256;
+/// The number of bytes used by the WASI I/O functions: an `iovec`,
+/// the number of bytes read or written, and a buffer for printing numbers.
+const IO_SIZE: usize = 64;
+
+impl Wat {
+    /// The address of the bottom of the ref stack.
+    fn refs_start(&self) -> usize {
+        self.tape_size * 8
+    }
+
+    /// The address of the bottom of the FFI channel.
+    fn ffi_channel_start(&self) -> usize {
+        self.refs_start() + self.ref_stack_size * 4
+    }
+
+    /// The address of the memory used by the WASI I/O functions,
+    /// after the FFI channel and the spare cell at its end.
+    fn io_start(&self) -> usize {
+        self.ffi_channel_start() + (FFI_CHANNEL_SIZE + 1) * 8
+    }
+
+    /// The address of the start of the heap.
+    fn heap_start(&self) -> usize {
+        self.io_start() + IO_SIZE
+    }
+
+    /// The I/O functions for a WASI command, implemented with `fd_read` and `fd_write`.
+    fn wasi_io(&self) -> String {
+        let iov = self.io_start();
+        let count = iov + 8;
+        let buf = iov + 16;
+        let buf_end = self.heap_start();
+        format!(
+            r#"(func $write (param $start i32) (param $len i32)
+	i32.const {iov} local.get $start i32.store
+	i32.const {iov} local.get $len i32.store offset=4
+	i32.const 1 i32.const {iov} i32.const 1 i32.const {count} call $fd_write drop
+)
+(func $getchar (result i32)
+	i32.const {iov} i32.const {buf} i32.store
+	i32.const {iov} i32.const 1 i32.store offset=4
+	i32.const 0 i32.const {iov} i32.const 1 i32.const {count} call $fd_read
+	if (result i32) i32.const -1 else
+		i32.const {count} i32.load
+		if (result i32) i32.const {buf} i32.load8_u else i32.const -1 end
+	end
+)
+(func $putchar (param $c i32)
+	i32.const {buf} local.get $c i32.store8
+	i32.const {buf} i32.const 1 call $write
+)
+(func $putdigits (param $n i64) (param $width i32)
+	(local $i i32)
+	i32.const {buf_end} local.set $i
+	loop
+		local.get $i i32.const 1 i32.sub local.tee $i
+		local.get $n i64.const 10 i64.rem_u i64.const 48 i64.add i64.store8
+		local.get $n i64.const 10 i64.div_u local.set $n
+		local.get $width i32.const 1 i32.sub local.set $width
+		local.get $n i64.eqz i32.eqz local.get $width i32.const 0 i32.gt_s i32.or br_if 0
+	end
+	local.get $i i32.const {buf_end} local.get $i i32.sub call $write
+)
+(func $putint (param $n i64)
+	local.get $n i64.const 0 i64.lt_s if
+		i32.const 45 call $putchar
+		i64.const 0 local.get $n i64.sub local.set $n
+	end
+	local.get $n i32.const 1 call $putdigits
+)
+(func $putfloat (param $x f64)
+	(local $int f64) (local $frac i64)
+	local.get $x local.get $x f64.ne if
+		i32.const 110 call $putchar i32.const 97 call $putchar i32.const 110 call $putchar
+		return
+	end
+	local.get $x i64.reinterpret_f64 i64.const 0 i64.lt_s if
+		i32.const 45 call $putchar
+		local.get $x f64.neg local.set $x
+	end
+	local.get $x f64.const inf f64.eq if
+		i32.const 105 call $putchar i32.const 110 call $putchar i32.const 102 call $putchar
+		return
+	end
+	local.get $x f64.trunc local.set $int
+	local.get $x local.get $int f64.sub f64.const 1000000 f64.mul f64.nearest i64.trunc_sat_f64_u local.set $frac
+	local.get $frac i64.const 1000000 i64.eq if
+		local.get $int f64.const 1 f64.add local.set $int
+		i64.const 0 local.set $frac
+	end
+	local.get $int i64.trunc_sat_f64_u i32.const 1 call $putdigits
+	i32.const 46 call $putchar
+	local.get $frac i32.const 6 call $putdigits
+)
+"#
+        )
+    }
+
+    /// Apply a binary float operation to the register and the cell under the pointer.
+    fn float_binop(op: &str) -> String {
+        format!(
+            "global.get $reg f64.reinterpret_i64 global.get $ptr f64.load {op} i64.reinterpret_f64 global.set $reg"
+        )
+    }
+
+    /// Apply an imported unary float function to the register.
+    fn float_fn(name: &str) -> String {
+        format!(
+            "global.get $reg f64.reinterpret_i64 call ${name} i64.reinterpret_f64 global.set $reg"
+        )
+    }
+}
+
+impl Architecture for Wat {
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(i.mode, InputMode::StdinChar)
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!(";; {}", text.replace('\n', "\n;; ").replace('\r', ""))
+            }
+            CoreOp::While => "block loop global.get $reg i64.eqz br_if 1".to_string(),
+            CoreOp::If => "global.get $reg i64.eqz i32.eqz if".to_string(),
+            CoreOp::Else => "else".to_string(),
+            CoreOp::Set(n) => format!("i64.const {n} global.set $reg"),
+            CoreOp::Call => {
+                "global.get $reg i32.wrap_i64 call_indirect (type $fun)".to_string()
+            }
+            CoreOp::Return => "return".to_string(),
+            CoreOp::Save => "global.get $ptr global.get $reg i64.store".to_string(),
+            CoreOp::Restore => "global.get $ptr i64.load global.set $reg".to_string(),
+            CoreOp::Move(n) => format!(
+                "global.get $ptr i32.const {} i32.add global.set $ptr",
+                n * 8
+            ),
+            CoreOp::Where => "global.get $ptr i64.extend_i32_u global.set $reg".to_string(),
+            CoreOp::Deref => "global.get $ref global.get $ptr i32.store global.get $ref i32.const 4 i32.add global.set $ref global.get $ptr i64.load i32.wrap_i64 global.set $ptr".to_string(),
+            CoreOp::Refer => "global.get $ref i32.const 4 i32.sub global.set $ref global.get $ref i32.load global.set $ptr".to_string(),
+            CoreOp::Index => "global.get $reg global.get $ptr i64.load i64.const 8 i64.mul i64.add global.set $reg".to_string(),
+            CoreOp::BitwiseNand => "global.get $reg global.get $ptr i64.load i64.and i64.const -1 i64.xor global.set $reg".to_string(),
+            CoreOp::Add => "global.get $reg global.get $ptr i64.load i64.add global.set $reg".to_string(),
+            CoreOp::Sub => "global.get $reg global.get $ptr i64.load i64.sub global.set $reg".to_string(),
+            CoreOp::Mul => "global.get $reg global.get $ptr i64.load i64.mul global.set $reg".to_string(),
+            CoreOp::Div => "global.get $reg global.get $ptr i64.load i64.div_s global.set $reg".to_string(),
+            CoreOp::Rem => "global.get $reg global.get $ptr i64.load i64.rem_s global.set $reg".to_string(),
+            CoreOp::IsNonNegative => {
+                "global.get $reg i64.const 0 i64.ge_s i64.extend_i32_u global.set $reg".to_string()
+            }
+            _ => unreachable!("Invalid op for Wat target {op:?}"),
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Sin
+            | StandardOp::Cos
+            | StandardOp::Tan
+            | StandardOp::ASin
+            | StandardOp::ACos
+            | StandardOp::ATan
+            | StandardOp::Pow
+                if self.wasi =>
+            {
+                return Err(format!(
+                    "`{op}` is not supported by the Wat target with WASI"
+                ))
+            }
+            StandardOp::Call(ffi) => {
+                return Err(format!(
+                    "Foreign function {ffi} is not supported by the Wat target"
+                ))
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) => {
+                let n = if n.is_nan() {
+                    "nan".to_string()
+                } else if n.is_infinite() {
+                    if *n > 0.0 { "inf" } else { "-inf" }.to_string()
+                } else {
+                    format!("{n:?}")
+                };
+                format!("f64.const {n} i64.reinterpret_f64 global.set $reg")
+            }
+            StandardOp::ToInt => {
+                "global.get $reg f64.reinterpret_i64 i64.trunc_sat_f64_s global.set $reg"
+                    .to_string()
+            }
+            StandardOp::ToFloat => {
+                "global.get $reg f64.convert_i64_s i64.reinterpret_f64 global.set $reg"
+                    .to_string()
+            }
+            StandardOp::ACos => Self::float_fn("acos"),
+            StandardOp::ASin => Self::float_fn("asin"),
+            StandardOp::ATan => Self::float_fn("atan"),
+            StandardOp::Sin => Self::float_fn("sin"),
+            StandardOp::Cos => Self::float_fn("cos"),
+            StandardOp::Tan => Self::float_fn("tan"),
+            StandardOp::Add => Self::float_binop("f64.add"),
+            StandardOp::Sub => Self::float_binop("f64.sub"),
+            StandardOp::Mul => Self::float_binop("f64.mul"),
+            StandardOp::Div => Self::float_binop("f64.div"),
+            // WebAssembly has no float remainder, so compute `a - trunc(a / b) * b`.
+            StandardOp::Rem => "global.get $reg f64.reinterpret_i64 global.get $reg f64.reinterpret_i64 global.get $ptr f64.load f64.div f64.trunc global.get $ptr f64.load f64.mul f64.sub i64.reinterpret_f64 global.set $reg".to_string(),
+            StandardOp::Pow => Self::float_binop("call $pow"),
+            StandardOp::IsNonNegative => "global.get $reg f64.reinterpret_i64 f64.const 0 f64.ge i64.extend_i32_u global.set $reg".to_string(),
+            StandardOp::Alloc => "global.get $reg call $alloc global.set $reg".to_string(),
+            // The heap is a bump allocator, so freeing memory does nothing.
+            StandardOp::Free => "nop".to_string(),
+            _ => return Err(format!("Invalid standard op for Wat target {op:?}")),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        match (matching, fun) {
+            (CoreOp::Function, _) => ")".to_string(),
+            (CoreOp::While, _) => "br 0 end end".to_string(),
+            (CoreOp::If | CoreOp::Else, _) => "end".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("(func $f{label_id}")
+    }
+
+    fn name(&self) -> &str {
+        "Wat"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => {
+                Ok("call $getchar i64.extend_i32_s global.set $reg".to_string())
+            }
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        match dst.mode {
+            OutputMode::StdoutChar => Ok("global.get $reg i32.wrap_i64 call $putchar".to_string()),
+            OutputMode::StdoutInt => Ok("global.get $reg call $putint".to_string()),
+            OutputMode::StdoutFloat => {
+                Ok("global.get $reg f64.reinterpret_i64 call $putfloat".to_string())
+            }
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    /// Peek a value from the FFI channel. This uses the same convention as the C target:
+    /// `ffi_ptr` points to the last cell written, and `Peek` pops it into the register.
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("global.get $ffi_ptr i64.load global.set $reg global.get $ffi_ptr i32.const 8 i32.sub global.set $ffi_ptr".to_string())
+    }
+    /// Poke a value to the FFI channel. See `peek` for the calling convention.
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("global.get $ffi_ptr i32.const 8 i32.add global.set $ffi_ptr global.get $ffi_ptr global.get $reg i64.store".to_string())
+    }
+
+    fn prelude(&self, is_core: bool) -> Option<String> {
+        // Leave a spare page for the heap, and the cells on either side of the FFI channel.
+        let pages = self.heap_start() / 65536 + 2;
+        let mut result = if self.wasi {
+            r#"(module
+(import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+(import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+"#
+        } else {
+            r#"(module
+(import "env" "getchar" (func $getchar (result i32)))
+(import "env" "putchar" (func $putchar (param i32)))
+(import "env" "putint" (func $putint (param i64)))
+(import "env" "putfloat" (func $putfloat (param f64)))
+"#
+        }
+        .to_string();
+
+        if !is_core && !self.wasi {
+            for name in ["sin", "cos", "tan", "asin", "acos", "atan"] {
+                result += &format!(
+                    "(import \"env\" \"{name}\" (func ${name} (param f64) (result f64)))\n"
+                );
+            }
+            result += "(import \"env\" \"pow\" (func $pow (param f64 f64) (result f64)))\n";
+        }
+
+        result += &format!(
+            r#"(memory (export "memory") {pages})
+(type $fun (func))
+(global $reg (mut i64) (i64.const 0))
+(global $ptr (mut i32) (i32.const 0))
+(global $ref (mut i32) (i32.const {refs}))
+(global $ffi_ptr (mut i32) (i32.const {ffi_channel}))
+"#,
+            refs = self.refs_start(),
+            ffi_channel = self.ffi_channel_start(),
+        );
+
+        if self.wasi {
+            result += &self.wasi_io();
+        }
+
+        if !is_core {
+            // A bump allocator for `Alloc`, which grows the memory as needed.
+            result += &format!(
+                r#"(global $heap (mut i32) (i32.const {heap}))
+(func $alloc (param $size i64) (result i64)
+	(local $result i32)
+	global.get $heap local.set $result
+	global.get $heap local.get $size i32.wrap_i64 i32.const 8 i32.mul i32.add global.set $heap
+	block loop
+		global.get $heap memory.size i32.const 65536 i32.mul i32.le_u br_if 1
+		i32.const 1 memory.grow drop br 0
+	end end
+	local.get $result i64.extend_i32_u
+)
+"#,
+                heap = self.heap_start()
+            );
+        }
+
+        Some(result)
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut funs = funs;
+        funs.sort();
+        let mut result = format!("(table {} funcref)\n", funs.len());
+        if !funs.is_empty() {
+            result += "(elem (i32.const 0)";
+            for fun in funs {
+                result += &format!(" $f{fun}");
+            }
+            result += ")\n";
+        }
+        // A WASI command is started by calling `_start`.
+        let export = if self.wasi { "_start" } else { "main" };
+        result += &format!("(func $main (export \"{export}\")\n");
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some(")\n)\n".to_string())
+    }
+}
+
+impl CompiledTarget for Wat {}
@@ -1,8 +1,30 @@
 //! # x86 Target
 //!
-//! An implementation of the virtual machine for x86.
+//! An implementation of the virtual machine for x86-64.
 //!
-//! This allows the virtual machine to target the x86 CPU architecture.
+//! This allows the virtual machine to target the 64-bit x86 (AMD64) CPU architecture,
+//! generating GNU assembly with 8-byte cells. There is no 32-bit x86 target, so
+//! `-t x86-64` is another name for `-t x86`.
+//!
+//! ## Input and Output
+//!
+//! Characters are read and written by calling external functions with the C calling
+//! convention, which are libc's `getchar` and `putchar` by default. The names of these
+//! functions can be changed to link the program against a different runtime.
+//!
+//! ## Position Independence
+//!
+//! By default, the generated code refers to the function table and the format strings
+//! by their absolute addresses, so it must be linked as a position dependent executable:
+//!
+//! ```bash
+//! cc -no-pie out.s -o out
+//! ```
+//!
+//! With `pic` set, they're addressed relative to `%rip` instead, and libc is called
+//! through the PLT, so the output links on toolchains which build position independent
+//! executables by default, like `cc out.s -o out`, or `as out.s -o out.o` followed by
+//! linking `out.o` with `cc -pie`.
 use super::{Architecture, CompiledTarget};
 use crate::{
     side_effects::{Input, InputMode, Output, OutputMode},
@@ -10,15 +32,123 @@ use crate::{
 };
 
 /// The type for the x86 target which implements the `Target` trait.
-/// This allows the compiler to target the C language.
-#[derive(Default)]
+/// This allows the compiler to target the x86-64 CPU architecture.
 pub struct X86 {
-    branch_count: usize,
+    /// The function called to read a character, which returns it like `getchar`.
+    pub getchar_sym: String,
+    /// The function called to write the character passed to it, like `putchar`.
+    pub putchar_sym: String,
+    /// Emit position independent code, which can be linked into a PIE.
+    pub pic: bool,
     fun_count: usize,
-    branch_match: Vec<String>,
+    control_flow: ControlFlow,
     float_defs: Vec<f64>,
 }
 
+impl Default for X86 {
+    fn default() -> Self {
+        Self {
+            getchar_sym: "getchar".to_string(),
+            putchar_sym: "putchar".to_string(),
+            pic: false,
+            fun_count: 0,
+            control_flow: ControlFlow::new("jmp"),
+            float_defs: vec![],
+        }
+    }
+}
+
+/// The lowering of `While`, `If`, and `Else` into labels and jumps,
+/// shared by the x86 and AArch64 targets.
+pub(super) struct ControlFlow {
+    /// The instruction which jumps unconditionally to a label.
+    jump: &'static str,
+    branch_count: usize,
+    branch_match: Vec<String>,
+}
+
+impl ControlFlow {
+    /// Create the control flow lowering for an instruction set,
+    /// which uses `jump` to jump unconditionally to a label.
+    pub(super) fn new(jump: &'static str) -> Self {
+        Self {
+            jump,
+            branch_count: 0,
+            branch_match: vec![],
+        }
+    }
+
+    /// Lower a `While` instruction. `branch_if_zero` is the code which jumps
+    /// to the label following it when the register is zero.
+    pub(super) fn begin_while(&mut self, branch_if_zero: &str, indent: &str) -> String {
+        let n = self.branch_count;
+        self.branch_count += 1;
+        self.branch_match
+            .push(format!("{} while{n}\nwhile_end{n}", self.jump));
+        format!("while{n}:\n{indent}{branch_if_zero} while_end{n}")
+    }
+
+    /// Lower an `If` instruction. `branch_if_zero` is the code which jumps
+    /// to the label following it when the register is zero.
+    pub(super) fn begin_if(&mut self, branch_if_zero: &str) -> String {
+        let n = self.branch_count;
+        self.branch_count += 1;
+        self.branch_match.push(format!("if_end{n}"));
+        format!("{branch_if_zero} if_end{n}")
+    }
+
+    /// Lower an `Else` instruction.
+    pub(super) fn begin_else(&mut self) -> String {
+        let n = self.branch_count;
+        self.branch_count += 1;
+        let label = self.pop_label();
+        self.branch_match.push(format!("else_end{n}"));
+        format!("{} else_end{n}\n{label}:", self.jump)
+    }
+
+    /// Push a label to be placed by the matching `End` instruction.
+    pub(super) fn push_label(&mut self, label: String) {
+        self.branch_match.push(label);
+    }
+
+    /// Pop the label for the matching `End` instruction.
+    pub(super) fn pop_label(&mut self) -> String {
+        self.branch_match.pop().expect("Unexpected end")
+    }
+}
+
+impl X86 {
+    /// The operand used to call an external function, through the PLT if the code is
+    /// position independent.
+    fn external(&self, function: &str) -> String {
+        if self.pic {
+            format!("{function}@PLT")
+        } else {
+            function.to_string()
+        }
+    }
+
+    /// Load the address of a label into a register.
+    fn load_address(&self, label: &str, register: &str) -> String {
+        if self.pic {
+            format!("leaq {label}(%rip), {register}")
+        } else {
+            format!("movq ${label}, {register}")
+        }
+    }
+
+    /// Load the function at the index in `%rax` from the function table, and call it.
+    fn call_function(&self) -> String {
+        let indent = self.indentation().unwrap_or("    ".to_string());
+        let load = if self.pic {
+            format!("leaq funs(%rip), %rcx\n{indent}movq (%rcx,%rax,8), %rax")
+        } else {
+            "movq funs(,%rax,8), %rax".to_string()
+        };
+        format!("movq reg(%rip), %rax\n{indent}{load}\n{indent}call    *%rax")
+    }
+}
+
 impl Architecture for X86 {
     fn supports_input(&self, i: &Input) -> bool {
         matches!(i.mode, InputMode::StdinChar)
@@ -32,34 +162,18 @@ impl Architecture for X86 {
     }
 
     fn op(&mut self, op: &CoreOp) -> String {
-        let branch_count = self.branch_count;
         let indent = self.indentation().unwrap_or("    ".to_string());
+        let test = format!("movq reg(%rip), %rax\n{indent}testq %rax, %rax\n{indent}je");
         match op {
             CoreOp::Comment(_) => {
                 "".to_string()
             }
-            CoreOp::While => {
-                let result = format!("while{branch_count}:\n{indent}movq reg(%rip), %rax\n{indent}testq %rax, %rax\n{indent}je while_end{branch_count}");
-                self.branch_match.push(format!("jmp while{branch_count}\nwhile_end{branch_count}"));
-                self.branch_count += 1;
-                result
-            }
-            CoreOp::If => {
-                let result = format!("movq reg(%rip), %rax\n{indent}\n{indent}testq   %rax, %rax\n{indent}je if_end{branch_count}");
-                self.branch_match.push(format!("if_end{branch_count}"));
-                self.branch_count += 1;
-                result
-            }
-            CoreOp::Else => {
-                let label = self.branch_match.pop().unwrap();
-                let result = format!("jmp else_end{branch_count}\n{label}:");
-                self.branch_match.push(format!("else_end{branch_count}"));
-                self.branch_count += 1;
-                result
-            }
+            CoreOp::While => self.control_flow.begin_while(&test, &indent),
+            CoreOp::If => self.control_flow.begin_if(&test),
+            CoreOp::Else => self.control_flow.begin_else(),
             CoreOp::Set(n) => format!("movq ${n}, %rax\n{indent}movq %rax, reg(%rip)"),
             CoreOp::Move(n) => format!("movq ptr(%rip), %rax\n{indent}addq ${}, %rax\n{indent}movq %rax, ptr(%rip)\n", n * 8),
-            CoreOp::Call => format!("movq reg(%rip), %rax\n{indent}movq funs(,%rax,8), %rax\n{indent}call    *%rax"),
+            CoreOp::Call => self.call_function(),
             CoreOp::Return => format!("popq %rbp\n{indent}ret"),
             CoreOp::Save => format!("movq ptr(%rip), %rax\n{indent}movq reg(%rip), %rdx\n{indent}movq %rdx, (%rax)"),
             CoreOp::Restore => format!("movq ptr(%rip), %rax\n{indent}movq (%rax), %rdx\n{indent}movq %rdx, reg(%rip)"),
@@ -99,8 +213,8 @@ impl Architecture for X86 {
     }
 
     fn std_op(&mut self, std_op: &StandardOp) -> Result<String, String> {
-        let branch_count = self.branch_count;
         let indent = self.indentation().unwrap_or("    ".to_string());
+        let test = format!("movq reg(%rip), %rax\n{indent}testq %rax, %rax\n{indent}je");
         Ok(match std_op {
             StandardOp::Set(n) => {
                 self.float_defs.push(*n);
@@ -111,40 +225,24 @@ impl Architecture for X86 {
 {indent}movq %rax, %rbx     # Move the value in rax to rbx for multiplication
 {indent}leaq (%rbx,%rbx,8), %rbx    # Multiply by 8 to get the number of bytes needed
 {indent}movq %rbx, %rdi      # Pass the number of bytes to allocate to rdi
-{indent}call malloc          # Allocate memory on the heap
+{indent}call {malloc}          # Allocate memory on the heap
 {indent}movq %rax, %rax      # Store the pointer to the allocated memory in rax
 {indent}movq %rax, reg(%rip)
-", indent = self.indentation().unwrap_or("    ".to_string()))
+", indent = self.indentation().unwrap_or("    ".to_string()), malloc = self.external("malloc"))
             }
             StandardOp::Free => {
-                "movq reg(%rip), %rdi\ncall free".to_string()
+                format!("movq reg(%rip), %rdi\ncall {}", self.external("free"))
             }
             StandardOp::CoreOp(op) => match op {
                 CoreOp::Comment(_) => {
                     "".to_string()
                 }
-                CoreOp::While => {
-                let result = format!("while{branch_count}:\n{indent}movq reg(%rip), %rax\n{indent}testq %rax, %rax\n{indent}je while_end{branch_count}");
-                    self.branch_match.push(format!("jmp while{branch_count}\nwhile_end{branch_count}"));
-                    self.branch_count += 1;
-                    result
-                }
-                CoreOp::If => {
-                let result = format!("movq reg(%rip), %rax\n{indent}\n{indent}testq   %rax, %rax\n{indent}je if_end{branch_count}");
-                    self.branch_match.push(format!("if_end{branch_count}"));
-                    self.branch_count += 1;
-                    result
-                }
-                CoreOp::Else => {
-                let label = self.branch_match.pop().unwrap();
-                    let result = format!("jmp else_end{branch_count}\n{label}:");
-                    self.branch_match.push(format!("else_end{branch_count}"));
-                    self.branch_count += 1;
-                    result
-                }
+                CoreOp::While => self.control_flow.begin_while(&test, &indent),
+                CoreOp::If => self.control_flow.begin_if(&test),
+                CoreOp::Else => self.control_flow.begin_else(),
                 CoreOp::Set(n) => format!("movq ${n}, reg(%rip)"),
                 CoreOp::Move(n) => format!("movq ptr(%rip), %rax\n{indent}addq ${}, %rax\n{indent}movq %rax, ptr(%rip)\n", n * 8),
-                CoreOp::Call => format!("movq reg(%rip), %rax\n{indent}movq funs(,%rax,8), %rax\n{indent}call    *%rax"),
+                CoreOp::Call => self.call_function(),
                 CoreOp::Return => format!("popq %rbp\n{indent}ret"),
                 CoreOp::Save => format!("movq ptr(%rip), %rax\n{indent}movq reg(%rip), %rdx\n{indent}movq %rdx, (%rax)"),
                 CoreOp::Restore => format!("movq ptr(%rip), %rax\n{indent}movq (%rax), %rdx\n{indent}movq %rdx, reg(%rip)"),
@@ -188,7 +286,7 @@ impl Architecture for X86 {
     fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
         match matching {
             CoreOp::Function => {
-                let label = self.branch_match.pop().unwrap();
+                let label = self.control_flow.pop_label();
                 format!(
                     "popq %rbp\n{indent}ret\n{label}:\n",
                     indent = self.indentation().unwrap(),
@@ -196,7 +294,7 @@ impl Architecture for X86 {
                 )
             }
             CoreOp::While | CoreOp::If | CoreOp::Else => {
-                let label = self.branch_match.pop().unwrap();
+                let label = self.control_flow.pop_label();
                 format!("{label}:")
             }
             otherwise => panic!("Unexpected end: {:?}", otherwise),
@@ -204,9 +302,24 @@ impl Architecture for X86 {
     }
 
     fn declare_proc(&mut self, _label_id: usize) -> String {
-        let result = format!("movq $fun{fun_count}, funs+{}(%rip)\n{indent}jmp fun_end{fun_count}\nfun{fun_count}:\n{indent}pushq   %rbp\n{indent}movq %rsp, %rbp\n", self.fun_count * 8, fun_count = self.fun_count, indent = self.indentation().unwrap());
-        self.branch_match
-            .push(format!("fun_end{fun_count}", fun_count = self.fun_count));
+        let indent = self.indentation().unwrap();
+        // Store the address of the function in the function table.
+        let store = if self.pic {
+            format!(
+                "leaq fun{fun_count}(%rip), %rax\n{indent}movq %rax, funs+{}(%rip)",
+                self.fun_count * 8,
+                fun_count = self.fun_count
+            )
+        } else {
+            format!(
+                "movq $fun{fun_count}, funs+{}(%rip)",
+                self.fun_count * 8,
+                fun_count = self.fun_count
+            )
+        };
+        let result = format!("{store}\n{indent}jmp fun_end{fun_count}\nfun{fun_count}:\n{indent}pushq   %rbp\n{indent}movq %rsp, %rbp\n", fun_count = self.fun_count);
+        self.control_flow
+            .push_label(format!("fun_end{fun_count}", fun_count = self.fun_count));
         self.fun_count += 1;
         result
     }
@@ -226,7 +339,10 @@ impl Architecture for X86 {
         let ch = src.channel.0;
         let indent = self.indentation().unwrap();
         if src.mode == InputMode::StdinChar && ch == 0 {
-            Ok(format!("call getchar\n{indent}movq %rdi, %rax"))
+            Ok(format!(
+                "call {}\n{indent}cltq\n{indent}movq %rax, reg(%rip)",
+                self.external(&self.getchar_sym)
+            ))
         } else {
             Err("Output not supported by this target".to_string())
         }
@@ -235,27 +351,32 @@ impl Architecture for X86 {
         let indent = self.indentation().unwrap();
         match dst.mode {
             OutputMode::StdoutChar => Ok(format!(
-                "movq reg(%rip), %rax\n{indent}movl %eax, %edi\n{indent}call    putchar"
+                "movq reg(%rip), %rax\n{indent}movl %eax, %edi\n{indent}call    {}",
+                self.external(&self.putchar_sym)
             )),
             // OutputMode::StdoutInt => Ok(format!("movq reg(%rip), %rax\n{indent}movq %rax, %xmm0\n{indent}movl $int_print, %edi\n{indent}movl $1, %eax\n{indent}call    printf", indent = self.indentation().unwrap())),
             OutputMode::StdoutInt => Ok(format!(
                 "{indent}movq reg(%rip), %rax            # Load the integer value into rax
 {indent}movq %rax, %rsi
-{indent}movq $int_print, %rdi           # Load the address of the format string
+{indent}{load_format}           # Load the address of the format string
 {indent}movq $0, %rax                   # Clear rax (return value)
-{indent}call printf                     # Call the printf function",
-                indent = self.indentation().unwrap()
+{indent}call {printf}                     # Call the printf function",
+                indent = self.indentation().unwrap(),
+                load_format = self.load_address("int_print", "%rdi"),
+                printf = self.external("printf")
             )),
             OutputMode::StdoutFloat => Ok(format!(
                 "{indent}movq reg(%rip), %xmm0            # Load the integer value into rax
 subq $8, %rsp                   # Allocate space on the stack for the float argument
 movsd %xmm0, (%rsp)   # Move the double value to the stack
 movq $1, %rax       # File descriptor 1 represents standard output
-movq $float_print, %rdi      # Load the address of the format string into RDI
+{load_format}      # Load the address of the format string into RDI
 movq (%rsp), %xmm0  # Load the double value into XMM0
-call printf        # Call printf function
+call {printf}        # Call printf function
 addq $8, %rsp       # Deallocate the space on the stack",
-                indent = self.indentation().unwrap()
+                indent = self.indentation().unwrap(),
+                load_format = self.load_address("float_print", "%rdi"),
+                printf = self.external("printf")
             )),
             _ => Err("Output not supported by this target".to_string()),
         }
@@ -309,6 +430,10 @@ int_print:
         for (i, val) in self.float_defs.iter().enumerate() {
             data += format!("float_const{i}: .quad {val}\n").as_str();
         }
+        if self.pic {
+            // Mark the stack as non-executable, which linkers for PIEs warn about otherwise.
+            data += ".section .note.GNU-stack,\"\",@progbits\n";
+        }
         Some(format!(
             "{indent}movl $0, %eax\n{indent}ret\n{data}",
             indent = self.indentation().unwrap()
@@ -0,0 +1,526 @@
+//! A compact binary encoding of virtual machine programs.
+//!
+//! This lets programs be saved and reloaded without re-parsing the textual form.
+//!
+//! ## Format
+//!
+//! Bytecode starts with the magic bytes `SAGE`, followed by a byte for the format version.
+//! Every instruction after that is an opcode byte followed by its operands:
+//! - Integers are zigzag encoded LEB128 varints, so small values take a single byte.
+//! - Sizes and channels are unsigned LEB128 varints.
+//! - Floats are their 8 little endian IEEE 754 bytes.
+//! - Strings are a size followed by their UTF-8 bytes.
+//!
+//! Core instructions have the same encoding in both variants, so core bytecode
+//! can always be loaded as a standard program.
+use super::{CoreOp, CoreProgram, Error, StandardOp, StandardProgram};
+use crate::side_effects::{
+    Axis, Color, Direction, FFIBinding, Input, InputMode, Output, OutputMode,
+};
+
+/// The magic bytes at the start of every bytecode program.
+const MAGIC: &[u8] = b"SAGE";
+/// The version of the bytecode format.
+const VERSION: u8 = 1;
+
+/// The tag for a custom input or output mode.
+const CUSTOM_MODE: u8 = 0xff;
+/// The tag for `OutputMode::SetCursorChar`, which is followed by a color.
+const SET_CURSOR_CHAR: u8 = 0xfe;
+/// The tag for `OutputMode::SetCursorPixel`, which is followed by a color.
+const SET_CURSOR_PIXEL: u8 = 0xfd;
+/// The tag for an RGB color, which is followed by its three components.
+const RGB_COLOR: u8 = 0xff;
+
+/// The input modes without any custom data, tagged by their index.
+/// New modes must only be added to the end of this list.
+const INPUT_MODES: &[InputMode] = &[
+    InputMode::StdinChar,
+    InputMode::StdinInt,
+    InputMode::StdinFloat,
+    InputMode::Random,
+    InputMode::DPad(Direction::Up),
+    InputMode::DPad(Direction::Down),
+    InputMode::DPad(Direction::Left),
+    InputMode::DPad(Direction::Right),
+    InputMode::Button,
+    InputMode::Keyboard,
+    InputMode::JoyStick(Direction::Up),
+    InputMode::JoyStick(Direction::Down),
+    InputMode::JoyStick(Direction::Left),
+    InputMode::JoyStick(Direction::Right),
+    InputMode::Clock,
+    InputMode::Accelerometer(Axis::X),
+    InputMode::Accelerometer(Axis::Y),
+    InputMode::Accelerometer(Axis::Z),
+    InputMode::Gyroscope(Axis::X),
+    InputMode::Gyroscope(Axis::Y),
+    InputMode::Gyroscope(Axis::Z),
+    InputMode::Microphone,
+    InputMode::RedLight,
+    InputMode::GreenLight,
+    InputMode::BlueLight,
+    InputMode::Brightness,
+    InputMode::Humidity,
+    InputMode::Barometer,
+    InputMode::Magnetometer(Axis::X),
+    InputMode::Magnetometer(Axis::Y),
+    InputMode::Magnetometer(Axis::Z),
+    InputMode::Thermometer,
+    InputMode::RainGauge,
+    InputMode::UVSensor,
+    InputMode::WindSpeed,
+    InputMode::WindDirection,
+    InputMode::PressureGauge,
+    InputMode::FlowSensor,
+    InputMode::VolumeSensor,
+    InputMode::WeightSensor,
+    InputMode::PHSensor,
+    InputMode::ConductivitySensor,
+    InputMode::Speedometer(None),
+    InputMode::Speedometer(Some(Axis::X)),
+    InputMode::Speedometer(Some(Axis::Y)),
+    InputMode::Speedometer(Some(Axis::Z)),
+    InputMode::Odometer,
+    InputMode::Position(Axis::X),
+    InputMode::Position(Axis::Y),
+    InputMode::Position(Axis::Z),
+    InputMode::Compass,
+    InputMode::Proximity,
+    InputMode::Altimeter,
+    InputMode::DepthSensor,
+    InputMode::AnalogPin,
+    InputMode::DigitalPin,
+    InputMode::StdinUtf8,
+];
+
+/// The output modes without any custom data, tagged by their index.
+/// New modes must only be added to the end of this list.
+const OUTPUT_MODES: &[OutputMode] = &[
+    OutputMode::StdoutChar,
+    OutputMode::StdoutInt,
+    OutputMode::StdoutFloat,
+    OutputMode::StderrChar,
+    OutputMode::StderrInt,
+    OutputMode::StderrFloat,
+    OutputMode::PrinterChar,
+    OutputMode::PrinterInt,
+    OutputMode::PrinterFloat,
+    OutputMode::Brightness,
+    OutputMode::AnalogPin,
+    OutputMode::DigitalPin,
+    OutputMode::StepperMotor,
+    OutputMode::Solenoid,
+    OutputMode::Valve,
+    OutputMode::MotorSpeed,
+    OutputMode::Servo,
+    OutputMode::Temperature,
+    OutputMode::Pump,
+    OutputMode::Fan,
+    OutputMode::Blower,
+    OutputMode::Heater,
+    OutputMode::Cooler,
+    OutputMode::Pressure,
+    OutputMode::Buzzer,
+    OutputMode::Bell,
+    OutputMode::Note,
+    OutputMode::SpeakerVolume,
+    OutputMode::SpeakerFrequency,
+    OutputMode::UpdateDisplay,
+    OutputMode::ClearDisplay,
+    OutputMode::SetCursorRow,
+    OutputMode::SetCursorColumn,
+    OutputMode::MoveCursorUp,
+    OutputMode::MoveCursorDown,
+    OutputMode::MoveCursorLeft,
+    OutputMode::MoveCursorRight,
+    OutputMode::StdoutUtf8,
+    OutputMode::StderrUtf8,
+];
+
+/// The named colors, tagged by their index.
+const COLORS: &[Color] = &[
+    Color::Black,
+    Color::White,
+    Color::Red,
+    Color::Green,
+    Color::Blue,
+    Color::Yellow,
+    Color::Cyan,
+    Color::Magenta,
+    Color::Orange,
+];
+
+impl CoreProgram {
+    /// Encode the program as bytecode.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for op in &self.0 {
+            encode_core_op(op, &mut bytes);
+        }
+        bytes
+    }
+
+    /// Decode a program from bytecode. This fails if the bytecode
+    /// contains any standard instructions.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut result = vec![];
+        for op in StandardProgram::from_bytes(bytes)?.0 {
+            match op {
+                StandardOp::CoreOp(op) => result.push(op),
+                op => return Err(Error::ExpectedCore(op)),
+            }
+        }
+        Ok(Self(result))
+    }
+}
+
+impl StandardProgram {
+    /// Encode the program as bytecode.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for op in &self.0 {
+            encode_std_op(op, &mut bytes);
+        }
+        bytes
+    }
+
+    /// Decode a program from bytecode.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut decoder = Decoder { bytes, offset: 0 };
+        if !bytes.starts_with(MAGIC) {
+            return Err(decoder.error("missing magic bytes"));
+        }
+        decoder.offset = MAGIC.len();
+        let version = decoder.byte()?;
+        if version != VERSION {
+            return Err(decoder.error(format!("unsupported version {version}")));
+        }
+
+        let mut result = vec![];
+        while decoder.offset < bytes.len() {
+            result.push(decoder.std_op()?);
+        }
+        Ok(Self(result))
+    }
+}
+
+/// Encode a core instruction.
+fn encode_core_op(op: &CoreOp, bytes: &mut Vec<u8>) {
+    match op {
+        CoreOp::Comment(text) => {
+            bytes.push(0x00);
+            encode_string(text, bytes);
+        }
+        CoreOp::Set(n) => {
+            bytes.push(0x01);
+            encode_signed(*n, bytes);
+        }
+        CoreOp::Function => bytes.push(0x02),
+        CoreOp::Call => bytes.push(0x03),
+        CoreOp::Return => bytes.push(0x04),
+        CoreOp::While => bytes.push(0x05),
+        CoreOp::If => bytes.push(0x06),
+        CoreOp::Else => bytes.push(0x07),
+        CoreOp::End => bytes.push(0x08),
+        CoreOp::Save => bytes.push(0x09),
+        CoreOp::Restore => bytes.push(0x0a),
+        CoreOp::Move(n) => {
+            bytes.push(0x0b);
+            encode_signed(*n as i64, bytes);
+        }
+        CoreOp::Where => bytes.push(0x0c),
+        CoreOp::Deref => bytes.push(0x0d),
+        CoreOp::Refer => bytes.push(0x0e),
+        CoreOp::Index => bytes.push(0x0f),
+        CoreOp::BitwiseNand => bytes.push(0x10),
+        CoreOp::Add => bytes.push(0x11),
+        CoreOp::Sub => bytes.push(0x12),
+        CoreOp::Mul => bytes.push(0x13),
+        CoreOp::Div => bytes.push(0x14),
+        CoreOp::Rem => bytes.push(0x15),
+        CoreOp::IsNonNegative => bytes.push(0x16),
+        CoreOp::Get(input) => {
+            bytes.push(0x17);
+            encode_input(input, bytes);
+        }
+        CoreOp::Put(output) => {
+            bytes.push(0x18);
+            encode_output(output, bytes);
+        }
+    }
+}
+
+/// Encode a standard instruction.
+fn encode_std_op(op: &StandardOp, bytes: &mut Vec<u8>) {
+    match op {
+        StandardOp::CoreOp(op) => encode_core_op(op, bytes),
+        StandardOp::Set(n) => {
+            bytes.push(0x80);
+            bytes.extend(n.to_le_bytes());
+        }
+        StandardOp::Alloc => bytes.push(0x81),
+        StandardOp::Free => bytes.push(0x82),
+        StandardOp::ToInt => bytes.push(0x83),
+        StandardOp::ToFloat => bytes.push(0x84),
+        StandardOp::Add => bytes.push(0x85),
+        StandardOp::Sub => bytes.push(0x86),
+        StandardOp::Mul => bytes.push(0x87),
+        StandardOp::Div => bytes.push(0x88),
+        StandardOp::Rem => bytes.push(0x89),
+        StandardOp::IsNonNegative => bytes.push(0x8a),
+        StandardOp::Sin => bytes.push(0x8b),
+        StandardOp::Cos => bytes.push(0x8c),
+        StandardOp::Tan => bytes.push(0x8d),
+        StandardOp::ASin => bytes.push(0x8e),
+        StandardOp::ACos => bytes.push(0x8f),
+        StandardOp::ATan => bytes.push(0x90),
+        StandardOp::Pow => bytes.push(0x91),
+        StandardOp::Peek => bytes.push(0x92),
+        StandardOp::Poke => bytes.push(0x93),
+        StandardOp::Call(ffi) => {
+            bytes.push(0x94);
+            encode_string(&ffi.name, bytes);
+            encode_unsigned(ffi.input_cells as u64, bytes);
+            encode_unsigned(ffi.output_cells as u64, bytes);
+        }
+    }
+}
+
+/// Encode an input source as its mode followed by its channel.
+fn encode_input(input: &Input, bytes: &mut Vec<u8>) {
+    match &input.mode {
+        InputMode::Custom(name) => {
+            bytes.push(CUSTOM_MODE);
+            encode_string(name, bytes);
+        }
+        mode => bytes.push(
+            INPUT_MODES
+                .iter()
+                .position(|m| m == mode)
+                .expect("every input mode has a tag") as u8,
+        ),
+    }
+    encode_unsigned(input.channel.0 as u64, bytes);
+}
+
+/// Encode an output destination as its mode followed by its channel.
+fn encode_output(output: &Output, bytes: &mut Vec<u8>) {
+    match &output.mode {
+        OutputMode::Custom(name) => {
+            bytes.push(CUSTOM_MODE);
+            encode_string(name, bytes);
+        }
+        OutputMode::SetCursorChar(color) => {
+            bytes.push(SET_CURSOR_CHAR);
+            encode_color(color, bytes);
+        }
+        OutputMode::SetCursorPixel(color) => {
+            bytes.push(SET_CURSOR_PIXEL);
+            encode_color(color, bytes);
+        }
+        mode => bytes.push(
+            OUTPUT_MODES
+                .iter()
+                .position(|m| m == mode)
+                .expect("every output mode has a tag") as u8,
+        ),
+    }
+    encode_unsigned(output.channel.0 as u64, bytes);
+}
+
+/// Encode a color.
+fn encode_color(color: &Color, bytes: &mut Vec<u8>) {
+    match color {
+        Color::RGB(r, g, b) => bytes.extend([RGB_COLOR, *r, *g, *b]),
+        color => bytes.push(
+            COLORS
+                .iter()
+                .position(|c| c == color)
+                .expect("every named color has a tag") as u8,
+        ),
+    }
+}
+
+/// Encode an unsigned integer as a LEB128 varint.
+fn encode_unsigned(mut n: u64, bytes: &mut Vec<u8>) {
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+/// Encode a signed integer as a zigzag encoded LEB128 varint.
+fn encode_signed(n: i64, bytes: &mut Vec<u8>) {
+    encode_unsigned(((n << 1) ^ (n >> 63)) as u64, bytes)
+}
+
+/// Encode a string as its size followed by its UTF-8 bytes.
+fn encode_string(text: &str, bytes: &mut Vec<u8>) {
+    encode_unsigned(text.len() as u64, bytes);
+    bytes.extend(text.as_bytes());
+}
+
+/// Reads instructions from bytecode.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Decoder<'_> {
+    /// Create an error at the current offset in the bytecode.
+    fn error(&self, message: impl ToString) -> Error {
+        Error::InvalidBytecode(self.offset, message.to_string())
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or_else(|| self.error("unexpected end of bytecode"))?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn unsigned(&mut self) -> Result<u64, Error> {
+        let mut result = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(self.error("varint is too long"))
+    }
+
+    fn signed(&mut self) -> Result<i64, Error> {
+        let n = self.unsigned()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn size(&mut self) -> Result<usize, Error> {
+        let n = self.unsigned()?;
+        usize::try_from(n).map_err(|_| self.error(format!("size {n} is too large")))
+    }
+
+    fn float(&mut self) -> Result<f64, Error> {
+        let mut result = [0; 8];
+        for byte in &mut result {
+            *byte = self.byte()?;
+        }
+        Ok(f64::from_le_bytes(result))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let size = self.size()?;
+        let bytes = self
+            .bytes
+            .get(self.offset..)
+            .and_then(|bytes| bytes.get(..size))
+            .ok_or_else(|| self.error("unexpected end of bytecode"))?;
+        let result =
+            String::from_utf8(bytes.to_vec()).map_err(|_| self.error("string is not UTF-8"))?;
+        self.offset += size;
+        Ok(result)
+    }
+
+    fn std_op(&mut self) -> Result<StandardOp, Error> {
+        let opcode = self.byte()?;
+        Ok(match opcode {
+            0x80 => StandardOp::Set(self.float()?),
+            0x81 => StandardOp::Alloc,
+            0x82 => StandardOp::Free,
+            0x83 => StandardOp::ToInt,
+            0x84 => StandardOp::ToFloat,
+            0x85 => StandardOp::Add,
+            0x86 => StandardOp::Sub,
+            0x87 => StandardOp::Mul,
+            0x88 => StandardOp::Div,
+            0x89 => StandardOp::Rem,
+            0x8a => StandardOp::IsNonNegative,
+            0x8b => StandardOp::Sin,
+            0x8c => StandardOp::Cos,
+            0x8d => StandardOp::Tan,
+            0x8e => StandardOp::ASin,
+            0x8f => StandardOp::ACos,
+            0x90 => StandardOp::ATan,
+            0x91 => StandardOp::Pow,
+            0x92 => StandardOp::Peek,
+            0x93 => StandardOp::Poke,
+            0x94 => StandardOp::Call(FFIBinding::new(self.string()?, self.size()?, self.size()?)),
+            _ => StandardOp::CoreOp(self.core_op(opcode)?),
+        })
+    }
+
+    fn core_op(&mut self, opcode: u8) -> Result<CoreOp, Error> {
+        Ok(match opcode {
+            0x00 => CoreOp::Comment(self.string()?),
+            0x01 => CoreOp::Set(self.signed()?),
+            0x02 => CoreOp::Function,
+            0x03 => CoreOp::Call,
+            0x04 => CoreOp::Return,
+            0x05 => CoreOp::While,
+            0x06 => CoreOp::If,
+            0x07 => CoreOp::Else,
+            0x08 => CoreOp::End,
+            0x09 => CoreOp::Save,
+            0x0a => CoreOp::Restore,
+            0x0b => CoreOp::Move(self.signed()? as isize),
+            0x0c => CoreOp::Where,
+            0x0d => CoreOp::Deref,
+            0x0e => CoreOp::Refer,
+            0x0f => CoreOp::Index,
+            0x10 => CoreOp::BitwiseNand,
+            0x11 => CoreOp::Add,
+            0x12 => CoreOp::Sub,
+            0x13 => CoreOp::Mul,
+            0x14 => CoreOp::Div,
+            0x15 => CoreOp::Rem,
+            0x16 => CoreOp::IsNonNegative,
+            0x17 => CoreOp::Get(self.input()?),
+            0x18 => CoreOp::Put(self.output()?),
+            _ => {
+                self.offset -= 1;
+                return Err(self.error(format!("unknown opcode {opcode:#04x}")));
+            }
+        })
+    }
+
+    fn input(&mut self) -> Result<Input, Error> {
+        let mode = match self.byte()? {
+            CUSTOM_MODE => InputMode::Custom(self.string()?),
+            tag => INPUT_MODES
+                .get(tag as usize)
+                .cloned()
+                .ok_or_else(|| self.error(format!("unknown input mode {tag}")))?,
+        };
+        Ok(Input::new(mode, self.size()?))
+    }
+
+    fn output(&mut self) -> Result<Output, Error> {
+        let mode = match self.byte()? {
+            CUSTOM_MODE => OutputMode::Custom(self.string()?),
+            SET_CURSOR_CHAR => OutputMode::SetCursorChar(self.color()?),
+            SET_CURSOR_PIXEL => OutputMode::SetCursorPixel(self.color()?),
+            tag => OUTPUT_MODES
+                .get(tag as usize)
+                .cloned()
+                .ok_or_else(|| self.error(format!("unknown output mode {tag}")))?,
+        };
+        Ok(Output::new(mode, self.size()?))
+    }
+
+    fn color(&mut self) -> Result<Color, Error> {
+        match self.byte()? {
+            RGB_COLOR => Ok(Color::RGB(self.byte()?, self.byte()?, self.byte()?)),
+            tag => COLORS
+                .get(tag as usize)
+                .copied()
+                .ok_or_else(|| self.error(format!("unknown color {tag}"))),
+        }
+    }
+}
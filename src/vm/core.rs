@@ -5,9 +5,12 @@
 //! for ***every*** target.
 use crate::side_effects::{Input, Output};
 
-use super::{Error, StandardOp, StandardProgram, VirtualMachineProgram};
+use super::{validate_blocks, Error, StandardOp, StandardProgram, VirtualMachineProgram};
 use core::fmt;
-use std::{collections::HashMap, hash::Hash};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
 
 impl VirtualMachineProgram for CoreProgram {
     fn op(&mut self, op: CoreOp) {
@@ -28,8 +31,15 @@ impl VirtualMachineProgram for CoreProgram {
 pub struct CoreProgram(pub Vec<CoreOp>);
 
 impl CoreProgram {
-    /// Flatten a core program so that all of its functions
-    /// are defined sequentially at the beginning.
+    /// Flatten a core program so that all of its functions are defined
+    /// sequentially at the beginning, followed by the code outside of any functions.
+    ///
+    /// Functions nested in other functions or blocks are moved out of them. The functions
+    /// stay in the order they're declared in, so each one keeps its index for `Call`,
+    /// and the instructions in each function and in the rest of the code stay in order.
+    /// Each `If`, `Else`, and `While` stays in the same function as its `End`, so the
+    /// flattened code of a valid program is valid too: this is what the targets rely on
+    /// when they compile the functions and the main code separately.
     pub fn flatten(self) -> Self {
         Self(flatten(self.0).0)
     }
@@ -39,7 +49,8 @@ impl CoreProgram {
         flatten(self.0.clone()).2
     }
 
-    /// Get the code for each function.
+    /// Get the code for each function, by its index. The functions are numbered
+    /// from zero in the order they're declared in, like `flatten` defines them.
     pub fn get_functions(&self) -> HashMap<i32, Vec<CoreOp>> {
         flatten(self.0.clone()).1
     }
@@ -49,6 +60,36 @@ impl CoreProgram {
         let (_, functions, main) = flatten(self.0);
         (main, functions)
     }
+
+    /// Check that every `Function`, `While`, `If`, and `Else` block
+    /// in the program is properly closed with an `End`.
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_blocks(self.0.iter().map(Some))
+    }
+
+    /// Print the program as a listing, with the index of every instruction before it.
+    /// Nested blocks are indented by the width of the formatter, like the program itself.
+    pub fn listing(&self) -> Listing<'_, Self> {
+        Listing(self)
+    }
+
+    /// Print the program with its comments, which the plain form leaves out.
+    /// The comments are skipped when the code is parsed, so it can still be read back in.
+    pub fn commented(&self) -> Commented<'_, Self> {
+        Commented(self)
+    }
+
+    /// Count the number of times each instruction is used in the program, by name.
+    /// Comments are not counted.
+    pub fn op_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for op in &self.0 {
+            if !matches!(op, CoreOp::Comment(_)) {
+                *counts.entry(op.name()).or_default() += 1;
+            }
+        }
+        counts
+    }
 }
 
 /// Take all of the functions defined in a list of CoreOps,
@@ -61,6 +102,9 @@ fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<Co
 
     // The current function body we are in.
     let mut fun = -1;
+    // The index of the next function declared. Functions are numbered in the
+    // order they're declared in, however deeply they're nested.
+    let mut next_fun = 0;
     // Keep track of when we end the current function,
     // instead of just an if-else-conditional or a while loop.
     // This is essentially the number of end statements remaining before
@@ -91,12 +135,8 @@ fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<Co
                 // Reset the matching-end counter for the new scope.
                 matching_end = 0;
                 // Start defining the next function.
-                fun += 1;
-                // If that function is already defined,
-                // just go past the last function defined.
-                if functions.contains_key(&fun) {
-                    fun = functions.len() as i32
-                }
+                fun = next_fun;
+                next_fun += 1;
             }
             CoreOp::If | CoreOp::While => {
                 // Increment the number of matching `End`
@@ -146,23 +186,66 @@ fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<Co
     (result, result_functions, main_instructions)
 }
 
+/// Nested blocks are indented by the width of the formatter in spaces, or three
+/// spaces by default, so a width of zero prints every instruction without indentation.
+/// The alternate form `{:#}` also prints the address of every instruction.
 impl fmt::Display for CoreProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_code(f, false, false)
+    }
+}
+
+/// A program printed as a listing, with the index of every instruction before it.
+/// The indices count every instruction from zero, including comments, so they're
+/// the same indices used by the interpreter's breakpoints.
+pub struct Listing<'a, P>(pub(super) &'a P);
+
+/// A program printed with its comments, like the `file:line` comments
+/// added by `annotate_source`.
+pub struct Commented<'a, P>(pub(super) &'a P);
+
+impl fmt::Display for Commented<'_, CoreProgram> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_code(f, false, true)
+    }
+}
+
+impl fmt::Display for Listing<'_, CoreProgram> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_code(f, true, true)
+    }
+}
+
+impl CoreProgram {
+    /// Write the instructions of the program, with the index of each instruction before it
+    /// if `listing` is set, or with its address in the alternate form if it isn't.
+    /// Comments are only written in a listing, in the alternate form, or if `comments` is set.
+    fn fmt_code(&self, f: &mut fmt::Formatter, listing: bool, comments: bool) -> fmt::Result {
         let mut comment_count = 0;
         let mut indent = 0;
+        let tab = " ".repeat(f.width().unwrap_or(3));
         for (i, op) in self.0.iter().enumerate() {
-            if f.alternate() {
+            if listing {
+                write!(f, "{i:04}: ")?;
+                if let CoreOp::Comment(comment) = op {
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment)?;
+                    continue;
+                }
+            } else if f.alternate() {
                 if let CoreOp::Comment(comment) = op {
                     if f.alternate() {
                         write!(f, "{:8}  ", "")?;
                     }
                     comment_count += 1;
-                    writeln!(f, "{}// {}", "   ".repeat(indent), comment,)?;
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment,)?;
                     continue;
                 }
 
                 write!(f, "{:08x?}: ", i - comment_count)?;
-            } else if let CoreOp::Comment(_) = op {
+            } else if let CoreOp::Comment(comment) = op {
+                if comments {
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment)?;
+                }
                 continue;
             }
 
@@ -172,16 +255,16 @@ impl fmt::Display for CoreProgram {
                 match op {
                     CoreOp::Function | CoreOp::If | CoreOp::While => {
                         indent += 1;
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     CoreOp::Else => {
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     CoreOp::End => {
                         indent -= 1;
-                        "   ".repeat(indent)
+                        tab.repeat(indent)
                     }
-                    _ => "   ".repeat(indent),
+                    _ => tab.repeat(indent),
                 },
                 op
             )?
@@ -272,6 +355,39 @@ pub enum CoreOp {
     Put(Output),
 }
 
+impl CoreOp {
+    /// The name of the instruction, as it's written in the textual VM code.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoreOp::Comment(_) => "comment",
+            CoreOp::Set(_) => "set",
+            CoreOp::Function => "fun",
+            CoreOp::Call => "call",
+            CoreOp::Return => "ret",
+            CoreOp::While => "while",
+            CoreOp::If => "if",
+            CoreOp::Else => "else",
+            CoreOp::End => "end",
+            CoreOp::Save => "sav",
+            CoreOp::Restore => "res",
+            CoreOp::Move(_) => "mov",
+            CoreOp::Where => "where",
+            CoreOp::Deref => "deref",
+            CoreOp::Refer => "ref",
+            CoreOp::Index => "index",
+            CoreOp::BitwiseNand => "bitwise-nand",
+            CoreOp::Add => "add",
+            CoreOp::Sub => "sub",
+            CoreOp::Mul => "mul",
+            CoreOp::Div => "div",
+            CoreOp::Rem => "rem",
+            CoreOp::IsNonNegative => "gez",
+            CoreOp::Get(_) => "get",
+            CoreOp::Put(_) => "put",
+        }
+    }
+}
+
 impl fmt::Display for CoreOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -2,7 +2,9 @@
 //!
 //! This module implements an interpreter for the Core virtual machine
 //! variant.
+use super::{check_frame, save_frame, Frame, Trace, TraceEntry};
 use crate::vm::{CoreOp, CoreProgram, Device, StandardDevice};
+use std::collections::{BTreeMap, BTreeSet};
 
 impl Default for CoreInterpreter<StandardDevice> {
     fn default() -> Self {
@@ -11,11 +13,22 @@ impl Default for CoreInterpreter<StandardDevice> {
             pointer: 0,
             register: 0,
             cells: vec![],
+            high_water_mark: 0,
             functions: vec![],
             calls: vec![],
             refs: vec![],
             i: 0,
             done: false,
+            steps: 0,
+            max_steps: None,
+            profile: None,
+            cell_bits: 64,
+            trap_overflow: false,
+            check_frames: false,
+            frames: vec![],
+            trace: None,
+            breakpoints: BTreeSet::new(),
+            paused: false,
         }
     }
 }
@@ -33,6 +46,8 @@ where
     register: i64,
     /// The turing tape (composed of integer cells)
     cells: Vec<i64>,
+    /// One past the highest cell accessed through the pointer.
+    high_water_mark: usize,
     /// The addresses of defined functions. `functions[N]` is the
     /// instruction pointer for the Nth function's code.
     functions: Vec<usize>,
@@ -47,6 +62,26 @@ where
     i: usize,
     /// Is the interpreter finished interpreting?s
     done: bool,
+    /// The number of instructions executed so far.
+    steps: u64,
+    /// The maximum number of instructions to execute before halting with an error.
+    max_steps: Option<u64>,
+    /// The number of times each kind of instruction was executed, if profiling.
+    profile: Option<BTreeMap<&'static str, usize>>,
+    /// The number of bits in a cell, which integer arithmetic wraps at.
+    cell_bits: u32,
+    /// Is overflowing a cell in integer arithmetic an error, instead of wrapping?
+    trap_overflow: bool,
+    /// Is the frame pointer convention of the assembly language checked at every call?
+    check_frames: bool,
+    /// The frame registers saved at each call, if the frames are being checked.
+    frames: Vec<Frame>,
+    /// The log of the instructions executed, if tracing.
+    trace: Option<Trace>,
+    /// The indices of the instructions to pause before executing.
+    breakpoints: BTreeSet<usize>,
+    /// Did the interpreter just pause at the breakpoint for the current instruction?
+    paused: bool,
 }
 
 impl<T> CoreInterpreter<T>
@@ -59,14 +94,118 @@ where
             pointer: 0,
             register: 0,
             cells: vec![],
+            high_water_mark: 0,
             functions: vec![],
             calls: vec![],
             refs: vec![],
             i: 0,
             done: false,
+            steps: 0,
+            max_steps: None,
+            profile: None,
+            cell_bits: 64,
+            trap_overflow: false,
+            check_frames: false,
+            frames: vec![],
+            trace: None,
+            breakpoints: BTreeSet::new(),
+            paused: false,
         }
     }
 
+    /// Limit the number of instructions the interpreter executes. If the program
+    /// hasn't halted after `max_steps` instructions, `run` returns an error.
+    pub fn with_max_steps(self, max_steps: Option<u64>) -> Self {
+        Self { max_steps, ..self }
+    }
+
+    /// Count the number of times each kind of instruction is executed.
+    /// When this isn't enabled, nothing is counted.
+    pub fn with_profile(self, enabled: bool) -> Self {
+        Self {
+            profile: enabled.then(BTreeMap::new),
+            ..self
+        }
+    }
+
+    /// Wrap the results of integer arithmetic at the given number of bits, like the cells
+    /// of a target with narrower integers. By default, cells have 64 bits.
+    pub fn with_cell_bits(self, cell_bits: u32) -> Self {
+        assert!(
+            (1..=64).contains(&cell_bits),
+            "cells must have between 1 and 64 bits"
+        );
+        Self { cell_bits, ..self }
+    }
+
+    /// Return an error when the result of `Add`, `Sub`, or `Mul` overflows a cell,
+    /// instead of wrapping it. By default, the results wrap.
+    pub fn with_trap_overflow(self, trap_overflow: bool) -> Self {
+        Self {
+            trap_overflow,
+            ..self
+        }
+    }
+
+    /// Check the frame pointer convention of the assembly language at every `Call` and
+    /// `Return`, and return an error with the index of the instruction which finds the
+    /// frame registers corrupted. Only use this for programs compiled from assembly code:
+    /// programs which deliberately move their stack frames are reported as corrupted too.
+    pub fn with_check_frames(self, check_frames: bool) -> Self {
+        Self {
+            check_frames,
+            ..self
+        }
+    }
+
+    /// Record every instruction executed (other than comments) in an execution trace,
+    /// with the register and the pointer before it. The trace is finished when `run`
+    /// returns, or when `finish_trace` is called.
+    pub fn with_trace(self, trace: Option<Trace>) -> Self {
+        Self { trace, ..self }
+    }
+
+    /// Pause before executing the instructions at the given indices in the program,
+    /// counting every instruction (including comments) from zero.
+    /// Breakpoints are only checked by `execute_until_break`.
+    pub fn with_breakpoints(self, breakpoints: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            breakpoints: breakpoints.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Wrap an integer to the width of a cell, and sign extend it.
+    fn wrap(&self, n: i64) -> i64 {
+        let shift = 64 - self.cell_bits;
+        (n << shift) >> shift
+    }
+
+    /// Compute `register <symbol> n` for an integer arithmetic instruction. The result
+    /// wraps to the width of a cell, unless overflow is trapped, where it's an error instead.
+    fn arithmetic(
+        &self,
+        symbol: char,
+        n: i64,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+    ) -> Result<i64, String> {
+        let result = self.wrap(wrapping(self.register, n));
+        if self.trap_overflow && checked(self.register, n) != Some(result) {
+            return Err(format!(
+                "Instruction #{} overflowed a {}-bit cell computing {} {symbol} {n}",
+                self.i, self.cell_bits, self.register
+            ));
+        }
+        Ok(result)
+    }
+
+    /// The number of times each kind of instruction (other than comments) was executed,
+    /// by name, if profiling was enabled with `with_profile`.
+    pub fn profile(&self) -> Option<&BTreeMap<&'static str, usize>> {
+        self.profile.as_ref()
+    }
+
     /// Fetch the current instruction pointed to in the program
     fn fetch<'a>(&self, code: &'a CoreProgram) -> Option<&'a CoreOp> {
         if self.i < code.0.len() {
@@ -231,24 +370,95 @@ where
         while self.pointer >= self.cells.len() {
             self.cells.extend(vec![0; 1000]);
         }
+        self.high_water_mark = self.high_water_mark.max(self.pointer + 1);
 
         &mut self.cells[self.pointer]
     }
 
     /// Run a core program using this interpreter and its device.
     pub fn run(mut self, code: &CoreProgram) -> Result<T, String> {
+        let result = self.execute(code);
+        self.finish_trace()?;
+        result?;
+        Ok(self.device)
+    }
+
+    /// Run a program without consuming the interpreter, so that its state
+    /// (like the tape) can be inspected after the program halts.
+    pub fn execute(&mut self, code: &CoreProgram) -> Result<(), String> {
         while !self.done {
             self.step(code)?
         }
-        Ok(self.device)
+        Ok(())
+    }
+
+    /// Run a program until it halts, or until it's about to execute an instruction
+    /// with a breakpoint. This returns `true` if the program paused at a breakpoint:
+    /// calling this again continues the program from there.
+    pub fn execute_until_break(&mut self, code: &CoreProgram) -> Result<bool, String> {
+        while !self.done {
+            if !self.paused && self.breakpoints.contains(&self.i) {
+                self.paused = true;
+                return Ok(true);
+            }
+            self.paused = false;
+            self.step(code)?
+        }
+        Ok(false)
+    }
+
+    /// Describe the next instruction to execute, the register, the pointer,
+    /// and the cells around the pointer. This is printed at breakpoints.
+    pub fn describe_state(&self, code: &CoreProgram) -> String {
+        super::describe_state(
+            self.i,
+            self.fetch(code).map(|op| op as &dyn std::fmt::Display),
+            self.register,
+            self.pointer,
+            &self.cells,
+        )
+    }
+
+    /// Write the end of the execution trace, if tracing. A trace limited to the last
+    /// instructions is only written here, so call this after the program halts (or fails).
+    pub fn finish_trace(&mut self) -> Result<(), String> {
+        match &mut self.trace {
+            Some(trace) => trace.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// The cells of the tape, up to the highest cell the program accessed.
+    pub fn tape(&self) -> &[i64] {
+        &self.cells[..self.high_water_mark]
     }
 
     /// Run a single step of the interpreter.
     fn step(&mut self, code: &CoreProgram) -> Result<(), String> {
         if let Some(op) = self.fetch(code) {
+            self.steps += 1;
+            if matches!(self.max_steps, Some(max_steps) if self.steps > max_steps) {
+                return Err(String::from("step limit exceeded"));
+            }
+            if let Some(profile) = &mut self.profile {
+                if !matches!(op, CoreOp::Comment(_)) {
+                    *profile.entry(op.name()).or_default() += 1;
+                }
+            }
+            if let Some(trace) = &mut self.trace {
+                if !matches!(op, CoreOp::Comment(_)) {
+                    trace.record(TraceEntry {
+                        index: self.i,
+                        opcode: op.name(),
+                        reg: self.register,
+                        ptr: self.pointer,
+                    })?;
+                }
+            }
+
             match op {
                 CoreOp::Comment(_) => {}
-                CoreOp::Set(n) => self.register = *n,
+                CoreOp::Set(n) => self.register = self.wrap(*n),
                 CoreOp::Function => {
                     if !self.functions.contains(&self.i) {
                         self.functions.push(self.i);
@@ -256,8 +466,19 @@ where
                     }
                     self.jmp_to_end(code)
                 }
-                CoreOp::Call => self.call(code)?,
-                CoreOp::Return => self.ret(),
+                CoreOp::Call => {
+                    if self.check_frames {
+                        let frame = save_frame(self.i, self.frames.last(), &self.cells)?;
+                        self.frames.push(frame);
+                    }
+                    self.call(code)?
+                }
+                CoreOp::Return => {
+                    if let Some(frame) = self.frames.pop() {
+                        check_frame(self.i, frame, &self.cells)?;
+                    }
+                    self.ret()
+                }
                 CoreOp::While => {
                     if self.register == 0 {
                         self.jmp_to_end(code)
@@ -302,19 +523,28 @@ where
                 CoreOp::BitwiseNand => {
                     self.register = !(self.register & *self.get_cell());
                 }
-                CoreOp::Add => self.register += *self.get_cell(),
-                CoreOp::Sub => self.register -= *self.get_cell(),
-                CoreOp::Mul => self.register *= *self.get_cell(),
+                CoreOp::Add => {
+                    let n = *self.get_cell();
+                    self.register = self.arithmetic('+', n, i64::checked_add, i64::wrapping_add)?
+                }
+                CoreOp::Sub => {
+                    let n = *self.get_cell();
+                    self.register = self.arithmetic('-', n, i64::checked_sub, i64::wrapping_sub)?
+                }
+                CoreOp::Mul => {
+                    let n = *self.get_cell();
+                    self.register = self.arithmetic('*', n, i64::checked_mul, i64::wrapping_mul)?
+                }
                 CoreOp::Div => {
                     let d = *self.get_cell();
                     if d != 0 {
-                        self.register /= d
+                        self.register = self.wrap(self.register.wrapping_div(d))
                     }
                 }
                 CoreOp::Rem => {
                     let d = *self.get_cell();
                     if d != 0 {
-                        self.register %= d
+                        self.register = self.wrap(self.register.wrapping_rem(d))
                     }
                 }
 
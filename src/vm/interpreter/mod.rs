@@ -6,7 +6,10 @@
 //! supplying the input and handling the output of the program. For testing the compiler,
 //! assembler, and virtual machine, we use a `TestingDevice` object to supply sample input
 //! and capture the output to test against the predicted output.
-use crate::side_effects::{FFIBinding, Input, InputMode, Output, OutputMode};
+use crate::{
+    asm::{Location, FP, FP_STACK},
+    side_effects::{FFIBinding, Input, InputMode, Output, OutputMode},
+};
 
 use log::{error, trace, warn};
 
@@ -17,31 +20,248 @@ pub use self::std::*;
 
 use ::std::{
     collections::{HashMap, VecDeque},
-    io::{stdin, stdout, Read, Write},
+    fs::File,
+    io::{stderr, stdin, stdout, Read, Write},
+    sync::Arc,
 };
 
+/// The number of cells on each side of the pointer shown by `describe_state`.
+const NEARBY_CELLS: usize = 4;
+
+/// Describe the state of an interpreter which is about to execute the
+/// instruction `op` at index `i`: the register, the pointer, and the cells
+/// around the pointer, with the cell under the pointer in brackets.
+fn describe_state(
+    i: usize,
+    op: Option<&dyn ::std::fmt::Display>,
+    register: i64,
+    pointer: usize,
+    cells: &[i64],
+) -> String {
+    let op = match op {
+        Some(op) => format!("`{op}`"),
+        None => String::from("the end of the program"),
+    };
+    let start = pointer.saturating_sub(NEARBY_CELLS);
+    let end = pointer + NEARBY_CELLS;
+    let nearby = (start..=end)
+        .map(|n| {
+            let cell = cells.get(n).copied().unwrap_or(0);
+            if n == pointer {
+                format!("[{cell}]")
+            } else {
+                cell.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "breakpoint at instruction #{i} ({op})\n  register: {register}\n  pointer: {pointer}\n  cells {start}..={end}: {nearby}"
+    )
+}
+
+/// The registers of the assembly language's calling convention when a function was
+/// called, which are compared against the registers when the function returns.
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    /// The index of the `Call` instruction.
+    call: usize,
+    /// The frame pointer of the caller.
+    fp: i64,
+    /// The top of the frame pointer stack of the caller.
+    fp_stack: i64,
+}
+
+/// The address of a register of the assembly language on the tape.
+fn register_address(register: Location) -> usize {
+    match register {
+        Location::Address(address) => address,
+        _ => unreachable!("registers are constant addresses"),
+    }
+}
+
+/// Read a cell of the tape, where cells the program hasn't used yet are zero.
+fn read_cell(cells: &[i64], address: i64) -> i64 {
+    usize::try_from(address)
+        .ok()
+        .and_then(|address| cells.get(address).copied())
+        .unwrap_or(0)
+}
+
+/// Save the frame registers before the `Call` instruction at index `i`.
+///
+/// The assembly language stores the caller's frame pointer at `FP_STACK.deref()`
+/// when a function begins, so if this call is made from inside another function
+/// (the frame `caller`), that cell must still hold the frame pointer saved when
+/// the caller was called. Otherwise, the frame pointer stack has been corrupted.
+fn save_frame(i: usize, caller: Option<&Frame>, cells: &[i64]) -> Result<Frame, String> {
+    let fp = read_cell(cells, register_address(FP) as i64);
+    let fp_stack = read_cell(cells, register_address(FP_STACK) as i64);
+    if let Some(caller) = caller {
+        let saved = read_cell(cells, fp_stack);
+        if saved != caller.fp {
+            return Err(format!(
+                "Instruction #{i} made a call with a corrupted frame pointer stack: the frame pointer saved by the function called at instruction #{} should be {}, but it's {saved}",
+                caller.call, caller.fp
+            ));
+        }
+    }
+    Ok(Frame {
+        call: i,
+        fp,
+        fp_stack,
+    })
+}
+
+/// Check that the `Return` instruction at index `i` restored the frame registers
+/// saved by the call it returns from.
+fn check_frame(i: usize, frame: Frame, cells: &[i64]) -> Result<(), String> {
+    for (name, register, expected) in [("FP", FP, frame.fp), ("FP_STACK", FP_STACK, frame.fp_stack)]
+    {
+        let found = read_cell(cells, register_address(register) as i64);
+        if found != expected {
+            return Err(format!(
+                "Instruction #{i} returned from the call at instruction #{} with a corrupted frame: `{name}` should be restored to {expected}, but it's {found}",
+                frame.call
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// An instruction executed by an interpreter, as recorded in an execution trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The index of the instruction in the program, counting every instruction
+    /// (including comments) from zero.
+    pub index: usize,
+    /// The name of the instruction, as counted by the profiler.
+    pub opcode: &'static str,
+    /// The register before the instruction executed.
+    pub reg: i64,
+    /// The pointer before the instruction executed.
+    pub ptr: usize,
+}
+
+/// Each entry is written as a JSON object on its own line.
+impl ::std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        write!(
+            f,
+            "{{\"index\": {}, \"opcode\": {:?}, \"reg\": {}, \"ptr\": {}}}",
+            self.index, self.opcode, self.reg, self.ptr
+        )
+    }
+}
+
+/// An ordered log of the instructions executed by an interpreter (other than comments),
+/// written to a writer as JSON lines. Unlike the profile, which only counts the
+/// instructions, this records the order they ran in, for debugging a crash after the fact.
+pub struct Trace {
+    /// Where the entries are written.
+    writer: Box<dyn Write>,
+    /// The most entries to keep, if only the last instructions are written.
+    limit: Option<usize>,
+    /// The last entries recorded, when there's a limit.
+    last: VecDeque<TraceEntry>,
+}
+
+impl Trace {
+    /// Write every instruction to the writer as it's executed.
+    pub fn new(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            limit: None,
+            last: VecDeque::new(),
+        }
+    }
+
+    /// Only keep the last `limit` instructions executed, and write them when
+    /// the trace is finished. This keeps the trace of a long running program small.
+    pub fn with_limit(self, limit: Option<usize>) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// Record an executed instruction.
+    fn record(&mut self, entry: TraceEntry) -> Result<(), String> {
+        match self.limit {
+            Some(limit) => {
+                if self.last.len() == limit {
+                    self.last.pop_front();
+                }
+                if limit > 0 {
+                    self.last.push_back(entry);
+                }
+                Ok(())
+            }
+            None => writeln!(self.writer, "{entry}").map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Write the instructions kept by a limited trace, and flush the writer.
+    fn finish(&mut self) -> Result<(), String> {
+        for entry in self.last.drain(..) {
+            writeln!(self.writer, "{entry}").map_err(|e| e.to_string())?;
+        }
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// The character written for a cell in a UTF-8 output mode. Cells which aren't
+/// Unicode scalar values (like surrogates, or negative numbers) are written as
+/// the replacement character `U+FFFD`.
+fn utf8_char(val: i64) -> char {
+    u32::try_from(val)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
 /// Create an input / output device for the virtual machine interpreter
 /// to operate on. The method `get` retrieves the device's input, and the
 /// function `put` writes to the devices output.
 ///
+/// Both interpreters are generic over their device, so a program can embed
+/// the interpreter with its own I/O by implementing this trait and passing
+/// the device to `CoreInterpreter::new` or `StandardInterpreter::new`.
+/// The device is returned by `run` once the program halts.
+///
+/// Every value passes through the device as an `i64` cell. Floats are stored
+/// as their bits: use `as_float` and `as_int` to convert them for the float modes.
+/// Any error returned by a device method halts the interpreter with that error.
+///
 /// TODO: Make a trait for a device with the standard variant, which requires
 /// `get_char`, `put_char`, `get_int`, `put_int`, `get_float`, and `put_float` methods.
 pub trait Device {
     /// Get the next input (from a given input source).
+    ///
+    /// Called by both interpreters for every `Get` instruction.
+    /// The result is stored in the register.
     fn get(&mut self, src: Input) -> Result<i64, String>;
     /// Put the given value to the given output destination.
+    ///
+    /// Called by both interpreters for every `Put` instruction,
+    /// with the value of the register.
     fn put(&mut self, val: i64, dst: Output) -> Result<(), String>;
 
     /// Peek at the next value in the FFI buffer for the FFI function calls.
     /// Store the peeked value in the register.
+    ///
+    /// Only called by the standard interpreter, for the `Peek` instruction.
     fn peek(&mut self) -> Result<i64, String>;
     /// Poke a value into the FFI buffer for the FFI function calls.
+    ///
+    /// Only called by the standard interpreter, for the `Poke` instruction,
+    /// with the value of the register.
     fn poke(&mut self, val: i64) -> Result<(), String>;
 
     /// FFI call to the device. This will get the FFI binding for the device
     /// and call the function associated with the binding. If the tape is
     /// provided, the foreign function may mutate the tape. Otherwise all
     /// interaction with the FFI is done through the FFI channel.
+    ///
+    /// Only called by the standard interpreter, for the `Call` instruction
+    /// with an FFI binding. The standard interpreter always provides the tape.
     fn ffi_call(&mut self, ffi: &FFIBinding, tape: Option<&mut Vec<i64>>) -> Result<(), String>;
 }
 
@@ -57,6 +277,8 @@ pub struct TestingDevice {
     pub ffi_channel: VecDeque<i64>,
     pub input: VecDeque<i64>,
     pub output: Vec<(i64, Output)>,
+    /// The output written to standard-error, kept apart from the standard-out output.
+    pub error_output: Vec<(i64, Output)>,
 }
 
 impl TestingDevice {
@@ -71,6 +293,7 @@ impl TestingDevice {
                 .map(|ch| ch as i64)
                 .collect(),
             output: vec![],
+            error_output: vec![],
         }
     }
 
@@ -80,24 +303,14 @@ impl TestingDevice {
             ffi_channel: VecDeque::new(),
             input: input.into(),
             output: vec![],
+            error_output: vec![],
         }
     }
 
-    fn put_char(&mut self, ch: char) -> Result<(), String> {
-        self.output.push((ch as u64 as i64, Output::stdout_char()));
-        Ok(())
-    }
-
-    fn put_int(&mut self, val: i64) -> Result<(), String> {
-        for ch in val.to_string().chars() {
-            self.put_char(ch)?
-        }
-        Ok(())
-    }
-
-    fn put_float(&mut self, val: f64) -> Result<(), String> {
-        for ch in format!("{val:?}").chars() {
-            self.put_char(ch)?
+    /// Write the characters of a string to the output of a character mode.
+    fn put_str(&mut self, text: &str, dst: Output) -> Result<(), String> {
+        for ch in text.chars() {
+            self.put(ch as u64 as i64, dst.clone())?
         }
         Ok(())
     }
@@ -173,16 +386,31 @@ impl TestingDevice {
         }
     }
 
-    /// Get the output of the testing device as a string (ascii).
+    /// Get the output of the testing device as a string (ascii, or
+    /// whole characters for the output written in a UTF-8 mode).
     pub fn output_str(&self) -> String {
-        let mut result = String::new();
-        for (ch, _) in &self.output {
-            result.push(*ch as i8 as u8 as char)
-        }
+        let result = Self::chars(&self.output);
         trace!("Output from testing device: {}", result);
         result
     }
 
+    /// Get the standard-error output of the testing device as a string (ascii, or
+    /// whole characters for the output written in a UTF-8 mode).
+    pub fn error_str(&self) -> String {
+        Self::chars(&self.error_output)
+    }
+
+    /// Turn the output written in a character mode into a string.
+    fn chars(output: &[(i64, Output)]) -> String {
+        output
+            .iter()
+            .map(|(ch, dst)| match dst.mode {
+                OutputMode::StdoutUtf8 | OutputMode::StderrUtf8 => utf8_char(*ch),
+                _ => *ch as i8 as u8 as char,
+            })
+            .collect()
+    }
+
     pub fn output_vals(&self) -> Vec<i64> {
         self.output.iter().map(|(val, _)| *val).collect()
     }
@@ -202,6 +430,8 @@ impl Device for TestingDevice {
             }
             InputMode::StdinInt => self.get_int(),
             InputMode::StdinFloat => self.get_float().map(as_int),
+            // The sample input is already made of whole characters.
+            InputMode::StdinUtf8 => self.get(Input::stdin_char()),
             _ => {
                 warn!("Requested input mode: {}", src.mode);
                 Ok(0)
@@ -211,12 +441,30 @@ impl Device for TestingDevice {
 
     fn put(&mut self, val: i64, dst: Output) -> Result<(), String> {
         match dst.mode {
-            OutputMode::StdoutChar => {
+            OutputMode::StdoutChar | OutputMode::StdoutUtf8 => {
                 self.output.push((val, dst));
                 Ok(())
             }
-            OutputMode::StdoutInt => self.put_int(val),
-            OutputMode::StdoutFloat => self.put_float(as_float(val)),
+            OutputMode::StdoutInt => self.put_str(
+                &val.to_string(),
+                Output::new(OutputMode::StdoutChar, dst.channel.0),
+            ),
+            OutputMode::StdoutFloat => self.put_str(
+                &format!("{:?}", as_float(val)),
+                Output::new(OutputMode::StdoutChar, dst.channel.0),
+            ),
+            OutputMode::StderrChar | OutputMode::StderrUtf8 => {
+                self.error_output.push((val, dst));
+                Ok(())
+            }
+            OutputMode::StderrInt => self.put_str(
+                &val.to_string(),
+                Output::new(OutputMode::StderrChar, dst.channel.0),
+            ),
+            OutputMode::StderrFloat => self.put_str(
+                &format!("{:?}", as_float(val)),
+                Output::new(OutputMode::StderrChar, dst.channel.0),
+            ),
             _ => {
                 warn!("Requested output mode: {} (with output={val})", dst.mode);
                 Ok(())
@@ -252,11 +500,22 @@ impl Device for TestingDevice {
 
 /// A device used for standard input and output.
 /// This simply retrieves a character from standard-in with `get`,
-/// and writes a character to standard-out with `put`.
+/// and writes a character to standard-out (or standard-error) with `put`.
+///
+/// The input can also be read from a file (or any other reader) instead
+/// of standard-in, by creating the device with `StandardDevice::with_input`,
+/// and the standard-out output can be written to a file with `StandardDevice::with_output`.
 #[derive(Debug, Clone)]
 pub struct StandardDevice {
     ffi: HashMap<FFIBinding, fn(&mut VecDeque<i64>, Option<&mut Vec<i64>>)>,
     ffi_channel: VecDeque<i64>,
+    /// The input to read instead of standard-in, if any.
+    input: Option<VecDeque<u8>>,
+    /// The file to write instead of standard-out, if any.
+    output: Option<Arc<File>>,
+    /// A byte which was read while decoding an invalid UTF-8 character,
+    /// but which isn't part of it, so it's read again next.
+    unread: Option<u8>,
 }
 
 impl Default for StandardDevice {
@@ -264,6 +523,9 @@ impl Default for StandardDevice {
         let mut result = Self {
             ffi: HashMap::new(),
             ffi_channel: VecDeque::new(),
+            input: None,
+            output: None,
+            unread: None,
         };
 
         result.add_binding(
@@ -285,6 +547,26 @@ impl Default for StandardDevice {
 }
 
 impl StandardDevice {
+    /// Create a device which reads its input from the given reader (like a file)
+    /// instead of standard-in. The output is still written to standard-out.
+    pub fn with_input(mut input: impl Read) -> ::std::io::Result<Self> {
+        let mut buf = vec![];
+        input.read_to_end(&mut buf)?;
+        Ok(Self {
+            input: Some(buf.into()),
+            ..Self::default()
+        })
+    }
+
+    /// Write the standard-out output of the device to the given file instead.
+    /// The output is written unbuffered, exactly as the program produces it.
+    pub fn with_output(self, output: File) -> Self {
+        Self {
+            output: Some(Arc::new(output)),
+            ..self
+        }
+    }
+
     pub fn add_binding(
         &mut self,
         ffi: FFIBinding,
@@ -294,39 +576,113 @@ impl StandardDevice {
         self.ffi.insert(ffi, f);
     }
 
-    fn get_char(&mut self) -> Result<char, String> {
+    /// Read the next byte of input, or `None` at the end of the input.
+    fn read_byte(&mut self) -> Result<Option<u8>, String> {
+        if let Some(byte) = self.unread.take() {
+            return Ok(Some(byte));
+        }
+        if let Some(input) = &mut self.input {
+            return Ok(input.pop_front());
+        }
+
         let mut buf = [0];
+        match stdin().read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(_) => {
+                error!("Could not read input, do you have a terminal?");
+                Err("Could not get user input".to_string())
+            }
+        }
+    }
+
+    /// Write bytes to the standard-out output (or the output file) of the device.
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let result = match &self.output {
+            Some(file) => file.as_ref().write_all(bytes),
+            None => stdout().write_all(bytes),
+        };
+        result.map_err(|_| String::from("could not write output"))
+    }
+
+    /// Write bytes to the standard-error output of the device.
+    fn write_stderr(&mut self, bytes: &[u8]) -> Result<(), String> {
+        stderr()
+            .write_all(bytes)
+            .map_err(|_| String::from("could not write error output"))
+    }
+
+    /// Flush the output before reading input, so that prompts are visible.
+    fn flush(&mut self) -> Result<(), String> {
         if stdout().flush().is_err() {
             error!("Could not flush output, do you have a terminal?");
             return Err("Could not flush output".to_string());
         }
-        if stdin().read(&mut buf).is_err() {
-            error!("Could not flush output, do you have a terminal?");
-            return Err("Could not get user input".to_string());
+        Ok(())
+    }
+
+    /// Get the next character of input, or a null character at the end of the input.
+    fn get_char(&mut self) -> Result<char, String> {
+        self.flush()?;
+        Ok(self.read_byte()?.unwrap_or(0) as char)
+    }
+
+    /// Get the code point of the next UTF-8 encoded character of input, or zero at
+    /// the end of the input. Invalid UTF-8 (including overlong encodings, encoded
+    /// surrogates, and truncated characters) is read as the replacement character `U+FFFD`.
+    fn get_utf8(&mut self) -> Result<i64, String> {
+        self.flush()?;
+        let first = match self.read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(0),
+        };
+        let len = match first {
+            0x00..=0x7f => return Ok(first as i64),
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return Ok(char::REPLACEMENT_CHARACTER as i64),
+        };
+
+        let mut bytes = vec![first];
+        while bytes.len() < len {
+            match self.read_byte()? {
+                Some(byte @ 0x80..=0xbf) => bytes.push(byte),
+                // This byte starts the next character, so it's read again.
+                byte => {
+                    self.unread = byte;
+                    return Ok(char::REPLACEMENT_CHARACTER as i64);
+                }
+            }
         }
-        Ok(buf[0] as char)
+        // The lengths of the sequences are right, but the decoder still
+        // rejects the overlong encodings, surrogates, and code points past `U+10FFFF`.
+        Ok(match ::std::str::from_utf8(&bytes) {
+            Ok(text) => text.chars().next().unwrap() as i64,
+            Err(_) => char::REPLACEMENT_CHARACTER as i64,
+        })
     }
 
     fn get_int(&mut self) -> Result<i64, String> {
-        let mut buf = [0];
-        if stdout().flush().is_err() {
-            error!("Could not flush output, do you have a terminal?");
-            return Err("Could not flush output".to_string());
-        }
+        self.flush()?;
 
-        while stdin().read(&mut buf).is_ok() && (buf[0] as char).is_whitespace() {}
+        let mut byte = self.read_byte()?;
+        while matches!(byte, Some(b) if b.is_ascii_whitespace()) {
+            byte = self.read_byte()?;
+        }
 
-        let mut result = if buf[0].is_ascii_digit() {
-            (buf[0] - b'0') as i64
-        } else {
-            warn!("EOF while parsing integer");
-            0
+        let mut result = match byte {
+            Some(b) if b.is_ascii_digit() => (b - b'0') as i64,
+            _ => {
+                warn!("EOF while parsing integer");
+                0
+            }
         };
 
-        while stdin().read(&mut buf).is_ok() {
-            if buf[0].is_ascii_digit() {
+        while let Some(b) = self.read_byte()? {
+            if b.is_ascii_digit() {
                 result *= 10;
-                result += (buf[0] - b'0') as i64
+                result += (b - b'0') as i64
             } else {
                 break;
             }
@@ -338,15 +694,17 @@ impl StandardDevice {
     }
 
     fn get_float(&mut self) -> Result<f64, String> {
-        let mut buf = String::new();
-        if stdout().flush().is_err() {
-            error!("Could not flush output, do you have a terminal?");
-            return Err("Could not flush output".to_string());
-        }
-        if stdin().read_line(&mut buf).is_err() {
-            error!("Could not flush output, do you have a terminal?");
-            return Err("Could not get user input".to_string());
+        self.flush()?;
+
+        let mut buf = vec![];
+        while let Some(b) = self.read_byte()? {
+            if b == b'\n' {
+                break;
+            }
+            buf.push(b);
         }
+
+        let buf = String::from_utf8_lossy(&buf);
         Ok(buf.trim().parse::<f64>().unwrap_or_else(|s| {
             warn!("Could not parse float: {s:?}, defaulting to 0.0");
             0.0
@@ -360,6 +718,7 @@ impl Device for StandardDevice {
             InputMode::StdinChar => self.get_char()? as i64,
             InputMode::StdinInt => self.get_int()?,
             InputMode::StdinFloat => as_int(self.get_float()?),
+            InputMode::StdinUtf8 => self.get_utf8()?,
             InputMode::Thermometer => as_int(295.15),
             _ => {
                 warn!(
@@ -374,12 +733,24 @@ impl Device for StandardDevice {
     fn put(&mut self, val: i64, dst: Output) -> Result<(), String> {
         // Print the character without a newline
         match dst.mode {
-            OutputMode::StdoutChar => print!("{}", val as u8 as char),
-            OutputMode::StdoutInt => print!("{}", val),
-            OutputMode::StdoutFloat => print!("{:?}", as_float(val)),
-            OutputMode::StderrChar => eprint!("{}", val as u8 as char),
-            OutputMode::StderrInt => eprint!("{}", val),
-            OutputMode::StderrFloat => eprint!("{:?}", as_float(val)),
+            OutputMode::StdoutChar => {
+                self.write((val as u8 as char).encode_utf8(&mut [0; 4]).as_bytes())?
+            }
+            OutputMode::StdoutInt => self.write(val.to_string().as_bytes())?,
+            OutputMode::StdoutFloat => self.write(format!("{:?}", as_float(val)).as_bytes())?,
+            OutputMode::StderrChar => {
+                self.write_stderr((val as u8 as char).encode_utf8(&mut [0; 4]).as_bytes())?
+            }
+            OutputMode::StderrInt => self.write_stderr(val.to_string().as_bytes())?,
+            OutputMode::StderrFloat => {
+                self.write_stderr(format!("{:?}", as_float(val)).as_bytes())?
+            }
+            OutputMode::StdoutUtf8 => {
+                self.write(utf8_char(val).encode_utf8(&mut [0; 4]).as_bytes())?
+            }
+            OutputMode::StderrUtf8 => {
+                self.write_stderr(utf8_char(val).encode_utf8(&mut [0; 4]).as_bytes())?
+            }
             _ => {
                 warn!(
                     "Requested output mode: {} (on channel #{}) with output={val}",
@@ -419,3 +790,73 @@ impl Device for StandardDevice {
         }
     }
 }
+
+/// A device which supplies pseudo-random input from a seeded generator,
+/// instead of reading it. Everything else is passed through to the wrapped device.
+///
+/// With the same seed, a program gets the same input every time it runs,
+/// so the output of randomized programs can be tested.
+#[derive(Debug, Clone)]
+pub struct RandomDevice<D> {
+    device: D,
+    state: u64,
+}
+
+impl<D: Device> RandomDevice<D> {
+    /// Wrap a device, supplying the input from a generator with the given seed.
+    pub fn new(device: D, seed: u64) -> Self {
+        Self {
+            device,
+            state: seed,
+        }
+    }
+
+    /// Get the wrapped device back.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Get the next pseudo-random number from the generator (SplitMix64).
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl<D: Device> Device for RandomDevice<D> {
+    fn get(&mut self, src: Input) -> Result<i64, String> {
+        Ok(match src.mode {
+            // A random byte.
+            InputMode::StdinChar => (self.next() >> 56) as i64,
+            // A random non-negative integer.
+            InputMode::StdinInt => (self.next() >> 1) as i64,
+            // A random float in `[0, 1)`.
+            InputMode::StdinFloat => as_int((self.next() >> 11) as f64 / (1u64 << 53) as f64),
+            // A random Unicode scalar value, skipping over the surrogates.
+            InputMode::StdinUtf8 => {
+                let n = (self.next() >> 32) % (0x110000 - 0x800);
+                (if n < 0xd800 { n } else { n + 0x800 }) as i64
+            }
+            _ => return self.device.get(src),
+        })
+    }
+
+    fn put(&mut self, val: i64, dst: Output) -> Result<(), String> {
+        self.device.put(val, dst)
+    }
+
+    fn peek(&mut self) -> Result<i64, String> {
+        self.device.peek()
+    }
+
+    fn poke(&mut self, val: i64) -> Result<(), String> {
+        self.device.poke(val)
+    }
+
+    fn ffi_call(&mut self, ffi: &FFIBinding, tape: Option<&mut Vec<i64>>) -> Result<(), String> {
+        self.device.ffi_call(ffi, tape)
+    }
+}
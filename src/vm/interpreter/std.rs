@@ -3,7 +3,9 @@
 //! This module implements an interpreter for the Standard virtual machine
 //! variant.
 
+use super::{check_frame, save_frame, Frame, Trace, TraceEntry};
 use crate::vm::{CoreOp, Device, StandardDevice, StandardOp, StandardProgram};
+use std::collections::{BTreeMap, BTreeSet};
 
 /// A function to reinterpret the bits of an integer as a float.
 pub fn as_float(n: i64) -> f64 {
@@ -34,6 +36,8 @@ where
     register: i64,
     /// The turing tape (composed of integer cells)
     cells: Vec<i64>,
+    /// One past the highest cell accessed through the pointer.
+    high_water_mark: usize,
     /// The addresses of defined functions. `functions[N]` is the
     /// instruction pointer for the Nth function's code.
     functions: Vec<usize>,
@@ -48,6 +52,26 @@ where
     i: usize,
     /// Is the interpreter finished interpreting?s
     done: bool,
+    /// The number of instructions executed so far.
+    steps: u64,
+    /// The maximum number of instructions to execute before halting with an error.
+    max_steps: Option<u64>,
+    /// The number of times each kind of instruction was executed, if profiling.
+    profile: Option<BTreeMap<&'static str, usize>>,
+    /// The number of bits in a cell, which integer arithmetic wraps at.
+    cell_bits: u32,
+    /// Is overflowing a cell in integer arithmetic an error, instead of wrapping?
+    trap_overflow: bool,
+    /// Is the frame pointer convention of the assembly language checked at every call?
+    check_frames: bool,
+    /// The frame registers saved at each call, if the frames are being checked.
+    frames: Vec<Frame>,
+    /// The log of the instructions executed, if tracing.
+    trace: Option<Trace>,
+    /// The indices of the instructions to pause before executing.
+    breakpoints: BTreeSet<usize>,
+    /// Did the interpreter just pause at the breakpoint for the current instruction?
+    paused: bool,
 }
 
 impl<T> StandardInterpreter<T>
@@ -60,14 +84,118 @@ where
             pointer: 0,
             register: 0,
             cells: vec![],
+            high_water_mark: 0,
             functions: vec![],
             calls: vec![],
             refs: vec![],
             i: 0,
             done: false,
+            steps: 0,
+            max_steps: None,
+            profile: None,
+            cell_bits: 64,
+            trap_overflow: false,
+            check_frames: false,
+            frames: vec![],
+            trace: None,
+            breakpoints: BTreeSet::new(),
+            paused: false,
         }
     }
 
+    /// Limit the number of instructions the interpreter executes. If the program
+    /// hasn't halted after `max_steps` instructions, `run` returns an error.
+    pub fn with_max_steps(self, max_steps: Option<u64>) -> Self {
+        Self { max_steps, ..self }
+    }
+
+    /// Count the number of times each kind of instruction is executed.
+    /// When this isn't enabled, nothing is counted.
+    pub fn with_profile(self, enabled: bool) -> Self {
+        Self {
+            profile: enabled.then(BTreeMap::new),
+            ..self
+        }
+    }
+
+    /// Wrap the results of integer arithmetic at the given number of bits, like the cells
+    /// of a target with narrower integers. By default, cells have 64 bits.
+    pub fn with_cell_bits(self, cell_bits: u32) -> Self {
+        assert!(
+            (1..=64).contains(&cell_bits),
+            "cells must have between 1 and 64 bits"
+        );
+        Self { cell_bits, ..self }
+    }
+
+    /// Return an error when the result of `Add`, `Sub`, or `Mul` overflows a cell,
+    /// instead of wrapping it. By default, the results wrap.
+    pub fn with_trap_overflow(self, trap_overflow: bool) -> Self {
+        Self {
+            trap_overflow,
+            ..self
+        }
+    }
+
+    /// Check the frame pointer convention of the assembly language at every `Call` and
+    /// `Return`, and return an error with the index of the instruction which finds the
+    /// frame registers corrupted. Only use this for programs compiled from assembly code:
+    /// programs which deliberately move their stack frames are reported as corrupted too.
+    pub fn with_check_frames(self, check_frames: bool) -> Self {
+        Self {
+            check_frames,
+            ..self
+        }
+    }
+
+    /// Record every instruction executed (other than comments) in an execution trace,
+    /// with the register and the pointer before it. The trace is finished when `run`
+    /// returns, or when `finish_trace` is called.
+    pub fn with_trace(self, trace: Option<Trace>) -> Self {
+        Self { trace, ..self }
+    }
+
+    /// Pause before executing the instructions at the given indices in the program,
+    /// counting every instruction (including comments) from zero.
+    /// Breakpoints are only checked by `execute_until_break`.
+    pub fn with_breakpoints(self, breakpoints: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            breakpoints: breakpoints.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Wrap an integer to the width of a cell, and sign extend it.
+    fn wrap(&self, n: i64) -> i64 {
+        let shift = 64 - self.cell_bits;
+        (n << shift) >> shift
+    }
+
+    /// Compute `register <symbol> n` for an integer arithmetic instruction. The result
+    /// wraps to the width of a cell, unless overflow is trapped, where it's an error instead.
+    fn arithmetic(
+        &self,
+        symbol: char,
+        n: i64,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+    ) -> Result<i64, String> {
+        let result = self.wrap(wrapping(self.register, n));
+        if self.trap_overflow && checked(self.register, n) != Some(result) {
+            return Err(format!(
+                "Instruction #{} overflowed a {}-bit cell computing {} {symbol} {n}",
+                self.i, self.cell_bits, self.register
+            ));
+        }
+        Ok(result)
+    }
+
+    /// The number of times each kind of instruction (other than comments) was executed,
+    /// by name, if profiling was enabled with `with_profile`.
+    pub fn profile(&self) -> Option<&BTreeMap<&'static str, usize>> {
+        self.profile.as_ref()
+    }
+
     /// Fetch the current instruction pointed to in the program
     fn fetch<'a>(&self, code: &'a StandardProgram) -> Option<&'a StandardOp> {
         if self.i < code.0.len() {
@@ -240,26 +368,97 @@ where
         while self.pointer >= self.cells.len() {
             self.cells.extend(vec![0; 1000]);
         }
+        self.high_water_mark = self.high_water_mark.max(self.pointer + 1);
 
         &mut self.cells[self.pointer]
     }
 
     /// Run a core program using this interpreter and its device.
     pub fn run(mut self, code: &StandardProgram) -> Result<T, String> {
+        let result = self.execute(code);
+        self.finish_trace()?;
+        result?;
+
+        Ok(self.device)
+    }
+
+    /// Run a program without consuming the interpreter, so that its state
+    /// (like the tape) can be inspected after the program halts.
+    pub fn execute(&mut self, code: &StandardProgram) -> Result<(), String> {
         while !self.done {
             self.step(code)?
         }
+        Ok(())
+    }
 
-        Ok(self.device)
+    /// Run a program until it halts, or until it's about to execute an instruction
+    /// with a breakpoint. This returns `true` if the program paused at a breakpoint:
+    /// calling this again continues the program from there.
+    pub fn execute_until_break(&mut self, code: &StandardProgram) -> Result<bool, String> {
+        while !self.done {
+            if !self.paused && self.breakpoints.contains(&self.i) {
+                self.paused = true;
+                return Ok(true);
+            }
+            self.paused = false;
+            self.step(code)?
+        }
+        Ok(false)
+    }
+
+    /// Describe the next instruction to execute, the register, the pointer,
+    /// and the cells around the pointer. This is printed at breakpoints.
+    pub fn describe_state(&self, code: &StandardProgram) -> String {
+        super::describe_state(
+            self.i,
+            self.fetch(code).map(|op| op as &dyn std::fmt::Display),
+            self.register,
+            self.pointer,
+            &self.cells,
+        )
+    }
+
+    /// Write the end of the execution trace, if tracing. A trace limited to the last
+    /// instructions is only written here, so call this after the program halts (or fails).
+    pub fn finish_trace(&mut self) -> Result<(), String> {
+        match &mut self.trace {
+            Some(trace) => trace.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// The cells of the tape, up to the highest cell the program accessed.
+    pub fn tape(&self) -> &[i64] {
+        &self.cells[..self.high_water_mark]
     }
 
     /// Run a single step of the interpreter.
     fn step(&mut self, code: &StandardProgram) -> Result<(), String> {
         if let Some(op) = self.fetch(code) {
+            self.steps += 1;
+            if matches!(self.max_steps, Some(max_steps) if self.steps > max_steps) {
+                return Err(String::from("step limit exceeded"));
+            }
+            if let Some(profile) = &mut self.profile {
+                if !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))) {
+                    *profile.entry(op.name()).or_default() += 1;
+                }
+            }
+            if let Some(trace) = &mut self.trace {
+                if !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))) {
+                    trace.record(TraceEntry {
+                        index: self.i,
+                        opcode: op.name(),
+                        reg: self.register,
+                        ptr: self.pointer,
+                    })?;
+                }
+            }
+
             match op {
                 StandardOp::CoreOp(core_op) => match core_op {
                     CoreOp::Comment(_) => {}
-                    CoreOp::Set(n) => self.register = *n,
+                    CoreOp::Set(n) => self.register = self.wrap(*n),
                     CoreOp::Function => {
                         if !self.functions.contains(&self.i) {
                             self.functions.push(self.i);
@@ -267,8 +466,19 @@ where
                         }
                         self.jmp_to_end(code)
                     }
-                    CoreOp::Call => self.call(code)?,
-                    CoreOp::Return => self.ret(),
+                    CoreOp::Call => {
+                        if self.check_frames {
+                            let frame = save_frame(self.i, self.frames.last(), &self.cells)?;
+                            self.frames.push(frame);
+                        }
+                        self.call(code)?
+                    }
+                    CoreOp::Return => {
+                        if let Some(frame) = self.frames.pop() {
+                            check_frame(self.i, frame, &self.cells)?;
+                        }
+                        self.ret()
+                    }
                     CoreOp::While => {
                         if self.register == 0 {
                             self.jmp_to_end(code)
@@ -313,24 +523,30 @@ where
                         self.register = !(self.register & *self.get_cell());
                     }
                     CoreOp::Add => {
-                        self.register = self.register.overflowing_add(*self.get_cell()).0
+                        let n = *self.get_cell();
+                        self.register =
+                            self.arithmetic('+', n, i64::checked_add, i64::wrapping_add)?
                     }
                     CoreOp::Sub => {
-                        self.register = self.register.overflowing_sub(*self.get_cell()).0
+                        let n = *self.get_cell();
+                        self.register =
+                            self.arithmetic('-', n, i64::checked_sub, i64::wrapping_sub)?
                     }
                     CoreOp::Mul => {
-                        self.register = self.register.overflowing_mul(*self.get_cell()).0
+                        let n = *self.get_cell();
+                        self.register =
+                            self.arithmetic('*', n, i64::checked_mul, i64::wrapping_mul)?
                     }
                     CoreOp::Div => {
                         let d = *self.get_cell();
                         if d != 0 {
-                            self.register = self.register.overflowing_div(d).0
+                            self.register = self.wrap(self.register.overflowing_div(d).0)
                         }
                     }
                     CoreOp::Rem => {
                         let d = *self.get_cell();
                         if d != 0 {
-                            self.register = self.register.overflowing_rem(d).0
+                            self.register = self.wrap(self.register.overflowing_rem(d).0)
                         }
                     }
 
@@ -342,7 +558,7 @@ where
                 StandardOp::Set(n) => self.register = as_int(*n),
                 StandardOp::ToInt => {
                     // self.register = f64::from_bits(self.register as u64) as i64
-                    self.register = as_float(self.register) as i64;
+                    self.register = self.wrap(as_float(self.register) as i64);
                 }
                 StandardOp::ToFloat => {
                     // self.register = (self.register as f64).to_bits() as i64
@@ -375,7 +591,10 @@ where
                     let b = as_float(*self.get_cell());
                     self.register = as_int(a % b)
                 }
-                StandardOp::IsNonNegative => self.register = i64::from(self.register >= 0),
+                // Compare as a float like the C target, so `-0.0` is non-negative and NaN isn't.
+                StandardOp::IsNonNegative => {
+                    self.register = i64::from(as_float(self.register) >= 0.0)
+                }
                 StandardOp::Sin => self.register = as_int(as_float(self.register).sin()),
                 StandardOp::Cos => self.register = as_int(as_float(self.register).cos()),
                 StandardOp::Tan => self.register = as_int(as_float(self.register).tan()),
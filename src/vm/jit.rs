@@ -0,0 +1,934 @@
+//! # JIT Module
+//!
+//! This module implements a just-in-time compiler for the virtual machine, using
+//! Cranelift. Instead of interpreting the program one instruction at a time, the
+//! whole program is compiled to native code in memory and run in-process, which
+//! is much faster for compute-heavy programs.
+//!
+//! Every function in the program is compiled to a native function which takes a
+//! pointer to the machine's `Context`. `Call` is an indirect call through a table of
+//! the compiled functions, and `While`, `If`, and `Else` are compiled to blocks.
+//! The register and the pointer live in native registers inside of a function,
+//! and are stored in the context around calls.
+//!
+//! Input and output, the FFI, and `Alloc` go through the same `Device` and tape as
+//! the standard interpreter, by calling back into Rust. The tape grows whenever the
+//! program accesses a cell past its end, just like the interpreter's. Arithmetic wraps,
+//! and dividing by zero leaves the register unchanged, also like the interpreter.
+//! Core programs are run by converting them to standard programs.
+use crate::side_effects::{FFIBinding, Input, Output};
+use crate::vm::{as_int, CoreOp, Device, StandardOp, StandardProgram};
+
+use cranelift::prelude::*;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::mem::offset_of;
+
+/// The state of the machine which is shared between the compiled code and the
+/// functions it calls back into. The fields used by the compiled code come first.
+#[repr(C)]
+struct Context<'a> {
+    /// A pointer to the first cell of the tape.
+    tape: *mut i64,
+    /// The number of cells on the tape.
+    len: i64,
+    /// The register, while it isn't in a native register.
+    register: i64,
+    /// The pointer, while it isn't in a native register.
+    pointer: i64,
+    /// The table of compiled functions used by `Call`.
+    functions: *const *const u8,
+    /// The number of compiled functions.
+    function_count: i64,
+    /// The cells of the tape.
+    cells: Vec<i64>,
+    /// The stack of dereferences made by the program.
+    refs: Vec<i64>,
+    /// The operands of the instructions which call back into Rust.
+    operands: Operands,
+    /// The machine's I/O device.
+    device: &'a mut dyn Device,
+    /// The error which halted the program, if any.
+    error: Option<String>,
+}
+
+impl Context<'_> {
+    /// Update the pointer to the tape and its length after the cells change.
+    fn sync_tape(&mut self) {
+        self.tape = self.cells.as_mut_ptr();
+        self.len = self.cells.len() as i64;
+    }
+}
+
+/// The operands of the instructions which call back into Rust, which the
+/// compiled code refers to by their index.
+#[derive(Default)]
+struct Operands {
+    /// The inputs used by `Get` instructions.
+    inputs: Vec<Input>,
+    /// The outputs used by `Put` instructions.
+    outputs: Vec<Output>,
+    /// The foreign functions used by `Call` instructions.
+    bindings: Vec<FFIBinding>,
+}
+
+/// Grow the tape so that the given pointer is on it.
+extern "C" fn grow(ctx: &mut Context, pointer: i64) -> u8 {
+    if pointer < 0 {
+        ctx.error = Some(format!(
+            "tried to access the tape at a negative index {pointer}"
+        ));
+        return 1;
+    }
+    while pointer as usize >= ctx.cells.len() {
+        ctx.cells.extend(vec![0; 1000]);
+    }
+    ctx.sync_tape();
+    0
+}
+
+/// Get an input from the device, and store it in the register.
+extern "C" fn get(ctx: &mut Context, input: i64) -> u8 {
+    match ctx.device.get(ctx.operands.inputs[input as usize].clone()) {
+        Ok(value) => {
+            ctx.register = value;
+            0
+        }
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+/// Put a value to the device.
+extern "C" fn put(ctx: &mut Context, output: i64, value: i64) -> u8 {
+    match ctx
+        .device
+        .put(value, ctx.operands.outputs[output as usize].clone())
+    {
+        Ok(()) => 0,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+/// Push a pointer onto the stack of dereferences.
+extern "C" fn push_ref(ctx: &mut Context, pointer: i64) {
+    ctx.refs.push(pointer);
+}
+
+/// Pop a pointer from the stack of dereferences, and store it in the pointer.
+extern "C" fn pop_ref(ctx: &mut Context) -> u8 {
+    match ctx.refs.pop() {
+        Some(pointer) => {
+            ctx.pointer = pointer;
+            0
+        }
+        None => {
+            ctx.error = Some(String::from("cannot Refer due to empty Deref stack"));
+            1
+        }
+    }
+}
+
+/// Fail to call a function which isn't defined.
+extern "C" fn undefined_function(ctx: &mut Context, index: i64) -> u8 {
+    ctx.error = Some(format!("function {index} not defined"));
+    1
+}
+
+/// Allocate cells at the end of the tape, and store their address in the register.
+extern "C" fn alloc(ctx: &mut Context, size: i64) -> u8 {
+    if size < 0 {
+        ctx.error = Some(format!("cannot allocate {size} cells"));
+        return 1;
+    }
+    // Leave room for the cells used without allocating them, like the interpreter.
+    if ctx.cells.len() < 30000 {
+        ctx.cells.extend(vec![0; 30000]);
+    }
+    ctx.register = ctx.cells.len() as i64 - 1;
+    ctx.cells.extend(vec![0; size as usize]);
+    ctx.sync_tape();
+    0
+}
+
+/// Peek a value from the device's FFI channel, and store it in the register.
+extern "C" fn peek(ctx: &mut Context) -> u8 {
+    match ctx.device.peek() {
+        Ok(value) => {
+            ctx.register = value;
+            0
+        }
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+/// Poke a value to the device's FFI channel.
+extern "C" fn poke(ctx: &mut Context, value: i64) -> u8 {
+    match ctx.device.poke(value) {
+        Ok(()) => 0,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+/// Call a foreign function with the device, which may change the tape.
+extern "C" fn ffi_call(ctx: &mut Context, binding: i64) -> u8 {
+    let binding = &ctx.operands.bindings[binding as usize];
+    let result = ctx.device.ffi_call(binding, Some(&mut ctx.cells));
+    ctx.sync_tape();
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+/// The float operations which are computed by calling back into Rust,
+/// so that they give exactly the same results as the interpreter.
+#[repr(i64)]
+#[derive(Clone, Copy)]
+enum FloatOp {
+    Rem,
+    Pow,
+    Sin,
+    Cos,
+    Tan,
+    ASin,
+    ACos,
+    ATan,
+}
+
+/// Compute a float operation on the register and the cell (which unary operations ignore).
+extern "C" fn float_op(op: FloatOp, a: f64, b: f64) -> f64 {
+    match op {
+        FloatOp::Rem => a % b,
+        FloatOp::Pow => a.powf(b),
+        FloatOp::Sin => a.sin(),
+        FloatOp::Cos => a.cos(),
+        FloatOp::Tan => a.tan(),
+        FloatOp::ASin => a.asin(),
+        FloatOp::ACos => a.acos(),
+        FloatOp::ATan => a.atan(),
+    }
+}
+
+/// The functions the compiled code calls back into, imported into a compiled function.
+struct Helpers {
+    grow: codegen::ir::FuncRef,
+    get: codegen::ir::FuncRef,
+    put: codegen::ir::FuncRef,
+    push_ref: codegen::ir::FuncRef,
+    pop_ref: codegen::ir::FuncRef,
+    undefined_function: codegen::ir::FuncRef,
+    alloc: codegen::ir::FuncRef,
+    peek: codegen::ir::FuncRef,
+    poke: codegen::ir::FuncRef,
+    ffi_call: codegen::ir::FuncRef,
+    float_op: codegen::ir::FuncRef,
+}
+
+/// An open `While`, `If`, or `Else` block in the function being compiled.
+enum Scope {
+    /// A loop, with the block that checks its condition and the block after it.
+    While { header: Block, exit: Block },
+    /// A conditional, with the block for its `Else` (if it's reached), and the block after it.
+    If {
+        otherwise: Block,
+        merge: Block,
+        has_else: bool,
+    },
+}
+
+/// Compiles the instructions of a single function with a `FunctionBuilder`.
+struct FunctionTranslator<'a> {
+    builder: FunctionBuilder<'a>,
+    helpers: Helpers,
+    /// The signature of the compiled functions, for `Call`.
+    signature: codegen::ir::SigRef,
+    /// The pointer to the context.
+    ctx: Value,
+    register: Variable,
+    pointer: Variable,
+    /// The block which returns from the function after an error.
+    fail: Block,
+    operands: &'a mut Operands,
+}
+
+impl FunctionTranslator<'_> {
+    /// Load a field of the context.
+    fn load(&mut self, offset: usize) -> Value {
+        self.builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), self.ctx, offset as i32)
+    }
+
+    /// Store a value in a field of the context.
+    fn store(&mut self, offset: usize, value: Value) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.ctx, offset as i32);
+    }
+
+    /// Store the register and the pointer in the context.
+    fn spill(&mut self) {
+        let register = self.builder.use_var(self.register);
+        let pointer = self.builder.use_var(self.pointer);
+        self.store(offset_of!(Context, register), register);
+        self.store(offset_of!(Context, pointer), pointer);
+    }
+
+    /// Load the register and the pointer from the context.
+    fn reload(&mut self) {
+        let register = self.load(offset_of!(Context, register));
+        let pointer = self.load(offset_of!(Context, pointer));
+        self.builder.def_var(self.register, register);
+        self.builder.def_var(self.pointer, pointer);
+    }
+
+    /// Return from the function if a helper returned a failing status.
+    fn check(&mut self, status: Value) {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(status, self.fail, &[], next, &[]);
+        self.builder.seal_block(next);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Get the address of the cell under the pointer, growing the tape if needed.
+    fn cell(&mut self) -> Value {
+        let pointer = self.builder.use_var(self.pointer);
+        let len = self.load(offset_of!(Context, len));
+        let in_bounds = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, pointer, len);
+        let grow = self.builder.create_block();
+        let access = self.builder.create_block();
+        self.builder.ins().brif(in_bounds, access, &[], grow, &[]);
+
+        self.builder.seal_block(grow);
+        self.builder.switch_to_block(grow);
+        let call = self
+            .builder
+            .ins()
+            .call(self.helpers.grow, &[self.ctx, pointer]);
+        let status = self.builder.inst_results(call)[0];
+        self.builder.ins().brif(status, self.fail, &[], access, &[]);
+
+        self.builder.seal_block(access);
+        self.builder.switch_to_block(access);
+        let tape = self.load(offset_of!(Context, tape));
+        let offset = self.builder.ins().ishl_imm(pointer, 3);
+        self.builder.ins().iadd(tape, offset)
+    }
+
+    /// Load the value of the cell under the pointer.
+    fn read_cell(&mut self) -> Value {
+        let cell = self.cell();
+        self.builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), cell, 0)
+    }
+
+    /// Store the register and the pointer, and return a status from the function.
+    /// Any instructions after this are unreachable, so they go in a new block.
+    fn ret(&mut self, status: i64) {
+        if status == 0 {
+            self.spill();
+        }
+        let status = self.builder.ins().iconst(types::I8, status);
+        self.builder.ins().return_(&[status]);
+        let unreachable = self.builder.create_block();
+        self.builder.seal_block(unreachable);
+        self.builder.switch_to_block(unreachable);
+    }
+
+    /// Divide (or take the remainder of) the register by the cell under the pointer.
+    /// Dividing by zero leaves the register unchanged, and dividing by -1 wraps.
+    fn divide(&mut self, rem: bool) -> Value {
+        let register = self.builder.use_var(self.register);
+        let divisor = self.read_cell();
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, divisor, 0);
+        let is_negative_one = self.builder.ins().icmp_imm(IntCC::Equal, divisor, -1);
+        let unsafe_divisor = self.builder.ins().bor(is_zero, is_negative_one);
+        let one = self.builder.ins().iconst(types::I64, 1);
+        let safe_divisor = self.builder.ins().select(unsafe_divisor, one, divisor);
+        let (result, negative_one_result) = if rem {
+            let zero = self.builder.ins().iconst(types::I64, 0);
+            (self.builder.ins().srem(register, safe_divisor), zero)
+        } else {
+            let negated = self.builder.ins().ineg(register);
+            (self.builder.ins().sdiv(register, safe_divisor), negated)
+        };
+        let result = self
+            .builder
+            .ins()
+            .select(is_negative_one, negative_one_result, result);
+        self.builder.ins().select(is_zero, register, result)
+    }
+
+    /// Call a helper which returns a status and stores its result in the register.
+    fn call_into_register(&mut self, helper: codegen::ir::FuncRef, args: &[Value]) {
+        let call = self.builder.ins().call(helper, args);
+        let status = self.builder.inst_results(call)[0];
+        self.check(status);
+        let value = self.load(offset_of!(Context, register));
+        self.builder.def_var(self.register, value);
+    }
+
+    /// Reinterpret the bits of an integer as a float.
+    fn as_float(&mut self, value: Value) -> Value {
+        self.builder
+            .ins()
+            .bitcast(types::F64, MemFlags::new(), value)
+    }
+
+    /// Reinterpret the bits of a float as an integer, and store it in the register.
+    fn set_float(&mut self, value: Value) {
+        let value = self
+            .builder
+            .ins()
+            .bitcast(types::I64, MemFlags::new(), value);
+        self.builder.def_var(self.register, value);
+    }
+
+    /// Apply a binary float instruction to the register and the cell under the pointer.
+    fn float_binop(&mut self, op: fn(&mut FunctionBuilder, Value, Value) -> Value) {
+        let register = self.builder.use_var(self.register);
+        let a = self.as_float(register);
+        let cell = self.read_cell();
+        let b = self.as_float(cell);
+        let result = op(&mut self.builder, a, b);
+        self.set_float(result);
+    }
+
+    /// Compute a float operation with a helper. Only binary operations read the cell.
+    fn float_call(&mut self, op: FloatOp, binary: bool) {
+        let register = self.builder.use_var(self.register);
+        let a = self.as_float(register);
+        let b = if binary {
+            let cell = self.read_cell();
+            self.as_float(cell)
+        } else {
+            a
+        };
+        let op = self.builder.ins().iconst(types::I64, op as i64);
+        let call = self.builder.ins().call(self.helpers.float_op, &[op, a, b]);
+        let result = self.builder.inst_results(call)[0];
+        self.set_float(result);
+    }
+
+    /// Compile a single standard instruction.
+    fn translate_std(&mut self, op: &StandardOp, scopes: &mut Vec<Scope>) -> Result<(), String> {
+        let register = self.builder.use_var(self.register);
+        match op {
+            StandardOp::CoreOp(op) => return self.translate(op, scopes),
+            StandardOp::Set(n) => {
+                let value = self.builder.ins().iconst(types::I64, as_int(*n));
+                self.builder.def_var(self.register, value);
+            }
+            // Like Rust's `as`, this saturates, and turns NaN into zero.
+            StandardOp::ToInt => {
+                let value = self.as_float(register);
+                let value = self.builder.ins().fcvt_to_sint_sat(types::I64, value);
+                self.builder.def_var(self.register, value);
+            }
+            StandardOp::ToFloat => {
+                let value = self.builder.ins().fcvt_from_sint(types::F64, register);
+                self.set_float(value);
+            }
+            StandardOp::Add => self.float_binop(|builder, a, b| builder.ins().fadd(a, b)),
+            StandardOp::Sub => self.float_binop(|builder, a, b| builder.ins().fsub(a, b)),
+            StandardOp::Mul => self.float_binop(|builder, a, b| builder.ins().fmul(a, b)),
+            StandardOp::Div => self.float_binop(|builder, a, b| builder.ins().fdiv(a, b)),
+            StandardOp::Rem => self.float_call(FloatOp::Rem, true),
+            StandardOp::Pow => self.float_call(FloatOp::Pow, true),
+            StandardOp::Sin => self.float_call(FloatOp::Sin, false),
+            StandardOp::Cos => self.float_call(FloatOp::Cos, false),
+            StandardOp::Tan => self.float_call(FloatOp::Tan, false),
+            StandardOp::ASin => self.float_call(FloatOp::ASin, false),
+            StandardOp::ACos => self.float_call(FloatOp::ACos, false),
+            StandardOp::ATan => self.float_call(FloatOp::ATan, false),
+            // Compare as a float, so `-0.0` is non-negative and NaN isn't.
+            StandardOp::IsNonNegative => {
+                let a = self.as_float(register);
+                let zero = self.builder.ins().f64const(0.0);
+                let result = self
+                    .builder
+                    .ins()
+                    .fcmp(FloatCC::GreaterThanOrEqual, a, zero);
+                let result = self.builder.ins().uextend(types::I64, result);
+                self.builder.def_var(self.register, result);
+            }
+            StandardOp::Alloc => {
+                self.call_into_register(self.helpers.alloc, &[self.ctx, register]);
+            }
+            StandardOp::Free => {}
+            StandardOp::Peek => self.call_into_register(self.helpers.peek, &[self.ctx]),
+            StandardOp::Poke => {
+                let call = self
+                    .builder
+                    .ins()
+                    .call(self.helpers.poke, &[self.ctx, register]);
+                let status = self.builder.inst_results(call)[0];
+                self.check(status);
+            }
+            StandardOp::Call(binding) => {
+                let index = self
+                    .builder
+                    .ins()
+                    .iconst(types::I64, self.operands.bindings.len() as i64);
+                self.operands.bindings.push(binding.clone());
+                let call = self
+                    .builder
+                    .ins()
+                    .call(self.helpers.ffi_call, &[self.ctx, index]);
+                let status = self.builder.inst_results(call)[0];
+                self.check(status);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile a single core instruction.
+    fn translate(&mut self, op: &CoreOp, scopes: &mut Vec<Scope>) -> Result<(), String> {
+        let register = self.builder.use_var(self.register);
+        match op {
+            CoreOp::Comment(_) => {}
+            CoreOp::Set(n) => {
+                let value = self.builder.ins().iconst(types::I64, *n);
+                self.builder.def_var(self.register, value);
+            }
+            CoreOp::Function => return Err(String::from("unexpected nested function")),
+            CoreOp::Call => {
+                // Check that the function is defined.
+                let count = self.load(offset_of!(Context, function_count));
+                let defined = self
+                    .builder
+                    .ins()
+                    .icmp(IntCC::UnsignedLessThan, register, count);
+                let undefined = self.builder.create_block();
+                let call = self.builder.create_block();
+                self.builder.ins().brif(defined, call, &[], undefined, &[]);
+                self.builder.seal_block(undefined);
+                self.builder.switch_to_block(undefined);
+                self.builder
+                    .ins()
+                    .call(self.helpers.undefined_function, &[self.ctx, register]);
+                self.builder.ins().jump(self.fail, &[]);
+
+                // Look up the function in the table and call it.
+                self.builder.seal_block(call);
+                self.builder.switch_to_block(call);
+                self.spill();
+                let table = self.load(offset_of!(Context, functions));
+                let offset = self.builder.ins().ishl_imm(register, 3);
+                let entry = self.builder.ins().iadd(table, offset);
+                let function = self
+                    .builder
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), entry, 0);
+                let call = self
+                    .builder
+                    .ins()
+                    .call_indirect(self.signature, function, &[self.ctx]);
+                let status = self.builder.inst_results(call)[0];
+                self.check(status);
+                self.reload();
+            }
+            CoreOp::Return => self.ret(0),
+            CoreOp::While => {
+                let header = self.builder.create_block();
+                let body = self.builder.create_block();
+                let exit = self.builder.create_block();
+                self.builder.ins().jump(header, &[]);
+                self.builder.switch_to_block(header);
+                let register = self.builder.use_var(self.register);
+                self.builder.ins().brif(register, body, &[], exit, &[]);
+                self.builder.seal_block(body);
+                self.builder.switch_to_block(body);
+                scopes.push(Scope::While { header, exit });
+            }
+            CoreOp::If => {
+                let then = self.builder.create_block();
+                let otherwise = self.builder.create_block();
+                let merge = self.builder.create_block();
+                self.builder.ins().brif(register, then, &[], otherwise, &[]);
+                self.builder.seal_block(then);
+                self.builder.seal_block(otherwise);
+                self.builder.switch_to_block(then);
+                scopes.push(Scope::If {
+                    otherwise,
+                    merge,
+                    has_else: false,
+                });
+            }
+            CoreOp::Else => match scopes.last_mut() {
+                Some(Scope::If {
+                    otherwise,
+                    merge,
+                    has_else: has_else @ false,
+                }) => {
+                    *has_else = true;
+                    self.builder.ins().jump(*merge, &[]);
+                    self.builder.switch_to_block(*otherwise);
+                }
+                _ => return Err(String::from("unexpected else")),
+            },
+            CoreOp::End => match scopes.pop() {
+                Some(Scope::While { header, exit }) => {
+                    self.builder.ins().jump(header, &[]);
+                    self.builder.seal_block(header);
+                    self.builder.seal_block(exit);
+                    self.builder.switch_to_block(exit);
+                }
+                Some(Scope::If {
+                    otherwise,
+                    merge,
+                    has_else,
+                }) => {
+                    self.builder.ins().jump(merge, &[]);
+                    if !has_else {
+                        self.builder.switch_to_block(otherwise);
+                        self.builder.ins().jump(merge, &[]);
+                    }
+                    self.builder.seal_block(merge);
+                    self.builder.switch_to_block(merge);
+                }
+                None => return Err(String::from("unexpected end")),
+            },
+            CoreOp::Save => {
+                let cell = self.cell();
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), register, cell, 0);
+            }
+            CoreOp::Restore => {
+                let value = self.read_cell();
+                self.builder.def_var(self.register, value);
+            }
+            CoreOp::Move(n) => {
+                let pointer = self.builder.use_var(self.pointer);
+                let pointer = self.builder.ins().iadd_imm(pointer, *n as i64);
+                self.builder.def_var(self.pointer, pointer);
+            }
+            CoreOp::Where => {
+                let pointer = self.builder.use_var(self.pointer);
+                self.builder.def_var(self.register, pointer);
+            }
+            CoreOp::Deref => {
+                let pointer = self.builder.use_var(self.pointer);
+                self.builder
+                    .ins()
+                    .call(self.helpers.push_ref, &[self.ctx, pointer]);
+                let value = self.read_cell();
+                self.builder.def_var(self.pointer, value);
+            }
+            CoreOp::Refer => {
+                let call = self.builder.ins().call(self.helpers.pop_ref, &[self.ctx]);
+                let status = self.builder.inst_results(call)[0];
+                self.check(status);
+                let pointer = self.load(offset_of!(Context, pointer));
+                self.builder.def_var(self.pointer, pointer);
+            }
+            CoreOp::Index | CoreOp::Add => {
+                let value = self.read_cell();
+                let result = self.builder.ins().iadd(register, value);
+                self.builder.def_var(self.register, result);
+            }
+            CoreOp::Sub => {
+                let value = self.read_cell();
+                let result = self.builder.ins().isub(register, value);
+                self.builder.def_var(self.register, result);
+            }
+            CoreOp::Mul => {
+                let value = self.read_cell();
+                let result = self.builder.ins().imul(register, value);
+                self.builder.def_var(self.register, result);
+            }
+            CoreOp::BitwiseNand => {
+                let value = self.read_cell();
+                let result = self.builder.ins().band(register, value);
+                let result = self.builder.ins().bnot(result);
+                self.builder.def_var(self.register, result);
+            }
+            CoreOp::Div | CoreOp::Rem => {
+                let result = self.divide(matches!(op, CoreOp::Rem));
+                self.builder.def_var(self.register, result);
+            }
+            CoreOp::IsNonNegative => {
+                let result =
+                    self.builder
+                        .ins()
+                        .icmp_imm(IntCC::SignedGreaterThanOrEqual, register, 0);
+                let result = self.builder.ins().uextend(types::I64, result);
+                self.builder.def_var(self.register, result);
+            }
+            CoreOp::Get(input) => {
+                let index = self
+                    .builder
+                    .ins()
+                    .iconst(types::I64, self.operands.inputs.len() as i64);
+                self.operands.inputs.push(input.clone());
+                self.call_into_register(self.helpers.get, &[self.ctx, index]);
+            }
+            CoreOp::Put(output) => {
+                let index = self
+                    .builder
+                    .ins()
+                    .iconst(types::I64, self.operands.outputs.len() as i64);
+                self.operands.outputs.push(output.clone());
+                let call = self
+                    .builder
+                    .ins()
+                    .call(self.helpers.put, &[self.ctx, index, register]);
+                let status = self.builder.inst_results(call)[0];
+                self.check(status);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A just-in-time compiler which runs virtual machine programs as native code.
+pub struct Jit<T>
+where
+    T: Device,
+{
+    /// The JIT's I/O device.
+    device: T,
+    /// The turing tape (composed of integer cells)
+    cells: Vec<i64>,
+}
+
+impl<T> Jit<T>
+where
+    T: Device,
+{
+    pub fn new(device: T) -> Self {
+        Self {
+            device,
+            cells: vec![],
+        }
+    }
+
+    /// Compile and run a program using this device. Core programs can be
+    /// run by converting them to standard programs with `into`.
+    pub fn run(mut self, code: &StandardProgram) -> Result<T, String> {
+        self.execute(code)?;
+        Ok(self.device)
+    }
+
+    /// Compile and run a program without consuming the JIT, so that its state
+    /// (like the tape) can be inspected after the program halts.
+    pub fn execute(&mut self, code: &StandardProgram) -> Result<(), String> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|e| e.to_string())?;
+        // Verifying the generated code takes most of the compile time for large programs.
+        flags
+            .set("enable_verifier", "false")
+            .map_err(|e| e.to_string())?;
+        let isa = cranelift_native::builder()
+            .map_err(String::from)?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| e.to_string())?;
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("sage_grow", grow as *const u8);
+        builder.symbol("sage_get", get as *const u8);
+        builder.symbol("sage_put", put as *const u8);
+        builder.symbol("sage_push_ref", push_ref as *const u8);
+        builder.symbol("sage_pop_ref", pop_ref as *const u8);
+        builder.symbol("sage_undefined_function", undefined_function as *const u8);
+        builder.symbol("sage_alloc", alloc as *const u8);
+        builder.symbol("sage_peek", peek as *const u8);
+        builder.symbol("sage_poke", poke as *const u8);
+        builder.symbol("sage_ffi_call", ffi_call as *const u8);
+        builder.symbol("sage_float_op", float_op as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let result = Self::compile(&mut module, code).and_then(|(main, functions, operands)| {
+            let functions = functions
+                .into_iter()
+                .map(|id| module.get_finalized_function(id))
+                .collect::<Vec<_>>();
+            let mut ctx = Context {
+                tape: self.cells.as_mut_ptr(),
+                len: self.cells.len() as i64,
+                register: 0,
+                pointer: 0,
+                functions: functions.as_ptr(),
+                function_count: functions.len() as i64,
+                cells: std::mem::take(&mut self.cells),
+                refs: vec![],
+                operands,
+                device: &mut self.device,
+                error: None,
+            };
+            // SAFETY: the function was compiled with this signature, and it only
+            // accesses the tape after checking that the pointer is on it.
+            let main: extern "C" fn(&mut Context) -> u8 =
+                unsafe { std::mem::transmute(module.get_finalized_function(main)) };
+            let status = main(&mut ctx);
+            self.cells = std::mem::take(&mut ctx.cells);
+            match ctx.error {
+                Some(e) if status != 0 => Err(e),
+                _ => Ok(()),
+            }
+        });
+        // SAFETY: none of the compiled functions are used after this.
+        unsafe { module.free_memory() };
+        result
+    }
+
+    /// The cells of the tape.
+    pub fn tape(&self) -> &[i64] {
+        &self.cells
+    }
+
+    /// Compile every function in the program, and the code outside of them.
+    /// Returns the compiled main code and functions, and the operands they use.
+    fn compile(
+        module: &mut JITModule,
+        code: &StandardProgram,
+    ) -> Result<(FuncId, Vec<FuncId>, Operands), String> {
+        let pointer_type = module.target_config().pointer_type();
+        // Every compiled function takes the context, and returns a nonzero status on an error.
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer_type));
+        signature.returns.push(AbiParam::new(types::I8));
+
+        let mut helper = |name: &str, params: &[Type], returns: &[Type]| {
+            let mut signature = module.make_signature();
+            signature
+                .params
+                .extend(params.iter().map(|param| AbiParam::new(*param)));
+            signature
+                .returns
+                .extend(returns.iter().map(|ret| AbiParam::new(*ret)));
+            module
+                .declare_function(name, Linkage::Import, &signature)
+                .map_err(|e| e.to_string())
+        };
+        let (ctx, int, status, float) = (pointer_type, types::I64, types::I8, types::F64);
+        let helper_ids = [
+            helper("sage_grow", &[ctx, int], &[status])?,
+            helper("sage_get", &[ctx, int], &[status])?,
+            helper("sage_put", &[ctx, int, int], &[status])?,
+            helper("sage_push_ref", &[ctx, int], &[])?,
+            helper("sage_pop_ref", &[ctx], &[status])?,
+            helper("sage_undefined_function", &[ctx, int], &[status])?,
+            helper("sage_alloc", &[ctx, int], &[status])?,
+            helper("sage_peek", &[ctx], &[status])?,
+            helper("sage_poke", &[ctx, int], &[status])?,
+            helper("sage_ffi_call", &[ctx, int], &[status])?,
+            helper("sage_float_op", &[int, float, float], &[float])?,
+        ];
+
+        let (main_ops, mut function_defs) = code.clone().get_main_and_functions();
+        let mut bodies = vec![];
+        let mut ids = vec![];
+        for i in 0..function_defs.len() as i32 {
+            let body = function_defs
+                .remove(&i)
+                .ok_or_else(|| format!("function {i} not defined"))?;
+            ids.push(
+                module
+                    .declare_function(&format!("f{i}"), Linkage::Local, &signature)
+                    .map_err(|e| e.to_string())?,
+            );
+            bodies.push(body);
+        }
+        let main = module
+            .declare_function("main", Linkage::Local, &signature)
+            .map_err(|e| e.to_string())?;
+
+        let mut operands = Operands::default();
+        let mut ctx = module.make_context();
+        let mut builder_ctx = FunctionBuilderContext::new();
+        for (id, body) in ids
+            .iter()
+            .chain([&main])
+            .zip(bodies.iter().chain([&main_ops]))
+        {
+            ctx.func.signature = signature.clone();
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+            let [grow, get, put, push_ref, pop_ref, undefined_function, alloc, peek, poke, ffi_call, float_op] =
+                helper_ids.map(|id| module.declare_func_in_func(id, builder.func));
+            let signature = builder.import_signature(signature.clone());
+
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            builder.seal_block(entry);
+            let fail = builder.create_block();
+            let register = Variable::new(0);
+            let pointer = Variable::new(1);
+            builder.declare_var(register, types::I64);
+            builder.declare_var(pointer, types::I64);
+
+            let mut translator = FunctionTranslator {
+                ctx: builder.block_params(entry)[0],
+                builder,
+                helpers: Helpers {
+                    grow,
+                    get,
+                    put,
+                    push_ref,
+                    pop_ref,
+                    undefined_function,
+                    alloc,
+                    peek,
+                    poke,
+                    ffi_call,
+                    float_op,
+                },
+                signature,
+                register,
+                pointer,
+                fail,
+                operands: &mut operands,
+            };
+            translator.reload();
+            let mut scopes = vec![];
+            // The code for functions includes their `Function` and `End` instructions.
+            let ops = match body.as_slice() {
+                [StandardOp::CoreOp(CoreOp::Function), ops @ .., StandardOp::CoreOp(CoreOp::End)] => {
+                    ops
+                }
+                ops => ops,
+            };
+            for op in ops {
+                translator.translate_std(op, &mut scopes)?;
+            }
+            if !scopes.is_empty() {
+                return Err(String::from("unterminated block"));
+            }
+            // Falling off the end of a function returns from it.
+            translator.ret(0);
+            translator.builder.ins().jump(fail, &[]);
+            translator.builder.switch_to_block(fail);
+            let status = translator.builder.ins().iconst(types::I8, 1);
+            translator.builder.ins().return_(&[status]);
+            translator.builder.seal_all_blocks();
+            translator.builder.finalize();
+
+            module
+                .define_function(*id, &mut ctx)
+                .map_err(|e| format!("{e:?}"))?;
+            module.clear_context(&mut ctx);
+        }
+        module.finalize_definitions().map_err(|e| e.to_string())?;
+        Ok((main, ids, operands))
+    }
+}
@@ -65,6 +65,15 @@ pub use self::std::*;
 mod interpreter;
 pub use interpreter::*;
 
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(feature = "jit")]
+pub use jit::*;
+
+mod bytecode;
+
+mod optimize;
+
 /// An error generated by the virtual machine.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Error {
@@ -74,6 +83,17 @@ pub enum Error {
     /// When the virtual machine attempts to get the program as core,
     /// but finds standard instructions, this error is triggered.
     ExpectedCore(StandardOp),
+    /// When an `End` instruction at the given index has no block to close,
+    /// this error is triggered.
+    UnmatchedEnd(usize),
+    /// When an `Else` instruction at the given index does not directly
+    /// follow the body of an `If`, this error is triggered.
+    UnexpectedElse(usize),
+    /// When a `Function`, `While`, `If`, or `Else` block starting at the
+    /// given index is never closed with an `End`, this error is triggered.
+    UnterminatedBlock(CoreOp, usize),
+    /// When bytecode is malformed at the given byte offset, this error is triggered.
+    InvalidBytecode(usize, String),
 }
 
 impl Display for Error {
@@ -81,10 +101,46 @@ impl Display for Error {
         match self {
             Self::UnsupportedInstruction(op) => write!(f, "Unsupported instruction: {}", op),
             Self::ExpectedCore(op) => write!(f, "Expected core instruction, found: {}", op),
+            Self::UnmatchedEnd(i) => write!(f, "Unmatched `end` at instruction {}", i),
+            Self::UnexpectedElse(i) => write!(f, "Unexpected `else` at instruction {}", i),
+            Self::UnterminatedBlock(op, i) => {
+                write!(f, "Unterminated `{}` at instruction {}", op, i)
+            }
+            Self::InvalidBytecode(offset, e) => {
+                write!(f, "Invalid bytecode at byte {}: {}", offset, e)
+            }
         }
     }
 }
 
+/// Check that the `Function`, `While`, `If`, `Else`, and `End` instructions
+/// in a program are balanced. Instructions which cannot open or close a block
+/// are passed as `None`.
+fn validate_blocks<'a>(ops: impl IntoIterator<Item = Option<&'a CoreOp>>) -> Result<(), Error> {
+    // The block instructions which are still open, and their indices.
+    let mut blocks: Vec<(&CoreOp, usize)> = vec![];
+    for (i, op) in ops.into_iter().enumerate() {
+        match op {
+            Some(op @ (CoreOp::Function | CoreOp::While | CoreOp::If)) => blocks.push((op, i)),
+            Some(CoreOp::Else) => match blocks.last_mut() {
+                // An `Else` closes the body of an `If` and opens its own block.
+                Some(block @ (CoreOp::If, _)) => *block = (&CoreOp::Else, i),
+                _ => return Err(Error::UnexpectedElse(i)),
+            },
+            Some(CoreOp::End) => {
+                blocks.pop().ok_or(Error::UnmatchedEnd(i))?;
+            }
+            _ => {}
+        }
+    }
+
+    match blocks.pop() {
+        // Report the innermost block that was left open.
+        Some((op, i)) => Err(Error::UnterminatedBlock(op.clone(), i)),
+        None => Ok(()),
+    }
+}
+
 /// An interface to conveniently create virtual machine programs,
 /// of either the core or standard variant.
 pub trait VirtualMachineProgram {
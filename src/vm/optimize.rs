@@ -0,0 +1,401 @@
+//! Optimization passes over virtual machine programs.
+//!
+//! These passes rewrite a program into an equivalent program with fewer
+//! instructions. They're run before the program is handed to a target,
+//! so every target benefits from them.
+//!
+//! ## Optimization Levels
+//!
+//! The passes are grouped into levels, which are run by `optimize`:
+//!
+//! - `0` runs no passes.
+//! - `1` runs the cheap local passes, which only compare neighboring instructions:
+//!   `optimize_moves` and `eliminate_redundant_saves`.
+//! - `2` runs every pass. Before the passes of level `1`, `remove_unreachable`
+//!   drops the code after each `Return` in its block, and `fold_constants`
+//!   follows the values of the register and the current cell to evaluate
+//!   arithmetic at compile time.
+//!
+//! Levels above `2` are the same as `2`.
+use super::{CoreOp, CoreProgram, StandardOp, StandardProgram};
+use crate::parse::SourceCodeLocation;
+
+impl CoreProgram {
+    /// Run the optimization passes for an optimization level on the program.
+    pub fn optimize(self, level: u8) -> Self {
+        self.optimize_with_cell_bits(level, 64)
+    }
+
+    /// Run the optimization passes for an optimization level on the program,
+    /// which will run with cells of the given number of bits.
+    pub fn optimize_with_cell_bits(self, level: u8, cell_bits: u32) -> Self {
+        match level {
+            0 => self,
+            1 => self.optimize_moves().eliminate_redundant_saves(),
+            _ => self
+                .remove_unreachable()
+                .fold_constants_with_cell_bits(cell_bits)
+                .optimize(1),
+        }
+    }
+
+    /// Merge consecutive `Move` instructions into a single `Move`,
+    /// and remove any moves that cancel out.
+    pub fn optimize_moves(self) -> Self {
+        Self(coalesce_moves(
+            self.0,
+            |op| match op {
+                CoreOp::Move(n) => Some(*n),
+                _ => None,
+            },
+            CoreOp::Move,
+        ))
+    }
+
+    /// Remove `Save` and `Restore` instructions which don't change anything,
+    /// because the register already holds the value in the current cell.
+    pub fn eliminate_redundant_saves(self) -> Self {
+        Self(remove_redundant_saves(self.0, |op| Some(op)))
+    }
+
+    /// Remove all of the comments from the program.
+    pub fn strip_comments(self) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .filter(|op| !matches!(op, CoreOp::Comment(_)))
+                .collect(),
+        )
+    }
+
+    /// Replace the line markers left by the compiler with `file:line` comments,
+    /// which say where in the source code the instructions after them came from.
+    pub fn annotate_source(self) -> Self {
+        Self(annotate_source(
+            self.0,
+            |op| match op {
+                CoreOp::Comment(comment) => Some(comment),
+                _ => None,
+            },
+            CoreOp::Comment,
+        ))
+    }
+
+    /// Replace arithmetic on constant operands with a `Set` of the result.
+    pub fn fold_constants(self) -> Self {
+        self.fold_constants_with_cell_bits(64)
+    }
+
+    /// Replace arithmetic on constant operands with a `Set` of the result, unless the
+    /// operands or the result don't fit in a cell of the given number of bits.
+    /// Those are left for the target to wrap or to report as an overflow.
+    pub fn fold_constants_with_cell_bits(self, cell_bits: u32) -> Self {
+        Self(fold_constants(
+            self.0,
+            cell_bits,
+            |op| Some(op),
+            CoreOp::Set,
+        ))
+    }
+
+    /// Remove the instructions after a `Return` which can never run,
+    /// up to the `End` or `Else` of the enclosing block.
+    pub fn remove_unreachable(self) -> Self {
+        Self(remove_unreachable(self.0, |op| Some(op)))
+    }
+}
+
+impl StandardProgram {
+    /// Run the optimization passes for an optimization level on the program.
+    pub fn optimize(self, level: u8) -> Self {
+        self.optimize_with_cell_bits(level, 64)
+    }
+
+    /// Run the optimization passes for an optimization level on the program,
+    /// which will run with cells of the given number of bits.
+    pub fn optimize_with_cell_bits(self, level: u8, cell_bits: u32) -> Self {
+        match level {
+            0 => self,
+            1 => self.optimize_moves().eliminate_redundant_saves(),
+            _ => self
+                .remove_unreachable()
+                .fold_constants_with_cell_bits(cell_bits)
+                .optimize(1),
+        }
+    }
+
+    /// Merge consecutive `Move` instructions into a single `Move`,
+    /// and remove any moves that cancel out.
+    pub fn optimize_moves(self) -> Self {
+        Self(coalesce_moves(
+            self.0,
+            |op| match op {
+                StandardOp::CoreOp(CoreOp::Move(n)) => Some(*n),
+                _ => None,
+            },
+            |n| StandardOp::CoreOp(CoreOp::Move(n)),
+        ))
+    }
+
+    /// Remove `Save` and `Restore` instructions which don't change anything,
+    /// because the register already holds the value in the current cell.
+    pub fn eliminate_redundant_saves(self) -> Self {
+        Self(remove_redundant_saves(self.0, |op| match op {
+            StandardOp::CoreOp(op) => Some(op),
+            _ => None,
+        }))
+    }
+
+    /// Remove all of the comments from the program.
+    pub fn strip_comments(self) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .filter(|op| !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))))
+                .collect(),
+        )
+    }
+
+    /// Replace the line markers left by the compiler with `file:line` comments,
+    /// which say where in the source code the instructions after them came from.
+    pub fn annotate_source(self) -> Self {
+        Self(annotate_source(
+            self.0,
+            |op| match op {
+                StandardOp::CoreOp(CoreOp::Comment(comment)) => Some(comment),
+                _ => None,
+            },
+            |comment| StandardOp::CoreOp(CoreOp::Comment(comment)),
+        ))
+    }
+
+    /// Replace arithmetic on constant operands with a `Set` of the result.
+    pub fn fold_constants(self) -> Self {
+        self.fold_constants_with_cell_bits(64)
+    }
+
+    /// Replace arithmetic on constant operands with a `Set` of the result, unless the
+    /// operands or the result don't fit in a cell of the given number of bits.
+    /// Those are left for the target to wrap or to report as an overflow.
+    pub fn fold_constants_with_cell_bits(self, cell_bits: u32) -> Self {
+        Self(fold_constants(
+            self.0,
+            cell_bits,
+            |op| match op {
+                StandardOp::CoreOp(op) => Some(op),
+                _ => None,
+            },
+            |n| StandardOp::CoreOp(CoreOp::Set(n)),
+        ))
+    }
+
+    /// Remove the instructions after a `Return` which can never run,
+    /// up to the `End` or `Else` of the enclosing block.
+    pub fn remove_unreachable(self) -> Self {
+        Self(remove_unreachable(self.0, |op| match op {
+            StandardOp::CoreOp(op) => Some(op),
+            _ => None,
+        }))
+    }
+}
+
+/// Rewrite the line markers in a list of instructions as `file:line` comments.
+/// A marker for the same line as the marker before it is dropped, since the
+/// statements nested in a statement on one line all mark it. `as_comment` gets
+/// the text of a comment instruction, and `make_comment` creates one.
+fn annotate_source<T>(
+    code: Vec<T>,
+    as_comment: impl Fn(&T) -> Option<&String>,
+    make_comment: impl Fn(String) -> T,
+) -> Vec<T> {
+    let mut result = Vec::with_capacity(code.len());
+    let mut last_marker = None;
+    for op in code {
+        let marker = as_comment(&op).and_then(|c| SourceCodeLocation::parse_line_marker(c));
+        match marker {
+            Some((line, filename)) => {
+                let comment = format!("{filename}:{line}");
+                if last_marker.as_ref() != Some(&comment) {
+                    last_marker = Some(comment.clone());
+                    result.push(make_comment(comment));
+                }
+            }
+            None => result.push(op),
+        }
+    }
+    result
+}
+
+/// Merge runs of adjacent moves in a list of instructions.
+/// `as_move` gets the distance of a move instruction, and `make_move` creates one.
+fn coalesce_moves<T>(
+    code: Vec<T>,
+    as_move: impl Fn(&T) -> Option<isize>,
+    make_move: impl Fn(isize) -> T,
+) -> Vec<T> {
+    let mut result: Vec<T> = Vec::with_capacity(code.len());
+    for op in code {
+        let n = match as_move(&op) {
+            Some(n) => n,
+            None => {
+                result.push(op);
+                continue;
+            }
+        };
+
+        // Fold this move into the previous instruction if it's also a move.
+        let n = match result.last().and_then(&as_move) {
+            Some(m) => {
+                result.pop();
+                m + n
+            }
+            None => n,
+        };
+        if n != 0 {
+            result.push(make_move(n));
+        }
+    }
+    result
+}
+
+/// Remove the `Save` and `Restore` instructions in a list of instructions that
+/// happen while the register is known to equal the current cell.
+/// `as_core` gets the core instruction of an instruction, if it is one.
+/// Any other instructions are assumed to change the register.
+fn remove_redundant_saves<T>(code: Vec<T>, as_core: fn(&T) -> Option<&CoreOp>) -> Vec<T> {
+    // Whether the register is known to hold the value of the current cell.
+    let mut in_sync = false;
+    let mut result = Vec::with_capacity(code.len());
+    for op in code {
+        match as_core(&op) {
+            // Saving or restoring only syncs the register and the cell.
+            Some(CoreOp::Save | CoreOp::Restore) if in_sync => continue,
+            Some(CoreOp::Save | CoreOp::Restore) => in_sync = true,
+            // These don't touch the register, the pointer, or the tape.
+            Some(CoreOp::Comment(_) | CoreOp::Put(_)) => {}
+            // Anything else might change them, or be jumped to from elsewhere.
+            _ => in_sync = false,
+        }
+        result.push(op);
+    }
+    result
+}
+
+/// Evaluate the arithmetic in a list of instructions whose operands are known constants.
+/// `as_core` gets the core instruction of an instruction, if it is one, and `make_set`
+/// creates a `Set` instruction. Any other instructions are assumed to change the register
+/// and the tape. Values which don't fit in a cell of `cell_bits` bits are never folded,
+/// so the program still wraps or traps on them the same way when it runs.
+fn fold_constants<T>(
+    code: Vec<T>,
+    cell_bits: u32,
+    as_core: fn(&T) -> Option<&CoreOp>,
+    make_set: impl Fn(i64) -> T,
+) -> Vec<T> {
+    // Whether a value is unchanged by wrapping it to the width of a cell.
+    let fits = |n: &i64| {
+        let shift = 64 - cell_bits;
+        (n << shift) >> shift == *n
+    };
+    // The known values of the register and of the current cell.
+    let mut register: Option<i64> = None;
+    let mut cell: Option<i64> = None;
+    let mut result = Vec::with_capacity(code.len());
+    for op in code {
+        let core = as_core(&op);
+        // The result of the instruction, if it's arithmetic on known operands.
+        let folded = match (core, register, cell) {
+            (Some(CoreOp::IsNonNegative), Some(a), _) => Some(i64::from(a >= 0)),
+            (Some(CoreOp::BitwiseNand), Some(a), Some(b)) => Some(!(a & b)),
+            (Some(CoreOp::Add), Some(a), Some(b)) => a.checked_add(b),
+            (Some(CoreOp::Sub), Some(a), Some(b)) => a.checked_sub(b),
+            (Some(CoreOp::Mul), Some(a), Some(b)) => a.checked_mul(b),
+            // Division by zero is left to the target to handle.
+            (Some(CoreOp::Div), Some(a), Some(b)) if b != 0 => a.checked_div(b),
+            (Some(CoreOp::Rem), Some(a), Some(b)) if b != 0 => a.checked_rem(b),
+            _ => None,
+        }
+        .filter(fits);
+
+        if let Some(n) = folded {
+            // The register is overwritten here, so a `Set` right before this is dead.
+            if matches!(result.last().and_then(as_core), Some(CoreOp::Set(_))) {
+                result.pop();
+            }
+            register = Some(n);
+            result.push(make_set(n));
+            continue;
+        }
+
+        match core {
+            Some(CoreOp::Set(n)) => register = Some(*n).filter(fits),
+            Some(CoreOp::Save) => cell = register,
+            Some(CoreOp::Restore) => register = cell,
+            Some(CoreOp::Comment(_) | CoreOp::Put(_)) => {}
+            // Arithmetic only changes the register.
+            Some(
+                CoreOp::IsNonNegative
+                | CoreOp::BitwiseNand
+                | CoreOp::Add
+                | CoreOp::Sub
+                | CoreOp::Mul
+                | CoreOp::Div
+                | CoreOp::Rem,
+            ) => register = None,
+            // Anything else might change the register or the tape, or be jumped to from elsewhere.
+            _ => {
+                register = None;
+                cell = None;
+            }
+        }
+        result.push(op);
+    }
+    result
+}
+
+/// Remove the instructions in a list of instructions between a `Return` and the
+/// `End` or `Else` of the block it's in. `as_core` gets the core instruction of an
+/// instruction, if it is one. Functions defined in the removed code are kept, since
+/// they can still be called, and removing them would renumber the functions after them.
+fn remove_unreachable<T>(code: Vec<T>, as_core: fn(&T) -> Option<&CoreOp>) -> Vec<T> {
+    // For each function being defined, the number of blocks open in its reachable code,
+    // and the number of blocks open in its unreachable code, if we're in unreachable code.
+    let mut functions: Vec<(usize, Option<usize>)> = vec![(0, None)];
+    let mut result = Vec::with_capacity(code.len());
+    for op in code {
+        // Whether we're in a function, so an `End` at depth zero ends it.
+        let in_function = functions.len() > 1;
+        let (open, unreachable) = functions.last_mut().unwrap();
+        match (*unreachable, as_core(&op)) {
+            (_, Some(CoreOp::Function)) => functions.push((0, None)),
+            (None, Some(CoreOp::Return)) => *unreachable = Some(0),
+            (None, Some(CoreOp::If | CoreOp::While)) => *open += 1,
+            (None, Some(CoreOp::End)) if *open == 0 && in_function => {
+                functions.pop();
+            }
+            (None, Some(CoreOp::End)) => *open = open.saturating_sub(1),
+            (None, _) => {}
+            (Some(depth), Some(CoreOp::If | CoreOp::While)) => {
+                *unreachable = Some(depth + 1);
+                continue;
+            }
+            // The end of the block with the `Return` can be reached from elsewhere.
+            (Some(0), Some(CoreOp::Else)) => *unreachable = None,
+            (Some(0), Some(CoreOp::End)) => {
+                *unreachable = None;
+                if *open == 0 && in_function {
+                    functions.pop();
+                } else {
+                    *open = open.saturating_sub(1);
+                }
+            }
+            (Some(depth), Some(CoreOp::End)) => {
+                *unreachable = Some(depth - 1);
+                continue;
+            }
+            (Some(_), _) => continue,
+        }
+        result.push(op);
+    }
+    result
+}
@@ -35,10 +35,12 @@
 //! This way, a developer can write a program in such a manner that user input
 //! cannot be confused with custom encoded instructions sent to and from the I/O device
 //! using `Put` and `Get`.
-use super::{CoreOp, CoreProgram, Error, VirtualMachineProgram};
+use super::{
+    validate_blocks, Commented, CoreOp, CoreProgram, Error, Listing, VirtualMachineProgram,
+};
 use crate::side_effects::*;
 use core::fmt;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 impl VirtualMachineProgram for StandardProgram {
     fn op(&mut self, op: CoreOp) {
@@ -68,8 +70,15 @@ impl VirtualMachineProgram for StandardProgram {
 pub struct StandardProgram(pub Vec<StandardOp>);
 
 impl StandardProgram {
-    /// Flatten a core program so that all of its functions
-    /// are defined sequentially at the beginning.
+    /// Flatten a standard program so that all of its functions are defined
+    /// sequentially at the beginning, followed by the code outside of any functions.
+    ///
+    /// Functions nested in other functions or blocks are moved out of them. The functions
+    /// stay in the order they're declared in, so each one keeps its index for `Call`,
+    /// and the instructions in each function and in the rest of the code stay in order.
+    /// Each `If`, `Else`, and `While` stays in the same function as its `End`, so the
+    /// flattened code of a valid program is valid too: this is what the targets rely on
+    /// when they compile the functions and the main code separately.
     pub fn flatten(self) -> Self {
         Self(flatten(self.0).0)
     }
@@ -79,7 +88,8 @@ impl StandardProgram {
         flatten(self.0.clone()).2
     }
 
-    /// Get the code for each function.
+    /// Get the code for each function, by its index. The functions are numbered
+    /// from zero in the order they're declared in, like `flatten` defines them.
     pub fn get_functions(&self) -> HashMap<i32, Vec<StandardOp>> {
         flatten(self.0.clone()).1
     }
@@ -89,6 +99,39 @@ impl StandardProgram {
         let (_, functions, main) = flatten(self.0);
         (main, functions)
     }
+
+    /// Check that every `Function`, `While`, `If`, and `Else` block
+    /// in the program is properly closed with an `End`.
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_blocks(self.0.iter().map(|op| match op {
+            StandardOp::CoreOp(op) => Some(op),
+            _ => None,
+        }))
+    }
+
+    /// Print the program as a listing, with the index of every instruction before it.
+    /// Nested blocks are indented by the width of the formatter, like the program itself.
+    pub fn listing(&self) -> Listing<'_, Self> {
+        Listing(self)
+    }
+
+    /// Print the program with its comments, which the plain form leaves out.
+    /// The comments are skipped when the code is parsed, so it can still be read back in.
+    pub fn commented(&self) -> Commented<'_, Self> {
+        Commented(self)
+    }
+
+    /// Count the number of times each instruction is used in the program, by name.
+    /// Comments are not counted.
+    pub fn op_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for op in &self.0 {
+            if !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))) {
+                *counts.entry(op.name()).or_default() += 1;
+            }
+        }
+        counts
+    }
 }
 
 /// Take all of the functions defined in a list of StandardOps,
@@ -107,6 +150,9 @@ fn flatten(
 
     // The current function body we are in.
     let mut fun = -1;
+    // The index of the next function declared. Functions are numbered in the
+    // order they're declared in, however deeply they're nested.
+    let mut next_fun = 0;
     // Keep track of when we end the current function,
     // instead of just an if-else-conditional or a while loop.
     // This is essentially the number of end statements remaining before
@@ -138,12 +184,8 @@ fn flatten(
                     // Reset the matching-end counter for the new scope.
                     matching_end = 0;
                     // Start defining the next function.
-                    fun += 1;
-                    // If that function is already defined,
-                    // just go past the last function defined.
-                    if functions.contains_key(&fun) {
-                        fun = functions.len() as i32
-                    }
+                    fun = next_fun;
+                    next_fun += 1;
                 }
                 CoreOp::If | CoreOp::While => {
                     // Increment the number of matching `End`
@@ -195,23 +237,56 @@ fn flatten(
     (result, result_functions, main_instructions)
 }
 
+/// Nested blocks are indented by the width of the formatter in spaces (three by default),
+/// like the core variant.
 impl fmt::Display for StandardProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_code(f, false, false)
+    }
+}
+
+impl fmt::Display for Commented<'_, StandardProgram> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_code(f, false, true)
+    }
+}
+
+impl fmt::Display for Listing<'_, StandardProgram> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_code(f, true, true)
+    }
+}
+
+impl StandardProgram {
+    /// Write the instructions of the program, with the index of each instruction before it
+    /// if `listing` is set, or with its address in the alternate form if it isn't.
+    /// Comments are only written in a listing, in the alternate form, or if `comments` is set.
+    fn fmt_code(&self, f: &mut fmt::Formatter, listing: bool, comments: bool) -> fmt::Result {
         let mut comment_count = 0;
         let mut indent = 0;
+        let tab = " ".repeat(f.width().unwrap_or(3));
         for (i, op) in self.0.iter().enumerate() {
-            if f.alternate() {
+            if listing {
+                write!(f, "{i:04}: ")?;
+                if let StandardOp::CoreOp(CoreOp::Comment(comment)) = op {
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment)?;
+                    continue;
+                }
+            } else if f.alternate() {
                 if let StandardOp::CoreOp(CoreOp::Comment(comment)) = op {
                     if f.alternate() {
                         write!(f, "{:8}  ", "")?;
                     }
                     comment_count += 1;
-                    writeln!(f, "{}// {}", "   ".repeat(indent), comment,)?;
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment,)?;
                     continue;
                 }
 
                 write!(f, "{:08x?}: ", i - comment_count)?;
-            } else if let StandardOp::CoreOp(CoreOp::Comment(_)) = op {
+            } else if let StandardOp::CoreOp(CoreOp::Comment(comment)) = op {
+                if comments {
+                    writeln!(f, "{}// {}", tab.repeat(indent), comment)?;
+                }
                 continue;
             }
 
@@ -221,16 +296,16 @@ impl fmt::Display for StandardProgram {
                 match op {
                     StandardOp::CoreOp(CoreOp::Function | CoreOp::If | CoreOp::While) => {
                         indent += 1;
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     StandardOp::CoreOp(CoreOp::Else) => {
-                        "   ".repeat(indent - 1)
+                        tab.repeat(indent - 1)
                     }
                     StandardOp::CoreOp(CoreOp::End) => {
                         indent -= 1;
-                        "   ".repeat(indent)
+                        tab.repeat(indent)
                     }
-                    _ => "   ".repeat(indent),
+                    _ => tab.repeat(indent),
                 },
                 op
             )?
@@ -312,6 +387,37 @@ pub enum StandardOp {
     Call(FFIBinding),
 }
 
+impl StandardOp {
+    /// The name of the instruction, as it's written in the textual VM code.
+    /// Foreign function calls are named `call-ffi`, to tell them apart from core calls.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StandardOp::CoreOp(op) => op.name(),
+            StandardOp::Set(_) => "set-f",
+            StandardOp::Alloc => "alloc",
+            StandardOp::Free => "free",
+            StandardOp::ToInt => "to-int",
+            StandardOp::ToFloat => "to-float",
+            StandardOp::Add => "add-f",
+            StandardOp::Sub => "sub-f",
+            StandardOp::Mul => "mul-f",
+            StandardOp::Div => "div-f",
+            StandardOp::Rem => "rem-f",
+            StandardOp::IsNonNegative => "gez-f",
+            StandardOp::Sin => "sin",
+            StandardOp::Cos => "cos",
+            StandardOp::Tan => "tan",
+            StandardOp::ASin => "asin",
+            StandardOp::ACos => "acos",
+            StandardOp::ATan => "atan",
+            StandardOp::Pow => "pow",
+            StandardOp::Peek => "peek",
+            StandardOp::Poke => "poke",
+            StandardOp::Call(_) => "call-ffi",
+        }
+    }
+}
+
 impl fmt::Display for StandardOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
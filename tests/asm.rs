@@ -2,7 +2,7 @@ use sage::{
     asm::*,
     parse::parse_asm,
     side_effects::{Input, Output},
-    vm::{CoreInterpreter, TestingDevice},
+    vm::{CoreInterpreter, StandardInterpreter, TestingDevice},
 };
 
 #[test]
@@ -365,3 +365,614 @@ fn test_factorial() {
 
     assert_eq!(&device.output_str(), "3628800")
 }
+
+#[test]
+fn test_bitwise() {
+    use CoreOp::*;
+
+    let program = CoreProgram::new(vec![
+        Set(A, 0b1100),
+        Set(B, 0b1010),
+        BitwiseAnd { src: B, dst: A },
+        Put(A, Output::stdout_char()),
+        Set(A, 0b1100),
+        BitwiseOr { src: B, dst: A },
+        Put(A, Output::stdout_char()),
+        Set(A, 0b1100),
+        BitwiseXor { src: B, dst: A },
+        Put(A, Output::stdout_char()),
+        // The source is left unchanged.
+        Put(B, Output::stdout_char()),
+        Set(A, -1),
+        Set(B, 0x0f0f),
+        BitwiseXor { src: B, dst: A },
+        Put(A, Output::stdout_char()),
+    ])
+    .assemble(32)
+    .unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(
+        device.output_vals(),
+        vec![0b1000, 0b1110, 0b0110, 0b1010, !0x0f0f]
+    );
+}
+
+#[test]
+fn test_negate() {
+    use CoreOp::*;
+
+    let program = CoreProgram::new(vec![
+        Set(A, 5),
+        Neg(A),
+        Put(A, Output::stdout_char()),
+        Neg(A),
+        Put(A, Output::stdout_char()),
+        Set(A, 0),
+        Neg(A),
+        Put(A, Output::stdout_char()),
+        BitwiseNot(A),
+        Put(A, Output::stdout_char()),
+        Set(A, 0b1010),
+        BitwiseNot(A),
+        Put(A, Output::stdout_char()),
+    ])
+    .assemble(32)
+    .unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_vals(), vec![-5, 5, 0, -1, !0b1010]);
+}
+
+#[test]
+fn test_shifts() {
+    // The shift amounts are read from the input, so they're only known at runtime.
+    let shifts = r#"
+    get-int B
+    set A, 3
+    shift-left B, A
+    put-char A
+
+    get-int B
+    set A, 100
+    shift-right B, A
+    put-char A
+    set A, -100
+    shift-right B, A
+    put-char A
+
+    get-int B
+    set A, -1
+    logical-shift-right B, A
+    put-char A
+    set A, -1
+    shift-right B, A
+    put-char A
+
+    set A, 7
+    set B, 0
+    shift-left B, A
+    shift-right B, A
+    put-char A
+    "#;
+
+    let asm_core = parse_asm(shifts).unwrap().unwrap();
+    let vm_code = asm_core.assemble(32).unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::new("4 3 60"))
+        .run(&vm_code)
+        .unwrap();
+
+    assert_eq!(
+        device.output_vals(),
+        vec![
+            3 << 4,
+            100 >> 3,
+            -100 >> 3,
+            (-1i64 as u64 >> 60) as i64,
+            -1,
+            7
+        ]
+    );
+}
+
+#[test]
+fn test_min_max() {
+    let min_max = r#"
+    fun @bounds
+        copy A, C
+        min B, C
+        put-int C
+        put-char D
+        copy A, C
+        max B, C
+        put-int C
+        put-char D
+    end
+
+    set D, 32
+    set A, 3 set B, 5
+    call @bounds
+    set A, 5 set B, 3
+    call @bounds
+    set A, -4 set B, -4
+    call @bounds
+    set A, -7 set B, 2
+    call @bounds
+    "#;
+
+    let asm_core = parse_asm(min_max).unwrap().unwrap();
+    let vm_code = asm_core.assemble(32).unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&vm_code)
+        .unwrap();
+
+    assert_eq!(device.output_str(), "3 5 3 5 -4 -4 -7 2 ");
+}
+
+#[test]
+fn test_equality() {
+    let equality = r#"
+    fun @check
+        eq A, B, C
+        put-int C
+        neq A, B, C
+        put-int C
+        cmp A, B, C
+        put-int C
+        put-char D
+    end
+
+    set D, 32
+    set A, -3 set B, -3
+    call @check
+    set A, -3 set B, 3
+    call @check
+    set A, 3 set B, -3
+    call @check
+    set A, -5 set B, -2
+    call @check
+    set A, 0 set B, 0
+    call @check
+    "#;
+
+    let asm_core = parse_asm(equality).unwrap().unwrap();
+    let vm_code = asm_core.assemble(32).unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&vm_code)
+        .unwrap();
+
+    assert_eq!(device.output_str(), "100 01-1 011 01-1 100 ");
+}
+
+#[test]
+fn test_swap() {
+    use CoreOp::*;
+
+    let program = CoreProgram::new(vec![
+        Set(A, 'a' as i64),
+        Set(B, 'b' as i64),
+        Swap(A, B),
+        Put(A, Output::stdout_char()),
+        Put(B, Output::stdout_char()),
+        // Swap a cell on the stack with a register.
+        Push(A, 1),
+        Set(C, 'c' as i64),
+        Swap(SP.deref(), C),
+        Pop(Some(A), 1),
+        Put(A, Output::stdout_char()),
+        Put(C, Output::stdout_char()),
+    ])
+    .assemble(32)
+    .unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "bacb");
+}
+
+#[test]
+fn test_copy_range() {
+    use CoreOp::*;
+
+    let mut ops = vec![GetAddress {
+        addr: SP.deref().offset(1),
+        dst: A,
+    }];
+    // Push the region [1, 2, 3, 4, 5, 0, 0, 0, 0, 0] onto the stack.
+    for val in [1, 2, 3, 4, 5, 0, 0, 0, 0, 0] {
+        ops.push(Set(C, val));
+        ops.push(Push(C, 1));
+    }
+    ops.extend([
+        GetAddress {
+            addr: A.deref().offset(5),
+            dst: B,
+        },
+        // Copy the first half of the region into the second half.
+        CopyRange {
+            src: A,
+            dst: B,
+            size: 5,
+        },
+        // Copy an overlapping range one cell backwards.
+        GetAddress {
+            addr: A.deref().offset(1),
+            dst: C,
+        },
+        CopyRange {
+            src: C,
+            dst: A,
+            size: 5,
+        },
+    ]);
+    for i in 0..10 {
+        ops.push(Put(A.deref().offset(i), Output::stdout_char()));
+    }
+    // The pointers are left unchanged.
+    ops.push(Put(B.deref(), Output::stdout_char()));
+    ops.push(Put(C.deref(), Output::stdout_char()));
+
+    let program = CoreProgram::new(ops).assemble(32).unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(
+        device.output_vals(),
+        vec![2, 3, 4, 5, 1, 1, 2, 3, 4, 5, 1, 3]
+    );
+}
+
+#[test]
+fn test_fill_range() {
+    let mut fill = String::from(
+        r#"
+    lea [SP + 1], A
+    fill-range A, 7, 10
+    "#,
+    );
+    // Print the filled cells, and the cell just past them.
+    for i in 0..11 {
+        fill += &format!("put-char [A + {i}]\n");
+    }
+
+    let asm_core = parse_asm(fill).unwrap().unwrap();
+    let vm_code = asm_core.assemble(32).unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&vm_code)
+        .unwrap();
+
+    assert_eq!(device.output_vals(), vec![7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 0]);
+}
+
+#[test]
+fn test_pow() {
+    let pow = r#"
+    fun @raise
+        pow-int B, A
+        put-int A
+        put-char D
+    end
+
+    set D, 32
+    set A, 2 set B, 0
+    call @raise
+    set A, 2 set B, 10
+    call @raise
+    set A, 0 set B, 0
+    call @raise
+    set A, -2 set B, 3
+    call @raise
+    set A, 5 set B, -1
+    call @raise
+    "#;
+
+    let asm_core = parse_asm(pow).unwrap().unwrap();
+    let vm_code = asm_core.assemble(32).unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&vm_code)
+        .unwrap();
+
+    assert_eq!(device.output_str(), "1 1024 1 -8 1 ");
+}
+
+#[test]
+fn test_offset_folding() {
+    use CoreOp::*;
+
+    let nested = Location::Offset(Box::new(Location::Address(8)), -2);
+    assert_eq!(nested.offset(2), Location::Address(8));
+    assert_eq!(nested.offset(3).fold(), Location::Address(9));
+    assert_eq!(
+        Location::Offset(Box::new(SP.deref().offset(2)), -2).fold(),
+        SP.deref()
+    );
+
+    // Chained offsets compile to a single pointer movement.
+    let assemble = |src: Location| {
+        CoreProgram::new(vec![Add { src, dst: A }])
+            .assemble(32)
+            .unwrap()
+            .0
+    };
+    assert_eq!(
+        assemble(Location::Offset(Box::new(nested.clone()), 3)),
+        assemble(Location::Address(9))
+    );
+    assert_eq!(
+        assemble(Location::Offset(Box::new(nested), 2)),
+        assemble(Location::Address(8))
+    );
+}
+
+#[test]
+fn test_call_stack_size() {
+    // A chain of 100 functions, each of which calls the next.
+    let mut chain = String::new();
+    for i in (0..100).rev() {
+        chain += &format!("fun @f{i}\n");
+        chain += &match i {
+            99 => "set A, 42 put-int A\n".to_string(),
+            _ => format!("call @f{}\n", i + 1),
+        };
+        chain += "end\n";
+    }
+    chain += "call @f0\n";
+    let asm_core = parse_asm(chain).unwrap().unwrap();
+
+    // The first cell of the call stack is never used.
+    assert_eq!(
+        asm_core.assemble(100).err(),
+        Some(Error::CallStackTooSmall(101, 100))
+    );
+    let vm_code = asm_core.assemble(101).unwrap();
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&vm_code)
+        .unwrap();
+    assert_eq!(&device.output_str(), "42");
+
+    // Recursive calls can't be checked while assembling.
+    let factorial = r#"
+    fun @fact
+        if [FP]
+            call @fact
+        end
+    end
+    set A, 10 push A
+    call @fact
+    "#;
+    let asm_core = parse_asm(factorial).unwrap().unwrap();
+    assert_eq!(
+        asm_core.assemble(1).err(),
+        Some(Error::CallStackTooSmall(2, 1))
+    );
+    assert!(asm_core.assemble(2).is_ok());
+}
+
+#[test]
+fn test_pointer_lint() {
+    let code = r#"
+    lea [SP + 1], A
+    mov A, B
+    inc B
+    next A
+    dec A
+    push A
+    set C, 2
+    add C, [SP]
+    // Once overwritten with an integer, the register isn't a pointer anymore.
+    set A, 0
+    inc A
+    // We can't tell what a function returns.
+    lea [SP], A
+    call @f
+    inc A
+    fun @f
+        ret
+    end
+    "#;
+
+    let lints = match parse_asm(code).unwrap() {
+        Ok(core) => core.lint(),
+        Err(std) => std.lint(),
+    };
+    let found = lints
+        .iter()
+        .map(|lint| (lint.index, lint.op.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![(2, "inc B"), (4, "dec A"), (7, "add C, [SP]")]);
+    assert!(lints[0].message.contains("use `next` instead"));
+    assert!(lints[1].message.contains("use `prev` instead"));
+
+    // Memory from the allocator holds a pointer too.
+    let code = "set A, 4 alloc A add-f B, A";
+    let lints = parse_asm(code).unwrap().unwrap_err().lint();
+    assert_eq!(lints.len(), 1);
+    assert_eq!(lints[0].index, 2);
+}
+
+#[test]
+fn test_scratch_registers() {
+    use CoreOp::*;
+
+    // Recurse three times, so that three frame pointers are saved. Without any
+    // reserved registers, these would overwrite the first three scratch cells.
+    let mut program = CoreProgram::new(vec![
+        Fn("f".to_string()),
+        Dec(A),
+        If(A),
+        CallLabel("f".to_string()),
+        End,
+        Return,
+        End,
+        Set(scratch(0), 'x' as i64),
+        Set(scratch(1), 'y' as i64),
+        Set(scratch(2), 'z' as i64),
+        Set(A, 'a' as i64),
+        Push(A, 1),
+        Set(A, 3),
+        CallLabel("f".to_string()),
+        Pop(Some(B), 1),
+        Put(scratch(0), Output::stdout_char()),
+        Put(scratch(1), Output::stdout_char()),
+        Put(scratch(2), Output::stdout_char()),
+        Put(B, Output::stdout_char()),
+    ]);
+    program.reserve_registers(3);
+    // Reserving fewer registers afterwards keeps the ones already reserved.
+    program.reserve_registers(1);
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program.clone().assemble(32).unwrap())
+        .unwrap();
+    assert_eq!(device.output_str(), "xyza");
+
+    // The registers stay reserved when the program is converted to a standard program.
+    let device = StandardInterpreter::new(TestingDevice::default())
+        .run(&StandardProgram::from(program).assemble(32).unwrap())
+        .unwrap();
+    assert_eq!(device.output_str(), "xyza");
+}
+
+#[test]
+fn test_check_frames() {
+    use CoreOp::*;
+
+    // Without any reserved registers, `scratch(1)` is where the first call
+    // saves the caller's frame pointer, so writing to it corrupts the frame.
+    let program = |body: Vec<CoreOp>| {
+        let mut code = vec![Fn("g".to_string()), End, Fn("f".to_string())];
+        code.push(Set(scratch(1), 99));
+        code.extend(body);
+        code.extend([End, CallLabel("f".to_string()), Set(A, 'a' as i64)]);
+        code.push(Put(A, Output::stdout_char()));
+        CoreProgram::new(code)
+    };
+    let run = |program: &CoreProgram| {
+        let vm_code = program.clone().assemble(32).unwrap();
+        CoreInterpreter::new(TestingDevice::default())
+            .with_check_frames(true)
+            .run(&vm_code)
+            .map(|device| device.output_str())
+    };
+
+    let err = run(&program(vec![])).unwrap_err();
+    assert!(
+        err.contains("returned from the call at instruction #"),
+        "{err}"
+    );
+    assert!(err.contains("`FP` should be restored to"), "{err}");
+
+    let err = run(&program(vec![CallLabel("g".to_string())])).unwrap_err();
+    assert!(
+        err.contains("made a call with a corrupted frame pointer stack"),
+        "{err}"
+    );
+
+    // Without checking the frames, the corrupted frame pointer goes unnoticed here.
+    let vm_code = program(vec![]).assemble(32).unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&vm_code)
+        .unwrap();
+    assert_eq!(device.output_str(), "a");
+
+    // Once the register is reserved, the frames are intact.
+    for body in [vec![], vec![CallLabel("g".to_string())]] {
+        let mut program = program(body);
+        program.reserve_registers(2);
+        assert_eq!(run(&program).unwrap(), "a");
+    }
+}
+
+#[test]
+fn test_scratch_cells() {
+    use sage::vm::{self, VirtualMachineProgram};
+
+    // The loop counter is cell 0, which the pointer stays on between the helpers.
+    let one = Location::Address(1);
+    let (largest, smallest, x) = (
+        Location::Address(2),
+        Location::Address(3),
+        Location::Address(4),
+    );
+    let (scratch_a, scratch_b) = (Location::Address(5), Location::Address(6));
+
+    let mut program = vm::CoreProgram(vec![]);
+    for (cell, value) in [(0, 3), (1, 1), (2, 0), (3, 100), (4, 5)] {
+        program.move_pointer(cell);
+        program.set_register(value);
+        program.save();
+        program.move_pointer(-cell);
+    }
+    // Loop three times, using the counter as the caller's own scratch cell.
+    program.restore();
+    program.begin_while();
+    // Each helper gets a scratch cell of its own, so none of them
+    // clobber each other's cells or the counter.
+    largest.max_using(&x, &scratch_a, &mut program);
+    smallest.min_using(&x, &scratch_b, &mut program);
+    x.shift_left_using(&one, &scratch_a, &mut program);
+    x.bitwise_xor_using(&one, &scratch_b, &mut program);
+    // counter -= 1
+    program.restore();
+    program.move_pointer(1);
+    program.op(vm::CoreOp::Sub);
+    program.move_pointer(-1);
+    program.save();
+    program.end();
+    for cell in [2, 3, 4] {
+        program.move_pointer(cell);
+        program.restore();
+        program.put(Output::stdout_int());
+        program.set_register(' ' as i64);
+        program.put(Output::stdout_char());
+        program.move_pointer(-cell);
+    }
+
+    // x goes from 5 to 11, 23, and then 47.
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "23 5 47 ");
+
+    // The swapped cells are exchanged, and the scratch cell holds the first value.
+    let mut program = vm::CoreProgram(vec![]);
+    for (cell, value) in [(0, 1), (1, 2)] {
+        program.move_pointer(cell);
+        program.set_register(value);
+        program.save();
+        program.move_pointer(-cell);
+    }
+    Location::Address(0).swap_using(&Location::Address(1), &Location::Address(2), &mut program);
+    let mut vm = CoreInterpreter::new(TestingDevice::default());
+    vm.execute(&program).unwrap();
+    assert_eq!(vm.tape(), &[2, 1, 1]);
+
+    // Fill cells 3 and 4, and copy them to cells 5 and 6, counting in cell 2.
+    let (src, dst, scratch) = (
+        Location::Address(0),
+        Location::Address(1),
+        Location::Address(2),
+    );
+    let mut program = vm::CoreProgram(vec![]);
+    for (cell, value) in [(0, 3), (1, 5)] {
+        program.move_pointer(cell);
+        program.set_register(value);
+        program.save();
+        program.move_pointer(-cell);
+    }
+    src.fill_range_using(7, 2, &scratch, &mut program);
+    src.copy_range_using(&dst, 2, &scratch, &mut program);
+    let mut vm = CoreInterpreter::new(TestingDevice::default());
+    vm.execute(&program).unwrap();
+    assert_eq!(vm.tape(), &[3, 5, 0, 7, 7, 7, 7]);
+}
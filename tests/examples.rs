@@ -1,6 +1,11 @@
-use sage::{lir::Compile, parse::*, vm::*};
+use sage::{
+    compile::{self, compile_files_to_vm, SourceFile, SourceType},
+    lir::Compile,
+    parse::*,
+    vm::*,
+};
 use std::{
-    fs::{read_dir, read_to_string},
+    fs::{create_dir_all, read_dir, read_to_string, write},
     path::PathBuf,
 };
 
@@ -323,3 +328,512 @@ fn test_asm_examples_helper() {
         }
     }
 }
+
+#[test]
+fn test_frontend_multiple_files() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_multiple_files_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_multiple_files_helper() {
+    let lib = "def square(x: Int): Int = x * x;\n";
+    let main = "println(square(3) + square(4));\n";
+
+    // The declarations of the earlier files are visible in the later ones.
+    let vm_code = parse_frontend_files([(lib, Some("lib.sg")), (main, Some("main.sg"))])
+        .unwrap()
+        .compile()
+        .unwrap()
+        .unwrap()
+        .assemble(CALL_STACK_SIZE)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&vm_code)
+        .unwrap();
+    assert_eq!(device.output_str(), "25\n");
+
+    // Declaring the same name in two files is an error which names both files.
+    let err = parse_frontend_files([(lib, Some("lib.sg")), (lib, Some("other.sg"))]).unwrap_err();
+    assert!(err.contains("`square`"));
+    assert!(err.contains("lib.sg") && err.contains("other.sg"));
+
+    // Syntax errors say which file they're in.
+    let bad = "let x = ;";
+    let err = parse_frontend_files([(lib, Some("lib.sg")), (bad, Some("bad.sg"))]).unwrap_err();
+    assert!(err.starts_with("in `bad.sg`"));
+}
+
+#[test]
+fn test_frontend_include() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_include_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_include_helper() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("include");
+    create_dir_all(dir.join("lib")).unwrap();
+    let write_file = |name: &str, code: &str| {
+        let path = dir.join(name);
+        write(&path, code).unwrap();
+        path.to_string_lossy().into_owned()
+    };
+    let compile = |path: &str| {
+        let code = read_to_string(path).unwrap();
+        parse_frontend_files([(code.as_str(), Some(path))])
+    };
+
+    // Included paths are relative to the including file, and a file
+    // included more than once only has its declarations added once.
+    write_file("util.sg", "def id(x: Int): Int = x;\n");
+    write_file(
+        "lib/math.sg",
+        "include \"../util.sg\";\ndef square(x: Int): Int = id(x) * x;\n",
+    );
+    let main = write_file(
+        "main.sg",
+        "include \"lib/math.sg\";\ninclude \"util.sg\";\nprintln(square(5));\n",
+    );
+    let vm_code = compile(&main)
+        .unwrap()
+        .compile()
+        .unwrap()
+        .unwrap()
+        .assemble(CALL_STACK_SIZE)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&vm_code)
+        .unwrap();
+    assert_eq!(device.output_str(), "25\n");
+
+    // Files which include each other are an error.
+    write_file("a.sg", "include \"b.sg\";\n");
+    let b = write_file("b.sg", "include \"a.sg\";\n");
+    let err = compile(&b).unwrap_err();
+    assert!(err.contains("cyclic include"));
+
+    // So are missing files, and syntax errors name the included file.
+    let missing = write_file("missing.sg", "include \"nope.sg\";\n");
+    assert!(compile(&missing).unwrap_err().contains("nope.sg"));
+    write_file("bad.sg", "let x = ;\n");
+    let includes_bad = write_file("includes-bad.sg", "include \"bad.sg\";\n");
+    assert!(compile(&includes_bad).unwrap_err().contains("bad.sg`"));
+
+    // Files which aren't next to the including file are searched for in the
+    // include directories, in order, after the directory of the including file.
+    create_dir_all(dir.join("first")).unwrap();
+    create_dir_all(dir.join("second")).unwrap();
+    write_file("first/greet.sg", "def greet() { println(1); }\n");
+    write_file("second/greet.sg", "def greet() { println(2); }\n");
+    write_file("second/other.sg", "def other() { println(3); }\n");
+    let code = "include \"greet.sg\";\ninclude \"other.sg\";\ngreet();\nother();\n";
+    let uses_dirs = write_file("uses-dirs.sg", code);
+    let include_dirs = [dir.join("first"), dir.join("second")];
+    let compile_with_dirs = |include_dirs: &[PathBuf]| {
+        parse_frontend_files_with_include_dirs(
+            [(code, Some(uses_dirs.as_str()))],
+            None,
+            include_dirs,
+        )
+    };
+    let vm_code = compile_with_dirs(&include_dirs)
+        .unwrap()
+        .compile()
+        .unwrap()
+        .unwrap()
+        .assemble(CALL_STACK_SIZE)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&vm_code)
+        .unwrap();
+    assert_eq!(device.output_str(), "1\n3\n");
+
+    // When a file can't be found, every directory searched is listed.
+    let err = compile_with_dirs(&include_dirs[..1]).unwrap_err();
+    assert!(err.contains("could not find `other.sg`"), "{err}");
+    assert!(err.contains(&format!("`{}`", dir.display())), "{err}");
+    assert!(
+        err.contains(&format!("`{}`", include_dirs[0].display())),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_frontend_all_errors() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_all_errors_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_all_errors_helper() {
+    let code = r#"
+def f(x: Int): Int {
+    return x + true;
+}
+
+def g(): Bool {
+    let y: Bool = 5;
+    return y;
+}
+
+println(f(1) + undefined_thing);
+"#;
+    let expr = parse_frontend_files([(code, Some("errors.sg"))]).unwrap();
+
+    // The errors in each procedure and in the body are all reported.
+    let errors = expr.clone().compile_all_errors().unwrap_err();
+    assert_eq!(errors.len(), 3);
+    let messages = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert!(messages[0].contains("true"));
+    assert!(messages[1].contains("mismatched types"));
+    assert!(messages[2].contains("undefined_thing"));
+
+    // `compile` still stops at the first error it finds.
+    assert!(messages.contains(&expr.compile().unwrap_err().to_string()));
+}
+
+#[test]
+fn test_frontend_redefinition() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_redefinition_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_redefinition_helper() {
+    let code = "def twice(x: Int): Int = x * 2;\n\ndef twice(x: Int): Int {\n    return x + x;\n}\n\nprintln(twice(4));\n";
+    let expr = parse_frontend_files([(code, Some("twice.sg"))]).unwrap();
+
+    // The error names the symbol, and says where it was first defined.
+    let err = expr.compile().unwrap_err().to_string();
+    assert!(err.contains("symbol twice redefined"), "{err}");
+    assert!(err.contains("twice.sg:1:5"), "{err}");
+
+    // The builtins can still be shadowed by the program's own definitions.
+    let code = "def free(x: &Int) { println(1); }\nfree(Null);\n";
+    let vm_code = parse_frontend_files([(code, Some("free.sg"))])
+        .unwrap()
+        .compile()
+        .unwrap()
+        .unwrap()
+        .assemble(CALL_STACK_SIZE)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&vm_code)
+        .unwrap();
+    assert_eq!(device.output_str(), "1\n");
+}
+
+#[test]
+fn test_frontend_entry() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_entry_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_entry_helper() {
+    let code = r#"
+let static mut COUNT: Int = 5;
+def first() { println("first"); }
+def second() {
+    COUNT += 1;
+    println(COUNT);
+}
+def add(a: Int, b: Int): Int = a + b;
+println("top level");
+"#;
+    let run = |entry| {
+        let vm_code = parse_frontend_files_with_entry([(code, Some("entry.sg"))], entry)
+            .unwrap()
+            .compile()
+            .unwrap()
+            .unwrap()
+            .assemble(CALL_STACK_SIZE)
+            .unwrap();
+        CoreInterpreter::new(TestingDevice::default())
+            .run(&vm_code)
+            .unwrap()
+            .output_str()
+    };
+
+    // The top-level statements are replaced by a call to the entry point,
+    // but the static variables it uses are still declared.
+    assert_eq!(run(None), "top level\n");
+    assert_eq!(run(Some("first")), "first\n");
+    assert_eq!(run(Some("second")), "6\n");
+
+    // Missing entry points list the procedures which could be used instead.
+    let err =
+        parse_frontend_files_with_entry([(code, Some("entry.sg"))], Some("main")).unwrap_err();
+    assert!(err.contains("`main`"));
+    assert!(err.contains("`first`, `second`"));
+    let err = parse_frontend_files_with_entry([(code, Some("entry.sg"))], Some("add")).unwrap_err();
+    assert!(err.contains("takes parameters"));
+}
+
+#[test]
+fn test_frontend_tail_call() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_tail_call_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_tail_call_helper() {
+    // Without reusing the frame, the calls to `count` overflow the call stack
+    // and overwrite `CALLS`.
+    let code = r#"
+let static mut CALLS: Int = 0;
+def count(n: Int, acc: Int): Int {
+    CALLS += 1;
+    if n == 0 {
+        return acc;
+    }
+    return count(n - 1, acc + 1);
+}
+def sum(n: Int): Int {
+    if n == 0 { return 0; }
+    return n + sum(n - 1);
+}
+// The frame can't be reused while an argument may point into it.
+def f(n: Int, p: &Int): Int {
+    let x = n * 10;
+    if n == 0 { return *p; }
+    return f(n - 1, &x);
+}
+let y = 7;
+println(count(100000, 0), " ", CALLS, " ", sum(100), " ", f(1, &y));
+"#;
+    let vm_code = parse_frontend_files([(code, Some("tail.sg"))])
+        .unwrap()
+        .compile()
+        .unwrap()
+        .unwrap()
+        .assemble(CALL_STACK_SIZE)
+        .unwrap();
+    let output = CoreInterpreter::new(TestingDevice::default())
+        .run(&vm_code)
+        .unwrap()
+        .output_str();
+    assert_eq!(output, "100000 100001 5050 10\n");
+}
+
+#[test]
+fn test_compile_to_vm() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_compile_to_vm_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_compile_to_vm_helper() {
+    let run = |vm_code: Result<CoreProgram, StandardProgram>| match vm_code {
+        Ok(core) => CoreInterpreter::new(TestingDevice::default())
+            .run(&core)
+            .unwrap()
+            .output_str(),
+        Err(std) => StandardInterpreter::new(TestingDevice::default())
+            .run(&std)
+            .unwrap()
+            .output_str(),
+    };
+
+    let vm_code = sage::compile_to_vm("println(6 * 7);", SourceType::Sage, CALL_STACK_SIZE);
+    assert_eq!(run(vm_code.unwrap()), "42\n");
+    let vm_code = sage::compile_to_vm("set 65\nput stdout.char", SourceType::CoreVM, 0);
+    assert_eq!(run(vm_code.unwrap()), "A");
+
+    // Every phase of the compilation is reported.
+    let sources = [
+        SourceFile {
+            name: Some("lib.sg".to_string()),
+            contents: b"def main() { println(\"main\"); }".to_vec(),
+        },
+        SourceFile {
+            name: Some("main.sg".to_string()),
+            contents: b"println(\"top level\");".to_vec(),
+        },
+    ];
+    let mut phases = vec![];
+    let vm_code = compile_files_to_vm(
+        &sources,
+        SourceType::Sage,
+        Some("main"),
+        &[],
+        CALL_STACK_SIZE,
+        |phase, _| phases.push(phase.to_string()),
+    );
+    assert_eq!(run(vm_code.unwrap()), "main\n");
+    assert_eq!(phases, ["parse", "compile", "assemble"]);
+
+    // Errors are returned instead of being printed.
+    assert!(matches!(
+        sage::compile_to_vm("println(1 + \"a\");", SourceType::Sage, CALL_STACK_SIZE),
+        Err(compile::Error::LirError(_))
+    ));
+    assert!(matches!(
+        sage::compile_to_vm([0xff], SourceType::StdVM, CALL_STACK_SIZE),
+        Err(compile::Error::Encoding(_))
+    ));
+    assert!(matches!(
+        compile_files_to_vm(
+            &sources,
+            SourceType::LowIR,
+            None,
+            &[],
+            CALL_STACK_SIZE,
+            |_, _| {}
+        ),
+        Err(compile::Error::InvalidSource(_))
+    ));
+}
+
+#[test]
+fn test_frontend_error_location_after_comments() {
+    // Compiling the builtins overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_frontend_error_location_after_comments_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_error_location_after_comments_helper() {
+    let code = r#"/* A comment
+   over several lines, with a "quote". */
+let x: Int = 1; // The number.
+let s = "// not a comment"; let c = '"';
+let y: Bool = /* an inline comment */ x;
+"#;
+    let expr = parse_frontend(code, Some("comments.sg")).unwrap();
+    let err = expr.compile().unwrap_err();
+    let loc = match &err {
+        sage::lir::Error::Annotated(_, annotation) => annotation.location().unwrap().clone(),
+        _ => panic!("Expected an error with a location, got {err}"),
+    };
+
+    // The location is in the original code, with the comments left in.
+    assert_eq!(loc.line, 5);
+    assert_eq!(loc.column, 1);
+    let start = code.find("let y").unwrap();
+    assert_eq!(loc.offset, start);
+    let end = loc.offset + loc.length.unwrap();
+    assert_eq!(&code[start..end], "let y: Bool = /* an inline comment */ x");
+}
+
+#[test]
+fn test_frontend_deeply_nested() {
+    // A long block used to nest one level deeper with each statement,
+    // which overflowed a stack this size after a few dozen statements.
+    let child = std::thread::Builder::new()
+        .stack_size(8 * 1024 * 1024)
+        .spawn(test_frontend_deeply_nested_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_frontend_deeply_nested_helper() {
+    let run = |code: &str| {
+        let vm_code = sage::compile_to_vm(code, SourceType::Sage, CALL_STACK_SIZE)?;
+        Ok::<_, compile::Error>(match vm_code {
+            Ok(core) => CoreInterpreter::new(TestingDevice::default())
+                .run(&core)
+                .unwrap()
+                .output_str(),
+            Err(std) => StandardInterpreter::new(TestingDevice::default())
+                .run(&std)
+                .unwrap()
+                .output_str(),
+        })
+    };
+
+    // Each statement in a block is compiled at the same depth.
+    let code = format!("let mut x = 0;\n{}println(x);", "x += 1;\n".repeat(300));
+    assert_eq!(run(&code).unwrap(), "300\n");
+
+    // Nested expressions are rejected past the maximum depth, instead of overflowing the stack.
+    let code = format!("println(1{});", " + 1".repeat(15));
+    assert_eq!(run(&code).unwrap(), "16\n");
+    sage::lir::set_max_depth(Some(10));
+    let err = run(&code).unwrap_err().to_string();
+    assert!(err.contains("nested more than 10 levels deep"), "{err}");
+    assert_eq!(run("println(1 + 1);").unwrap(), "2\n");
+    sage::lir::set_max_depth(None);
+    assert_eq!(run(&code).unwrap(), "16\n");
+}
+
+#[test]
+fn test_symbol_map() {
+    // Compiling procedures overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_symbol_map_helper)
+        .unwrap();
+    child.join().unwrap();
+}
+
+fn test_symbol_map_helper() {
+    let code = r#"
+def fact(n: Int, acc: Int): Int {
+    if n <= 1 { return acc; }
+    return fact(n - 1, acc * n);
+}
+def square(x: Int): Int = x * x;
+println(fact(5, 1) + square(3));
+"#;
+    let asm_code = parse_frontend(code, None)
+        .unwrap()
+        .compile()
+        .unwrap()
+        .unwrap();
+    // The body of a tail recursive procedure is a separate function with the same name.
+    let symbols = asm_code.symbols();
+    let names = symbols
+        .iter()
+        .map(|symbol| (symbol.index, symbol.name.as_deref()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![(0, Some("fact")), (1, Some("fact")), (2, Some("square"))]
+    );
+    assert!(symbols[1].to_string().starts_with("1 __LAMBDA_"));
+    assert!(symbols[1].to_string().ends_with("_BODY fact"));
+
+    // The indices are the ones the functions are called by in the assembled code.
+    let vm_code = asm_code.assemble(CALL_STACK_SIZE).unwrap();
+    let functions = vm_code
+        .0
+        .iter()
+        .filter(|op| matches!(op, CoreOp::Function))
+        .count();
+    assert_eq!(functions, symbols.len());
+
+    // Handwritten assembly code only has labels.
+    let asm_code = parse_asm("fun @f\n ret\nend\nfun @g\n ret\nend\n")
+        .unwrap()
+        .unwrap();
+    let symbols = asm_code.symbols();
+    assert_eq!(
+        symbols.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        vec!["0 f", "1 g"]
+    );
+}
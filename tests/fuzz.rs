@@ -0,0 +1,180 @@
+//! Feed random and malformed input to every parser, and check that each one
+//! returns an error instead of panicking.
+//!
+//! The inputs come from a small seeded random number generator, so a failure
+//! always reproduces, and the panicking input is printed with the failure.
+//! Besides raw bytes, the parsers are given "token soup": random sequences of
+//! keywords, literals, and punctuation from all of the languages, which get much
+//! further into each grammar than random bytes do.
+use sage::parse::{parse_asm, parse_frontend, parse_lir, parse_vm};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// The number of random inputs given to each parser, of each kind.
+const ITERATIONS: usize = 100;
+
+/// The pieces of source code which are put together to make token soup.
+#[rustfmt::skip]
+const TOKENS: &[&str] = &[
+    // Virtual machine code and assembly code
+    "set", "fun", "call", "ret", "while", "if", "else", "end", "save", "restore", "move",
+    "where", "deref", "refer", "index", "put", "get", "stdout.char", "stderr.int",
+    "stdin.char", "mov", "lea", "push", "pop", "add", "sub", "set-f", "add-f", "alloc",
+    "global", "halt", "SP", "FP", "A", "B", "R0", "[", "]", "#", "@", "$", ".",
+    // LIR code and frontend code
+    "let", "const", "type", "proc", "def", "fun", "struct", "enum", "match", "for", "in",
+    "return", "Int", "Float", "Char", "Bool", "None", "Null", "True", "False", "&", "*",
+    "->", "=>", "=", "==", ":", "::", ";", ",", "(", ")", "{", "}", "<", ">", "+", "-",
+    "/", "%", "!", "?", "|", "core {", "std {", "include", "\"main.sg\"",
+    // Literals, including ones which don't fit in their types
+    "0", "1", "-1", "42", "1.5", "-0.0", "1e10", "0x10", "-0x10", "0b102", "0o17",
+    "9223372036854775807", "9223372036854775808", "-9223372036854775808",
+    "-9223372036854775809", "99999999999999999999999", "0xffffffffffffffffff",
+    "1e999", ".0", "'a'", "'\\n'", "'", "\"", "\"abc\"", "\"\\u{1234}\"", "\\",
+    // Whitespace and characters which are more than one byte long
+    " ", "\n", "\t", "\r\n", "é", "λ", "\u{3000}", "🦀",
+];
+
+/// A small random number generator (SplitMix64), so that the test doesn't need
+/// any dependencies, and so that every run tries the same inputs.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Random bytes, which may not be valid UTF-8.
+    fn bytes(&mut self, max_len: usize) -> String {
+        let bytes = (0..self.below(max_len + 1))
+            .map(|_| self.next() as u8)
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Random tokens, separated by whitespace or nothing at all.
+    fn token_soup(&mut self, max_tokens: usize) -> String {
+        let mut soup = String::new();
+        for _ in 0..self.below(max_tokens + 1) {
+            soup += TOKENS[self.below(TOKENS.len())];
+            soup += [" ", "", "\n"][self.below(3)];
+        }
+        soup
+    }
+}
+
+/// Parse an input, and fail with the input if the parser panics.
+fn check(parser: &str, parse: impl Fn(&str), input: &str) {
+    if catch_unwind(AssertUnwindSafe(|| parse(input))).is_err() {
+        panic!("The {parser} parser panicked on the input {input:?}");
+    }
+}
+
+/// Parse the inputs which used to panic, and then random inputs.
+fn fuzz(parser: &str, parse: impl Fn(&str), max_tokens: usize) {
+    for input in [
+        "",
+        "set 9223372036854775808",
+        "set -9223372036854775809",
+        "put stdout.char #99999999999999999999",
+        "set-f 1e999",
+        "let x = 99999999999999999999 in x",
+        "let x = 0x10000000000000000; x",
+        "let x = -0x10; x",
+        "let x = (1, 2).99999999999999999999; x",
+        "é 1",
+        "\u{3000}é",
+        "\u{3000}\u{3000}é\n\u{3000}1",
+    ] {
+        check(parser, &parse, input);
+    }
+
+    let mut rng = Rng(0x5a6e);
+    for _ in 0..ITERATIONS {
+        check(parser, &parse, &rng.bytes(max_tokens * 4));
+        check(parser, &parse, &rng.token_soup(max_tokens));
+    }
+}
+
+#[test]
+fn test_fuzz_vm() {
+    fuzz(
+        "virtual machine",
+        |input| {
+            let _ = parse_vm(input);
+        },
+        64,
+    );
+}
+
+#[test]
+fn test_fuzz_asm() {
+    fuzz(
+        "assembly",
+        |input| {
+            let _ = parse_asm(input);
+        },
+        64,
+    );
+}
+
+#[test]
+fn test_fuzz_lir() {
+    fuzz(
+        "LIR",
+        |input| {
+            let _ = parse_lir(input);
+        },
+        64,
+    );
+}
+
+#[test]
+fn test_fuzz_frontend() {
+    // The frontend parser backtracks a lot on nested expressions,
+    // so it's given shorter inputs to keep the test fast.
+    fuzz(
+        "frontend",
+        |input| {
+            let _ = parse_frontend(input, None);
+        },
+        16,
+    );
+}
+
+#[test]
+fn test_out_of_range_literals() {
+    // The error points at the literal, on the line it's on.
+    let check = |err: String, line: &str| {
+        assert!(err.contains(line), "{err}");
+        assert!(err.contains("^-------------------\n"), "{err}");
+        assert!(err.ends_with("= integer literal is out of range"), "{err}");
+    };
+    let big = "99999999999999999999";
+    check(
+        parse_vm(format!("set 1\nset {big} put stdout.int"))
+            .err()
+            .unwrap(),
+        &format!("2 | set {big} put stdout.int"),
+    );
+    check(
+        parse_asm(format!("set A, 1\nset B, {big}")).err().unwrap(),
+        &format!("2 | set B, {big}"),
+    );
+    check(
+        parse_lir(format!("let x = 1 in\nx + {big}")).unwrap_err(),
+        &format!("2 | x + {big}"),
+    );
+    // Inline assembly is parsed separately, but its errors point into the LIR code.
+    check(
+        parse_lir(format!("proc f() = core {{\n    set A, {big}\n}} in f()")).unwrap_err(),
+        &format!("2 | set A, {big}"),
+    );
+}
@@ -0,0 +1,487 @@
+//! Compile every fixture in [`examples/round-trip`](../examples/round-trip) from every
+//! source type that can read it, to every target the `sage` binary supports.
+//!
+//! The targets are read from the binary itself, so a new backend is exercised here
+//! as soon as it is added to the command line. Every combination is expected to
+//! build, except for the ones listed in `expected_error`. The targets which run the
+//! program have their output checked against the golden files in `examples/test-output`.
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// The path to the compiler binary.
+const SAGE: &str = env!("CARGO_BIN_EXE_sage");
+
+/// The folder of fixtures to compile.
+const FIXTURES: &str = "./examples/round-trip";
+/// The folder of the expected outputs of the fixtures.
+const OUTPUT_DIR: &str = "./examples/test-output";
+
+/// The error expected when compiling from the given source type to the given target,
+/// or `None` if the combination is legal and should build successfully.
+fn expected_error(source_type: &str, target: &str) -> Option<&'static str> {
+    match (source_type, target) {
+        // Virtual machine code can't be turned back into assembly code.
+        ("core-vm" | "std-vm" | "core-vm-bin" | "std-vm-bin", "core-asm" | "std-asm") => {
+            Some("Invalid source: cannot compile virtual machine code")
+        }
+        // Standard variant code can't be turned into core variant code, even if it
+        // only uses core instructions.
+        ("std-asm" | "std-vm" | "std-vm-bin", "core-asm" | "core-vm" | "core-vm-bin") => {
+            Some("Invalid source: cannot compile standard variant code")
+        }
+        // Brainfuck only supports a subset of the core instructions:
+        // it has no functions, and can't print integers.
+        (_, "brainfuck") => Some("not supported on target Brainfuck"),
+        _ => None,
+    }
+}
+
+/// Is this a target which runs the program, whose output is checked against the golden file?
+fn is_running_target(target: &str) -> bool {
+    matches!(target, "run" | "jit" | "c-run")
+}
+
+/// The source types which can read a fixture, based on its extension.
+fn source_types(path: &Path) -> &'static [&'static str] {
+    let name = path.to_str().unwrap();
+    if name.ends_with(".asm.sg") {
+        &["core-asm", "std-asm"]
+    } else if name.ends_with(".vm.sg") {
+        &["core-vm", "std-vm"]
+    } else if name.ends_with(".lir.sg") {
+        &["low-ir"]
+    } else {
+        &["sage"]
+    }
+}
+
+/// Run the compiler with the given arguments.
+fn sage(args: &[&str]) -> Output {
+    Command::new(SAGE)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+/// Get the names of all of the targets supported by the compiler, by asking it to
+/// build an invalid target and reading the possible values from the error message.
+fn targets(fixture: &Path) -> Vec<String> {
+    let output = sage(&[fixture.to_str().unwrap(), "-t", "not-a-target"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let (_, values) = stderr
+        .split_once("possible values: ")
+        .unwrap_or_else(|| panic!("Could not find the list of targets in:\n{stderr}"));
+    let values = values.lines().next().unwrap().trim_end_matches(']');
+    values.split(", ").map(|t| t.trim().to_string()).collect()
+}
+
+/// Is there a C compiler available to build the `c-run` target?
+fn has_c_compiler() -> bool {
+    Command::new("cc").arg("--version").output().is_ok()
+}
+
+/// Compile a fixture from a source type to a target, checking the result.
+/// Any problems are added to `failures`, so that they're all reported together.
+fn check(
+    fixture: &Path,
+    source_type: &str,
+    target: &str,
+    golden: &str,
+    out_dir: &Path,
+    failures: &mut Vec<String>,
+) {
+    let name = fixture.file_name().unwrap().to_str().unwrap();
+    let out = out_dir.join(format!("{name}-{source_type}-{target}"));
+    let output = sage(&[
+        fixture.to_str().unwrap(),
+        "-s",
+        source_type,
+        "-t",
+        target,
+        "-o",
+        out.to_str().unwrap(),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let case = format!("{name} (-s {source_type} -t {target})");
+
+    match expected_error(source_type, target) {
+        Some(error) => {
+            if output.status.success() {
+                failures.push(format!("{case}: expected `{error}`, but it succeeded"));
+            } else if !stderr.contains(error) {
+                failures.push(format!("{case}: expected `{error}`, got:\n{stderr}"));
+            }
+        }
+        None => {
+            if !output.status.success() {
+                failures.push(format!("{case}: failed with {}:\n{stderr}", output.status));
+            } else if is_running_target(target) && stdout != golden {
+                failures.push(format!(
+                    "{case}: expected output {golden:?}, got {stdout:?}"
+                ));
+            } else if target == "none" && !stderr.trim_end().ends_with(" instructions") {
+                failures.push(format!(
+                    "{case}: expected an instruction count, got:\n{stderr}"
+                ));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("round-trip");
+    std::fs::create_dir_all(&out_dir).unwrap();
+
+    let mut fixtures = std::fs::read_dir(FIXTURES)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sg"))
+        .collect::<Vec<_>>();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "No round-trip fixtures found");
+
+    let targets = targets(&fixtures[0]);
+    assert!(
+        targets.iter().any(|t| t == "run"),
+        "Could not read the targets: {targets:?}"
+    );
+    let has_cc = has_c_compiler();
+
+    let mut failures = vec![];
+    for fixture in &fixtures {
+        let name = fixture.file_name().unwrap().to_str().unwrap();
+        let golden_path = Path::new(OUTPUT_DIR).join(name.replace(".sg", ".txt"));
+        let golden = std::fs::read_to_string(&golden_path)
+            .unwrap_or_else(|_| panic!("Missing golden file {golden_path:?}"));
+
+        // Virtual machine code is also round-tripped through bytecode,
+        // which is then read back as the source of every target.
+        let mut sources = source_types(fixture)
+            .iter()
+            .map(|source_type| (fixture.clone(), source_type.to_string()))
+            .collect::<Vec<_>>();
+        for (source_type, bin_type) in [("core-vm", "core-vm-bin"), ("std-vm", "std-vm-bin")] {
+            if !source_types(fixture).contains(&source_type) {
+                continue;
+            }
+            let bin = out_dir.join(format!("{name}-{bin_type}.vm.sgb"));
+            let output = sage(&[
+                fixture.to_str().unwrap(),
+                "-s",
+                source_type,
+                "-t",
+                bin_type,
+                "-o",
+                bin.to_str().unwrap(),
+            ]);
+            assert!(
+                output.status.success(),
+                "Could not build {bin_type} from {name}:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            sources.push((bin, bin_type.to_string()));
+        }
+
+        for (path, source_type) in &sources {
+            for target in &targets {
+                if target == "c-run" && !has_cc {
+                    continue;
+                }
+                check(path, source_type, target, &golden, &out_dir, &mut failures);
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} round-trip failures:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_stats_formats() {
+    let fixture = Path::new(FIXTURES).join("round-trip.vm.sg");
+    let fixture = fixture.to_str().unwrap();
+
+    // The table ends with the total, which is 100% of the instructions.
+    let output = sage(&[fixture, "--stats"]);
+    assert!(output.status.success());
+    let table = String::from_utf8_lossy(&output.stderr);
+    let total = table.lines().last().unwrap();
+    assert!(total.starts_with("total "), "{table}");
+    assert!(total.ends_with(" 100.0%"), "{table}");
+    // The rows are sorted from most to least common, with right-aligned counts.
+    let rows = table.lines().collect::<Vec<_>>();
+    let counts = rows
+        .iter()
+        .map(|row| {
+            row.split_whitespace()
+                .nth(1)
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let total_count = counts.last().copied().unwrap();
+    let counts = &counts[..counts.len() - 1];
+    assert!(counts.windows(2).all(|pair| pair[0] >= pair[1]), "{table}");
+    assert_eq!(counts.iter().sum::<usize>(), total_count);
+    assert!(rows.iter().all(|row| row.len() == rows[0].len()), "{table}");
+
+    let output = sage(&[fixture, "--stats", "--stats-format", "json"]);
+    assert!(output.status.success());
+    let json = String::from_utf8_lossy(&output.stderr);
+    assert!(json.starts_with("{\"counts\": {\""), "{json}");
+    assert!(
+        json.trim_end()
+            .ends_with(&format!("}}, \"total\": {total_count}}}")),
+        "{json}"
+    );
+}
+
+#[test]
+fn test_compiler_stack_size() {
+    let fixture = Path::new(FIXTURES).join("round-trip.vm.sg");
+    let fixture = fixture.to_str().unwrap();
+
+    // Small programs compile with a much smaller stack than the default.
+    let output = sage(&[fixture, "--compiler-stack-mb", "8"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // A stack with no space at all is rejected before compiling anything.
+    let output = sage(&[fixture, "--compiler-stack-mb", "0"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("at least 1 MB"), "{stderr}");
+}
+
+#[test]
+fn test_output_names() {
+    let fixture = Path::new(FIXTURES).join("round-trip.vm.sg");
+    let fixture = fixture.to_str().unwrap();
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("output-names");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A bare name gets the extension of each target.
+    let bare = dir.join("bare");
+    let output = sage(&[fixture, "--emit", "c,std-vm", "-o", bare.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(dir.join("bare.c").exists());
+    assert!(dir.join("bare.vm.sg").exists());
+
+    // A name with an extension is used as it is.
+    for name in ["explicit.c", "explicit.h"] {
+        let explicit = dir.join(name);
+        let output = sage(&[fixture, "-t", "c", "-o", explicit.to_str().unwrap()]);
+        assert!(output.status.success());
+        assert!(explicit.exists(), "{name} was not written");
+        assert!(!dir.join(format!("{name}.c")).exists());
+    }
+
+    // Several targets can't all be written to one name with an extension.
+    let clash = dir.join("clash.c");
+    let output = sage(&[fixture, "--emit", "c,x86", "-o", clash.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("can only be used for one target"),
+        "{stderr}"
+    );
+    assert!(!clash.exists());
+}
+
+#[test]
+fn test_annotate_source() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("annotate-source");
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("annotate.sg");
+    std::fs::write(&src, "let x = 5;\nprintln(x * 2);\n").unwrap();
+    let src = src.to_str().unwrap();
+
+    for target in ["core-vm", "std-vm", "c"] {
+        let output = sage(&[src, "-t", target, "--annotate-source", "-o", "-"]);
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("annotate.sg:2"), "{target}: {stdout}");
+
+        // Without the flag, the code isn't marked at all.
+        let output = sage(&[src, "-t", target, "-o", "-"]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(!stdout.contains("annotate.sg"), "{target}: {stdout}");
+    }
+
+    // The annotated virtual machine code can still be read back in.
+    let output = sage(&[src, "-t", "core-vm", "--annotate-source", "-o", "-"]);
+    let vm_code = dir.join("annotate.vm.sg");
+    std::fs::write(&vm_code, &output.stdout).unwrap();
+    let output = sage(&[vm_code.to_str().unwrap(), "-s", "core-vm"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "10\n");
+
+    // The markers would be stripped along with the other comments.
+    let output = sage(&[src, "-t", "c", "--annotate-source", "--strip-comments"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+#[test]
+fn test_exit_codes() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exit-codes");
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let ok = write("ok.sg", "println(1);\n");
+    let parse = write("parse.sg", "let x = ;\n");
+    let lir = write("lir.sg", "println(y);\n");
+    let asm = write("asm.asm.sg", "call @nowhere\n");
+    let missing = dir.join("missing.sg");
+    let missing = missing.to_str().unwrap();
+
+    // Each kind of error has its own exit code, which scripts can rely on.
+    for (args, code) in [
+        (&[missing][..], 2),
+        (&[&parse], 3),
+        (&[&lir], 4),
+        (&[&asm, "-s", "core-asm"], 5),
+        (&[&ok, "--max-steps", "1"], 6),
+    ] {
+        let output = sage(args);
+        assert_eq!(
+            output.status.code(),
+            Some(code),
+            "{args:?}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // In quiet mode, the error is printed without its log level.
+    let output = sage(&[missing, "-q"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("IO error"), "{stderr}");
+
+    // Warnings only fail the compilation with `--deny-warnings`.
+    let warned = write("warned.asm.sg", "fun @a\nend\nfun @a\nend\n");
+    assert!(sage(&[&warned, "-s", "core-asm"]).status.success());
+    let output = sage(&[&warned, "-s", "core-asm", "--deny-warnings"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Label a already declared"), "{stderr}");
+
+    // `-o -` writes every emitted target to stdout.
+    let output = sage(&[&ok, "--emit", "c,std-vm", "-o", "-"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("int main"), "{stdout}");
+    assert!(stdout.contains("\nwhere\n"), "{stdout}");
+}
+
+#[test]
+fn test_stdio_files() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("stdio-files");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = "héllo\r\n\0€🦀";
+    let (src, stdin, stdout) = (
+        dir.join("echo.vm.sg"),
+        dir.join("stdin.bin"),
+        dir.join("stdout.bin"),
+    );
+    // Echo each character, and then write a character cell, which is encoded as UTF-8.
+    let echo = "get stdin.utf8 put stdout.utf8\n".repeat(input.chars().count());
+    std::fs::write(&src, echo + "set 233 put stdout.char\n").unwrap();
+    std::fs::write(&stdin, input).unwrap();
+
+    let output = sage(&[
+        src.to_str().unwrap(),
+        "-s",
+        "core-vm",
+        "--stdin-file",
+        stdin.to_str().unwrap(),
+        "--stdout-file",
+        stdout.to_str().unwrap(),
+    ]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(output.stdout.is_empty());
+    // The bytes read from the file are written back unchanged.
+    assert_eq!(
+        std::fs::read(&stdout).unwrap(),
+        format!("{input}é").as_bytes()
+    );
+}
+
+#[test]
+fn test_optimized_overflow() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("optimized-overflow");
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("overflow.vm.sg");
+    std::fs::write(&src, "set 100 sav set 100 add put stdout.int #0\n").unwrap();
+    let src = src.to_str().unwrap();
+
+    // Folding the addition doesn't hide the overflow of an 8-bit cell.
+    for level in ["-O0", "-O2"] {
+        let output = sage(&[
+            src,
+            "-s",
+            "core-vm",
+            level,
+            "--cell-bits",
+            "8",
+            "--trap-overflow",
+        ]);
+        assert_eq!(output.status.code(), Some(6), "{level}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("overflowed a 8-bit cell"),
+            "{level}: {stderr}"
+        );
+
+        let output = sage(&[src, "-s", "core-vm", level, "--cell-bits", "8"]);
+        assert!(output.status.success(), "{level}");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "-56", "{level}");
+    }
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_rejects_interpreter_options() {
+    let fixture = Path::new(FIXTURES).join("round-trip.vm.sg");
+    let fixture = fixture.to_str().unwrap();
+    for options in [
+        &["--max-steps", "100"][..],
+        &["--profile"],
+        &["--cell-bits", "32"],
+        &["--trap-overflow"],
+        &["--check-frames"],
+        &["--trace-exec", "trace.jsonl"],
+        &["--break-at", "0"],
+    ] {
+        let output = sage(&[&[fixture, "-t", "jit"], options].concat());
+        assert!(!output.status.success(), "{options:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!(
+                "`{}` is only supported by the interpreter",
+                options[0]
+            )),
+            "{stderr}"
+        );
+    }
+}
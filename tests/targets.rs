@@ -0,0 +1,1418 @@
+use sage::{
+    lir::Compile,
+    parse::{parse_frontend, parse_vm},
+    side_effects::{Input, Output},
+    targets::{self, CompiledTarget},
+    vm::*,
+};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Compile some generated C code with the system C compiler, and run it with the given input.
+/// This returns the standard output of the program, or `None` if there is no C compiler available.
+fn run_c(name: &str, code: &str, input: &str) -> Option<String> {
+    let exe = compile_c(name, code)?;
+    Some(run_exe(&exe, input))
+}
+
+/// Compile some generated C code with the system C compiler.
+/// This returns the path to the executable, or `None` if there is no C compiler available.
+fn compile_c(name: &str, code: &str) -> Option<PathBuf> {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join(format!("{name}.c"));
+    let exe = dir.join(name);
+    std::fs::write(&src, code).unwrap();
+
+    // Compile as standard C, so that GNU extensions like nested functions are rejected.
+    let status = Command::new("cc")
+        .arg("-std=c99")
+        .arg("-pedantic-errors")
+        .arg(&src)
+        .arg("-o")
+        .arg(&exe)
+        .arg("-lm")
+        .status()
+        .ok()?;
+    assert!(status.success(), "Could not compile `{name}`");
+    Some(exe)
+}
+
+/// Run a compiled program with the given input, and return its standard output.
+fn run_exe(exe: &Path, input: &str) -> String {
+    let mut child = Command::new(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Run a Brainfuck program with 8-bit wrapping cells and the given input, and return its output.
+/// Reading past the end of the input leaves the cell unchanged.
+fn run_brainfuck(code: &str, input: &str) -> String {
+    let code = code.as_bytes();
+    let mut input = input.bytes();
+    let mut tape = vec![0u8; 30000];
+    let mut output = vec![];
+    let (mut pc, mut ptr) = (0, 0);
+    // Find the bracket matching the one at `pc`, searching in the given direction.
+    let matching = |mut pc: usize, forward: bool| {
+        let mut depth = 0;
+        loop {
+            match code[pc] {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return pc;
+            }
+            pc = if forward { pc + 1 } else { pc - 1 };
+        }
+    };
+    while pc < code.len() {
+        match code[pc] {
+            b'>' => ptr += 1,
+            b'<' => ptr -= 1,
+            b'+' => tape[ptr] = tape[ptr].wrapping_add(1),
+            b'-' => tape[ptr] = tape[ptr].wrapping_sub(1),
+            b'.' => output.push(tape[ptr]),
+            b',' => tape[ptr] = input.next().unwrap_or(tape[ptr]),
+            b'[' if tape[ptr] == 0 => pc = matching(pc, true),
+            b']' if tape[ptr] != 0 => pc = matching(pc, false),
+            _ => {}
+        }
+        pc += 1;
+    }
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_c_peek_poke() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(42)), // Set the register to 42
+        StandardOp::Poke,                    // Poke it to the FFI channel
+        StandardOp::CoreOp(CoreOp::Set(0)),  // Clear the register
+        StandardOp::Peek,                    // Peek it back from the FFI channel
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())), // Print the register
+    ]);
+
+    let i = StandardInterpreter::new(TestingDevice::default());
+    let device = i.run(&program).unwrap();
+    assert_eq!(device.output_str(), "42");
+
+    let code = targets::C::default().build_std(&program).unwrap();
+    if let Some(output) = run_c("peek_poke", &code, "") {
+        assert_eq!(output, device.output_str());
+    }
+}
+
+#[test]
+fn test_c_std_echo() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())), // Get a character
+        StandardOp::CoreOp(CoreOp::Save),                     // Save it to the tape
+        StandardOp::CoreOp(CoreOp::IsNonNegative),            // Check for EOF
+        StandardOp::CoreOp(CoreOp::While),                    // While we haven't reached EOF
+        StandardOp::CoreOp(CoreOp::Restore),                  // Restore the character
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())), // Print it
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())), // Get the next character
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::IsNonNegative),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+
+    let input = "Hello, world!\n";
+    let mut raw_input: Vec<i64> = input.chars().map(|ch| ch as i64).collect();
+    raw_input.push(-1);
+    let i = StandardInterpreter::new(TestingDevice::new_raw(raw_input));
+    let device = i.run(&program).unwrap();
+    assert_eq!(device.output_str(), input);
+
+    let code = targets::C::default().build_std(&program).unwrap();
+    if let Some(output) = run_c("std_echo", &code, input) {
+        assert_eq!(output, input);
+    }
+}
+
+#[test]
+fn test_c_utf8_io() {
+    // Echo a fixed number of characters, each read and written whole.
+    let input = "aé€🦀\n";
+    let mut ops = vec![];
+    for _ in input.chars() {
+        ops.push(CoreOp::Get(Input::stdin_utf8()));
+        ops.push(CoreOp::Put(Output::stdout_utf8()));
+    }
+    // Cells which aren't characters are written as replacement characters.
+    ops.extend([
+        CoreOp::Set(0xdfff),
+        CoreOp::Put(Output::stdout_utf8()),
+        CoreOp::Set(0x110000),
+        CoreOp::Put(Output::stdout_utf8()),
+    ]);
+    let program = CoreProgram(ops);
+
+    let i = CoreInterpreter::new(TestingDevice::new(input));
+    let device = i.run(&program).unwrap();
+    let expected = format!("{input}\u{fffd}\u{fffd}");
+    assert_eq!(device.output_str(), expected);
+
+    // The helpers are only emitted for programs which use them,
+    // even when the target compiled a program using them before.
+    let mut target = targets::C::default();
+    let code = target
+        .build_core(&CoreProgram(vec![CoreOp::Put(Output::stdout_utf8())]))
+        .unwrap();
+    assert!(!code.contains("sage_get_utf8"));
+    assert!(code.contains("sage_put_utf8"));
+    let code = target
+        .build_core(&CoreProgram(vec![CoreOp::Put(Output::stdout_int())]))
+        .unwrap();
+    assert!(!code.contains("utf8"));
+
+    let code = targets::C::default().build_core(&program).unwrap();
+    if let Some(exe) = compile_c("utf8_io", &code) {
+        assert_eq!(run_exe(&exe, input), expected);
+        // Invalid input is read as replacement characters, keeping the byte
+        // after a truncated character, like the interpreter does.
+        let output = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(b"\xc3(\xe0\x80\x80\xed\xa0\x80!")?;
+                child.wait_with_output()
+            })
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "\u{fffd}(\u{fffd}\u{fffd}!\u{fffd}\u{fffd}"
+        );
+    }
+}
+
+#[test]
+fn test_c_top_level_functions() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function that prints its argument if it's non-zero, twice
+        CoreOp::Set(2),
+        CoreOp::Save,
+        CoreOp::While,
+        CoreOp::Move(1),
+        CoreOp::Restore,
+        CoreOp::If,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Else,
+        CoreOp::Set(33),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::End,
+        CoreOp::Move(-1),
+        CoreOp::Set(-1),
+        CoreOp::Add,
+        CoreOp::Save,
+        CoreOp::End,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Move(1), // Call it with 42
+        CoreOp::Set(42),
+        CoreOp::Save,
+        CoreOp::Move(-1),
+        CoreOp::Set(0),
+        CoreOp::Call,
+    ]);
+
+    let i = CoreInterpreter::new(TestingDevice::default());
+    let device = i.run(&program).unwrap();
+    assert_eq!(device.output_str(), "**");
+
+    let code = targets::C::default()
+        .build_core(&program.flatten())
+        .unwrap();
+    // The function must be defined before `main`, not inside of it.
+    assert!(code.find("void f0()").unwrap() < code.find("int main").unwrap());
+    if let Some(output) = run_c("top_level_functions", &code, "") {
+        assert_eq!(output, device.output_str());
+    }
+}
+
+#[test]
+fn test_c_function_table() {
+    // Decrement the cell and call the other function, until the cell is zero.
+    let parity = |other: i64, letter: char| {
+        vec![
+            CoreOp::Function,
+            CoreOp::Restore,
+            CoreOp::If,
+            CoreOp::Set(-1),
+            CoreOp::Add,
+            CoreOp::Save,
+            CoreOp::Set(other),
+            CoreOp::Call,
+            CoreOp::Else,
+            CoreOp::Set(letter as i64),
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::End,
+            CoreOp::Return,
+            CoreOp::End,
+        ]
+    };
+    // Function 0 prints `E` for even numbers, and function 1 prints `O` for odd ones.
+    let mut ops = parity(1, 'E');
+    ops.extend(parity(0, 'O'));
+    // Function 2 checks the parity of the cell, and prints a space after it.
+    ops.extend([
+        CoreOp::Function,
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Set(' ' as i64),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::End,
+    ]);
+    for n in [7, 10, 0] {
+        ops.extend([CoreOp::Set(n), CoreOp::Save, CoreOp::Set(2), CoreOp::Call]);
+    }
+    let program = CoreProgram(ops);
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "O E E ");
+
+    let code = targets::C::default().build_core(&program).unwrap();
+    // The table is filled in by a single initializer before `main`.
+    let table = code.find("void (*funs[10000])(void) = {").unwrap();
+    assert!(code.find("[2] = f2,").unwrap() > table);
+    assert!(table < code.find("int main").unwrap());
+    assert!(!code.contains("funs[0] = f0;"));
+    if let Some(output) = run_c("function_table", &code, "") {
+        assert_eq!(output, device.output_str());
+    }
+
+    // A program without any functions doesn't define the table.
+    let program = CoreProgram(vec![CoreOp::Set(65), CoreOp::Put(Output::stdout_char())]);
+    let code = targets::C::default().build_core(&program).unwrap();
+    assert!(!code.contains(") = {"));
+    if let Some(output) = run_c("empty_function_table", &code, "") {
+        assert_eq!(output, "A");
+    }
+}
+
+#[test]
+fn test_c_pow() {
+    let program = StandardProgram(vec![
+        StandardOp::Set(10.0), // Save the exponent to the tape
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::Set(2.0), // Raise 2 to the exponent
+        StandardOp::Pow,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())), // Print the result
+    ]);
+
+    let i = StandardInterpreter::new(TestingDevice::default());
+    let device = i.run(&program).unwrap();
+    assert_eq!(device.output_str(), "1024.0");
+
+    let code = targets::C::default().build_std(&program).unwrap();
+    // The cells are doubles, so this must use the double precision `pow`.
+    assert!(code.contains("reg.f = pow(reg.f, ptr->f);"));
+    assert!(!code.contains("powf"));
+    if let Some(output) = run_c("pow", &code, "") {
+        assert_eq!(output, "1024.000000");
+    }
+}
+
+#[test]
+fn test_c_std_float_ops() {
+    // Each value is printed followed by a space.
+    let put = |ops: &mut Vec<StandardOp>, output: Output| {
+        ops.push(StandardOp::CoreOp(CoreOp::Put(output)));
+        ops.push(StandardOp::CoreOp(CoreOp::Set(b' ' as i64)));
+        ops.push(StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())));
+    };
+    let mut ops = vec![];
+    for (n, op) in [
+        (1.0, StandardOp::Sin),
+        (1.0, StandardOp::Cos),
+        (0.5, StandardOp::Tan),
+        (0.5, StandardOp::ASin),
+        (0.5, StandardOp::ACos),
+        (1.0, StandardOp::ATan),
+    ] {
+        ops.extend([StandardOp::Set(n), op]);
+        put(&mut ops, Output::stdout_float());
+    }
+    for (a, b, op) in [
+        (2.0, 0.5, StandardOp::Pow),
+        (3.0, 4.0, StandardOp::Pow),
+        (7.5, 2.0, StandardOp::Rem),
+        (-7.5, 2.0, StandardOp::Rem),
+    ] {
+        ops.extend([
+            StandardOp::Set(b),
+            StandardOp::CoreOp(CoreOp::Save),
+            StandardOp::Set(a),
+            op,
+        ]);
+        put(&mut ops, Output::stdout_float());
+    }
+    ops.extend([StandardOp::Set(-2.7), StandardOp::ToInt]);
+    put(&mut ops, Output::stdout_int());
+    ops.extend([StandardOp::CoreOp(CoreOp::Set(7)), StandardOp::ToFloat]);
+    put(&mut ops, Output::stdout_float());
+    // The sign is tested as a float, so negative zero isn't negative, and NaN isn't either.
+    for n in [-0.0, -1.0, f64::NAN, 2.0] {
+        ops.extend([StandardOp::Set(n), StandardOp::IsNonNegative]);
+        put(&mut ops, Output::stdout_int());
+    }
+    let program = StandardProgram(ops);
+
+    let device = StandardInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let output = device.output_str();
+    let values = |output: &str| -> Vec<f64> {
+        output
+            .split_whitespace()
+            .map(|value| value.parse().unwrap())
+            .collect()
+    };
+    let expected = [
+        1f64.sin(),
+        1f64.cos(),
+        0.5f64.tan(),
+        0.5f64.asin(),
+        0.5f64.acos(),
+        1f64.atan(),
+        2f64.sqrt(),
+        81.0,
+        1.5,
+        -1.5,
+        -2.0,
+        7.0,
+        1.0,
+        0.0,
+        0.0,
+        1.0,
+    ];
+    assert_eq!(values(&output), expected);
+
+    #[cfg(feature = "jit")]
+    {
+        let device = Jit::new(TestingDevice::default()).run(&program).unwrap();
+        assert_eq!(device.output_str(), output);
+    }
+
+    // The C target prints floats with six decimal places.
+    let code = targets::C::default().build_std(&program).unwrap();
+    if let Some(c_output) = run_c("std_float_ops", &code, "") {
+        let c_values = values(&c_output);
+        assert_eq!(c_values.len(), expected.len(), "{c_output}");
+        for (c_value, value) in c_values.iter().zip(expected) {
+            assert!((c_value - value).abs() < 1e-6, "{c_output}");
+        }
+    }
+}
+
+#[test]
+fn test_c_float_literals() {
+    let mut ops = vec![];
+    for n in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN, -2.5, 1e300] {
+        ops.push(StandardOp::Set(n));
+        ops.push(StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())));
+        ops.push(StandardOp::CoreOp(CoreOp::Set(b' ' as i64)));
+        ops.push(StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())));
+    }
+    let program = StandardProgram(ops);
+
+    let code = targets::C::default().build_std(&program).unwrap();
+    assert!(code.contains("reg.f = INFINITY;"));
+    assert!(code.contains("reg.f = -INFINITY;"));
+    assert!(code.contains("reg.f = NAN;"));
+    assert!(code.contains("reg.f = -2.5;"));
+    assert!(code.contains("reg.f = 1e300;"));
+    if let Some(output) = run_c("float_literals", &code, "") {
+        let values: Vec<&str> = output.split_whitespace().collect();
+        assert_eq!(values[..2], ["inf", "-inf"]);
+        assert!(values[2].contains("nan"));
+        assert_eq!(values[3], "-2.500000");
+        assert!(values[4].starts_with("1000000000"));
+    }
+}
+
+#[test]
+fn test_c_set_and_save() {
+    let program = CoreProgram(vec![
+        CoreOp::Set(5), // Store 5 in the first cell
+        CoreOp::Save,
+        CoreOp::Move(1), // Store 7 in the second cell, and add the first cell to it
+        CoreOp::Set(7),
+        CoreOp::Save,
+        CoreOp::Move(-1),
+        CoreOp::Add,
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Move(1), // Print the second cell
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "127");
+
+    // Each `Set` and the `Save` after it are a single statement,
+    // which still leaves the constant in the register.
+    let code = targets::C::default().build_core(&program).unwrap();
+    assert!(code.contains("ptr->i = reg.i = 5;"));
+    assert!(code.contains("ptr->i = reg.i = 7;"));
+    assert!(!code.contains("*ptr = reg;"));
+    if let Some(output) = run_c("set_and_save", &code, "") {
+        assert_eq!(output, "127");
+    }
+
+    // The standard variant is compiled the same way.
+    let code = targets::C::default()
+        .build_std(&program.clone().into())
+        .unwrap();
+    assert_eq!(code.matches("ptr->i = reg.i = ").count(), 2);
+}
+
+#[test]
+fn test_c_line_directives() {
+    let src = "let x = 5;\nprintln(x * 2);\n";
+    let compile = || {
+        parse_frontend(src, Some("line_directives.sg"))
+            .unwrap()
+            .compile()
+            .unwrap()
+            .unwrap()
+            .assemble(8192)
+            .unwrap()
+            .flatten()
+    };
+    let is_marker = |op: &CoreOp| matches!(op, CoreOp::Comment(c) if c.starts_with("#line "));
+
+    // The markers are only emitted when they're asked for, so they don't
+    // change the instructions of every program.
+    assert!(!compile().0.iter().any(is_marker));
+    sage::lir::set_line_markers(true);
+    let code = compile();
+    sage::lir::set_line_markers(false);
+    assert!(code.0.iter().any(is_marker));
+
+    let mut target = targets::C::default();
+    // Without the flag, the markers are left as comments.
+    assert!(!target.build_core(&code).unwrap().contains("\n\t#line"));
+
+    target.emit_line_directives = true;
+    let c_code = target.build_core(&code).unwrap();
+    assert!(c_code.contains("#line 2 \"line_directives.sg\""));
+    if let Some(output) = run_c("line_directives", &c_code, "") {
+        assert_eq!(output, "10\n");
+    }
+}
+
+#[test]
+fn test_c_checked_div() {
+    let program = CoreProgram(vec![
+        CoreOp::Set(0),
+        CoreOp::Save,
+        CoreOp::Set(1),
+        CoreOp::Div,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+
+    // Without the flag, division is emitted as-is.
+    assert!(!targets::C::default()
+        .build_core(&program)
+        .unwrap()
+        .contains("sage_abort"));
+
+    let mut target = targets::C::default();
+    target.checked_div = true;
+    let code = target.build_core(&program).unwrap();
+    if let Some(exe) = compile_c("checked_div", &code) {
+        let output = Command::new(exe).output().unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "sage: division by zero\n"
+        );
+    }
+}
+
+#[test]
+fn test_c_checked_refs() {
+    // Dereference the first cell, which points to itself, more times than fit on the stack.
+    let mut ops = vec![CoreOp::Where, CoreOp::Save];
+    ops.extend(vec![CoreOp::Deref; 5]);
+    ops.push(CoreOp::Put(Output::stdout_int()));
+    let program = CoreProgram(ops);
+
+    let mut target = targets::C::default();
+    target.ref_stack_size = 4;
+    target.checked_refs = true;
+    let code = target.build_core(&program).unwrap();
+    if let Some(exe) = compile_c("checked_refs", &code) {
+        let output = Command::new(exe).output().unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "sage: reference stack overflow\n"
+        );
+    }
+}
+
+#[test]
+fn test_c_heap_tape() {
+    // Store a number in a function, in the last cell of a very large tape.
+    let tape_size = 50_000_000;
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Set(42),
+        CoreOp::Save,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Move(tape_size as isize - 1),
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+
+    let mut target = targets::C::default();
+    target.tape_size = tape_size;
+    target.heap_tape = true;
+    let code = target.build_core(&program).unwrap();
+    assert!(code.contains("tape = calloc(50000000, sizeof(cell));"));
+    assert!(code.contains("funs[0] = f0;"));
+    assert!(!code.contains("tape[50000000]"));
+    if let Some(output) = run_c("heap_tape", &code, "") {
+        assert_eq!(output, "42");
+    }
+}
+
+#[test]
+fn test_c_unbalanced_blocks() {
+    // An `end` without a matching block.
+    let program = CoreProgram(vec![CoreOp::Set(1), CoreOp::End]);
+    assert_eq!(
+        targets::C::default().build_core(&program).unwrap_err(),
+        "Unmatched `end` at instruction 1"
+    );
+
+    // A function which is never closed.
+    let program = CoreProgram(vec![CoreOp::Function, CoreOp::Set(1), CoreOp::While]);
+    assert_eq!(
+        targets::C::default().build_core(&program).unwrap_err(),
+        "Unterminated `while` at instruction 2"
+    );
+
+    // An `else` without an `if`, in a standard program.
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::Set(1.0),
+        StandardOp::CoreOp(CoreOp::Else),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+    assert_eq!(
+        targets::C::default().build_std(&program).unwrap_err(),
+        "Unexpected `else` at instruction 2"
+    );
+}
+
+#[test]
+fn test_flattened_nested_functions() {
+    // Functions nested in other functions and in a loop, with blocks around them.
+    let program = parse_vm(
+        "
+        fun
+           fun set 66 put stdout.char ret end
+           set 65 put stdout.char
+           ret
+        end
+        set 1 call
+        set 0 call
+        set 2 sav
+        while
+           fun
+              res if set 89 else set 78 end put stdout.char
+              ret
+           end
+           set 2 call
+           res sav set -1 add sav
+        end
+        set 3 call
+        fun set 67 put stdout.char ret end
+        ",
+    )
+    .unwrap()
+    .ok()
+    .unwrap();
+    let expected = "BAYYC";
+    let core = program.clone().flatten();
+    let std = StandardProgram::from(program).flatten();
+
+    // Every target accepts the flattened code, whichever variant it is.
+    fn check(
+        name: &str,
+        target: &mut impl CompiledTarget,
+        core: &CoreProgram,
+        std: &StandardProgram,
+    ) {
+        if let Err(e) = target.build_core(core) {
+            panic!("{name} rejected the flattened core program: {e}");
+        }
+        if let Err(e) = target.build_std(std) {
+            panic!("{name} rejected the flattened standard program: {e}");
+        }
+    }
+    check("C", &mut targets::C::default(), &core, &std);
+    check("Wat", &mut targets::Wat::default(), &core, &std);
+    check("LLVM", &mut targets::LLVM::default(), &core, &std);
+    check(
+        "JavaScript",
+        &mut targets::JavaScript::default(),
+        &core,
+        &std,
+    );
+    check("Python", &mut targets::Python::default(), &core, &std);
+    check("Go", &mut targets::Go::default(), &core, &std);
+    check("Rust", &mut targets::Rust::default(), &core, &std);
+    check("X86", &mut targets::X86::default(), &core, &std);
+    check("RiscV", &mut targets::RiscV::default(), &core, &std);
+    check("AArch64", &mut targets::AArch64::default(), &core, &std);
+
+    // The functions keep their indices, so the compiled programs call the right ones.
+    let code = targets::C::default().build_core(&core).unwrap();
+    if let Some(output) = run_c("flattened_core", &code, "") {
+        assert_eq!(output, expected);
+    }
+    let code = targets::C::default().build_std(&std).unwrap();
+    if let Some(output) = run_c("flattened_std", &code, "") {
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn test_wat_module() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function that prints the register
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(42), // Call it with 42
+        CoreOp::Save,
+        CoreOp::Set(0),
+        CoreOp::Call,
+    ]);
+
+    let code = targets::Wat::default()
+        .build_core(&program.flatten())
+        .unwrap();
+    assert!(code.starts_with("(module"));
+    assert!(code.contains("(func $f0"));
+    assert!(code.contains("(elem (i32.const 0) $f0)"));
+    assert!(code.contains("(func $main (export \"main\")"));
+    // Every S-expression must be closed.
+    assert_eq!(code.matches('(').count(), code.matches(')').count());
+}
+
+#[test]
+fn test_wat_wasi() {
+    let space = StandardOp::CoreOp(CoreOp::Set(' ' as i64));
+    let put_char = StandardOp::CoreOp(CoreOp::Put(Output::stdout_char()));
+    let echo = [
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())),
+        put_char.clone(),
+    ];
+    let mut ops = vec![
+        StandardOp::CoreOp(CoreOp::Set(i64::MIN)),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        space.clone(),
+        put_char.clone(),
+        StandardOp::Set(2.0 / 3.0),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        space.clone(),
+        put_char.clone(),
+        StandardOp::Set(-0.5),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        space,
+        put_char,
+    ];
+    ops.extend(echo.clone());
+    ops.extend(echo.clone());
+    ops.extend(echo);
+    let program = StandardProgram(ops);
+
+    let code = targets::Wat {
+        wasi: true,
+        ..Default::default()
+    }
+    .build_std(&program)
+    .unwrap();
+    assert!(code.contains("(import \"wasi_snapshot_preview1\" \"fd_read\""));
+    assert!(code.contains("(import \"wasi_snapshot_preview1\" \"fd_write\""));
+    assert!(!code.contains("(import \"env\""));
+    assert!(code.contains("(func $main (export \"_start\")"));
+    assert_eq!(code.matches('(').count(), code.matches(')').count());
+
+    // WASI doesn't provide any math functions.
+    let program = StandardProgram(vec![StandardOp::Sin]);
+    assert!(targets::Wat {
+        wasi: true,
+        ..Default::default()
+    }
+    .build_std(&program)
+    .is_err());
+
+    // Run the module if there's a WASI runtime available.
+    let src = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("wasi.wat");
+    std::fs::write(&src, &code).unwrap();
+    let Ok(mut child) = Command::new("wasmtime")
+        .arg(&src)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    else {
+        return;
+    };
+    child.stdin.take().unwrap().write_all(b"abc").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "-9223372036854775808 0.666667 -0.500000 abc"
+    );
+}
+
+#[test]
+fn test_llvm_module() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function that prints the register if it's non-zero
+        CoreOp::Restore,
+        CoreOp::If,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::End,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(42), // Call it with 42
+        CoreOp::Save,
+        CoreOp::Set(0),
+        CoreOp::Call,
+    ]);
+
+    let code = targets::LLVM::default()
+        .build_core(&program.clone().flatten())
+        .unwrap();
+    assert!(code.contains("define void @f0() {"));
+    assert!(code.contains("@funs = global [1 x ptr] [ptr @f0]"));
+    assert!(code.contains("define i32 @main() {"));
+    // The `If` must branch to its matching blocks.
+    assert!(code.contains("label %then0, label %else0"));
+    assert!(code.contains("else0:"));
+
+    // Run the module if there's an LLVM interpreter available.
+    // LLVM 14 only reads the `ptr` syntax with opaque pointers enabled.
+    let Ok(version) = Command::new("lli").arg("--version").output() else {
+        return;
+    };
+    let version = String::from_utf8_lossy(&version.stdout);
+    let src = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("llvm_module.ll");
+    std::fs::write(&src, &code).unwrap();
+    let mut lli = Command::new("lli");
+    if version.contains("LLVM version 14.") {
+        lli.arg("-opaque-pointers");
+    }
+    let output = lli.arg(&src).stdin(Stdio::null()).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        device.output_str()
+    );
+}
+
+#[test]
+fn test_javascript_module() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function that prints the register
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(42), // Call it with 42
+        CoreOp::Save,
+        CoreOp::Set(0),
+        CoreOp::Call,
+    ]);
+
+    let code = targets::JavaScript::default()
+        .build_core(&program.clone().flatten())
+        .unwrap();
+    assert!(code.contains("function f0() {"));
+    assert!(code.contains("funs[0] = f0;"));
+    assert!(code.contains("function main() {"));
+    assert!(code.trim_end().ends_with("main();"));
+    assert_eq!(code.matches('{').count(), code.matches('}').count());
+
+    // Run the module if there's a JavaScript runtime available.
+    let src = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("javascript_module.js");
+    std::fs::write(&src, &code).unwrap();
+    let Ok(output) = Command::new("node").arg(&src).stdin(Stdio::null()).output() else {
+        return;
+    };
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        device.output_str()
+    );
+}
+
+#[test]
+fn test_python_indentation() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function with an empty if-else
+        CoreOp::If,
+        CoreOp::Else,
+        CoreOp::Comment("nothing to see here".to_string()),
+        CoreOp::End,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(3), // Count down from 3
+        CoreOp::While,
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Set(0),
+        CoreOp::End,
+    ]);
+
+    let code = targets::Python::default()
+        .build_core(&program.flatten())
+        .unwrap();
+    assert!(code.contains("def f0():\n    global reg, ptr\n    if reg:\n        pass\n    else:\n        # nothing to see here\n        pass\n"));
+    assert!(code.contains("funs = {0: f0}"));
+    assert!(code.contains("while reg:\n    sys.stdout.buffer.write(b\"%d\" % reg)\n"));
+}
+
+#[test]
+fn test_python_raw_bytes() {
+    // Characters above 0x7f are written as raw bytes by every target, not encoded as UTF-8.
+    let mut ops = vec![];
+    for (n, output) in [
+        (0xa6, Output::stdout_char()),
+        (b'A' as i64, Output::stdout_char()),
+        (0xff, Output::stdout_char()),
+        (-5, Output::stdout_int()),
+        (0xa6, Output::stderr_char()),
+    ] {
+        ops.push(CoreOp::Set(n));
+        ops.push(CoreOp::Put(output));
+    }
+    let program = CoreProgram(ops);
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let bytes = |output: &[(i64, Output)]| output.iter().map(|(n, _)| *n as u8).collect();
+    let (stdout, stderr): (Vec<u8>, Vec<u8>) = (bytes(&device.output), bytes(&device.error_output));
+    assert_eq!(stdout, b"\xa6A\xff-5");
+    assert_eq!(stderr, b"\xa6");
+
+    let run = |program: &Path| Command::new(program).stdin(Stdio::null()).output().unwrap();
+    if let Some(exe) = compile_c(
+        "raw_bytes",
+        &targets::C::default().build_core(&program).unwrap(),
+    ) {
+        let output = run(&exe);
+        assert_eq!(
+            (output.stdout, output.stderr),
+            (stdout.clone(), stderr.clone())
+        );
+    }
+
+    let code = targets::Python::default().build_core(&program).unwrap();
+    let src = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("raw_bytes.py");
+    std::fs::write(&src, code).unwrap();
+    let Ok(output) = Command::new("python3")
+        .arg(&src)
+        .stdin(Stdio::null())
+        .output()
+    else {
+        return;
+    };
+    assert_eq!((output.stdout, output.stderr), (stdout, stderr));
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn test_x86_std_echo() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(6)), // Print an integer
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::CoreOp(CoreOp::Function), // Echo a character
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Return),
+        StandardOp::CoreOp(CoreOp::End),
+        StandardOp::CoreOp(CoreOp::Set(0)), // Call it twice
+        StandardOp::CoreOp(CoreOp::Call),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Call),
+    ]);
+
+    // Build position independent code, which links with the default flags.
+    let mut target = targets::X86::default();
+    target.pic = true;
+    let code = target.build_std(&program.flatten()).unwrap();
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join("x86_std_echo.s");
+    let exe = dir.join("x86_std_echo");
+    std::fs::write(&src, code).unwrap();
+    let Ok(status) = Command::new("cc")
+        .arg(&src)
+        .arg("-o")
+        .arg(&exe)
+        .arg("-lm")
+        .status()
+    else {
+        return;
+    };
+    assert!(status.success(), "Could not assemble `x86_std_echo`");
+    assert_eq!(run_exe(&exe, "ab"), "6ab");
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn test_x86_io_symbols() {
+    let program = CoreProgram(vec![
+        CoreOp::Get(Input::stdin_char()),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Get(Input::stdin_char()),
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+
+    // libc's functions are used by default.
+    let code = targets::X86::default().build_core(&program).unwrap();
+    assert!(code.contains("call getchar") && code.contains("putchar"));
+
+    // Other functions can be linked in instead.
+    let mut target = targets::X86::default();
+    target.getchar_sym = "read_upper".to_string();
+    target.putchar_sym = "write_twice".to_string();
+    let code = target.build_core(&program).unwrap();
+    assert!(!code.contains("getchar") && !code.contains("putchar"));
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join("x86_io_symbols.s");
+    let runtime = dir.join("x86_io_symbols_runtime.c");
+    let exe = dir.join("x86_io_symbols");
+    std::fs::write(&src, code).unwrap();
+    std::fs::write(
+        &runtime,
+        r#"#include <ctype.h>
+#include <stdio.h>
+int read_upper(void) { return toupper(getchar()); }
+int write_twice(int c) { putchar(c); return putchar(c); }
+"#,
+    )
+    .unwrap();
+    // By default, the generated code uses absolute addresses, so it isn't position independent.
+    let Ok(status) = Command::new("cc")
+        .arg("-no-pie")
+        .arg(&src)
+        .arg(&runtime)
+        .arg("-o")
+        .arg(&exe)
+        .status()
+    else {
+        return;
+    };
+    assert!(status.success(), "Could not assemble `x86_io_symbols`");
+    assert_eq!(run_exe(&exe, "ab"), "AABB");
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn test_x86_pic() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Echo a character
+        CoreOp::Get(Input::stdin_char()),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(0), // Call it twice
+        CoreOp::Call,
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Set(42),
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+
+    // Nothing is referred to by its absolute address.
+    let mut target = targets::X86::default();
+    target.pic = true;
+    let code = target.build_core(&program.flatten()).unwrap();
+    assert!(!code.contains("$fun") && !code.contains("funs(,"), "{code}");
+    assert!(!code.contains("$int_print") && code.contains("call getchar@PLT"));
+
+    // The code links as a position independent executable.
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join("x86_pic.s");
+    let exe = dir.join("x86_pic");
+    std::fs::write(&src, code).unwrap();
+    let Ok(status) = Command::new("cc")
+        .arg("-pie")
+        .arg(&src)
+        .arg("-o")
+        .arg(&exe)
+        .status()
+    else {
+        return;
+    };
+    assert!(status.success(), "Could not assemble `x86_pic`");
+    assert_eq!(run_exe(&exe, "ab"), "ab42");
+}
+
+#[test]
+fn test_riscv_labels() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function that prints the register if it's non-zero
+        CoreOp::Restore,
+        CoreOp::If,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Else,
+        CoreOp::Set(1),
+        CoreOp::End,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(42), // Call it with 42 while the register is non-zero
+        CoreOp::While,
+        CoreOp::Save,
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Set(0),
+        CoreOp::End,
+    ]);
+
+    let code = targets::RiscV::default()
+        .build_core(&program.flatten())
+        .unwrap();
+    assert!(code.contains("f0:\n\taddi sp, sp, -16\n\tsd ra, 8(sp)"));
+    assert!(code.contains(".dword f0"));
+    // Every label jumped to must be defined exactly once.
+    for line in code.lines() {
+        let line = line.trim();
+        if let Some(label) = line.strip_prefix("j ").or(line.strip_prefix("bnez a0, ")) {
+            let definition = format!("{label}:");
+            let definitions = code.lines().filter(|line| line.trim() == definition);
+            assert_eq!(definitions.count(), 1, "{label}");
+        }
+    }
+}
+
+#[test]
+fn test_aarch64_labels() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function that prints the register if it's non-zero
+        CoreOp::Restore,
+        CoreOp::If,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Else,
+        CoreOp::Set(1),
+        CoreOp::End,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(42), // Call it with 42 while the register is non-zero
+        CoreOp::While,
+        CoreOp::Save,
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Set(0x1234_0000_5678), // Load a constant too big for one instruction
+        CoreOp::Move(-1000),           // Move the pointer further than one instruction can
+        CoreOp::Set(0),
+        CoreOp::End,
+    ]);
+
+    let code = targets::AArch64::default()
+        .build_core(&program.flatten())
+        .unwrap();
+    assert!(code.contains("f0:\n\tstp x29, x30, [sp, #-16]!\n\tmov x29, sp"));
+    assert!(code.contains(".xword f0"));
+    assert!(code.contains("blr x9"));
+    assert!(code.contains("movz x0, #22136\n\tmovk x0, #4660, lsl #32\n"));
+    assert!(code.contains("mov x9, #-8000\n\tadd x19, x19, x9"));
+    // Every label branched to must be defined exactly once.
+    for line in code.lines() {
+        let line = line.trim();
+        if let Some(label) = line.strip_prefix("b ").or(line.strip_prefix("cbz x0, ")) {
+            let definition = format!("{label}:");
+            let definitions = code.lines().filter(|line| line.trim() == definition);
+            assert_eq!(definitions.count(), 1, "{label}");
+        }
+    }
+}
+
+#[test]
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn test_aarch64_std_echo() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(6)), // Print an integer
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::CoreOp(CoreOp::Function), // Echo a character
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Return),
+        StandardOp::CoreOp(CoreOp::End),
+        StandardOp::CoreOp(CoreOp::Set(0)), // Call it twice
+        StandardOp::CoreOp(CoreOp::Call),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Call),
+    ]);
+
+    let code = targets::AArch64::default()
+        .build_std(&program.flatten())
+        .unwrap();
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join("aarch64_std_echo.s");
+    let exe = dir.join("aarch64_std_echo");
+    std::fs::write(&src, code).unwrap();
+    let Ok(status) = Command::new("cc")
+        .arg(&src)
+        .arg("-o")
+        .arg(&exe)
+        .arg("-lm")
+        .status()
+    else {
+        return;
+    };
+    assert!(status.success(), "Could not assemble `aarch64_std_echo`");
+    assert_eq!(run_exe(&exe, "ab"), "6ab");
+}
+
+#[test]
+fn test_sage_os_console() {
+    let program = CoreProgram(vec![
+        CoreOp::Get(Input::stdin_char()), // Echo a character
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+
+    // By default, the standard console functions and `main` are used.
+    let code = targets::SageOS::default()
+        .build_core(&program.clone().flatten())
+        .unwrap();
+    assert!(code.contains("int main () {"));
+    assert!(code.contains("putchar(reg.i);"));
+    assert!(!code.contains("#define getchar"));
+
+    let code = targets::SageOS {
+        getchar: "myos_getchar".to_string(),
+        putchar: "myos_putchar".to_string(),
+        entry: Some("kernel_main".to_string()),
+    }
+    .build_core(&program.flatten())
+    .unwrap();
+    assert!(code.contains("#define getchar myos_getchar\n#define putchar myos_putchar\n"));
+    assert!(code.contains("void kernel_main(void) {"));
+    assert!(!code.contains("int main"));
+    assert!(code.contains("myos_putchar(reg.i);"));
+    assert!(code.trim_end().ends_with("return;\n}"));
+}
+
+#[test]
+fn test_brainfuck() {
+    let program = CoreProgram(vec![
+        CoreOp::Set(97), // Store 'a' in the first cell
+        CoreOp::Save,
+        CoreOp::Move(1), // Count down from 5 in the second cell
+        CoreOp::Set(5),
+        CoreOp::Save,
+        CoreOp::While,
+        CoreOp::Move(-1), // Print the letter, or '-' if it's 'c'
+        CoreOp::Set(99),
+        CoreOp::Sub,
+        CoreOp::If,
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Else,
+        CoreOp::Set(45),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::End,
+        CoreOp::Set(1), // Go to the next letter
+        CoreOp::Add,
+        CoreOp::Save,
+        CoreOp::Move(1), // Decrement the counter
+        CoreOp::Set(-1),
+        CoreOp::Add,
+        CoreOp::Save,
+        CoreOp::End,
+        CoreOp::Set(6), // Print 6 * 7 as a character
+        CoreOp::Save,
+        CoreOp::Set(7),
+        CoreOp::Mul,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Get(Input::stdin_char()), // Echo a character
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+
+    let device = CoreInterpreter::new(TestingDevice::new("!"))
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "ab-de*!");
+
+    let code = targets::Brainfuck.build_core(&program.flatten()).unwrap();
+    assert_eq!(run_brainfuck(&code, "!"), device.output_str());
+
+    // Instructions which can't be expressed in Brainfuck are errors.
+    let program = CoreProgram(vec![CoreOp::Set(2), CoreOp::Set(1), CoreOp::Div]);
+    let err = targets::Brainfuck.build_core(&program).unwrap_err();
+    assert!(err.contains("`div`"));
+    let program = CoreProgram(vec![CoreOp::Set(1000)]);
+    assert!(targets::Brainfuck.build_core(&program).is_err());
+}
+
+#[test]
+fn test_go_module() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Function), // Define a function that prints the cell as a float
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        StandardOp::CoreOp(CoreOp::Return),
+        StandardOp::CoreOp(CoreOp::End),
+        StandardOp::Set(1.5), // Call it with 1.5 * 4
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::Set(4.0),
+        StandardOp::Mul,
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Call),
+        StandardOp::CoreOp(CoreOp::Set(3)), // Echo 3 characters
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Set(-1)),
+        StandardOp::CoreOp(CoreOp::Add),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+
+    let device = StandardInterpreter::new(TestingDevice::new("abc"))
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "6.0abc");
+
+    let code = targets::Go::default()
+        .build_std(&program.flatten())
+        .unwrap();
+    assert!(code.starts_with("package main\n"));
+    assert!(code.contains("func f0() {"));
+    assert!(code.contains("funs = []func(){f0}"));
+    assert!(code.contains("for reg != 0 {"));
+    assert_eq!(code.matches('{').count(), code.matches('}').count());
+
+    // Run the program if there's a Go compiler available.
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join("go_module.go");
+    let exe = dir.join("go_module");
+    std::fs::write(&src, &code).unwrap();
+    let Ok(status) = Command::new("go")
+        .arg("build")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&src)
+        .status()
+    else {
+        return;
+    };
+    assert!(status.success(), "Could not compile `go_module`");
+    assert_eq!(run_exe(&exe, "abc"), device.output_str());
+}
+
+#[test]
+fn test_rust_program() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Function), // Define a function that prints the cell as a float
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        StandardOp::CoreOp(CoreOp::Return),
+        StandardOp::CoreOp(CoreOp::End),
+        StandardOp::Set(1.5), // Call it with 1.5 * 4
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::Set(4.0),
+        StandardOp::Mul,
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Call),
+        StandardOp::CoreOp(CoreOp::Move(1)), // Print (i64::MAX + i64::MAX) * 7, which wraps
+        StandardOp::CoreOp(CoreOp::Set(7)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Set(i64::MAX)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Add),
+        StandardOp::CoreOp(CoreOp::Move(-1)),
+        StandardOp::CoreOp(CoreOp::Mul),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::CoreOp(CoreOp::Move(-1)),
+        StandardOp::CoreOp(CoreOp::Set(3)), // Echo 3 characters
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Set(-1)),
+        StandardOp::CoreOp(CoreOp::Add),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+
+    let device = StandardInterpreter::new(TestingDevice::new("abc"))
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "6.0-14abc");
+
+    let code = targets::Rust::default()
+        .build_std(&program.flatten())
+        .unwrap();
+    assert!(code.contains("fn f0(vm: &mut Vm) {"));
+    assert!(code.contains("const FUNS: &[fn(&mut Vm)] = &[f0];"));
+    assert!(code.contains("while vm.reg != 0 {"));
+
+    // Build the program with `rustc`, and check it behaves like the interpreter.
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let src = dir.join("rust_program.rs");
+    let exe = dir.join("rust_program");
+    std::fs::write(&src, &code).unwrap();
+    let Ok(status) = Command::new("rustc").arg("-o").arg(&exe).arg(&src).status() else {
+        return;
+    };
+    assert!(status.success(), "Could not compile `rust_program`");
+    assert_eq!(run_exe(&exe, "abc"), device.output_str());
+}
@@ -1,4 +1,8 @@
-use sage::{side_effects::Output, vm::*};
+use sage::{
+    parse::parse_vm,
+    side_effects::{Color, FFIBinding, Input, InputMode, Output, OutputMode},
+    vm::*,
+};
 
 #[test]
 fn test_add() {
@@ -134,3 +138,1232 @@ fn test_pointers() {
 
     assert_eq!(device.output_vals(), vec![b, a]);
 }
+
+/// A device defined outside of the crate, with an in-memory input buffer
+/// and a captured output buffer.
+#[derive(Debug, Default)]
+struct BufferDevice {
+    input: std::collections::VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Device for BufferDevice {
+    fn get(&mut self, _src: Input) -> Result<i64, String> {
+        self.input
+            .pop_front()
+            .map(i64::from)
+            .ok_or_else(|| "out of input".to_string())
+    }
+
+    fn put(&mut self, val: i64, _dst: Output) -> Result<(), String> {
+        self.output.push(val as u8);
+        Ok(())
+    }
+
+    fn peek(&mut self) -> Result<i64, String> {
+        Err("no FFI".to_string())
+    }
+
+    fn poke(&mut self, _val: i64) -> Result<(), String> {
+        Err("no FFI".to_string())
+    }
+
+    fn ffi_call(&mut self, _ffi: &FFIBinding, _tape: Option<&mut Vec<i64>>) -> Result<(), String> {
+        Err("no FFI".to_string())
+    }
+}
+
+#[test]
+fn test_custom_device() {
+    let program = CoreProgram(vec![
+        CoreOp::Get(Input::stdin_char()), // Echo two characters
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Get(Input::stdin_char()),
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+
+    let device = BufferDevice {
+        input: b"hi".iter().copied().collect(),
+        ..BufferDevice::default()
+    };
+    let device = CoreInterpreter::new(device).run(&program).unwrap();
+    assert_eq!(device.output, b"hi");
+
+    // Errors from the device halt the interpreter.
+    let device = BufferDevice {
+        input: b"h".iter().copied().collect(),
+        ..BufferDevice::default()
+    };
+    assert_eq!(
+        CoreInterpreter::new(device).run(&program).unwrap_err(),
+        "out of input"
+    );
+}
+
+#[test]
+fn test_max_steps() {
+    let program = CoreProgram(vec![
+        CoreOp::Set(1), // Loop forever
+        CoreOp::While,
+        CoreOp::End,
+    ]);
+    assert_eq!(
+        CoreInterpreter::new(TestingDevice::default())
+            .with_max_steps(Some(1000))
+            .run(&program)
+            .unwrap_err(),
+        "step limit exceeded"
+    );
+
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(1)),
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+    assert_eq!(
+        StandardInterpreter::new(TestingDevice::default())
+            .with_max_steps(Some(1000))
+            .run(&program)
+            .unwrap_err(),
+        "step limit exceeded"
+    );
+
+    // A program that halts within the limit runs to completion.
+    let program = CoreProgram(vec![CoreOp::Set(65), CoreOp::Put(Output::stdout_char())]);
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .with_max_steps(Some(2))
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_vals(), vec![65]);
+}
+
+#[test]
+fn test_tape() {
+    let program = CoreProgram(vec![
+        CoreOp::Set(5), // Store 5 in the third cell
+        CoreOp::Move(2),
+        CoreOp::Save,
+        CoreOp::Move(-2),
+    ]);
+    let mut i = CoreInterpreter::new(TestingDevice::default());
+    i.execute(&program).unwrap();
+    assert_eq!(i.tape(), &[0, 0, 5]);
+}
+
+#[test]
+fn test_breakpoints() {
+    // Count down from 3 to 0.
+    let program = CoreProgram(vec![
+        CoreOp::Set(-1),
+        CoreOp::Move(1),
+        CoreOp::Save,
+        CoreOp::Move(-1),
+        CoreOp::Set(3),
+        CoreOp::While,
+        CoreOp::Move(1),
+        CoreOp::Add,
+        CoreOp::Move(-1),
+        CoreOp::End,
+    ]);
+
+    // Without any breakpoints, the program runs to completion.
+    let mut vm = CoreInterpreter::new(TestingDevice::default());
+    assert!(!vm.execute_until_break(&program).unwrap());
+
+    // The loop body pauses before every `add`, and then continues.
+    let mut vm = CoreInterpreter::new(TestingDevice::default()).with_breakpoints([7]);
+    let mut states = vec![];
+    while vm.execute_until_break(&program).unwrap() {
+        states.push(vm.describe_state(&program));
+    }
+    assert_eq!(states.len(), 3);
+    assert_eq!(
+        states[0],
+        "breakpoint at instruction #7 (`add`)\n  register: 3\n  pointer: 1\n  cells 0..=5: 0 [-1] 0 0 0 0"
+    );
+    assert!(states[2].contains("register: 1\n"));
+
+    // The standard interpreter pauses the same way.
+    let program = StandardProgram(vec![
+        StandardOp::Set(1.5),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Move(1)),
+    ]);
+    let mut vm = StandardInterpreter::new(TestingDevice::default()).with_breakpoints([0, 2]);
+    assert!(vm.execute_until_break(&program).unwrap());
+    assert!(vm.describe_state(&program).contains("#0 (`set-f 1.5`)"));
+    assert!(vm.execute_until_break(&program).unwrap());
+    assert!(vm.describe_state(&program).contains("#2 (`mov 1`)"));
+    assert!(!vm.execute_until_break(&program).unwrap());
+}
+
+#[test]
+fn test_validate() {
+    // A well formed program with a function, a loop, and an if-else.
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(1),
+        CoreOp::While,
+        CoreOp::If,
+        CoreOp::Set(0),
+        CoreOp::Else,
+        CoreOp::Set(1),
+        CoreOp::End,
+        CoreOp::End,
+    ]);
+    assert_eq!(program.validate(), Ok(()));
+    assert_eq!(StandardProgram::from(program).validate(), Ok(()));
+
+    // An `End` with no block to close.
+    let program = CoreProgram(vec![CoreOp::If, CoreOp::End, CoreOp::End]);
+    assert_eq!(program.validate(), Err(Error::UnmatchedEnd(2)));
+
+    // An `If` which is never closed.
+    let program = CoreProgram(vec![CoreOp::While, CoreOp::End, CoreOp::If, CoreOp::Set(1)]);
+    assert_eq!(
+        program.validate(),
+        Err(Error::UnterminatedBlock(CoreOp::If, 2))
+    );
+
+    // An `Else` outside of an `If`.
+    let program = StandardProgram(vec![
+        StandardOp::Set(1.0),
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::CoreOp(CoreOp::Else),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+    assert_eq!(program.validate(), Err(Error::UnexpectedElse(2)));
+
+    // A second `Else` for the same `If`.
+    let program = CoreProgram(vec![CoreOp::If, CoreOp::Else, CoreOp::Else, CoreOp::End]);
+    assert_eq!(program.validate(), Err(Error::UnexpectedElse(2)));
+}
+
+#[test]
+fn test_bytecode() {
+    let program = CoreProgram(vec![
+        CoreOp::Comment("read a character".to_string()),
+        CoreOp::Get(Input::stdin_char()),
+        CoreOp::Save,
+        CoreOp::Set(-1234567890123),
+        CoreOp::Move(-3),
+        CoreOp::While,
+        CoreOp::Put(Output::new(
+            OutputMode::SetCursorPixel(Color::RGB(1, 2, 3)),
+            2,
+        )),
+        CoreOp::Get(Input::new(InputMode::Custom("sensor".to_string()), 300)),
+        CoreOp::End,
+    ]);
+    let bytes = program.to_bytes();
+    assert_eq!(CoreProgram::from_bytes(&bytes).unwrap().0, program.0);
+    // Core bytecode can also be loaded as a standard program.
+    assert_eq!(
+        StandardProgram::from_bytes(&bytes).unwrap().0,
+        StandardProgram::from(program).0
+    );
+
+    let program = StandardProgram(vec![
+        StandardOp::Set(-0.5),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::Pow,
+        StandardOp::Call(FFIBinding::new("draw".to_string(), 3, 1)),
+    ]);
+    let bytes = program.to_bytes();
+    assert_eq!(StandardProgram::from_bytes(&bytes).unwrap().0, program.0);
+    assert_eq!(
+        CoreProgram::from_bytes(&bytes).map(|program| program.0),
+        Err(Error::ExpectedCore(StandardOp::Set(-0.5)))
+    );
+
+    // Truncated and corrupted bytecode is rejected.
+    assert!(StandardProgram::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(StandardProgram::from_bytes(b"SAGE\x01\x7f").is_err());
+    assert!(StandardProgram::from_bytes(b"not bytecode").is_err());
+    // Every truncation of the bytecode is decoded without panicking.
+    for end in 0..bytes.len() {
+        let _ = StandardProgram::from_bytes(&bytes[..end]);
+    }
+}
+
+#[test]
+fn test_utf8_io() {
+    // Echo every character of the input, until the end of the input.
+    let program = match parse_vm("get stdin.utf8\nwhile\nput stdout.utf8\nget stdin.utf8\nend") {
+        Ok(Ok(program)) => program,
+        _ => panic!("expected a core program"),
+    };
+    assert!(program.to_string().contains("put stdout.utf8 #0"));
+
+    let dir = std::path::PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let echo = |input: &[u8], name: &str| {
+        let path = dir.join(name);
+        let device = StandardDevice::with_input(input)
+            .unwrap()
+            .with_output(std::fs::File::create(&path).unwrap());
+        CoreInterpreter::new(device).run(&program).unwrap();
+        std::fs::read_to_string(&path).unwrap()
+    };
+
+    // Characters of every length are read and written whole.
+    assert_eq!(echo("aé€🦀\n".as_bytes(), "utf8_echo"), "aé€🦀\n");
+    // Invalid bytes, truncated characters, overlong encodings, and encoded surrogates
+    // are read as replacement characters. The byte after a truncated character is kept.
+    assert_eq!(
+        echo(
+            b"\xff!\xc3(\xe0\x80\x80\xed\xa0\x80\xf4\x90\x80\x80",
+            "utf8_invalid"
+        ),
+        "\u{fffd}!\u{fffd}(\u{fffd}\u{fffd}\u{fffd}"
+    );
+
+    // Cells which aren't characters are written as replacement characters.
+    let program = CoreProgram(vec![
+        CoreOp::Set(0x1f980),
+        CoreOp::Put(Output::stdout_utf8()),
+        CoreOp::Set(0xd800),
+        CoreOp::Put(Output::stdout_utf8()),
+        CoreOp::Set(-1),
+        CoreOp::Put(Output::stdout_utf8()),
+        CoreOp::Set('é' as i64),
+        CoreOp::Put(Output::stderr_utf8()),
+    ]);
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "🦀\u{fffd}\u{fffd}");
+    assert_eq!(device.error_str(), "é");
+
+    // The modes are kept in bytecode.
+    assert_eq!(
+        CoreProgram::from_bytes(&program.to_bytes()).unwrap().0,
+        program.0
+    );
+}
+
+#[test]
+fn test_optimize_moves() {
+    let program = CoreProgram(vec![CoreOp::Move(3), CoreOp::Move(-3)]);
+    assert_eq!(program.optimize_moves().0, vec![]);
+
+    // Runs of moves are merged, but not across other instructions.
+    let program = CoreProgram(vec![
+        CoreOp::Set(5),
+        CoreOp::Move(2),
+        CoreOp::Move(3),
+        CoreOp::Move(-1),
+        CoreOp::Save,
+        CoreOp::Move(-4),
+        CoreOp::Move(1),
+        CoreOp::Move(3),
+        CoreOp::Restore,
+        CoreOp::Move(4),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Move(-4),
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+    let optimized = program.clone().optimize_moves();
+    assert_eq!(
+        optimized.0,
+        vec![
+            CoreOp::Set(5),
+            CoreOp::Move(4),
+            CoreOp::Save,
+            CoreOp::Restore,
+            CoreOp::Move(4),
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Move(-4),
+            CoreOp::Restore,
+            CoreOp::Put(Output::stdout_char()),
+        ]
+    );
+
+    // The optimized program behaves the same as the original.
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&optimized)
+        .unwrap();
+    assert_eq!(device.output_vals(), expected.output_vals());
+    assert_eq!(device.output_vals(), vec![5, 5]);
+
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Move(-1)),
+        StandardOp::Set(1.5),
+    ]);
+    assert_eq!(program.optimize_moves().0, vec![StandardOp::Set(1.5)]);
+}
+
+#[test]
+fn test_eliminate_redundant_saves() {
+    let program = CoreProgram(vec![
+        CoreOp::Set(7),
+        CoreOp::Save,
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Save,
+        CoreOp::Move(1),
+        CoreOp::Restore,
+        CoreOp::Move(-1),
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Set(1),
+        CoreOp::While,
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Set(0),
+        CoreOp::End,
+    ]);
+    let optimized = program.clone().eliminate_redundant_saves();
+    assert_eq!(
+        optimized.0,
+        vec![
+            CoreOp::Set(7),
+            CoreOp::Save,
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Move(1),
+            CoreOp::Restore,
+            CoreOp::Move(-1),
+            CoreOp::Restore,
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Set(1),
+            CoreOp::While,
+            CoreOp::Restore,
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Set(0),
+            CoreOp::End,
+        ]
+    );
+
+    // The optimized program behaves the same as the original.
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&optimized)
+        .unwrap();
+    assert_eq!(device.output_vals(), expected.output_vals());
+    assert_eq!(device.output_vals(), vec![7, 7, 7]);
+}
+
+#[test]
+fn test_indent() {
+    let program = CoreProgram(vec![
+        CoreOp::While,
+        CoreOp::If,
+        CoreOp::Set(1),
+        CoreOp::End,
+        CoreOp::End,
+    ]);
+    let indents = |text: String| {
+        text.lines()
+            .map(|line| line.len() - line.trim_start().len())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(indents(program.to_string()), vec![0, 3, 6, 3, 0]);
+    assert_eq!(indents(format!("{program:4}")), vec![0, 4, 8, 4, 0]);
+    assert_eq!(indents(format!("{program:0$}", 0)), vec![0; 5]);
+
+    // The indentation doesn't change how the program is parsed.
+    for text in [format!("{program:0$}", 0), format!("{program:4}")] {
+        assert!(parse_vm(text).unwrap() == Ok(program.clone()));
+    }
+}
+
+#[test]
+fn test_listing() {
+    let program = CoreProgram(vec![
+        CoreOp::Comment("count down".to_string()),
+        CoreOp::Set(2),
+        CoreOp::While,
+        CoreOp::Sub,
+        CoreOp::End,
+    ]);
+    // Comments are numbered too, since the interpreter counts them.
+    assert_eq!(
+        program.listing().to_string(),
+        "0000: // count down\n0001: set 2\n0002: while\n0003:    sub\n0004: end\n"
+    );
+    assert_eq!(
+        format!("{:1}", program.listing()).lines().nth(3),
+        Some("0003:  sub")
+    );
+
+    // The index of each instruction is the one its breakpoint is given.
+    let mut vm = CoreInterpreter::new(TestingDevice::default()).with_breakpoints([3]);
+    assert!(vm.execute_until_break(&program).unwrap());
+    let listed = program.listing().to_string();
+    let line = listed.lines().nth(3).unwrap();
+    assert!(vm
+        .describe_state(&program)
+        .contains(&format!("#3 (`{}`)", line[6..].trim())));
+
+    let program = StandardProgram(vec![
+        StandardOp::Set(1.5),
+        StandardOp::CoreOp(CoreOp::Comment("print it".to_string())),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+    ]);
+    assert_eq!(
+        program.listing().to_string(),
+        "0000: set-f 1.5\n0001: // print it\n0002: put stdout.float #0\n"
+    );
+}
+
+#[test]
+fn test_strip_comments() {
+    let program = CoreProgram(vec![
+        CoreOp::Comment("set the register".to_string()),
+        CoreOp::Set(65),
+        CoreOp::Comment("print it".to_string()),
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+    let stripped = program.clone().strip_comments();
+    assert_eq!(
+        stripped.0,
+        vec![CoreOp::Set(65), CoreOp::Put(Output::stdout_char())]
+    );
+
+    // The stripped program round-trips through the parser.
+    let parsed = parse_vm(stripped.to_string()).unwrap();
+    assert!(parsed == Ok(stripped.clone()));
+
+    // The stripped program behaves the same as the original.
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&stripped)
+        .unwrap();
+    assert_eq!(device.output_vals(), expected.output_vals());
+}
+
+#[test]
+fn test_op_counts() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Comment("not counted".to_string())),
+        StandardOp::CoreOp(CoreOp::Set(1)),
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Move(-1)),
+        StandardOp::CoreOp(CoreOp::Add),
+        StandardOp::Add,
+    ]);
+    let counts = program.op_counts();
+    assert_eq!(
+        counts.into_iter().collect::<Vec<_>>(),
+        vec![("add", 1), ("add-f", 1), ("mov", 2), ("sav", 1), ("set", 1)]
+    );
+}
+
+#[test]
+fn test_fold_constants() {
+    // The cell still holds the saved value, so the `Save` is kept.
+    let program = CoreProgram(vec![
+        CoreOp::Set(2),
+        CoreOp::Save,
+        CoreOp::Set(3),
+        CoreOp::Add,
+    ]);
+    assert_eq!(
+        program.fold_constants().0,
+        vec![CoreOp::Set(2), CoreOp::Save, CoreOp::Set(5)]
+    );
+
+    let program = CoreProgram(vec![
+        CoreOp::Set(6),
+        CoreOp::Save,
+        CoreOp::Set(7),
+        CoreOp::Mul,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Save,
+        CoreOp::Set(-1),
+        CoreOp::Sub,
+        CoreOp::IsNonNegative,
+        CoreOp::Put(Output::stdout_char()),
+        // Division by zero and unknown cells are not folded.
+        CoreOp::Set(0),
+        CoreOp::Save,
+        CoreOp::Set(1),
+        CoreOp::Div,
+        CoreOp::Move(1),
+        CoreOp::Set(1),
+        CoreOp::Add,
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+    let folded = program.clone().fold_constants();
+    assert_eq!(
+        folded.0,
+        vec![
+            CoreOp::Set(6),
+            CoreOp::Save,
+            CoreOp::Set(42),
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Save,
+            CoreOp::Set(0),
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Set(0),
+            CoreOp::Save,
+            CoreOp::Set(1),
+            CoreOp::Div,
+            CoreOp::Move(1),
+            CoreOp::Set(1),
+            CoreOp::Add,
+            CoreOp::Put(Output::stdout_char()),
+        ]
+    );
+
+    // The folded program behaves the same as the original.
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&folded)
+        .unwrap();
+    assert_eq!(device.output_vals(), expected.output_vals());
+    assert_eq!(device.output_vals(), vec![42, 0, 1]);
+
+    // Nothing is folded which doesn't fit in the cells the program runs with.
+    let program = CoreProgram(vec![
+        CoreOp::Set(100),
+        CoreOp::Save,
+        CoreOp::Set(100),
+        CoreOp::Add,
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Set(256),
+        CoreOp::Save,
+        CoreOp::Set(-250),
+        CoreOp::Add,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+    assert_eq!(
+        program.clone().fold_constants_with_cell_bits(8).0,
+        program.0
+    );
+    assert_eq!(
+        program.clone().fold_constants().0,
+        vec![
+            CoreOp::Set(100),
+            CoreOp::Save,
+            CoreOp::Set(200),
+            CoreOp::Put(Output::stdout_int()),
+            CoreOp::Set(256),
+            CoreOp::Save,
+            CoreOp::Set(6),
+            CoreOp::Put(Output::stdout_int()),
+        ]
+    );
+    let err = CoreInterpreter::new(TestingDevice::default())
+        .with_cell_bits(8)
+        .with_trap_overflow(true)
+        .run(&program.optimize_with_cell_bits(2, 8))
+        .unwrap_err();
+    assert!(err.contains("overflowed a 8-bit cell"), "{err}");
+}
+
+#[test]
+fn test_remove_unreachable() {
+    let program = CoreProgram(vec![
+        // A function which returns early from a loop, and at its end.
+        CoreOp::Function,
+        CoreOp::Restore,
+        CoreOp::While,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::Set(0),
+        CoreOp::If,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::End,
+        CoreOp::End,
+        CoreOp::Return,
+        // A function defined after the return is kept, with its own dead code removed.
+        CoreOp::Function,
+        CoreOp::Set('!' as i64),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::End,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::End,
+        // Call both functions.
+        CoreOp::Set('A' as i64),
+        CoreOp::Save,
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Set(1),
+        CoreOp::Call,
+    ]);
+    let optimized = program.clone().remove_unreachable();
+    assert_eq!(
+        optimized.0,
+        vec![
+            CoreOp::Function,
+            CoreOp::Restore,
+            CoreOp::While,
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Return,
+            CoreOp::End,
+            CoreOp::Return,
+            CoreOp::Function,
+            CoreOp::Set('!' as i64),
+            CoreOp::Put(Output::stdout_char()),
+            CoreOp::Return,
+            CoreOp::End,
+            CoreOp::End,
+            CoreOp::Set('A' as i64),
+            CoreOp::Save,
+            CoreOp::Set(0),
+            CoreOp::Call,
+            CoreOp::Set(1),
+            CoreOp::Call,
+        ]
+    );
+    assert!(optimized.validate().is_ok());
+
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&optimized)
+        .unwrap();
+    assert_eq!(device.output_str(), expected.output_str());
+    assert_eq!(device.output_str(), "A!");
+
+    // The branch after a returning branch is still reachable.
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::If),
+        StandardOp::CoreOp(CoreOp::Return),
+        StandardOp::Set(1.0),
+        StandardOp::CoreOp(CoreOp::Else),
+        StandardOp::Set(2.0),
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+    assert_eq!(
+        program.remove_unreachable().0,
+        vec![
+            StandardOp::CoreOp(CoreOp::If),
+            StandardOp::CoreOp(CoreOp::Return),
+            StandardOp::CoreOp(CoreOp::Else),
+            StandardOp::Set(2.0),
+            StandardOp::CoreOp(CoreOp::End),
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_levels() {
+    let program = CoreProgram(vec![
+        // A function which prints a constant sum, with unreachable code after it returns.
+        CoreOp::Function,
+        CoreOp::Set(40),
+        CoreOp::Save,
+        CoreOp::Set(2),
+        CoreOp::Add,
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Return,
+        CoreOp::Set(1),
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::End,
+        // Call it, and print the value left in the register.
+        CoreOp::Set(0),
+        CoreOp::Call,
+        CoreOp::Move(3),
+        CoreOp::Move(-3),
+        CoreOp::Save,
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(expected.output_str(), "4242");
+
+    // Each level removes more instructions, without changing what the program does.
+    let mut lengths = vec![];
+    for level in 0..=2 {
+        let optimized = program.clone().optimize(level);
+        let device = CoreInterpreter::new(TestingDevice::default())
+            .run(&optimized)
+            .unwrap();
+        assert_eq!(device.output_str(), expected.output_str());
+        lengths.push(optimized.0.len());
+    }
+    // Level 1 merges the moves and removes the restore, and level 2 also
+    // removes the unreachable code and folds the addition.
+    assert_eq!(lengths, vec![17, 14, 11]);
+    assert_eq!(program.clone().optimize(3).0, program.optimize(2).0);
+}
+
+#[test]
+fn test_annotate_source() {
+    let marker = |line: usize| CoreOp::Comment(format!("#line {line} \"src/main.sg\""));
+    let program = CoreProgram(vec![
+        marker(1),
+        CoreOp::Set(1),
+        // Statements nested in a statement on the same line mark it again.
+        marker(1),
+        CoreOp::Put(Output::stdout_int()),
+        marker(2),
+        CoreOp::Comment("other comments are kept".to_string()),
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+    assert_eq!(
+        program.annotate_source().0,
+        vec![
+            CoreOp::Comment("src/main.sg:1".to_string()),
+            CoreOp::Set(1),
+            CoreOp::Put(Output::stdout_int()),
+            CoreOp::Comment("src/main.sg:2".to_string()),
+            CoreOp::Comment("other comments are kept".to_string()),
+            CoreOp::Put(Output::stdout_int()),
+        ]
+    );
+}
+
+#[test]
+fn test_random_device() {
+    // Read and print a few random characters.
+    let mut ops = vec![];
+    for _ in 0..8 {
+        ops.push(CoreOp::Get(Input::stdin_char()));
+        ops.push(CoreOp::Put(Output::stdout_char()));
+    }
+    let program = CoreProgram(ops);
+    let run = |seed| {
+        CoreInterpreter::new(RandomDevice::new(TestingDevice::default(), seed))
+            .run(&program)
+            .unwrap()
+            .into_inner()
+            .output_vals()
+    };
+
+    // The same seed always gives the same input.
+    assert_eq!(run(42), run(42));
+    assert_ne!(run(42), run(43));
+    assert!(run(42).iter().all(|n| (0..256).contains(n)));
+}
+
+#[test]
+fn test_profile() {
+    // Count down from 3 to 0.
+    let program = CoreProgram(vec![
+        CoreOp::Set(-1),
+        CoreOp::Move(1),
+        CoreOp::Save,
+        CoreOp::Move(-1),
+        CoreOp::Set(3),
+        CoreOp::While,
+        CoreOp::Comment("loop".to_string()),
+        CoreOp::Move(1),
+        CoreOp::Add,
+        CoreOp::Move(-1),
+        CoreOp::End,
+    ]);
+
+    let mut vm = CoreInterpreter::new(TestingDevice::default());
+    vm.execute(&program).unwrap();
+    assert!(vm.profile().is_none());
+
+    let mut vm = CoreInterpreter::new(TestingDevice::default()).with_profile(true);
+    vm.execute(&program).unwrap();
+    let profile = vm.profile().unwrap();
+    // The loop body runs three times, jumping back from the `end` each time.
+    assert_eq!(profile["while"], 1);
+    assert_eq!(profile["end"], 3);
+    assert_eq!(profile["add"], 3);
+    assert_eq!(profile["mov"], 8);
+    assert!(!profile.contains_key("comment"));
+}
+
+#[test]
+fn test_trace() {
+    let dir = std::path::PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("trace");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut program = CoreProgram(vec![
+        CoreOp::Set(2),
+        CoreOp::Save,
+        CoreOp::Comment("skipped".to_string()),
+        CoreOp::Move(1),
+        CoreOp::Set(5),
+        CoreOp::Move(-1),
+        CoreOp::Add,
+    ]);
+    let trace = |limit: Option<usize>, program: &CoreProgram| {
+        let path = dir.join("trace.jsonl");
+        let trace = Trace::new(std::fs::File::create(&path).unwrap()).with_limit(limit);
+        let result = CoreInterpreter::new(TestingDevice::default())
+            .with_trace(Some(trace))
+            .run(program);
+        (result.is_ok(), std::fs::read_to_string(&path).unwrap())
+    };
+
+    // Every instruction other than comments is traced in order,
+    // with the register and the pointer before it ran.
+    assert_eq!(
+        trace(None, &program),
+        (
+            true,
+            [
+                r#"{"index": 0, "opcode": "set", "reg": 0, "ptr": 0}"#,
+                r#"{"index": 1, "opcode": "sav", "reg": 2, "ptr": 0}"#,
+                r#"{"index": 3, "opcode": "mov", "reg": 2, "ptr": 0}"#,
+                r#"{"index": 4, "opcode": "set", "reg": 2, "ptr": 1}"#,
+                r#"{"index": 5, "opcode": "mov", "reg": 5, "ptr": 1}"#,
+                r#"{"index": 6, "opcode": "add", "reg": 5, "ptr": 0}"#,
+                "",
+            ]
+            .join("\n")
+        )
+    );
+
+    // A limited trace keeps the last instructions, which are
+    // still written when the program fails.
+    program.0.push(CoreOp::Refer);
+    assert_eq!(
+        trace(Some(2), &program),
+        (
+            false,
+            [
+                r#"{"index": 6, "opcode": "add", "reg": 5, "ptr": 0}"#,
+                r#"{"index": 7, "opcode": "ref", "reg": 7, "ptr": 0}"#,
+                "",
+            ]
+            .join("\n")
+        )
+    );
+}
+
+#[test]
+fn test_cell_bits() {
+    let program = CoreProgram(vec![
+        // Add one to the largest 32-bit integer.
+        CoreOp::Set(1),
+        CoreOp::Save,
+        CoreOp::Set(i32::MAX as i64),
+        CoreOp::Add,
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Set(' ' as i64),
+        CoreOp::Put(Output::stdout_char()),
+        // Square 2^16.
+        CoreOp::Set(1 << 16),
+        CoreOp::Save,
+        CoreOp::Mul,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "2147483648 4294967296");
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .with_cell_bits(32)
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "-2147483648 0");
+
+    let device = StandardInterpreter::new(TestingDevice::default())
+        .with_cell_bits(32)
+        .run(&program.into())
+        .unwrap();
+    assert_eq!(device.output_str(), "-2147483648 0");
+}
+
+#[test]
+fn test_stderr() {
+    let program = parse_vm(
+        "set 79 put stdout.char
+         set 75 put stdout.char
+         set 33 put stderr.char
+         set -12 put stderr.int
+         set 10 put stdout.char
+         set 10 put stderr.char #1",
+    )
+    .unwrap()
+    .ok()
+    .unwrap();
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "OK\n");
+    assert_eq!(device.error_str(), "!-12\n");
+    // The channel is kept with each character.
+    assert_eq!(
+        device.error_output.last(),
+        Some(&(10, Output::new(OutputMode::StderrChar, 1)))
+    );
+
+    let device = StandardInterpreter::new(TestingDevice::default())
+        .run(&program.into())
+        .unwrap();
+    assert_eq!(device.output_str(), "OK\n");
+    assert_eq!(device.error_str(), "!-12\n");
+}
+
+#[test]
+fn test_trap_overflow() {
+    let overflow = |n: i64, op: CoreOp| {
+        CoreProgram(vec![
+            CoreOp::Set(n),
+            CoreOp::Save,
+            CoreOp::Set(i64::MAX - 1),
+            op,
+            CoreOp::Put(Output::stdout_int()),
+        ])
+    };
+
+    // Without the trap, the result wraps.
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&overflow(2, CoreOp::Add))
+        .unwrap();
+    assert_eq!(device.output_str(), i64::MIN.to_string());
+
+    // Results which fit are fine.
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .with_trap_overflow(true)
+        .run(&overflow(1, CoreOp::Add))
+        .unwrap();
+    assert_eq!(device.output_str(), i64::MAX.to_string());
+
+    for (n, op) in [(2, CoreOp::Add), (-2, CoreOp::Sub), (2, CoreOp::Mul)] {
+        let program = overflow(n, op);
+        let err = CoreInterpreter::new(TestingDevice::default())
+            .with_trap_overflow(true)
+            .run(&program)
+            .unwrap_err();
+        assert!(
+            err.starts_with("Instruction #3 overflowed a 64-bit cell"),
+            "{err}"
+        );
+
+        let err = StandardInterpreter::new(TestingDevice::default())
+            .with_trap_overflow(true)
+            .run(&program.into())
+            .unwrap_err();
+        assert!(
+            err.starts_with("Instruction #3 overflowed a 64-bit cell"),
+            "{err}"
+        );
+    }
+
+    // Overflowing a narrower cell is trapped too.
+    let program = CoreProgram(vec![
+        CoreOp::Set(1),
+        CoreOp::Save,
+        CoreOp::Set(i32::MAX as i64),
+        CoreOp::Add,
+    ]);
+    let err = CoreInterpreter::new(TestingDevice::default())
+        .with_cell_bits(32)
+        .with_trap_overflow(true)
+        .run(&program)
+        .unwrap_err();
+    assert_eq!(
+        err,
+        "Instruction #3 overflowed a 32-bit cell computing 2147483647 + 1"
+    );
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit() {
+    let program = StandardProgram(vec![
+        // Define a function that prints the cell, and doubles it.
+        StandardOp::CoreOp(CoreOp::Function),
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::CoreOp(CoreOp::Add),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Return),
+        StandardOp::CoreOp(CoreOp::End),
+        // Call it five times, starting with 1.
+        StandardOp::CoreOp(CoreOp::Set(1)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Set(5)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::CoreOp(CoreOp::Move(-1)),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Call),
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Set(-1)),
+        StandardOp::CoreOp(CoreOp::Add),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::End),
+        // Dividing by zero leaves the register unchanged, and dividing by -1 wraps.
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Set(7)),
+        StandardOp::CoreOp(CoreOp::Div),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::CoreOp(CoreOp::Set(-1)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Set(i64::MIN)),
+        StandardOp::CoreOp(CoreOp::Div),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::CoreOp(CoreOp::Rem),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        // Take the else branch.
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::If),
+        StandardOp::CoreOp(CoreOp::Set(1)),
+        StandardOp::CoreOp(CoreOp::Else),
+        StandardOp::CoreOp(CoreOp::Set(2)),
+        StandardOp::CoreOp(CoreOp::End),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        // Do some float arithmetic.
+        StandardOp::Set(2.0),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::Set(3.0),
+        StandardOp::Pow,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        StandardOp::Set(7.5),
+        StandardOp::Rem,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        StandardOp::Set(0.5),
+        StandardOp::Sin,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        StandardOp::Set(-2.7),
+        StandardOp::Div,
+        StandardOp::ToInt,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        StandardOp::ToFloat,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+        // Allocate a cell, and store a value in it through a pointer.
+        StandardOp::CoreOp(CoreOp::Set(4)),
+        StandardOp::Alloc,
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Deref),
+        StandardOp::CoreOp(CoreOp::Set(42)),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Refer),
+        StandardOp::CoreOp(CoreOp::Set(0)),
+        StandardOp::CoreOp(CoreOp::Deref),
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::CoreOp(CoreOp::Refer),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_int())),
+        // Echo a character.
+        StandardOp::CoreOp(CoreOp::Get(Input::stdin_char())),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+    ]);
+
+    let expected = StandardInterpreter::new(TestingDevice::new("x"))
+        .run(&program)
+        .unwrap();
+    let device = Jit::new(TestingDevice::new("x")).run(&program).unwrap();
+    assert_eq!(device.output, expected.output);
+    assert!(device
+        .output_str()
+        .starts_with("1248167-922337203685477580802"));
+
+    // Core programs are run as standard programs.
+    let program = CoreProgram(vec![
+        CoreOp::Set(72),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Set(105),
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+    let device = Jit::new(TestingDevice::default())
+        .run(&program.into())
+        .unwrap();
+    assert_eq!(device.output_str(), "Hi");
+
+    // Errors halt the program.
+    let run = |ops| Jit::new(TestingDevice::default()).run(&StandardProgram(ops));
+    assert_eq!(
+        run(vec![StandardOp::CoreOp(CoreOp::Refer)]).err().unwrap(),
+        "cannot Refer due to empty Deref stack"
+    );
+    assert_eq!(
+        run(vec![
+            StandardOp::CoreOp(CoreOp::Set(3)),
+            StandardOp::CoreOp(CoreOp::Call)
+        ])
+        .err()
+        .unwrap(),
+        "function 3 not defined"
+    );
+    assert!(run(vec![
+        StandardOp::CoreOp(CoreOp::Move(-1)),
+        StandardOp::CoreOp(CoreOp::Save)
+    ])
+    .is_err());
+    assert!(run(vec![StandardOp::CoreOp(CoreOp::Get(Input::stdin_char()))]).is_err());
+}
+
+/// A program with functions nested in other functions and in a loop, and blocks around them.
+const NESTED_FUNCTIONS: &str = "
+fun
+   fun
+      set 66 put stdout.char
+      ret
+   end
+   set 65 put stdout.char
+   ret
+end
+set 1 call
+set 0 call
+set 2 sav
+while
+   fun
+      res if set 89 else set 78 end put stdout.char
+      ret
+   end
+   set 2 call
+   res sav set -1 add sav
+end
+set 3 call
+fun
+   set 67 put stdout.char
+   ret
+end
+";
+
+#[test]
+fn test_flatten() {
+    let program = parse_vm(NESTED_FUNCTIONS).unwrap().ok().unwrap();
+    let expected = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap()
+        .output_str();
+    assert_eq!(expected, "BAYYC");
+
+    let flat = program.clone().flatten();
+    assert!(flat.validate().is_ok());
+    // Flattening a flat program doesn't change it.
+    assert!(flat.clone().flatten() == flat);
+    // All of the functions come first, and they're numbered in the order they're declared.
+    let functions = program.get_functions();
+    let mut indices = functions.keys().copied().collect::<Vec<_>>();
+    indices.sort();
+    assert_eq!(indices, [0, 1, 2, 3]);
+    let defined = (0..4)
+        .flat_map(|i| functions[&i].clone())
+        .collect::<Vec<_>>();
+    assert!(flat.0.starts_with(&defined));
+    assert!(flat.0[defined.len()..] == program.get_main()[..]);
+    assert!(!program.get_main().contains(&CoreOp::Function));
+    let output = CoreInterpreter::new(TestingDevice::default())
+        .run(&flat)
+        .unwrap()
+        .output_str();
+    assert_eq!(output, expected);
+
+    // Standard programs are flattened the same way.
+    let program: StandardProgram = program.into();
+    let flat = program.clone().flatten();
+    assert!(flat.validate().is_ok());
+    assert_eq!(program.get_functions().len(), 4);
+    let output = StandardInterpreter::new(TestingDevice::default())
+        .run(&flat)
+        .unwrap()
+        .output_str();
+    assert_eq!(output, expected);
+}